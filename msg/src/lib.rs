@@ -1,41 +1,1086 @@
 pub static DEFAULT_SERVER_PORT: &str = "12345";
 
+/// Bumped on every change of the messages, or of the binary framing, that
+/// older builds cannot read.
+pub const PROTOCOL_VERSION: u32 = 10;
+
 pub mod command {
     use serde;
 
+    /// Chosen by the client, usually incrementing, and repeated in the reply.
+    pub type RequestId = u64;
+
+    /// Every command is sent wrapped in a request.
+    #[derive(serde::Deserialize, serde::Serialize, Debug)]
+    pub struct Request {
+        pub id: RequestId,
+        pub command: Command,
+    }
+
     #[derive(serde::Deserialize, serde::Serialize, Debug)]
     pub enum Command {
+        /// Optional first message, answered with `Response::Hello` or with
+        /// `Response::IncompatibleProtocol` before closing the connection
+        Hello(Hello),
         Status,
+        /// Scan with the default parameters
         Replay,
+        StartScan(ScanParameters),
+        /// Aborts the running scan after the current step
+        Stop,
+        Pause,
+        Resume,
+        /// Lets other clients control the scanner. Control is taken by the
+        /// first client sending a scanner command and released on disconnection
+        ReleaseControl,
+        /// Encoding of the point clouds sent to this client
+        SetPointCloudFormat(PointCloudFormat),
+        /// Past and running scans, oldest first
+        ListSessions,
+        /// Scan metadata and merged point cloud, by session id
+        GetSession(String),
+        RenameSession {
+            id: String,
+            name: String,
+        },
+        DeleteSession(String),
+        /// Merged point cloud of a session as a file, answered with
+        /// `Response::File`
+        ExportSession {
+            id: String,
+            format: ExportFormat,
+            /// The mesh of the scanned surface instead of the points
+            #[serde(default)]
+            mesh: bool,
+        },
+        /// Triangle mesh of the scanned surface of a session, answered with
+        /// `Response::Mesh`
+        MeshSession(String),
+        /// Aligns session `id` onto session `onto`, e.g. a scan of the
+        /// flipped object onto the upright one. Both clouds are merged into a
+        /// new session, answered with `Response::Session`
+        RegisterSession {
+            id: String,
+            onto: String,
+        },
+    }
+
+    #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+    pub struct Hello {
+        /// `PROTOCOL_VERSION` of the client build
+        pub protocol_version: u32,
+        pub client_name: String,
+        pub capabilities: Vec<Capability>,
+    }
+
+    /// Optional protocol features, advertised by both sides in the handshake.
+    #[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Capability {
+        /// `PointCloudFormat::Binary`
+        BinaryPointCloud,
+    }
+
+    #[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum PointCloudFormat {
+        /// Text messages, handy for debugging with wscat
+        #[default]
+        Json,
+        /// Binary messages framed as described in `msg::binary`
+        Binary,
+    }
+
+    #[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ExportFormat {
+        PlyAscii,
+        /// Binary little-endian PLY, much smaller and faster to load
+        PlyBinary,
+        /// One point per line, coordinates only
+        Xyz,
+        /// Like XYZ with a header line and every point attribute
+        Csv,
+        /// Binary PCL point cloud
+        Pcd,
+        Obj,
+        /// Binary STL, meshes only
+        Stl,
+        /// Binary glTF
+        Glb,
+    }
+
+    impl ExportFormat {
+        pub const ALL: [ExportFormat; 8] = [
+            ExportFormat::PlyAscii,
+            ExportFormat::PlyBinary,
+            ExportFormat::Xyz,
+            ExportFormat::Csv,
+            ExportFormat::Pcd,
+            ExportFormat::Obj,
+            ExportFormat::Stl,
+            ExportFormat::Glb,
+        ];
+    }
+
+    #[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ScanLasers {
+        Left,
+        Right,
+        Both,
+    }
+
+    /// Turntable angles are relative to its position when the scan starts.
+    #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+    pub struct ScanParameters {
+        pub step_deg: f32,
+        pub start_deg: f32,
+        /// Excluded, so that a full turn does not scan the first angle twice
+        pub end_deg: f32,
+        /// Wait after each turntable rotation, before capturing
+        pub settle_ms: u64,
+        pub lasers: ScanLasers,
+        /// Captures a color frame with the lasers off at each step, after
+        /// the other frames, to color the points
+        #[serde(default)]
+        pub color: bool,
+    }
+
+    impl Default for ScanParameters {
+        fn default() -> Self {
+            Self {
+                step_deg: 5_f32,
+                start_deg: 0_f32,
+                end_deg: 360_f32,
+                settle_ms: 100,
+                lasers: ScanLasers::Both,
+                color: false,
+            }
+        }
+    }
+
+    impl ScanParameters {
+        pub fn steps(&self) -> u32 {
+            ((self.end_deg - self.start_deg) / self.step_deg).ceil() as u32
+        }
+
+        pub fn angle_deg(&self, step: u32) -> f32 {
+            self.start_deg + step as f32 * self.step_deg
+        }
+
+        pub fn validate(&self) -> Result<(), String> {
+            if !(self.step_deg > 0_f32 && self.step_deg.is_finite()) {
+                return Err(format!("step_deg must be positive, got {}", self.step_deg));
+            }
+            let finite = self.start_deg.is_finite() && self.end_deg.is_finite();
+            if !finite || self.start_deg >= self.end_deg {
+                return Err(format!(
+                    "start_deg {} must be lower than end_deg {}",
+                    self.start_deg, self.end_deg
+                ));
+            }
+            if self.end_deg - self.start_deg > 360_f32 {
+                return Err("the scan cannot cover more than 360 degrees".to_string());
+            }
+            Ok(())
+        }
     }
 }
 
 pub mod response {
+    use crate::command::RequestId;
     use serde;
 
-    #[derive(serde::Deserialize, serde::Serialize)]
+    /// Everything sent by the server, as JSON text. Point cloud events can
+    /// also be binary, see `msg::binary`.
+    #[derive(serde::Deserialize, serde::Serialize, Clone)]
+    pub enum ServerMessage {
+        /// Answer to a request. The id is missing when the request could not
+        /// be parsed
+        Reply {
+            id: Option<RequestId>,
+            response: Response,
+        },
+        /// Unsolicited, e.g. scan data and status changes
+        Event(Response),
+    }
+
+    #[derive(serde::Deserialize, serde::Serialize, Clone)]
     pub enum Response {
+        Hello(ServerHello),
+        /// The client protocol version is not supported, the server closes
+        /// the connection
+        IncompatibleProtocol(IncompatibleProtocol),
         Ok,
-        Error(String),
+        Error(ServerError),
         Close,
         Status(Status),
         PointCloud(PointCloud),
+        Progress(Progress),
+        Sessions(Vec<SessionInfo>),
+        Session(Session),
+        File(File),
+        Mesh(Mesh),
     }
 
-    #[derive(serde::Deserialize, serde::Serialize)]
+    /// Lets clients react to failures without parsing the messages.
+    #[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ErrorCode {
+        /// The request could not be parsed
+        InvalidCommand,
+        InvalidParameters,
+        /// A scan is already running
+        Busy,
+        /// The command is not allowed in the current scanner state, e.g.
+        /// pausing while idle
+        InvalidState,
+        /// Another client controls the scanner
+        NotInControl,
+        CameraNotFound,
+        /// The camera is misconfigured or failed to capture
+        CameraFault,
+        CalibrationInvalid,
+        MotorFault,
+        /// No scan session with the requested id
+        SessionNotFound,
+        /// Anything else, see the message
+        Internal,
+    }
+
+    #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+    pub struct ServerError {
+        pub code: ErrorCode,
+        /// Human readable, not meant to be parsed
+        pub message: String,
+        /// Underlying causes, when known
+        pub details: Option<String>,
+    }
+
+    impl ServerError {
+        pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+            Self {
+                code,
+                message: message.into(),
+                details: None,
+            }
+        }
+    }
+
+    impl std::error::Error for ServerError {}
+
+    impl std::fmt::Display for ServerError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}", self.message)?;
+            if let Some(details) = &self.details {
+                write!(f, ": {details}")?;
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+    pub struct ServerHello {
+        pub protocol_version: u32,
+        pub server_name: String,
+        pub capabilities: Vec<crate::command::Capability>,
+    }
+
+    #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+    pub struct IncompatibleProtocol {
+        pub server_version: u32,
+        pub client_version: u32,
+    }
+
+    #[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum ScannerState {
+        #[default]
+        Idle,
+        Scanning,
+        Paused,
+        /// Stop requested, the scan ends after the current step
+        Stopping,
+        /// The last scan failed, see `Status::last_error`
+        Error,
+    }
+
+    #[derive(serde::Deserialize, serde::Serialize, Clone, Default)]
     pub struct Status {
+        pub state: ScannerState,
         pub lasers: LasersData,
-        pub motor_speed: f32,
+        /// Turntable angle relative to its position at the start of the last
+        /// scan, in degrees
+        pub angle_deg: f32,
+        pub last_error: Option<ServerError>,
     }
 
-    #[derive(serde::Deserialize, serde::Serialize)]
+    #[derive(serde::Deserialize, serde::Serialize, Clone, Default)]
     pub struct LasersData {
         pub laser_1: bool,
         pub laser_2: bool,
     }
 
-    #[derive(serde::Deserialize, serde::Serialize)]
+    /// Points with optional per-point attributes, each one as long as
+    /// `points` when present.
+    #[derive(serde::Deserialize, serde::Serialize, Clone, Default, Debug, PartialEq)]
     pub struct PointCloud {
         pub points: Vec<glam::Vec3>,
+        /// Brightness of the laser peak, in [0, 1]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub intensities: Option<Vec<f32>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub normals: Option<Vec<glam::Vec3>>,
+        /// Laser that lit the point
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub lasers: Option<Vec<Laser>>,
+        /// Scan step that captured the point
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub steps: Option<Vec<u32>>,
+        /// Position of the laser peak in the camera image, before
+        /// undistortion, in pixels
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub pixels: Option<Vec<glam::Vec2>>,
+        /// Color of the point in the color frame of its step
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub colors: Option<Vec<[u8; 3]>>,
+    }
+
+    impl PointCloud {
+        /// Appends the points of `other`. The attributes missing from one of
+        /// the clouds are dropped, unless this one has no points yet.
+        pub fn append(&mut self, other: PointCloud) {
+            if self.points.is_empty() {
+                *self = other;
+                return;
+            }
+            fn extend<T>(values: &mut Option<Vec<T>>, more: Option<Vec<T>>) {
+                *values = match (values.take(), more) {
+                    (Some(mut values), Some(more)) => {
+                        values.extend(more);
+                        Some(values)
+                    }
+                    _ => None,
+                };
+            }
+            self.points.extend(other.points);
+            extend(&mut self.intensities, other.intensities);
+            extend(&mut self.normals, other.normals);
+            extend(&mut self.lasers, other.lasers);
+            extend(&mut self.steps, other.steps);
+            extend(&mut self.pixels, other.pixels);
+            extend(&mut self.colors, other.colors);
+        }
+
+        /// The points at `indices` with their attributes.
+        pub fn select(&self, indices: &[usize]) -> PointCloud {
+            fn select<T: Clone>(values: &[T], indices: &[usize]) -> Vec<T> {
+                indices.iter().map(|&i| values[i].clone()).collect()
+            }
+            PointCloud {
+                points: select(&self.points, indices),
+                intensities: self.intensities.as_deref().map(|v| select(v, indices)),
+                normals: self.normals.as_deref().map(|v| select(v, indices)),
+                lasers: self.lasers.as_deref().map(|v| select(v, indices)),
+                steps: self.steps.as_deref().map(|v| select(v, indices)),
+                pixels: self.pixels.as_deref().map(|v| select(v, indices)),
+                colors: self.colors.as_deref().map(|v| select(v, indices)),
+            }
+        }
+    }
+
+    #[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Laser {
+        Left,
+        Right,
+    }
+
+    /// Sent after each scan step.
+    #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+    pub struct Progress {
+        /// Steps completed so far
+        pub step: u32,
+        pub total_steps: u32,
+        pub angle_deg: f32,
+        pub points_so_far: u64,
+        pub elapsed_ms: u64,
+        /// Estimated time to the end of the scan
+        pub eta_ms: u64,
+    }
+
+    /// Timestamps are milliseconds since the Unix epoch.
+    #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+    pub struct SessionInfo {
+        pub id: String,
+        pub name: String,
+        pub parameters: crate::command::ScanParameters,
+        pub started_at_ms: u64,
+        pub finished_at_ms: Option<u64>,
+        pub outcome: SessionOutcome,
+        pub steps: Vec<StepRecord>,
+        pub points: u64,
+        /// Set on the sessions merged by `Command::RegisterSession`, which
+        /// have no steps
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub registration: Option<Box<Registration>>,
+    }
+
+    #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+    pub enum SessionOutcome {
+        Running,
+        Completed,
+        Stopped,
+        Failed(ServerError),
+        /// The server exited during the scan
+        Interrupted,
+    }
+
+    /// How the points of a session were aligned onto another one.
+    #[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
+    pub struct Registration {
+        /// Session whose points were moved
+        pub source: String,
+        /// Session whose frame the merged cloud is in
+        pub target: String,
+        /// From the source to the target frame, column major
+        pub transform: glam::Mat4,
+        /// Fraction of the source points close to a target point after the
+        /// alignment
+        pub fitness: f32,
+        /// RMS distance of those points, in meters
+        pub rmse: f32,
+    }
+
+    /// A completed scan step.
+    #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+    pub struct StepRecord {
+        pub step: u32,
+        pub angle_deg: f32,
+        /// Points of the step in the merged cloud, the ones kept by the
+        /// post-processing once the scan has ended
+        pub points: u64,
+        pub captured_at_ms: u64,
+    }
+
+    /// A file for the client to save.
+    #[derive(serde::Deserialize, serde::Serialize, Clone)]
+    pub struct File {
+        /// Suggested file name
+        pub name: String,
+        /// Base64 encoded in JSON
+        #[serde(with = "base64_bytes")]
+        pub data: Vec<u8>,
+    }
+
+    mod base64_bytes {
+        use base64::Engine;
+
+        pub fn serialize<S: serde::Serializer>(data: &[u8], s: S) -> Result<S::Ok, S::Error> {
+            s.serialize_str(&base64::engine::general_purpose::STANDARD.encode(data))
+        }
+
+        pub fn deserialize<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+            let text = <String as serde::Deserialize>::deserialize(d)?;
+            base64::engine::general_purpose::STANDARD
+                .decode(text)
+                .map_err(serde::de::Error::custom)
+        }
+    }
+
+    #[derive(serde::Deserialize, serde::Serialize, Clone, Default)]
+    pub struct Mesh {
+        pub vertices: Vec<glam::Vec3>,
+        /// Vertex indices, counter-clockwise seen from outside
+        pub triangles: Vec<[u32; 3]>,
+    }
+
+    #[derive(serde::Deserialize, serde::Serialize, Clone)]
+    pub struct Session {
+        pub info: SessionInfo,
+        /// All the steps merged
+        pub cloud: PointCloud,
+    }
+}
+
+/// Compact encoding of `response::PointCloud` for WebSocket binary messages
+/// and session files.
+///
+/// A 12 bytes header is followed by the points, all values little-endian:
+/// - magic `b"PCLD"`
+/// - format version, `u8`
+/// - flags, `u8`: `HAS_INTENSITY`, `HAS_NORMALS`, `HAS_LASERS`, `HAS_STEPS`,
+///   `HAS_PIXELS` and `HAS_COLORS`
+/// - reserved, 2 bytes set to zero
+/// - number of points, `u32`
+///
+/// Each point is `x y z` as `f32`, followed by the attributes whose flag is
+/// set, in this order: the intensity as `f32`, the normal `nx ny nz` as
+/// `f32`, the laser as `u8` (0 left, 1 right), the step as `u32`, the
+/// pixel `u v` as `f32` and the color `r g b` as `u8`.
+pub mod binary {
+    use crate::response::{Laser, PointCloud};
+    use anyhow::{anyhow, bail, Result};
+
+    pub const MAGIC: [u8; 4] = *b"PCLD";
+    pub const VERSION: u8 = 1;
+    pub const HAS_INTENSITY: u8 = 0b000001;
+    pub const HAS_NORMALS: u8 = 0b000010;
+    pub const HAS_LASERS: u8 = 0b000100;
+    pub const HAS_STEPS: u8 = 0b001000;
+    pub const HAS_PIXELS: u8 = 0b010000;
+    pub const HAS_COLORS: u8 = 0b100000;
+    const ALL_FLAGS: u8 =
+        HAS_INTENSITY | HAS_NORMALS | HAS_LASERS | HAS_STEPS | HAS_PIXELS | HAS_COLORS;
+    const HEADER_SIZE: usize = 12;
+
+    pub fn encode_point_cloud(cloud: &PointCloud) -> Result<Vec<u8>> {
+        let count = cloud.points.len();
+        let mut flags = 0;
+        let attributes = [
+            (
+                "intensities",
+                HAS_INTENSITY,
+                cloud.intensities.as_ref().map(Vec::len),
+            ),
+            ("normals", HAS_NORMALS, cloud.normals.as_ref().map(Vec::len)),
+            ("lasers", HAS_LASERS, cloud.lasers.as_ref().map(Vec::len)),
+            ("steps", HAS_STEPS, cloud.steps.as_ref().map(Vec::len)),
+            ("pixels", HAS_PIXELS, cloud.pixels.as_ref().map(Vec::len)),
+            ("colors", HAS_COLORS, cloud.colors.as_ref().map(Vec::len)),
+        ];
+        for (name, flag, len) in attributes {
+            if let Some(len) = len {
+                check_len(name, len, count)?;
+                flags |= flag;
+            }
+        }
+
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + count * point_size(flags));
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&[VERSION, flags, 0, 0]);
+        bytes.extend_from_slice(&u32::try_from(count)?.to_le_bytes());
+        fn floats(bytes: &mut Vec<u8>, values: &[f32]) {
+            for value in values {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        for (i, point) in cloud.points.iter().enumerate() {
+            floats(&mut bytes, &point.to_array());
+            if let Some(intensities) = &cloud.intensities {
+                floats(&mut bytes, &[intensities[i]]);
+            }
+            if let Some(normals) = &cloud.normals {
+                floats(&mut bytes, &normals[i].to_array());
+            }
+            if let Some(lasers) = &cloud.lasers {
+                let laser: u8 = match lasers[i] {
+                    Laser::Left => 0,
+                    Laser::Right => 1,
+                };
+                bytes.push(laser);
+            }
+            if let Some(steps) = &cloud.steps {
+                bytes.extend_from_slice(&steps[i].to_le_bytes());
+            }
+            if let Some(pixels) = &cloud.pixels {
+                floats(&mut bytes, &pixels[i].to_array());
+            }
+            if let Some(colors) = &cloud.colors {
+                bytes.extend_from_slice(&colors[i]);
+            }
+        }
+        Ok(bytes)
+    }
+
+    pub fn decode_point_cloud(bytes: &[u8]) -> Result<PointCloud> {
+        if bytes.len() < HEADER_SIZE || bytes[0..4] != MAGIC {
+            bail!("not a binary point cloud");
+        }
+        let (version, flags) = (bytes[4], bytes[5]);
+        if version != VERSION {
+            bail!("unsupported binary point cloud version {version}, expected {VERSION}");
+        }
+        if flags & !ALL_FLAGS != 0 {
+            bail!("unknown binary point cloud flags {flags:#010b}");
+        }
+        let count = u32::from_le_bytes(bytes[8..12].try_into()?) as usize;
+        let expected_len = count
+            .checked_mul(point_size(flags))
+            .and_then(|size| size.checked_add(HEADER_SIZE))
+            .ok_or_else(|| anyhow!("binary point cloud too large: {count} points"))?;
+        if bytes.len() != expected_len {
+            bail!(
+                "binary point cloud of {count} points should be {expected_len} bytes, got {}",
+                bytes.len()
+            );
+        }
+
+        let has = |flag: u8| flags & flag != 0;
+        let mut cloud = PointCloud {
+            points: Vec::with_capacity(count),
+            intensities: has(HAS_INTENSITY).then(|| Vec::with_capacity(count)),
+            normals: has(HAS_NORMALS).then(|| Vec::with_capacity(count)),
+            lasers: has(HAS_LASERS).then(|| Vec::with_capacity(count)),
+            steps: has(HAS_STEPS).then(|| Vec::with_capacity(count)),
+            pixels: has(HAS_PIXELS).then(|| Vec::with_capacity(count)),
+            colors: has(HAS_COLORS).then(|| Vec::with_capacity(count)),
+        };
+        let mut rest = &bytes[HEADER_SIZE..];
+        let mut take = |n: usize| {
+            let (value, tail) = rest.split_at(n);
+            rest = tail;
+            value
+        };
+        let float = |b: &[u8]| f32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+        let vec3 = |b: &[u8]| glam::vec3(float(&b[0..4]), float(&b[4..8]), float(&b[8..12]));
+        for _ in 0..count {
+            cloud.points.push(vec3(take(12)));
+            if let Some(intensities) = &mut cloud.intensities {
+                intensities.push(float(take(4)));
+            }
+            if let Some(normals) = &mut cloud.normals {
+                normals.push(vec3(take(12)));
+            }
+            if let Some(lasers) = &mut cloud.lasers {
+                lasers.push(match take(1)[0] {
+                    0 => Laser::Left,
+                    1 => Laser::Right,
+                    laser => bail!("unknown laser {laser} in binary point cloud"),
+                });
+            }
+            if let Some(steps) = &mut cloud.steps {
+                steps.push(u32::from_le_bytes(take(4).try_into()?));
+            }
+            if let Some(pixels) = &mut cloud.pixels {
+                let pixel = take(8);
+                pixels.push(glam::vec2(float(&pixel[0..4]), float(&pixel[4..8])));
+            }
+            if let Some(colors) = &mut cloud.colors {
+                colors.push(take(3).try_into()?);
+            }
+        }
+        Ok(cloud)
+    }
+
+    fn point_size(flags: u8) -> usize {
+        let sizes = [
+            (HAS_INTENSITY, 4),
+            (HAS_NORMALS, 12),
+            (HAS_LASERS, 1),
+            (HAS_STEPS, 4),
+            (HAS_PIXELS, 8),
+            (HAS_COLORS, 3),
+        ];
+        12 + sizes
+            .iter()
+            .filter(|(flag, _)| flags & flag != 0)
+            .map(|(_, size)| size)
+            .sum::<usize>()
+    }
+
+    fn check_len(name: &str, len: usize, points: usize) -> Result<()> {
+        if len != points {
+            bail!("{len} {name} for {points} points");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::command::*;
+    use crate::response::*;
+    use crate::{binary, PROTOCOL_VERSION};
+    use glam::{Vec2, Vec3};
+
+    /// Checks that `value` is sent as `json` and that `json` reads back to
+    /// the same message.
+    fn assert_wire<T: serde::Serialize + serde::de::DeserializeOwned>(value: &T, json: &str) {
+        assert_eq!(serde_json::to_string(value).unwrap(), json);
+        let parsed: T = serde_json::from_str(json).unwrap();
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+    }
+
+    #[test]
+    fn commands_wire_format() {
+        let hello = Command::Hello(Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_name: "ui".to_string(),
+            capabilities: vec![Capability::BinaryPointCloud],
+        });
+        assert_wire(
+            &hello,
+            r#"{"Hello":{"protocol_version":10,"client_name":"ui","capabilities":["BinaryPointCloud"]}}"#,
+        );
+        assert_wire(&Command::Status, r#""Status""#);
+        assert_wire(&Command::Replay, r#""Replay""#);
+        assert_wire(
+            &Command::StartScan(ScanParameters::default()),
+            r#"{"StartScan":{"step_deg":5.0,"start_deg":0.0,"end_deg":360.0,"settle_ms":100,"lasers":"Both","color":false}}"#,
+        );
+        assert_wire(&Command::Stop, r#""Stop""#);
+        assert_wire(&Command::Pause, r#""Pause""#);
+        assert_wire(&Command::Resume, r#""Resume""#);
+        assert_wire(&Command::ReleaseControl, r#""ReleaseControl""#);
+        assert_wire(
+            &Command::SetPointCloudFormat(PointCloudFormat::Binary),
+            r#"{"SetPointCloudFormat":"Binary"}"#,
+        );
+        assert_wire(&Command::ListSessions, r#""ListSessions""#);
+        assert_wire(
+            &Command::GetSession("scan-1".to_string()),
+            r#"{"GetSession":"scan-1"}"#,
+        );
+        let rename = Command::RenameSession {
+            id: "scan-1".to_string(),
+            name: "mug".to_string(),
+        };
+        assert_wire(&rename, r#"{"RenameSession":{"id":"scan-1","name":"mug"}}"#);
+        assert_wire(
+            &Command::DeleteSession("scan-1".to_string()),
+            r#"{"DeleteSession":"scan-1"}"#,
+        );
+        let export = Command::ExportSession {
+            id: "scan-1".to_string(),
+            format: ExportFormat::PlyBinary,
+            mesh: true,
+        };
+        assert_wire(
+            &export,
+            r#"{"ExportSession":{"id":"scan-1","format":"PlyBinary","mesh":true}}"#,
+        );
+        // sent by clients of the previous version
+        let json = r#"{"ExportSession":{"id":"scan-1","format":"Stl"}}"#;
+        let Command::ExportSession { mesh, .. } = serde_json::from_str(json).unwrap() else {
+            panic!("not an export");
+        };
+        assert!(!mesh);
+        assert_wire(
+            &Command::MeshSession("scan-1".to_string()),
+            r#"{"MeshSession":"scan-1"}"#,
+        );
+        let register = Command::RegisterSession {
+            id: "scan-2".to_string(),
+            onto: "scan-1".to_string(),
+        };
+        assert_wire(
+            &register,
+            r#"{"RegisterSession":{"id":"scan-2","onto":"scan-1"}}"#,
+        );
+    }
+
+    #[test]
+    fn responses_wire_format() {
+        let hello = Response::Hello(ServerHello {
+            protocol_version: PROTOCOL_VERSION,
+            server_name: "server 0.1.0".to_string(),
+            capabilities: vec![Capability::BinaryPointCloud],
+        });
+        assert_wire(
+            &hello,
+            r#"{"Hello":{"protocol_version":10,"server_name":"server 0.1.0","capabilities":["BinaryPointCloud"]}}"#,
+        );
+        let incompatible = Response::IncompatibleProtocol(IncompatibleProtocol {
+            server_version: 1,
+            client_version: 2,
+        });
+        assert_wire(
+            &incompatible,
+            r#"{"IncompatibleProtocol":{"server_version":1,"client_version":2}}"#,
+        );
+        assert_wire(&Response::Ok, r#""Ok""#);
+        let error = ServerError {
+            code: ErrorCode::CameraNotFound,
+            message: "Camera not found".to_string(),
+            details: Some("no camera on the CSI port".to_string()),
+        };
+        assert_wire(
+            &Response::Error(error),
+            r#"{"Error":{"code":"CameraNotFound","message":"Camera not found","details":"no camera on the CSI port"}}"#,
+        );
+        assert_wire(&Response::Close, r#""Close""#);
+        assert_wire(
+            &Response::Status(Status::default()),
+            r#"{"Status":{"state":"Idle","lasers":{"laser_1":false,"laser_2":false},"angle_deg":0.0,"last_error":null}}"#,
+        );
+        let cloud = PointCloud {
+            points: vec![Vec3::new(1_f32, 2_f32, 3_f32)],
+            ..Default::default()
+        };
+        assert_wire(
+            &Response::PointCloud(cloud),
+            r#"{"PointCloud":{"points":[[1.0,2.0,3.0]]}}"#,
+        );
+        let cloud = PointCloud {
+            points: vec![Vec3::new(1_f32, 2_f32, 3_f32)],
+            intensities: Some(vec![0.5_f32]),
+            normals: Some(vec![Vec3::Z]),
+            lasers: Some(vec![Laser::Right]),
+            steps: Some(vec![4]),
+            pixels: Some(vec![Vec2::new(12.5_f32, 3_f32)]),
+            colors: Some(vec![[200, 30, 60]]),
+        };
+        assert_wire(
+            &Response::PointCloud(cloud),
+            r#"{"PointCloud":{"points":[[1.0,2.0,3.0]],"intensities":[0.5],"normals":[[0.0,0.0,1.0]],"lasers":["Right"],"steps":[4],"pixels":[[12.5,3.0]],"colors":[[200,30,60]]}}"#,
+        );
+        let progress = Progress {
+            step: 3,
+            total_steps: 72,
+            angle_deg: 15_f32,
+            points_so_far: 1200,
+            elapsed_ms: 900,
+            eta_ms: 20700,
+        };
+        assert_wire(
+            &Response::Progress(progress),
+            r#"{"Progress":{"step":3,"total_steps":72,"angle_deg":15.0,"points_so_far":1200,"elapsed_ms":900,"eta_ms":20700}}"#,
+        );
+        let info = SessionInfo {
+            id: "scan-1".to_string(),
+            name: "mug".to_string(),
+            parameters: ScanParameters::default(),
+            started_at_ms: 1000,
+            finished_at_ms: Some(2000),
+            outcome: SessionOutcome::Failed(ServerError::new(ErrorCode::MotorFault, "stalled")),
+            steps: vec![StepRecord {
+                step: 0,
+                angle_deg: 0_f32,
+                points: 1,
+                captured_at_ms: 1500,
+            }],
+            points: 1,
+            registration: None,
+        };
+        assert_wire(
+            &Response::Sessions(vec![info.clone()]),
+            r#"{"Sessions":[{"id":"scan-1","name":"mug","parameters":{"step_deg":5.0,"start_deg":0.0,"end_deg":360.0,"settle_ms":100,"lasers":"Both","color":false},"started_at_ms":1000,"finished_at_ms":2000,"outcome":{"Failed":{"code":"MotorFault","message":"stalled","details":null}},"steps":[{"step":0,"angle_deg":0.0,"points":1,"captured_at_ms":1500}],"points":1}]}"#,
+        );
+        let session = Session {
+            info: SessionInfo {
+                outcome: SessionOutcome::Completed,
+                steps: Vec::new(),
+                ..info.clone()
+            },
+            cloud: PointCloud {
+                points: vec![Vec3::X],
+                ..Default::default()
+            },
+        };
+        assert_wire(
+            &Response::Session(session),
+            r#"{"Session":{"info":{"id":"scan-1","name":"mug","parameters":{"step_deg":5.0,"start_deg":0.0,"end_deg":360.0,"settle_ms":100,"lasers":"Both","color":false},"started_at_ms":1000,"finished_at_ms":2000,"outcome":"Completed","steps":[],"points":1},"cloud":{"points":[[1.0,0.0,0.0]]}}}"#,
+        );
+        let registered = SessionInfo {
+            registration: Some(Box::new(Registration {
+                source: "scan-2".to_string(),
+                target: "scan-1".to_string(),
+                transform: glam::Mat4::from_translation(Vec3::Z),
+                fitness: 0.5_f32,
+                rmse: 0.001_f32,
+            })),
+            ..info
+        };
+        let json = serde_json::to_string(&registered).unwrap();
+        assert!(json.contains(r#""registration":{"source":"scan-2","target":"scan-1","transform":[1.0,0.0,0.0,0.0,0.0,1.0,0.0,0.0,0.0,0.0,1.0,0.0,0.0,0.0,1.0,1.0],"fitness":0.5,"rmse":0.001}"#));
+        let decoded: SessionInfo = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.registration, registered.registration);
+        let file = File {
+            name: "mug.ply".to_string(),
+            data: b"ply\n".to_vec(),
+        };
+        assert_wire(
+            &Response::File(file),
+            r#"{"File":{"name":"mug.ply","data":"cGx5Cg=="}}"#,
+        );
+        let mesh = Mesh {
+            vertices: vec![Vec3::ZERO, Vec3::X, Vec3::Y],
+            triangles: vec![[0, 1, 2]],
+        };
+        assert_wire(
+            &Response::Mesh(mesh),
+            r#"{"Mesh":{"vertices":[[0.0,0.0,0.0],[1.0,0.0,0.0],[0.0,1.0,0.0]],"triangles":[[0,1,2]]}}"#,
+        );
+    }
+
+    #[test]
+    fn envelopes_wire_format() {
+        let request = Request {
+            id: 7,
+            command: Command::Status,
+        };
+        assert_wire(&request, r#"{"id":7,"command":"Status"}"#);
+        let reply = ServerMessage::Reply {
+            id: Some(7),
+            response: Response::Ok,
+        };
+        assert_wire(&reply, r#"{"Reply":{"id":7,"response":"Ok"}}"#);
+        let reply = ServerMessage::Reply {
+            id: None,
+            response: Response::Error(ServerError::new(ErrorCode::InvalidCommand, "oops")),
+        };
+        assert_wire(
+            &reply,
+            r#"{"Reply":{"id":null,"response":{"Error":{"code":"InvalidCommand","message":"oops","details":null}}}}"#,
+        );
+        let event = ServerMessage::Event(Response::Close);
+        assert_wire(&event, r#"{"Event":"Close"}"#);
+    }
+
+    #[test]
+    fn binary_point_cloud_layout() {
+        // the attributes of each point follow its position
+        let cloud = PointCloud {
+            points: vec![Vec3::X, Vec3::Y],
+            intensities: Some(vec![0.5_f32, 0.25_f32]),
+            normals: Some(vec![Vec3::Z, Vec3::NEG_Z]),
+            ..Default::default()
+        };
+        let bytes = binary::encode_point_cloud(&cloud).unwrap();
+        let mut expected = b"PCLD".to_vec();
+        expected.extend_from_slice(&[binary::VERSION, 0b11, 0, 0, 2, 0, 0, 0]);
+        let floats = [
+            [1_f32, 0_f32, 0_f32, 0.5_f32, 0_f32, 0_f32, 1_f32],
+            [0_f32, 1_f32, 0_f32, 0.25_f32, 0_f32, 0_f32, -1_f32],
+        ];
+        for value in floats.iter().flatten() {
+            expected.extend_from_slice(&value.to_le_bytes());
+        }
+        assert_eq!(bytes, expected);
+
+        // an empty cloud is a bare header
+        let empty = binary::encode_point_cloud(&PointCloud::default()).unwrap();
+        assert_eq!(
+            empty,
+            [b'P', b'C', b'L', b'D', binary::VERSION, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            binary::decode_point_cloud(&empty).unwrap(),
+            PointCloud::default()
+        );
+    }
+
+    #[test]
+    fn scan_parameters_are_validated() {
+        let params = ScanParameters::default();
+        assert_eq!(params.validate(), Ok(()));
+        assert_eq!(params.steps(), 72);
+
+        let with = |step_deg: f32, start_deg: f32, end_deg: f32| ScanParameters {
+            step_deg,
+            start_deg,
+            end_deg,
+            ..Default::default()
+        };
+        for step_deg in [0_f32, -5_f32, f32::NAN, f32::INFINITY] {
+            assert!(with(step_deg, 0_f32, 360_f32).validate().is_err());
+        }
+        assert!(with(5_f32, 90_f32, 10_f32).validate().is_err());
+        assert!(with(5_f32, 90_f32, 90_f32).validate().is_err());
+        assert!(with(5_f32, f32::NEG_INFINITY, 90_f32).validate().is_err());
+        assert!(with(5_f32, -10_f32, 360_f32).validate().is_err());
+        assert_eq!(with(5_f32, -180_f32, 180_f32).validate(), Ok(()));
+        // a step larger than the range scans its start only
+        assert_eq!(with(90_f32, 0_f32, 10_f32).validate(), Ok(()));
+        assert_eq!(with(90_f32, 0_f32, 10_f32).steps(), 1);
+    }
+
+    #[test]
+    fn scan_steps_cover_the_range_once() {
+        let params = ScanParameters {
+            step_deg: 3_f32,
+            start_deg: 10_f32,
+            end_deg: 20_f32,
+            ..Default::default()
+        };
+        let angles: Vec<f32> = (0..params.steps()).map(|i| params.angle_deg(i)).collect();
+        assert_eq!(angles, [10_f32, 13_f32, 16_f32, 19_f32]);
+
+        // the end, which is the start again, is never scanned twice
+        for step_deg in [0.1_f32, 0.15, 0.3, 0.45, 0.7, 0.9, 1.1, 2.4, 7.2] {
+            let params = ScanParameters {
+                step_deg,
+                ..Default::default()
+            };
+            let last = params.angle_deg(params.steps() - 1);
+            assert!(
+                last < 360_f32 - 1e-3_f32 && last + step_deg >= 360_f32 - 1e-3_f32,
+                "last angle {last} for steps of {step_deg}"
+            );
+        }
+    }
+
+    #[test]
+    fn binary_point_cloud_round_trip() {
+        let cloud = PointCloud {
+            points: vec![Vec3::new(0.1_f32, 0.2_f32, 0.3_f32), Vec3::ZERO],
+            intensities: Some(vec![0.1_f32, 1_f32]),
+            normals: Some(vec![Vec3::X, Vec3::NEG_Y]),
+            ..Default::default()
+        };
+        let bytes = binary::encode_point_cloud(&cloud).unwrap();
+        assert_eq!(bytes.len(), 12 + 2 * 7 * 4);
+        assert_eq!(bytes[5], binary::HAS_INTENSITY | binary::HAS_NORMALS);
+        assert_eq!(binary::decode_point_cloud(&bytes).unwrap(), cloud);
+
+        let cloud = PointCloud {
+            lasers: Some(vec![Laser::Left, Laser::Right]),
+            steps: Some(vec![0, 71]),
+            pixels: Some(vec![Vec2::new(1640.25_f32, 3.5_f32), Vec2::ZERO]),
+            colors: Some(vec![[255, 0, 10], [0, 128, 0]]),
+            ..cloud
+        };
+        let bytes = binary::encode_point_cloud(&cloud).unwrap();
+        assert_eq!(bytes.len(), 12 + 2 * (7 * 4 + 1 + 4 + 2 * 4 + 3));
+        assert_eq!(binary::decode_point_cloud(&bytes).unwrap(), cloud);
+        let mut wrong_laser = bytes.clone();
+        wrong_laser[12 + 7 * 4] = 2;
+        assert!(binary::decode_point_cloud(&wrong_laser).is_err());
+    }
+
+    #[test]
+    fn point_clouds_are_appended_and_selected() {
+        let mut cloud = PointCloud::default();
+        cloud.append(PointCloud {
+            points: vec![Vec3::X, Vec3::Y],
+            lasers: Some(vec![Laser::Left, Laser::Right]),
+            steps: Some(vec![0, 0]),
+            ..Default::default()
+        });
+        cloud.append(PointCloud {
+            points: vec![Vec3::Z],
+            steps: Some(vec![1]),
+            ..Default::default()
+        });
+        assert_eq!(cloud.points, vec![Vec3::X, Vec3::Y, Vec3::Z]);
+        assert_eq!(cloud.steps, Some(vec![0, 0, 1]));
+        assert!(cloud.lasers.is_none());
+        let selected = cloud.select(&[2, 0]);
+        assert_eq!(selected.points, vec![Vec3::Z, Vec3::X]);
+        assert_eq!(selected.steps, Some(vec![1, 0]));
+    }
+
+    #[test]
+    fn binary_point_cloud_errors() {
+        let cloud = PointCloud {
+            points: vec![Vec3::ONE, Vec3::ONE],
+            intensities: Some(vec![1_f32]),
+            ..Default::default()
+        };
+        assert!(binary::encode_point_cloud(&cloud).is_err());
+
+        let cloud = PointCloud {
+            points: vec![Vec3::ONE],
+            ..Default::default()
+        };
+        let bytes = binary::encode_point_cloud(&cloud).unwrap();
+        assert!(binary::decode_point_cloud(&bytes[..bytes.len() - 1]).is_err());
+        assert!(binary::decode_point_cloud(b"not a point cloud").is_err());
+        let mut newer = bytes.clone();
+        newer[4] = binary::VERSION + 1;
+        assert!(binary::decode_point_cloud(&newer).is_err());
+
+        // flags from a newer format and counts the frame cannot hold
+        let mut unknown_flag = bytes.clone();
+        unknown_flag[5] = 0b1000_0000;
+        assert!(binary::decode_point_cloud(&unknown_flag).is_err());
+        let mut too_many = bytes.clone();
+        too_many[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(binary::decode_point_cloud(&too_many).is_err());
     }
 }
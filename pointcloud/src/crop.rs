@@ -0,0 +1,74 @@
+use glam::Vec3;
+
+/// Axis aligned box, bounds included.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BoundingBox {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl BoundingBox {
+    pub fn contains(&self, p: Vec3) -> bool {
+        p.cmpge(self.min).all() && p.cmple(self.max).all()
+    }
+}
+
+/// Cylinder standing on `base`, its disc center, along `axis`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cylinder {
+    pub base: Vec3,
+    pub axis: Vec3,
+    pub radius: f32,
+    pub height: f32,
+}
+
+impl Cylinder {
+    pub fn contains(&self, p: Vec3) -> bool {
+        let axis = self.axis.normalize();
+        let relative = p - self.base;
+        let height = relative.dot(axis);
+        let radius = (relative - height * axis).length();
+        (0_f32..=self.height).contains(&height) && radius <= self.radius
+    }
+}
+
+pub fn crop_box(points: &[Vec3], bounds: &BoundingBox) -> Vec<usize> {
+    (0..points.len())
+        .filter(|&i| bounds.contains(points[i]))
+        .collect()
+}
+
+/// With a cylinder standing slightly above the turntable, drops the
+/// turntable surface along with everything outside the scanned volume.
+pub fn crop_cylinder(points: &[Vec3], cylinder: &Cylinder) -> Vec<usize> {
+    (0..points.len())
+        .filter(|&i| cylinder.contains(points[i]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crops() {
+        let points = [
+            Vec3::new(0_f32, 0_f32, 0.001_f32),
+            Vec3::new(0.05_f32, 0_f32, 0.05_f32),
+            Vec3::new(0.2_f32, 0_f32, 0.05_f32),
+            Vec3::new(0_f32, 0.05_f32, 0.5_f32),
+        ];
+        let cylinder = Cylinder {
+            base: Vec3::new(0_f32, 0_f32, 0.005_f32),
+            axis: Vec3::Z,
+            radius: 0.1_f32,
+            height: 0.3_f32,
+        };
+        assert_eq!(crop_cylinder(&points, &cylinder), vec![1]);
+        let bounds = BoundingBox {
+            min: Vec3::ZERO,
+            max: Vec3::splat(0.1_f32),
+        };
+        assert_eq!(crop_box(&points, &bounds), vec![0, 1]);
+    }
+}
@@ -0,0 +1,138 @@
+use crate::KdTree;
+use glam::Vec3;
+use std::f32::consts::PI;
+
+const BINS: usize = 11;
+
+/// Fast Point Feature Histogram (Rusu et al., 2009): how the normals turn
+/// around a point, three histograms of 11 bins each summing to 100.
+pub type Fpfh = [f32; 3 * BINS];
+
+/// FPFH descriptor of every point, over its neighbours within `radius`. The
+/// normals must be oriented consistently, points with a zero normal or
+/// without neighbours get an empty histogram.
+pub fn fpfh(points: &[Vec3], normals: &[Vec3], radius: f32) -> Vec<Fpfh> {
+    let tree = KdTree::new(points);
+    let neighbours: Vec<Vec<usize>> = points
+        .iter()
+        .enumerate()
+        .map(|(i, p)| {
+            let mut found = tree.within(*p, radius);
+            found.retain(|&j| j != i && normals[j] != Vec3::ZERO);
+            found
+        })
+        .collect();
+    // the simplified histograms only look at the pairs with the point itself
+    let spfh: Vec<Fpfh> = (0..points.len())
+        .map(|i| {
+            let mut histogram = [0_f32; 3 * BINS];
+            if normals[i] == Vec3::ZERO || neighbours[i].is_empty() {
+                return histogram;
+            }
+            let weight = 100_f32 / neighbours[i].len() as f32;
+            for &j in &neighbours[i] {
+                let Some(features) = pair_features(points[i], normals[i], points[j], normals[j])
+                else {
+                    continue;
+                };
+                for (k, bin) in features.into_iter().enumerate() {
+                    histogram[k * BINS + bin] += weight;
+                }
+            }
+            histogram
+        })
+        .collect();
+    (0..points.len())
+        .map(|i| {
+            let mut histogram = spfh[i];
+            if neighbours[i].is_empty() {
+                return histogram;
+            }
+            let k = neighbours[i].len() as f32;
+            for &j in &neighbours[i] {
+                let weight = 1_f32 / (k * points[i].distance(points[j]).max(1e-6_f32));
+                for (h, s) in histogram.iter_mut().zip(spfh[j]) {
+                    *h += weight * s;
+                }
+            }
+            for sub in histogram.chunks_mut(BINS) {
+                let sum: f32 = sub.iter().sum();
+                if sum > 0_f32 {
+                    sub.iter_mut().for_each(|h| *h *= 100_f32 / sum);
+                }
+            }
+            histogram
+        })
+        .collect()
+}
+
+/// Bins of the angles of the Darboux frame between two oriented points, as
+/// in PCL: the frame starts from the normal making the smaller angle with
+/// the line between the points.
+fn pair_features(p1: Vec3, n1: Vec3, p2: Vec3, n2: Vec3) -> Option<[usize; 3]> {
+    let dp = p2 - p1;
+    let distance = dp.length();
+    if distance == 0_f32 {
+        return None;
+    }
+    let dp = dp / distance;
+    let (u, other, dp) = match n1.dot(dp).abs() >= n2.dot(dp).abs() {
+        true => (n1, n2, dp),
+        false => (n2, n1, -dp),
+    };
+    let cos_angle = u.dot(dp);
+    let v = dp.cross(u).try_normalize()?;
+    let w = u.cross(v);
+    let alpha = v.dot(other);
+    let theta = w.dot(other).atan2(u.dot(other));
+    let bin = |value: f32, min: f32, max: f32| {
+        (((value - min) / (max - min) * BINS as f32) as usize).min(BINS - 1)
+    };
+    Some([
+        bin(theta, -PI, PI),
+        bin(alpha, -1_f32, 1_f32),
+        bin(cos_angle, -1_f32, 1_f32),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histograms_tell_surfaces_apart() {
+        // a plane and a sphere with the same spacing
+        let plane: Vec<Vec3> = (0..400)
+            .map(|i| {
+                Vec3::new(
+                    (i % 20) as f32 * 0.01_f32,
+                    (i / 20) as f32 * 0.01_f32,
+                    0_f32,
+                )
+            })
+            .collect();
+        let plane_normals = vec![Vec3::Z; plane.len()];
+        let sphere: Vec<Vec3> = crate::sphere_directions(400)
+            .into_iter()
+            .map(|d| 0.05_f32 * d)
+            .collect();
+        let sphere_normals: Vec<Vec3> = sphere.iter().map(|p| p.normalize()).collect();
+
+        let flat = fpfh(&plane, &plane_normals, 0.025_f32);
+        let round = fpfh(&sphere, &sphere_normals, 0.025_f32);
+        for histogram in flat.iter().chain(&round) {
+            for sub in histogram.chunks(BINS) {
+                assert!((sub.iter().sum::<f32>() - 100_f32).abs() < 1e-3_f32);
+            }
+        }
+        let distance =
+            |a: &Fpfh, b: &Fpfh| -> f32 { a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum() };
+        // the middle of the plane looks like the rest of the plane
+        let middle = &flat[210];
+        assert!(distance(middle, &flat[0]) < distance(middle, &round[0]));
+        assert_eq!(
+            fpfh(&plane[..1], &plane_normals[..1], 0.025_f32),
+            vec![[0_f32; 33]]
+        );
+    }
+}
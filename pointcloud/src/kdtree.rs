@@ -0,0 +1,175 @@
+use glam::Vec3;
+
+/// Balanced k-d tree over borrowed points, for nearest neighbour and radius
+/// queries.
+///
+/// The tree is implicit: `order` holds the point indices so that in every
+/// range the middle point splits the others along the axis of its depth.
+pub struct KdTree<'a> {
+    points: &'a [Vec3],
+    order: Vec<u32>,
+}
+
+impl<'a> KdTree<'a> {
+    pub fn new(points: &'a [Vec3]) -> Self {
+        let mut order: Vec<u32> = (0..points.len() as u32).collect();
+        build(points, &mut order, 0);
+        Self { points, order }
+    }
+
+    /// The `k` points nearest to `p`, closest first, with their distances.
+    /// `p` itself is included when it is one of the points.
+    pub fn nearest(&self, p: Vec3, k: usize) -> Vec<(usize, f32)> {
+        let mut best = Vec::with_capacity(k + 1);
+        if k > 0 {
+            self.search_nearest(&self.order, 0, p, k, &mut best);
+        }
+        best.into_iter()
+            .map(|(d2, i): (f32, u32)| (i as usize, d2.sqrt()))
+            .collect()
+    }
+
+    /// Indices of the points closer than `radius` to `p`, in no particular
+    /// order.
+    pub fn within(&self, p: Vec3, radius: f32) -> Vec<usize> {
+        let mut found = Vec::new();
+        self.search_within(&self.order, 0, p, radius, &mut found);
+        found
+    }
+
+    /// `best` is sorted by squared distance and holds at most `k` points.
+    fn search_nearest(
+        &self,
+        order: &[u32],
+        depth: usize,
+        p: Vec3,
+        k: usize,
+        best: &mut Vec<(f32, u32)>,
+    ) {
+        if order.is_empty() {
+            return;
+        }
+        let middle = order.len() / 2;
+        let index = order[middle];
+        let q = self.points[index as usize];
+        let d2 = q.distance_squared(p);
+        if best.len() < k || d2 < best[best.len() - 1].0 {
+            let at = best.partition_point(|&(other, _)| other <= d2);
+            best.insert(at, (d2, index));
+            best.truncate(k);
+        }
+
+        let axis = depth % 3;
+        let offset = p[axis] - q[axis];
+        let (near, far) = match offset < 0_f32 {
+            true => (&order[..middle], &order[middle + 1..]),
+            false => (&order[middle + 1..], &order[..middle]),
+        };
+        self.search_nearest(near, depth + 1, p, k, best);
+        if best.len() < k || offset * offset < best[best.len() - 1].0 {
+            self.search_nearest(far, depth + 1, p, k, best);
+        }
+    }
+
+    fn search_within(
+        &self,
+        order: &[u32],
+        depth: usize,
+        p: Vec3,
+        radius: f32,
+        found: &mut Vec<usize>,
+    ) {
+        if order.is_empty() {
+            return;
+        }
+        let middle = order.len() / 2;
+        let index = order[middle];
+        let q = self.points[index as usize];
+        if q.distance_squared(p) < radius * radius {
+            found.push(index as usize);
+        }
+
+        let axis = depth % 3;
+        let offset = p[axis] - q[axis];
+        if offset < radius {
+            self.search_within(&order[..middle], depth + 1, p, radius, found);
+        }
+        if offset > -radius {
+            self.search_within(&order[middle + 1..], depth + 1, p, radius, found);
+        }
+    }
+}
+
+fn build(points: &[Vec3], order: &mut [u32], depth: usize) {
+    if order.len() <= 1 {
+        return;
+    }
+    let middle = order.len() / 2;
+    let axis = depth % 3;
+    order.select_nth_unstable_by(middle, |&a, &b| {
+        points[a as usize][axis].total_cmp(&points[b as usize][axis])
+    });
+    let (lower, upper) = order.split_at_mut(middle);
+    build(points, lower, depth + 1);
+    build(points, &mut upper[1..], depth + 1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic points spread over a cube.
+    fn points(n: usize) -> Vec<Vec3> {
+        let mut state = 12345_u32;
+        let mut random = move || {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 8) as f32 / (1 << 24) as f32
+        };
+        (0..n)
+            .map(|_| Vec3::new(random(), random(), random()))
+            .collect()
+    }
+
+    #[test]
+    fn queries_match_brute_force() {
+        let points = points(2000);
+        let tree = KdTree::new(&points);
+        for query in [
+            Vec3::splat(0.5_f32),
+            Vec3::ZERO,
+            Vec3::new(1.2_f32, 0.3_f32, -0.1_f32),
+        ] {
+            let mut expected: Vec<(usize, f32)> = points
+                .iter()
+                .enumerate()
+                .map(|(i, p)| (i, p.distance(query)))
+                .collect();
+            expected.sort_by(|a, b| a.1.total_cmp(&b.1));
+            expected.truncate(10);
+            assert_eq!(tree.nearest(query, 10), expected);
+
+            let radius = 0.15_f32;
+            let mut within = tree.within(query, radius);
+            within.sort();
+            let expected: Vec<usize> = (0..points.len())
+                .filter(|&i| points[i].distance_squared(query) < radius * radius)
+                .collect();
+            assert_eq!(within, expected);
+        }
+    }
+
+    #[test]
+    fn small_trees() {
+        let empty = KdTree::new(&[]);
+        assert!(empty.nearest(Vec3::ZERO, 3).is_empty());
+        assert!(empty.within(Vec3::ZERO, 1_f32).is_empty());
+
+        let points = [Vec3::X, Vec3::Y];
+        let tree = KdTree::new(&points);
+        assert_eq!(
+            tree.nearest(Vec3::X, 5),
+            vec![(0, 0_f32), (1, 2_f32.sqrt())]
+        );
+        assert!(tree.nearest(Vec3::X, 0).is_empty());
+    }
+}
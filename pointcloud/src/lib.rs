@@ -0,0 +1,129 @@
+//! Point cloud operations: neighbour queries, downsampling, outlier removal,
+//! cropping, normal estimation and registration, and the small dense linear
+//! algebra they rely on.
+//!
+//! Filters return the indices of the points they keep, in increasing order,
+//! so that callers can carry per-point attributes along with `select`.
+
+mod crop;
+mod features;
+mod kdtree;
+pub mod linalg;
+mod normals;
+mod outliers;
+mod registration;
+mod voxel;
+
+pub use crop::{crop_box, crop_cylinder, BoundingBox, Cylinder};
+pub use features::{fpfh, Fpfh};
+pub use kdtree::KdTree;
+pub use normals::{estimate_normals, orient_normals};
+pub use outliers::{radius_outliers, statistical_outliers};
+pub use registration::{
+    coarse_alignment, evaluate, icp_point_to_plane, rigid_transform, Alignment,
+};
+pub use voxel::voxel_downsample;
+
+use glam::Vec3;
+
+/// A step of a processing pipeline.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    /// Keeps the points inside the box
+    CropBox(BoundingBox),
+    /// Keeps the points inside the cylinder
+    CropCylinder(Cylinder),
+    /// Drops the points whose mean distance to their `neighbours` nearest
+    /// points is more than `std_ratio` standard deviations above the average
+    StatisticalOutliers { neighbours: usize, std_ratio: f32 },
+    /// Drops the points with fewer than `min_neighbours` other points closer
+    /// than `radius`
+    RadiusOutliers { radius: f32, min_neighbours: usize },
+    /// Keeps one point per cubic voxel of side `voxel_size`
+    VoxelDownsample { voxel_size: f32 },
+}
+
+impl Filter {
+    pub fn apply(&self, points: &[Vec3]) -> Vec<usize> {
+        match *self {
+            Filter::CropBox(ref bounds) => crop_box(points, bounds),
+            Filter::CropCylinder(ref cylinder) => crop_cylinder(points, cylinder),
+            Filter::StatisticalOutliers {
+                neighbours,
+                std_ratio,
+            } => statistical_outliers(points, neighbours, std_ratio),
+            Filter::RadiusOutliers {
+                radius,
+                min_neighbours,
+            } => radius_outliers(points, radius, min_neighbours),
+            Filter::VoxelDownsample { voxel_size } => voxel_downsample(points, voxel_size),
+        }
+    }
+}
+
+/// Runs the filters one after the other, returns the indices of the points
+/// kept by all of them.
+pub fn apply_all(filters: &[Filter], points: &[Vec3]) -> Vec<usize> {
+    let mut kept: Vec<usize> = (0..points.len()).collect();
+    for filter in filters {
+        let remaining = select(points, &kept);
+        kept = filter
+            .apply(&remaining)
+            .into_iter()
+            .map(|i| kept[i])
+            .collect();
+    }
+    kept
+}
+
+/// The values at `indices`, e.g. the attributes of the points kept by a
+/// filter.
+pub fn select<T: Clone>(values: &[T], indices: &[usize]) -> Vec<T> {
+    indices.iter().map(|&i| values[i].clone()).collect()
+}
+
+/// `n` directions spread evenly over the unit sphere, along a golden angle
+/// spiral from one pole to the other.
+pub fn sphere_directions(n: usize) -> Vec<Vec3> {
+    let golden_angle = std::f32::consts::PI * (3_f32 - 5_f32.sqrt());
+    (0..n)
+        .map(|i| {
+            let z = 1_f32 - 2_f32 * (i as f32 + 0.5_f32) / n as f32;
+            let r = (1_f32 - z * z).sqrt();
+            let azimuth = golden_angle * i as f32;
+            Vec3::new(r * azimuth.cos(), r * azimuth.sin(), z)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_are_chained() {
+        let points: Vec<Vec3> = (0..10).map(|i| Vec3::new(i as f32, 0_f32, 0_f32)).collect();
+        let filters = [
+            Filter::CropBox(BoundingBox {
+                min: Vec3::splat(-0.5_f32),
+                max: Vec3::new(6.5_f32, 0.5_f32, 0.5_f32),
+            }),
+            Filter::VoxelDownsample { voxel_size: 2_f32 },
+        ];
+        // voxels [0, 2), [2, 4), [4, 6) and [6, 8), the first point is the
+        // nearest to the centroid of each pair
+        assert_eq!(apply_all(&filters, &points), vec![0, 2, 4, 6]);
+        assert_eq!(apply_all(&[], &points).len(), points.len());
+        assert_eq!(select(&["a", "b", "c"], &[0, 2]), vec!["a", "c"]);
+    }
+
+    #[test]
+    fn sphere_directions_are_spread_evenly() {
+        let directions = sphere_directions(500);
+        assert!(directions
+            .iter()
+            .all(|d| (d.length() - 1_f32).abs() < 1e-5_f32));
+        let mean = directions.iter().sum::<Vec3>() / directions.len() as f32;
+        assert!(mean.length() < 1e-2_f32);
+    }
+}
@@ -0,0 +1,259 @@
+//! Small dense linear algebra: the normals and the registration, and the
+//! calibration of the scanner. The problems solved here have at most a few
+//! hundred unknowns, so simplicity is preferred over speed.
+
+use std::ops::{Index, IndexMut};
+
+/// Row-major dense matrix.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Matrix {
+    rows: usize,
+    cols: usize,
+    data: Vec<f64>,
+}
+
+impl Matrix {
+    pub fn zeros(rows: usize, cols: usize) -> Self {
+        Self {
+            rows,
+            cols,
+            data: vec![0_f64; rows * cols],
+        }
+    }
+
+    pub fn identity(n: usize) -> Self {
+        let mut m = Self::zeros(n, n);
+        for i in 0..n {
+            m[(i, i)] = 1_f64;
+        }
+        m
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn column(&self, col: usize) -> Vec<f64> {
+        (0..self.rows).map(|r| self[(r, col)]).collect()
+    }
+
+    /// Computes `selfᵀ * self`.
+    pub fn gram(&self) -> Matrix {
+        let mut result = Matrix::zeros(self.cols, self.cols);
+        for r in 0..self.rows {
+            let row = &self.data[r * self.cols..(r + 1) * self.cols];
+            for i in 0..self.cols {
+                for j in i..self.cols {
+                    result[(i, j)] += row[i] * row[j];
+                }
+            }
+        }
+        for i in 0..self.cols {
+            for j in 0..i {
+                result[(i, j)] = result[(j, i)];
+            }
+        }
+        result
+    }
+
+    /// Computes `selfᵀ * v`.
+    pub fn transpose_mul_vec(&self, v: &[f64]) -> Vec<f64> {
+        let mut result = vec![0_f64; self.cols];
+        for (r, value) in v.iter().enumerate().take(self.rows) {
+            for (c, res) in result.iter_mut().enumerate() {
+                *res += self[(r, c)] * value;
+            }
+        }
+        result
+    }
+}
+
+impl<const R: usize, const C: usize> From<[[f64; C]; R]> for Matrix {
+    fn from(rows: [[f64; C]; R]) -> Self {
+        Self {
+            rows: R,
+            cols: C,
+            data: rows.concat(),
+        }
+    }
+}
+
+impl Index<(usize, usize)> for Matrix {
+    type Output = f64;
+
+    fn index(&self, (row, col): (usize, usize)) -> &f64 {
+        &self.data[row * self.cols + col]
+    }
+}
+
+impl IndexMut<(usize, usize)> for Matrix {
+    fn index_mut(&mut self, (row, col): (usize, usize)) -> &mut f64 {
+        &mut self.data[row * self.cols + col]
+    }
+}
+
+/// Eigen decomposition of a symmetric matrix with the cyclic Jacobi method.
+/// Returns the eigenvalues in ascending order and the matching unit
+/// eigenvectors as the columns of a matrix.
+pub fn symmetric_eigen(m: &Matrix) -> (Vec<f64>, Matrix) {
+    const MAX_SWEEPS: usize = 100;
+
+    let n = m.rows();
+    let mut a = m.clone();
+    let mut v = Matrix::identity(n);
+
+    for _ in 0..MAX_SWEEPS {
+        // relative to the diagonal, the matrices range from the covariance
+        // of a few millimeters of points to the normal equations in pixels
+        let mut off_diagonal = 0_f64;
+        let mut diagonal = 0_f64;
+        for i in 0..n {
+            diagonal += a[(i, i)] * a[(i, i)];
+            off_diagonal += (i + 1..n).map(|j| a[(i, j)] * a[(i, j)]).sum::<f64>();
+        }
+        if off_diagonal <= 1e-24_f64 * diagonal {
+            break;
+        }
+
+        for p in 0..n {
+            for q in p + 1..n {
+                if a[(p, q)] == 0_f64 {
+                    continue;
+                }
+                // rotation in the (p, q) plane cancelling a[p][q]
+                let theta = (a[(q, q)] - a[(p, p)]) / (2_f64 * a[(p, q)]);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1_f64).sqrt());
+                let c = 1_f64 / (t * t + 1_f64).sqrt();
+                let s = t * c;
+
+                for k in 0..n {
+                    let akp = a[(k, p)];
+                    let akq = a[(k, q)];
+                    a[(k, p)] = c * akp - s * akq;
+                    a[(k, q)] = s * akp + c * akq;
+                }
+                for k in 0..n {
+                    let apk = a[(p, k)];
+                    let aqk = a[(q, k)];
+                    a[(p, k)] = c * apk - s * aqk;
+                    a[(q, k)] = s * apk + c * aqk;
+                }
+                for k in 0..n {
+                    let vkp = v[(k, p)];
+                    let vkq = v[(k, q)];
+                    v[(k, p)] = c * vkp - s * vkq;
+                    v[(k, q)] = s * vkp + c * vkq;
+                }
+            }
+        }
+    }
+
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&i, &j| a[(i, i)].total_cmp(&a[(j, j)]));
+
+    let eigenvalues = order.iter().map(|&i| a[(i, i)]).collect();
+    let mut eigenvectors = Matrix::zeros(n, n);
+    for (col, &i) in order.iter().enumerate() {
+        for row in 0..n {
+            eigenvectors[(row, col)] = v[(row, i)];
+        }
+    }
+    (eigenvalues, eigenvectors)
+}
+
+/// Unit vector `x` minimizing `|A x|`, i.e. the least squares solution of the
+/// homogeneous system `A x = 0`.
+pub fn null_vector(a: &Matrix) -> Vec<f64> {
+    let (_, eigenvectors) = symmetric_eigen(&a.gram());
+    eigenvectors.column(0)
+}
+
+/// Solves the square system `m x = b` with Gaussian elimination and partial
+/// pivoting. Returns `None` if the matrix is singular.
+pub fn solve(m: &Matrix, b: &[f64]) -> Option<Vec<f64>> {
+    let n = m.rows();
+    let mut a = m.clone();
+    let mut x = b.to_vec();
+
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&i, &j| a[(i, col)].abs().total_cmp(&a[(j, col)].abs()))?;
+        if a[(pivot, col)].abs() < 1e-12 {
+            return None;
+        }
+        if pivot != col {
+            for k in 0..n {
+                let tmp = a[(col, k)];
+                a[(col, k)] = a[(pivot, k)];
+                a[(pivot, k)] = tmp;
+            }
+            x.swap(col, pivot);
+        }
+
+        for row in col + 1..n {
+            let factor = a[(row, col)] / a[(col, col)];
+            if factor == 0_f64 {
+                continue;
+            }
+            for k in col..n {
+                a[(row, k)] -= factor * a[(col, k)];
+            }
+            x[row] -= factor * x[col];
+        }
+    }
+
+    for row in (0..n).rev() {
+        let sum: f64 = (row + 1..n).map(|k| a[(row, k)] * x[k]).sum();
+        x[row] = (x[row] - sum) / a[(row, row)];
+    }
+    Some(x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eigen_decomposition() {
+        let a = Matrix::from([
+            [4_f64, 1_f64, 0_f64, 0.5_f64],
+            [1_f64, 3_f64, 0.2_f64, 0_f64],
+            [0_f64, 0.2_f64, 1_f64, 0.1_f64],
+            [0.5_f64, 0_f64, 0.1_f64, 2_f64],
+        ]);
+        let (values, vectors) = symmetric_eigen(&a);
+        assert!(values.windows(2).all(|v| v[0] <= v[1]));
+        for i in 0..4 {
+            for row in 0..4 {
+                let av: f64 = (0..4).map(|k| a[(row, k)] * vectors[(k, i)]).sum();
+                assert!((av - values[i] * vectors[(row, i)]).abs() < 1e-9_f64);
+            }
+        }
+    }
+
+    #[test]
+    fn linear_systems() {
+        let a = Matrix::from([
+            [0_f64, 2_f64, 1_f64],
+            [1_f64, 1_f64, 0_f64],
+            [3_f64, 0_f64, 1_f64],
+        ]);
+        let x = solve(&a, &[5_f64, 3_f64, 4_f64]).unwrap();
+        for (xi, expected) in x.iter().zip([1_f64, 2_f64, 1_f64]) {
+            assert!((xi - expected).abs() < 1e-12_f64);
+        }
+        let singular = Matrix::from([[1_f64, 2_f64], [2_f64, 4_f64]]);
+        assert!(solve(&singular, &[1_f64, 2_f64]).is_none());
+    }
+
+    #[test]
+    fn null_vectors() {
+        // the rows are orthogonal to (1, -2, 1)
+        let a = Matrix::from([[1_f64, 1_f64, 1_f64], [2_f64, 1_f64, 0_f64]]);
+        let x = null_vector(&a);
+        let expected = [1_f64, -2_f64, 1_f64].map(|v| v / 6_f64.sqrt());
+        let sign = x[0].signum();
+        for (xi, e) in x.iter().zip(expected) {
+            assert!((sign * xi - e).abs() < 1e-9_f64);
+        }
+    }
+}
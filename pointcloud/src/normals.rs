@@ -0,0 +1,96 @@
+use crate::linalg;
+use crate::KdTree;
+use glam::Vec3;
+
+/// Unit normal of every point, from a principal component analysis of its
+/// `neighbours` nearest points: the normal is the direction in which the
+/// neighbourhood varies the least. The sign is arbitrary, see
+/// `orient_normals`. Points with fewer than 3 neighbours get a zero normal.
+pub fn estimate_normals(points: &[Vec3], neighbours: usize) -> Vec<Vec3> {
+    let tree = KdTree::new(points);
+    points
+        .iter()
+        .map(|p| {
+            // the nearest point is the point itself
+            let nearest = tree.nearest(*p, neighbours + 1);
+            if nearest.len() < 3 {
+                return Vec3::ZERO;
+            }
+            let n = nearest.len() as f64;
+            let centroid = nearest.iter().map(|&(i, _)| points[i]).sum::<Vec3>() / n as f32;
+            let mut covariance = [[0_f64; 3]; 3];
+            for &(i, _) in &nearest {
+                let d = (points[i] - centroid).as_dvec3().to_array();
+                for (row, a) in covariance.iter_mut().zip(d) {
+                    for (c, b) in row.iter_mut().zip(d) {
+                        *c += a * b / n;
+                    }
+                }
+            }
+            // the eigenvector of the smallest eigenvalue
+            let (_, vectors) = linalg::symmetric_eigen(&covariance.into());
+            glam::DVec3::from_slice(&vectors.column(0))
+                .as_vec3()
+                .normalize_or_zero()
+        })
+        .collect()
+}
+
+/// Flips the normals that face away from the viewpoint their point was seen
+/// from, so that they all point out of the scanned surface.
+pub fn orient_normals(normals: &mut [Vec3], points: &[Vec3], viewpoints: &[Vec3]) {
+    for ((normal, p), viewpoint) in normals.iter_mut().zip(points).zip(viewpoints) {
+        if normal.dot(*viewpoint - *p) < 0_f32 {
+            *normal = -*normal;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn planes_have_their_normal() {
+        // a slanted 10x10 grid with 1 cm spacing
+        let normal = Vec3::new(0_f32, -1_f32, 1_f32).normalize();
+        let points: Vec<Vec3> = (0..100)
+            .map(|i| {
+                let (x, y) = ((i % 10) as f32 * 0.01_f32, (i / 10) as f32 * 0.01_f32);
+                Vec3::new(x, y, y)
+            })
+            .collect();
+        let mut normals = estimate_normals(&points, 8);
+        let viewpoints = vec![Vec3::new(0_f32, -1_f32, 1_f32); points.len()];
+        orient_normals(&mut normals, &points, &viewpoints);
+        for n in normals {
+            assert!(n.distance(normal) < 1e-4_f32, "{n}");
+        }
+        assert_eq!(estimate_normals(&points[..2], 8), vec![Vec3::ZERO; 2]);
+    }
+
+    #[test]
+    fn sphere_normals_are_radial() {
+        let points: Vec<Vec3> = (0..20)
+            .flat_map(|i| {
+                let polar = (i as f32 + 0.5_f32) / 20_f32 * std::f32::consts::PI;
+                (0..40).map(move |j| {
+                    let azimuth = j as f32 / 40_f32 * std::f32::consts::TAU;
+                    0.1_f32
+                        * Vec3::new(
+                            polar.sin() * azimuth.cos(),
+                            polar.sin() * azimuth.sin(),
+                            polar.cos(),
+                        )
+                })
+            })
+            .collect();
+        let mut normals = estimate_normals(&points, 10);
+        // seen from outside, along each point
+        let viewpoints: Vec<Vec3> = points.iter().map(|p| 10_f32 * *p).collect();
+        orient_normals(&mut normals, &points, &viewpoints);
+        for (n, p) in normals.iter().zip(&points) {
+            assert!(n.dot(p.normalize()) > 0.98_f32, "{n} at {p}");
+        }
+    }
+}
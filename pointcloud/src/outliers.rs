@@ -0,0 +1,84 @@
+use crate::KdTree;
+use glam::Vec3;
+
+/// Keeps the points whose mean distance to their `neighbours` nearest points
+/// is at most `std_ratio` standard deviations above the mean over the cloud,
+/// like the filter of the same name in PCL. Isolated reflections and
+/// background hits are far from everything else.
+pub fn statistical_outliers(points: &[Vec3], neighbours: usize, std_ratio: f32) -> Vec<usize> {
+    if points.len() <= neighbours || neighbours == 0 {
+        return (0..points.len()).collect();
+    }
+    let tree = KdTree::new(points);
+    let mean_distances: Vec<f32> = points
+        .iter()
+        .map(|p| {
+            // the nearest point is the point itself
+            let nearest = tree.nearest(*p, neighbours + 1);
+            nearest[1..].iter().map(|(_, d)| d).sum::<f32>() / neighbours as f32
+        })
+        .collect();
+    let n = points.len() as f32;
+    let mean = mean_distances.iter().sum::<f32>() / n;
+    let variance = mean_distances
+        .iter()
+        .map(|d| (d - mean) * (d - mean))
+        .sum::<f32>()
+        / n;
+    let threshold = mean + std_ratio * variance.sqrt();
+    (0..points.len())
+        .filter(|&i| mean_distances[i] <= threshold)
+        .collect()
+}
+
+/// Keeps the points with at least `min_neighbours` other points closer than
+/// `radius`.
+pub fn radius_outliers(points: &[Vec3], radius: f32, min_neighbours: usize) -> Vec<usize> {
+    let tree = KdTree::new(points);
+    (0..points.len())
+        .filter(|&i| tree.within(points[i], radius).len() > min_neighbours)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A dense 10x10 grid with 1 cm spacing and two far away points.
+    fn grid_with_outliers() -> Vec<Vec3> {
+        let mut points: Vec<Vec3> = (0..100)
+            .map(|i| {
+                Vec3::new(
+                    (i % 10) as f32 * 0.01_f32,
+                    (i / 10) as f32 * 0.01_f32,
+                    0_f32,
+                )
+            })
+            .collect();
+        points.push(Vec3::new(0.05_f32, 0.05_f32, 0.2_f32));
+        points.push(Vec3::new(0.5_f32, 0_f32, 0_f32));
+        points
+    }
+
+    #[test]
+    fn statistical_outliers_are_removed() {
+        let points = grid_with_outliers();
+        assert_eq!(
+            statistical_outliers(&points, 8, 1_f32),
+            (0..100).collect::<Vec<_>>()
+        );
+        // not enough points to tell
+        assert_eq!(statistical_outliers(&points[..3], 8, 1_f32), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn radius_outliers_are_removed() {
+        let points = grid_with_outliers();
+        // corners have 3 neighbours within 1.5 cm
+        assert_eq!(
+            radius_outliers(&points, 0.015_f32, 3),
+            (0..100).collect::<Vec<_>>()
+        );
+        assert_eq!(radius_outliers(&points, 0.015_f32, 4).len(), 100 - 4);
+    }
+}
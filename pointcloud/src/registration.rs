@@ -0,0 +1,335 @@
+//! Rigid alignment of two clouds of the same surface: a coarse alignment by
+//! RANSAC over matching FPFH features, refined by point-to-plane ICP.
+
+use crate::{linalg, Fpfh, KdTree};
+use glam::{Affine3A, Quat, Vec3};
+
+/// How well `transform` aligns a source cloud onto a target cloud.
+#[derive(Clone, Copy, Debug)]
+pub struct Alignment {
+    /// From the source to the target frame
+    pub transform: Affine3A,
+    /// Fraction of the source points with a target point within the inlier
+    /// distance
+    pub fitness: f32,
+    /// RMS distance of those points
+    pub rmse: f32,
+}
+
+/// Scores `transform` against the target points in `tree`.
+pub fn evaluate(
+    source: &[Vec3],
+    tree: &KdTree,
+    transform: Affine3A,
+    inlier_distance: f32,
+) -> Alignment {
+    let (mut inliers, mut squares) = (0, 0_f32);
+    for s in source {
+        if let Some(&(_, d)) = tree.nearest(transform.transform_point3(*s), 1).first() {
+            if d < inlier_distance {
+                inliers += 1;
+                squares += d * d;
+            }
+        }
+    }
+    Alignment {
+        transform,
+        fitness: inliers as f32 / source.len().max(1) as f32,
+        rmse: (squares / inliers.max(1) as f32).sqrt(),
+    }
+}
+
+/// Rotation and translation mapping `source` onto the paired `target`
+/// points in the least squares sense, with the unit quaternion method of
+/// Horn (1987). `None` for fewer than 3 pairs.
+pub fn rigid_transform(source: &[Vec3], target: &[Vec3]) -> Option<Affine3A> {
+    if source.len() < 3 || source.len() != target.len() {
+        return None;
+    }
+    let n = source.len() as f32;
+    let source_centroid = source.iter().sum::<Vec3>() / n;
+    let target_centroid = target.iter().sum::<Vec3>() / n;
+    let mut s = [[0_f64; 3]; 3];
+    for (a, b) in source.iter().zip(target) {
+        let a = (*a - source_centroid).as_dvec3().to_array();
+        let b = (*b - target_centroid).as_dvec3().to_array();
+        for (row, ai) in s.iter_mut().zip(a) {
+            for (sij, bj) in row.iter_mut().zip(b) {
+                *sij += ai * bj;
+            }
+        }
+    }
+    let [[xx, xy, xz], [yx, yy, yz], [zx, zy, zz]] = s;
+    let horn = [
+        [xx + yy + zz, yz - zy, zx - xz, xy - yx],
+        [yz - zy, xx - yy - zz, xy + yx, zx + xz],
+        [zx - xz, xy + yx, -xx + yy - zz, yz + zy],
+        [xy - yx, zx + xz, yz + zy, -xx - yy + zz],
+    ];
+    // the eigenvector of the largest eigenvalue
+    let (_, vectors) = linalg::symmetric_eigen(&horn.into());
+    let q = vectors.column(3);
+    let rotation = Quat::from_xyzw(q[1] as f32, q[2] as f32, q[3] as f32, q[0] as f32).normalize();
+    let translation = target_centroid - rotation * source_centroid;
+    Some(Affine3A::from_rotation_translation(rotation, translation))
+}
+
+/// Aligns `source` onto `target` from their features, whatever their
+/// initial poses: triplets of matching points vote for the transform that
+/// brings the most matches within `inlier_distance`. `None` when the
+/// features do not match.
+pub fn coarse_alignment(
+    source: &[Vec3],
+    source_features: &[Fpfh],
+    target: &[Vec3],
+    target_features: &[Fpfh],
+    iterations: usize,
+    inlier_distance: f32,
+) -> Option<Alignment> {
+    let forward = nearest_features(source_features, target_features);
+    let backward = nearest_features(target_features, source_features);
+    // mutual matches are much more reliable, when there are enough of them
+    let mutual: Vec<(usize, usize)> = forward
+        .iter()
+        .enumerate()
+        .filter_map(|(i, &j)| Some((i, j?)))
+        .filter(|&(i, j)| backward[j] == Some(i))
+        .collect();
+    let matches = match mutual.len() >= 3 {
+        true => mutual,
+        false => forward
+            .iter()
+            .enumerate()
+            .filter_map(|(i, &j)| Some((i, j?)))
+            .collect(),
+    };
+    if matches.len() < 3 {
+        return None;
+    }
+
+    let mut random = Random(0x9e37_79b9_7f4a_7c15);
+    let similar = |a: f32, b: f32| a > 0.9_f32 * b && b > 0.9_f32 * a;
+    let mut best: Option<(usize, Affine3A)> = None;
+    for _ in 0..iterations {
+        let picked = [0; 3].map(|_| matches[random.below(matches.len())]);
+        let s = picked.map(|(i, _)| source[i]);
+        let t = picked.map(|(_, j)| target[j]);
+        // rigid transforms keep the distances, and thin triangles do not
+        // tell the rotation
+        let consistent = (0..3).all(|k| {
+            let l = (k + 1) % 3;
+            similar(s[k].distance(s[l]), t[k].distance(t[l]))
+        });
+        let area = (s[1] - s[0]).cross(s[2] - s[0]).length();
+        if !consistent || area < inlier_distance * inlier_distance {
+            continue;
+        }
+        let Some(transform) = rigid_transform(&s, &t) else {
+            continue;
+        };
+        let inliers = matches
+            .iter()
+            .filter(|&&(i, j)| {
+                transform.transform_point3(source[i]).distance(target[j]) < inlier_distance
+            })
+            .count();
+        if best.is_none_or(|(most, _)| inliers > most) {
+            best = Some((inliers, transform));
+        }
+    }
+    let (_, transform) = best?;
+    // refit on all the matches the best guess agrees with
+    let (s, t): (Vec<Vec3>, Vec<Vec3>) = matches
+        .iter()
+        .map(|&(i, j)| (source[i], target[j]))
+        .filter(|&(s, t)| transform.transform_point3(s).distance(t) < inlier_distance)
+        .unzip();
+    let transform = rigid_transform(&s, &t).unwrap_or(transform);
+    Some(evaluate(
+        source,
+        &KdTree::new(target),
+        transform,
+        inlier_distance,
+    ))
+}
+
+/// Refines `initial` by point-to-plane ICP: each step moves the source
+/// points to minimize their distances to the tangent planes of their
+/// nearest target points, the pairs further than `max_distance` apart being
+/// ignored.
+pub fn icp_point_to_plane(
+    source: &[Vec3],
+    target: &[Vec3],
+    target_normals: &[Vec3],
+    initial: Affine3A,
+    max_distance: f32,
+    iterations: usize,
+) -> Alignment {
+    let tree = KdTree::new(target);
+    let mut transform = initial;
+    for _ in 0..iterations {
+        // linearized around the current pose, the unknowns being a small
+        // rotation vector and a translation
+        let mut ata = [[0_f64; 6]; 6];
+        let mut atb = [0_f64; 6];
+        for s in source {
+            let p = transform.transform_point3(*s);
+            let Some(&(j, d)) = tree.nearest(p, 1).first() else {
+                continue;
+            };
+            let n = target_normals[j];
+            if d >= max_distance || n == Vec3::ZERO {
+                continue;
+            }
+            let residual = n.dot(target[j] - p) as f64;
+            let row = [p.cross(n).to_array(), n.to_array()].concat();
+            for (k, &rk) in row.iter().enumerate() {
+                for (l, &rl) in row.iter().enumerate() {
+                    ata[k][l] += rk as f64 * rl as f64;
+                }
+                atb[k] += rk as f64 * residual;
+            }
+        }
+        let Some(x) = linalg::solve(&ata.into(), &atb) else {
+            break;
+        };
+        let x: Vec<f32> = x.iter().map(|&v| v as f32).collect();
+        let rotation = Vec3::new(x[0], x[1], x[2]);
+        let translation = Vec3::new(x[3], x[4], x[5]);
+        transform =
+            Affine3A::from_rotation_translation(Quat::from_scaled_axis(rotation), translation)
+                * transform;
+        if rotation.length() < 1e-6_f32 && translation.length() < 1e-7_f32 {
+            break;
+        }
+    }
+    evaluate(source, &tree, transform, max_distance)
+}
+
+/// Index of the most similar target feature of each source feature, empty
+/// histograms match nothing.
+fn nearest_features(source: &[Fpfh], target: &[Fpfh]) -> Vec<Option<usize>> {
+    let empty = |f: &Fpfh| f.iter().all(|&h| h == 0_f32);
+    source
+        .iter()
+        .map(|a| {
+            if empty(a) {
+                return None;
+            }
+            let distance =
+                |b: &Fpfh| -> f32 { a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum() };
+            (0..target.len())
+                .filter(|&j| !empty(&target[j]))
+                .min_by(|&i, &j| distance(&target[i]).total_cmp(&distance(&target[j])))
+        })
+        .collect()
+}
+
+/// Xorshift generator with a fixed seed, so that the same clouds always
+/// give the same alignment.
+struct Random(u64);
+
+impl Random {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{estimate_normals, fpfh, orient_normals, sphere_directions};
+
+    /// A lumpy closed surface without any symmetry, `n` points spread over
+    /// its directions from the origin.
+    fn blob(n: usize) -> Vec<Vec3> {
+        sphere_directions(n)
+            .into_iter()
+            .map(|direction| {
+                let azimuth = direction.y.atan2(direction.x);
+                let polar = direction.z.acos();
+                let radius = 0.05_f32
+                    * (1_f32
+                        + 0.3_f32 * (2_f32 * azimuth + 0.5_f32).sin() * polar.sin()
+                        + 0.2_f32 * (3_f32 * polar).cos()
+                        + 0.15_f32 * azimuth.sin());
+                radius * direction
+            })
+            .collect()
+    }
+
+    fn outward_normals(points: &[Vec3]) -> Vec<Vec3> {
+        let mut normals = estimate_normals(points, 10);
+        let viewpoints: Vec<Vec3> = points.iter().map(|p| 10_f32 * *p).collect();
+        orient_normals(&mut normals, points, &viewpoints);
+        normals
+    }
+
+    #[test]
+    fn rigid_transforms_are_recovered() {
+        let transform = Affine3A::from_rotation_translation(
+            Quat::from_axis_angle(Vec3::new(1_f32, 2_f32, 3_f32).normalize(), 2_f32),
+            Vec3::new(0.1_f32, -0.2_f32, 0.3_f32),
+        );
+        let source = blob(50);
+        let target: Vec<Vec3> = source
+            .iter()
+            .map(|p| transform.transform_point3(*p))
+            .collect();
+        let estimated = rigid_transform(&source, &target).unwrap();
+        for (s, t) in source.iter().zip(&target) {
+            assert!(estimated.transform_point3(*s).distance(*t) < 1e-5_f32);
+        }
+        assert!(rigid_transform(&source[..2], &target[..2]).is_none());
+    }
+
+    #[test]
+    fn flipped_scans_are_registered() {
+        // the target and the source sample the surface differently, the
+        // source being upside down and moved aside
+        let target = blob(1000);
+        let flip = Affine3A::from_rotation_translation(
+            Quat::from_axis_angle(Vec3::new(1_f32, 0.2_f32, 0_f32).normalize(), 3_f32),
+            Vec3::new(0.03_f32, 0.01_f32, 0.2_f32),
+        );
+        let source: Vec<Vec3> = blob(800)
+            .iter()
+            .map(|p| flip.transform_point3(*p))
+            .collect();
+        // the normals of the flipped blob point away from its moved center
+        let source_normals: Vec<Vec3> = outward_normals(&blob(800))
+            .iter()
+            .map(|n| flip.transform_vector3(*n))
+            .collect();
+        let target_normals = outward_normals(&target);
+
+        let radius = 0.025_f32;
+        let coarse = coarse_alignment(
+            &source,
+            &fpfh(&source, &source_normals, radius),
+            &target,
+            &fpfh(&target, &target_normals, radius),
+            2000,
+            0.008_f32,
+        )
+        .unwrap();
+        let fine = icp_point_to_plane(
+            &source,
+            &target,
+            &target_normals,
+            coarse.transform,
+            0.01_f32,
+            30,
+        );
+        let expected = flip.inverse();
+        for p in &source {
+            let error = fine.transform.transform_point3(*p) - expected.transform_point3(*p);
+            assert!(error.length() < 1e-3_f32, "{error}");
+        }
+        assert!(fine.fitness > 0.95_f32);
+        assert!(fine.rmse < 0.003_f32);
+    }
+}
@@ -0,0 +1,50 @@
+use glam::Vec3;
+use std::collections::HashMap;
+
+/// Keeps one point per cubic voxel of side `voxel_size`, the one nearest to
+/// the centroid of the voxel points. Unlike averaging, the kept points are
+/// measured ones and their attributes stay valid.
+pub fn voxel_downsample(points: &[Vec3], voxel_size: f32) -> Vec<usize> {
+    let voxel = |p: Vec3| (p / voxel_size).floor().as_i64vec3().to_array();
+    let mut centroids: HashMap<[i64; 3], (Vec3, f32)> = HashMap::new();
+    for p in points {
+        let (sum, count) = centroids.entry(voxel(*p)).or_default();
+        *sum += *p;
+        *count += 1_f32;
+    }
+    let mut nearest: HashMap<[i64; 3], (usize, f32)> = HashMap::new();
+    for (i, p) in points.iter().enumerate() {
+        let key = voxel(*p);
+        let (sum, count) = centroids[&key];
+        let distance = p.distance_squared(sum / count);
+        nearest
+            .entry(key)
+            .and_modify(|best| {
+                if distance < best.1 {
+                    *best = (i, distance);
+                }
+            })
+            .or_insert((i, distance));
+    }
+    let mut kept: Vec<usize> = nearest.into_values().map(|(i, _)| i).collect();
+    kept.sort_unstable();
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_point_per_voxel() {
+        let points = [
+            Vec3::new(0.1_f32, 0.1_f32, 0.1_f32),
+            Vec3::new(0.5_f32, 0.5_f32, 0.5_f32),
+            Vec3::new(0.9_f32, 0.9_f32, 0.9_f32),
+            Vec3::new(1.5_f32, 0.5_f32, 0.5_f32),
+            Vec3::new(-0.5_f32, 0.5_f32, 0.5_f32),
+        ];
+        assert_eq!(voxel_downsample(&points, 1_f32), vec![1, 3, 4]);
+        assert!(voxel_downsample(&[], 1_f32).is_empty());
+    }
+}
@@ -1,11 +1,11 @@
 use crate::draw;
-use crate::render_ctx::{Point, RenderCtx};
+use crate::render_ctx::{MeshBuffers, Point, RenderCtx};
 use msg;
 
 use glam::{Mat4, Vec3};
 use serde_json;
-use std::cell::RefCell;
-use std::collections::VecDeque;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
 use std::rc::Rc;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
@@ -15,25 +15,52 @@ use wgpu::util::DeviceExt;
 
 static SERVER_IP: &str = "192.168.1.9";
 
+enum IncomingMessage {
+    Text(String),
+    /// Point cloud in the `msg::binary` format
+    Binary(Vec<u8>),
+}
+
 struct Connection {
     ws: WebSocket,
-    incoming_msg_queue: Rc<RefCell<VecDeque<String>>>,
+    incoming_msg_queue: Rc<RefCell<VecDeque<IncomingMessage>>>,
+    next_request_id: Rc<Cell<msg::command::RequestId>>,
+}
+
+fn send_request(
+    ws: &WebSocket,
+    next_request_id: &Cell<msg::command::RequestId>,
+    command: msg::command::Command,
+) -> anyhow::Result<msg::command::RequestId> {
+    let id = next_request_id.get();
+    next_request_id.set(id + 1);
+    let json_message = serde_json::to_string(&msg::command::Request { id, command })?;
+    ws.send_with_str(&json_message)
+        .map_err(|e| anyhow::Error::msg(format!("Failed to send {json_message}: {e:?}")))?;
+    Ok(id)
 }
 
 impl Connection {
     fn new(url: &str) -> anyhow::Result<Self> {
         let ws = WebSocket::new(url)
             .map_err(|e| anyhow::Error::msg(format!("Failed to create WebSocket: {e:?}")))?;
-        let incoming_msg_queue = Rc::new(RefCell::new(VecDeque::<String>::new()));
+        ws.set_binary_type(web_sys::BinaryType::Arraybuffer);
+        let incoming_msg_queue = Rc::new(RefCell::new(VecDeque::<IncomingMessage>::new()));
         let tx = incoming_msg_queue.clone();
 
         // Callback to handle incoming WebSocket messages
         let onmessage_callback = Closure::<dyn FnMut(MessageEvent)>::new(move |e: MessageEvent| {
             log::info!("onmessage_callback");
+            if let Ok(buffer) = e.data().dyn_into::<js_sys::ArrayBuffer>() {
+                let bytes = js_sys::Uint8Array::new(&buffer).to_vec();
+                log::info!("Received binary message of {} bytes", bytes.len());
+                tx.borrow_mut().push_back(IncomingMessage::Binary(bytes));
+                return;
+            }
             match e.data().as_string() {
                 Some(txt) => {
                     log::info!("Received message {txt}");
-                    tx.borrow_mut().push_back(txt)
+                    tx.borrow_mut().push_back(IncomingMessage::Text(txt))
                 }
                 None => log::error!("Failed to convert message to string"),
             }
@@ -47,8 +74,26 @@ impl Connection {
         ws.set_onerror(Some(onerror_callback.as_ref().unchecked_ref()));
         onerror_callback.forget();
 
+        let next_request_id = Rc::new(Cell::new(1));
+        let opened_ws = ws.clone();
+        let opened_next_request_id = next_request_id.clone();
         let onopen_callback = Closure::<dyn FnMut(_)>::new(move |_: web_sys::Event| {
             log::info!("WebSocket connection opened");
+            let hello = msg::command::Command::Hello(msg::command::Hello {
+                protocol_version: msg::PROTOCOL_VERSION,
+                client_name: format!("scanner_ui {}", env!("CARGO_PKG_VERSION")),
+                capabilities: vec![msg::command::Capability::BinaryPointCloud],
+            });
+            // point clouds are much smaller in binary than in JSON
+            let format =
+                msg::command::Command::SetPointCloudFormat(msg::command::PointCloudFormat::Binary);
+            // past scans survive a page reload
+            let sessions = msg::command::Command::ListSessions;
+            for command in [hello, format, sessions] {
+                if let Err(e) = send_request(&opened_ws, &opened_next_request_id, command) {
+                    log::error!("{e}");
+                }
+            }
         });
         ws.set_onopen(Some(onopen_callback.as_ref().unchecked_ref()));
         onopen_callback.forget();
@@ -62,34 +107,49 @@ impl Connection {
         Ok(Connection {
             ws,
             incoming_msg_queue,
+            next_request_id,
         })
     }
 
-    fn send_message(&self, message: msg::command::Command) -> anyhow::Result<()> {
-        let json_message = serde_json::to_string(&message)?;
-        // TODO(alberto): handle errors
-        self.ws.send_with_str(&json_message).unwrap();
-        Ok(())
+    /// Returns the id of the request, repeated in the server reply.
+    fn send_message(
+        &self,
+        message: msg::command::Command,
+    ) -> anyhow::Result<msg::command::RequestId> {
+        send_request(&self.ws, &self.next_request_id, message)
     }
 
-    fn try_receive_message(&self) -> anyhow::Result<Option<msg::response::Response>> {
-        let opt_response = self
-            .incoming_msg_queue
-            .borrow_mut()
-            .pop_front()
-            .map(|msg| serde_json::from_str(&msg))
-            .transpose()?;
-        Ok(opt_response)
+    fn try_receive_message(&self) -> anyhow::Result<Option<msg::response::ServerMessage>> {
+        let opt_message = match self.incoming_msg_queue.borrow_mut().pop_front() {
+            Some(IncomingMessage::Text(msg)) => Some(serde_json::from_str(&msg)?),
+            Some(IncomingMessage::Binary(bytes)) => Some(msg::response::ServerMessage::Event(
+                msg::response::Response::PointCloud(msg::binary::decode_point_cloud(&bytes)?),
+            )),
+            None => None,
+        };
+        Ok(opt_message)
     }
 }
 
 pub struct App {
     connection: Option<Connection>,
     status: msg::response::Status,
+    progress: Option<msg::response::Progress>,
     points: Vec<glam::Vec3>,
+    /// Surface of the loaded session, drawn under the points
+    mesh: Option<msg::response::Mesh>,
     render_ctx: Option<RenderCtx>,
     time_s: f32,
     freerun: bool,
+    /// Button that sent each request still waiting for a reply
+    pending_requests: HashMap<msg::command::RequestId, &'static str>,
+    last_reply: Option<String>,
+    sessions: Vec<msg::response::SessionInfo>,
+    export_format: msg::command::ExportFormat,
+    /// Export the mesh of the sessions instead of their points
+    export_mesh: bool,
+    /// Session the others are registered onto
+    registration_target: Option<String>,
 }
 
 impl App {
@@ -107,21 +167,55 @@ impl App {
 
         App {
             connection: None,
-            status: msg::response::Status {
-                lasers: msg::response::LasersData {
-                    laser_1: false,
-                    laser_2: false,
-                },
-                motor_speed: 0_f32,
-            },
+            status: msg::response::Status::default(),
+            progress: None,
             points: Vec::new(),
+            mesh: None,
             render_ctx: None,
             time_s: 0.0,
             freerun: false,
+            pending_requests: HashMap::new(),
+            last_reply: None,
+            sessions: Vec::new(),
+            export_format: msg::command::ExportFormat::PlyBinary,
+            export_mesh: false,
+            registration_target: None,
         }
     }
 }
 
+/// Tells the user what to do about the errors they can fix.
+fn error_hint(error: &msg::response::ServerError) -> String {
+    use msg::response::ErrorCode;
+    match error.code {
+        ErrorCode::Busy => "a scan is already running, stop it first".to_string(),
+        ErrorCode::NotInControl => "another client is controlling the scanner".to_string(),
+        ErrorCode::CameraNotFound => "camera not found, check its cable".to_string(),
+        _ => error.to_string(),
+    }
+}
+
+/// Hands the file to the browser, which saves it with the downloads.
+fn save_file(file: &msg::response::File) -> anyhow::Result<()> {
+    let js_error = |e: JsValue| anyhow::Error::msg(format!("Failed to save {}: {e:?}", file.name));
+    let parts = js_sys::Array::of1(&js_sys::Uint8Array::from(file.data.as_slice()));
+    let blob = web_sys::Blob::new_with_u8_array_sequence(&parts).map_err(js_error)?;
+    let url = web_sys::Url::create_object_url_with_blob(&blob).map_err(js_error)?;
+    let document = web_sys::window()
+        .and_then(|w| w.document())
+        .ok_or_else(|| anyhow::Error::msg("No document to save the file from"))?;
+    let anchor = document
+        .create_element("a")
+        .map_err(js_error)?
+        .dyn_into::<web_sys::HtmlAnchorElement>()
+        .map_err(|e| js_error(e.into()))?;
+    anchor.set_href(&url);
+    anchor.set_download(&file.name);
+    anchor.click();
+    web_sys::Url::revoke_object_url(&url).map_err(js_error)?;
+    Ok(())
+}
+
 fn to_string(ws_state: u16) -> String {
     let state_str = match ws_state {
         WebSocket::CONNECTING => "connecting",
@@ -179,6 +273,29 @@ impl eframe::App for App {
                 usage: wgpu::BufferUsages::VERTEX,
             });
 
+            let mesh = self.mesh.as_ref().filter(|m| !m.triangles.is_empty());
+            let mesh_buffers = mesh.map(|mesh| {
+                let vertices: Vec<Point> = mesh
+                    .vertices
+                    .iter()
+                    .map(|p| Point::new(&(10_f32 * *p)))
+                    .collect();
+                let indices: Vec<u32> = mesh.triangles.iter().flatten().copied().collect();
+                MeshBuffers {
+                    vertices: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                        label: Some("Mesh Vertex Buffer"),
+                        contents: bytemuck::cast_slice(&vertices),
+                        usage: wgpu::BufferUsages::VERTEX,
+                    }),
+                    indices: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                        label: Some("Mesh Index Buffer"),
+                        contents: bytemuck::cast_slice(&indices),
+                        usage: wgpu::BufferUsages::INDEX,
+                    }),
+                    num_indices: indices.len() as u32,
+                }
+            });
+
             let ctx = self.render_ctx.as_mut().unwrap();
 
             let camera_matrix = ctx.camera_projection * ctx.camera_position;
@@ -192,11 +309,20 @@ impl eframe::App for App {
             );
 
             log::info!("Rendering...");
-            let command_buffer = ctx.render(&device, &vertex_buffer, point_data.len() as u32);
+            let command_buffer = ctx.render(
+                &device,
+                &vertex_buffer,
+                point_data.len() as u32,
+                mesh_buffers.as_ref(),
+            );
 
             log::info!("Submitting command buffer...");
             queue.submit(std::iter::once(command_buffer));
             vertex_buffer.destroy();
+            if let Some(mesh) = mesh_buffers {
+                mesh.vertices.destroy();
+                mesh.indices.destroy();
+            }
 
             //log::info!("Updating camera position: {:?}", self.camera_position);
             //ctx.update_camera_position(self.camera_position.clone());
@@ -239,25 +365,78 @@ impl eframe::App for App {
         if let Some(conn) = &c {
             match conn.try_receive_message() {
                 Ok(msg_opt) => match msg_opt {
-                    Some(msg) => match msg {
-                        msg::response::Response::Ok => {
-                            log::info!("Received OK");
-                        }
-                        msg::response::Response::Error(e) => {
-                            log::info!("Received Error: {e}");
-                        }
-                        msg::response::Response::Close => {
-                            log::info!("Received Close");
-                            //self.connection = None;
+                    Some(message) => {
+                        let response = match message {
+                            msg::response::ServerMessage::Reply { id, response } => {
+                                let button = id.and_then(|id| self.pending_requests.remove(&id));
+                                if let Some(button) = button {
+                                    self.last_reply = Some(match &response {
+                                        msg::response::Response::Error(e) => {
+                                            format!("{button}: {}", error_hint(e))
+                                        }
+                                        _ => format!("{button}: done"),
+                                    });
+                                }
+                                response
+                            }
+                            msg::response::ServerMessage::Event(response) => response,
+                        };
+                        match response {
+                            msg::response::Response::Hello(hello) => {
+                                log::info!(
+                                    "Connected to {} with protocol version {}",
+                                    hello.server_name,
+                                    hello.protocol_version
+                                );
+                            }
+                            msg::response::Response::IncompatibleProtocol(e) => {
+                                log::error!(
+                                    "Server protocol version {} does not match UI version {}, rebuild the UI",
+                                    e.server_version,
+                                    e.client_version
+                                );
+                            }
+                            msg::response::Response::Ok => {
+                                log::info!("Received OK");
+                            }
+                            msg::response::Response::Error(e) => {
+                                log::info!("Received Error: {e}");
+                            }
+                            msg::response::Response::Close => {
+                                log::info!("Received Close");
+                                //self.connection = None;
+                            }
+                            msg::response::Response::Status(status) => {
+                                self.status = status;
+                            }
+                            msg::response::Response::PointCloud(mut pc) => {
+                                self.points.append(&mut pc.points);
+                                log::info!("Received PointCloud");
+                            }
+                            msg::response::Response::Progress(progress) => {
+                                self.status.angle_deg = progress.angle_deg;
+                                self.progress = Some(progress);
+                            }
+                            msg::response::Response::Sessions(sessions) => {
+                                self.sessions = sessions;
+                            }
+                            msg::response::Response::Session(session) => {
+                                log::info!("Loaded scan session {}", session.info.name);
+                                self.points = session.cloud.points;
+                                self.mesh = None;
+                            }
+                            msg::response::Response::Mesh(mesh) => {
+                                log::info!("Loaded a mesh of {} triangles", mesh.triangles.len());
+                                self.mesh = Some(mesh);
+                            }
+                            msg::response::Response::File(file) => {
+                                log::info!("Saving {}, {} bytes", file.name, file.data.len());
+                                if let Err(e) = save_file(&file) {
+                                    log::error!("{e}");
+                                }
+                            }
                         }
-                        msg::response::Response::Status(status) => {
-                            self.status = status;
-                        }
-                        msg::response::Response::PointCloud(mut pc) => {
-                            self.points.append(&mut pc.points);
-                            log::info!("Received PointCloud");
-                        }
-                    },
+                    }
                     None => {
                         // No message received, nothing to do
                     }
@@ -346,9 +525,11 @@ impl eframe::App for App {
                 log::info!("Sending status request");
                 if let Some(conn) = &c {
                     let command = msg::command::Command::Status;
-                    let res = conn.send_message(command);
-                    if let Err(e) = res {
-                        log::error!("Failed to send 'status' command: {}", e);
+                    match conn.send_message(command) {
+                        Ok(id) => {
+                            self.pending_requests.insert(id, "Get Status");
+                        }
+                        Err(e) => log::error!("Failed to send 'status' command: {}", e),
                     }
                 }
             }
@@ -358,18 +539,136 @@ impl eframe::App for App {
                 log::info!("Sending start request");
                 if let Some(conn) = &c {
                     let command = msg::command::Command::Replay;
-                    let res = conn.send_message(command);
-                    if let Err(e) = res {
-                        log::error!("Failed to send 'replay' command: {}", e);
+                    match conn.send_message(command) {
+                        Ok(id) => {
+                            self.pending_requests.insert(id, "Start");
+                        }
+                        Err(e) => log::error!("Failed to send 'replay' command: {}", e),
                     }
                 }
             }
+            if let Some(reply) = &self.last_reply {
+                ui.label(reply);
+            }
 
             ui.separator();
 
-            ui.label(format!("Motor speed: {}", self.status.motor_speed));
+            ui.label(format!("Scanner state: {:?}", self.status.state));
+            ui.label(format!("Turntable angle: {:.1}°", self.status.angle_deg));
             ui.label(format!("Laser 1: {}", self.status.lasers.laser_1));
             ui.label(format!("Laser 2: {}", self.status.lasers.laser_2));
+            if let Some(error) = &self.status.last_error {
+                ui.label(format!("Last error: {}", error_hint(error)));
+            }
+            if let Some(progress) = &self.progress {
+                let fraction = progress.step as f32 / progress.total_steps.max(1) as f32;
+                ui.add(egui::ProgressBar::new(fraction).text(format!(
+                    "Step {}/{}, {} points, {} s left",
+                    progress.step,
+                    progress.total_steps,
+                    progress.points_so_far,
+                    progress.eta_ms / 1000
+                )));
+            }
+
+            ui.separator();
+
+            ui.horizontal(|ui| {
+                ui.label("Scan sessions");
+                egui::ComboBox::from_label("Export format")
+                    .selected_text(format!("{:?}", self.export_format))
+                    .show_ui(ui, |ui| {
+                        for format in msg::command::ExportFormat::ALL {
+                            ui.selectable_value(
+                                &mut self.export_format,
+                                format,
+                                format!("{format:?}"),
+                            );
+                        }
+                    });
+                ui.checkbox(&mut self.export_mesh, "Mesh");
+                if ui.button("Refresh").clicked() {
+                    if let Some(conn) = &c {
+                        let command = msg::command::Command::ListSessions;
+                        match conn.send_message(command) {
+                            Ok(id) => {
+                                self.pending_requests.insert(id, "Refresh");
+                            }
+                            Err(e) => log::error!("Failed to send 'list sessions' command: {}", e),
+                        }
+                    }
+                }
+            });
+            for session in &self.sessions {
+                ui.horizontal(|ui| {
+                    ui.label(format!(
+                        "{}: {} points, {:?}",
+                        session.name, session.points, session.outcome
+                    ));
+                    if ui.button("Load").clicked() {
+                        if let Some(conn) = &c {
+                            let command = msg::command::Command::GetSession(session.id.clone());
+                            match conn.send_message(command) {
+                                Ok(id) => {
+                                    self.pending_requests.insert(id, "Load");
+                                }
+                                Err(e) => {
+                                    log::error!("Failed to send 'get session' command: {}", e)
+                                }
+                            }
+                        }
+                    }
+                    if ui.button("Mesh").clicked() {
+                        if let Some(conn) = &c {
+                            let command = msg::command::Command::MeshSession(session.id.clone());
+                            match conn.send_message(command) {
+                                Ok(id) => {
+                                    self.pending_requests.insert(id, "Mesh");
+                                }
+                                Err(e) => log::error!("Failed to send 'mesh' command: {}", e),
+                            }
+                        }
+                    }
+                    if ui.button("Export").clicked() {
+                        if let Some(conn) = &c {
+                            let command = msg::command::Command::ExportSession {
+                                id: session.id.clone(),
+                                format: self.export_format,
+                                mesh: self.export_mesh,
+                            };
+                            match conn.send_message(command) {
+                                Ok(id) => {
+                                    self.pending_requests.insert(id, "Export");
+                                }
+                                Err(e) => log::error!("Failed to send 'export' command: {}", e),
+                            }
+                        }
+                    }
+                    let is_target = self.registration_target.as_ref() == Some(&session.id);
+                    if ui.selectable_label(is_target, "Target").clicked() {
+                        self.registration_target = (!is_target).then(|| session.id.clone());
+                    }
+                    let onto = self
+                        .registration_target
+                        .clone()
+                        .filter(|onto| *onto != session.id);
+                    let register = ui.add_enabled(onto.is_some(), egui::Button::new("Register"));
+                    if register.clicked() {
+                        if let (Some(conn), Some(onto)) = (&c, onto) {
+                            let command = msg::command::Command::RegisterSession {
+                                id: session.id.clone(),
+                                onto,
+                            };
+                            match conn.send_message(command) {
+                                Ok(id) => {
+                                    self.pending_requests.insert(id, "Register");
+                                }
+                                Err(e) => log::error!("Failed to send 'register' command: {}", e),
+                            }
+                        }
+                    }
+                });
+            }
 
             ui.separator();
 
@@ -8,7 +8,8 @@ var<uniform> camera: Camera;
 
 struct VertexOutput {
     @builtin(position) position: vec4<f32>,
-    @location(0) color: vec3<f32>
+    @location(0) color: vec3<f32>,
+    @location(1) world: vec3<f32>
 };
 
 @vertex
@@ -20,6 +21,7 @@ fn vs_main(@location(0) pos: vec3<f32>) -> VertexOutput {
     let g = clamp(pos.y/ 3 , 0, 1);
     let b = clamp(pos.z/ 3 , 0, 1);
     out.color = vec3<f32>(r, g, b); // white
+    out.world = pos;
     return out;
 }
 
@@ -27,3 +29,11 @@ fn vs_main(@location(0) pos: vec3<f32>) -> VertexOutput {
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     return vec4<f32>(in.color, 1.0);
 }
+
+@fragment
+fn fs_mesh(in: VertexOutput) -> @location(0) vec4<f32> {
+    // flat shading, the face normal comes from the screen space derivatives
+    let normal = normalize(cross(dpdx(in.world), dpdy(in.world)));
+    let light = 0.3 + 0.7 * abs(dot(normal, normalize(vec3<f32>(0.3, 0.5, 1.0))));
+    return vec4<f32>(in.color * light, 1.0);
+}
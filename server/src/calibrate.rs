@@ -0,0 +1,1051 @@
+//! Calibration procedures that estimate the entries of `Calibration` from
+//! images of a chessboard target.
+
+use crate::calibration::{CameraCalib, CameraIntrinsics, Distortion, LaserCalib, TurntableAxis};
+use crate::chessboard;
+use crate::imgproc;
+use crate::linalg;
+use crate::peak::PeakDetector;
+
+use anyhow::{anyhow, Result};
+use glam::{DAffine3, DQuat, DVec2, DVec3};
+use log::{info, warn};
+use pointcloud::linalg::{null_vector, solve, symmetric_eigen, Matrix};
+use std::path::Path;
+
+const MIN_CALIBRATION_IMAGES: usize = 3;
+const REFINEMENT_ITERATIONS: usize = 100;
+/// Laser points further than this many RMS errors from the first plane fit
+/// are discarded before fitting again.
+const OUTLIER_RMS_FACTOR: f64 = 3_f64;
+/// Minimum ratio between the spread of the points across and along their
+/// main direction, below it they all lie on a line and the plane is undefined.
+const MIN_PLANE_SPREAD_RATIO: f64 = 0.01;
+
+/// Chessboard calibration target.
+#[derive(clap::Args)]
+pub struct Chessboard {
+    /// Inner corners along the chessboard rows
+    #[clap(long, default_value_t = 9)]
+    pub cols: usize,
+    /// Inner corners along the chessboard columns
+    #[clap(long, default_value_t = 6)]
+    pub rows: usize,
+    /// Side of a chessboard square in meters
+    #[clap(long, default_value_t = 0.025)]
+    pub square_size: f64,
+}
+
+impl Chessboard {
+    /// Corners in the board frame, row by row, on the z = 0 plane.
+    pub fn object_points(&self) -> Vec<DVec3> {
+        (0..self.rows)
+            .flat_map(|j| {
+                (0..self.cols).map(move |i| {
+                    DVec3::new(
+                        i as f64 * self.square_size,
+                        j as f64 * self.square_size,
+                        0_f64,
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Center of the inner corners in the board frame, it does not depend on
+    /// which corner the detection starts from.
+    pub fn center(&self) -> DVec3 {
+        let s = self.square_size;
+        DVec3::new(
+            (self.cols - 1) as f64 * s / 2_f64,
+            (self.rows - 1) as f64 * s / 2_f64,
+            0_f64,
+        )
+    }
+}
+
+pub struct CameraCalibrationResult {
+    pub intrinsics: CameraIntrinsics,
+    /// Board to camera optical frame transform of every used image
+    pub board_poses: Vec<DAffine3>,
+    /// Root mean square reprojection error in pixels
+    pub rms_error: f64,
+}
+
+/// Loads all the images of a directory in alphabetical order.
+pub fn load_images(dir: &Path) -> Result<Vec<(String, image::GrayImage)>> {
+    let mut paths: Vec<_> = dir
+        .read_dir()?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file())
+        .collect();
+    paths.sort();
+
+    let mut images = Vec::new();
+    for path in paths {
+        match image::open(&path) {
+            Ok(img) => images.push((path.display().to_string(), img.into_luma8())),
+            Err(e) => warn!("Skipping {}: {e}", path.display()),
+        }
+    }
+    Ok(images)
+}
+
+/// Estimates intrinsics and lens distortion from chessboard images with
+/// Zhang's method ("A flexible new technique for camera calibration", 2000),
+/// followed by a Levenberg-Marquardt refinement of all the parameters.
+pub fn calibrate_camera(
+    images: &[(String, image::GrayImage)],
+    board: &Chessboard,
+) -> Result<CameraCalibrationResult> {
+    let (width, height) = images
+        .first()
+        .map(|(_, img)| img.dimensions())
+        .ok_or(anyhow!("No calibration images"))?;
+
+    let mut observations = Vec::<Vec<DVec2>>::new();
+    for (name, img) in images {
+        if img.dimensions() != (width, height) {
+            warn!("Skipping {name}: image size differs from the first image");
+            continue;
+        }
+        match chessboard::find_corners(img, board.cols, board.rows) {
+            Some(corners) => {
+                info!("Found chessboard in {name}");
+                observations.push(corners.iter().map(|c| c.as_dvec2()).collect());
+            }
+            None => warn!("Chessboard not found in {name}"),
+        }
+    }
+    if observations.len() < MIN_CALIBRATION_IMAGES {
+        return Err(anyhow!(
+            "Chessboard found in {} images, at least {MIN_CALIBRATION_IMAGES} are required",
+            observations.len()
+        ));
+    }
+
+    let object_points = board.object_points();
+    let board_points: Vec<DVec2> = object_points.iter().map(|p| p.truncate()).collect();
+    let homographies: Vec<[[f64; 3]; 3]> = observations
+        .iter()
+        .map(|corners| estimate_homography(&board_points, corners))
+        .collect::<Option<_>>()
+        .ok_or(anyhow!("Degenerate chessboard detection"))?;
+
+    let pinhole = closed_form_intrinsics(&homographies)
+        .ok_or(anyhow!("Closed form intrinsics estimation failed"))?;
+    let poses: Vec<DAffine3> = homographies
+        .iter()
+        .map(|h| pose_from_homography(h, &pinhole))
+        .collect();
+
+    // parameters: fx, fy, cx, cy, k1, k2, p1, p2, k3 then rotation vector and
+    // translation of each view
+    let mut initial = vec![pinhole.fx, pinhole.fy, pinhole.cx, pinhole.cy];
+    initial.extend([0_f64; 5]);
+    for pose in &poses {
+        let (_, rotation, translation) = pose.to_scale_rotation_translation();
+        initial.extend(rotation.to_scaled_axis().to_array());
+        initial.extend(translation.to_array());
+    }
+
+    let residuals = |params: &[f64]| -> Vec<f64> {
+        let camera = CameraModel::from_params(&params[..9]);
+        observations
+            .iter()
+            .enumerate()
+            .flat_map(|(view, corners)| {
+                let pose = pose_from_params(&params[9 + 6 * view..15 + 6 * view]);
+                let camera = &camera;
+                object_points.iter().zip(corners).flat_map(move |(p, c)| {
+                    let projected = camera.project(pose.transform_point3(*p));
+                    [projected.x - c.x, projected.y - c.y]
+                })
+            })
+            .collect()
+    };
+    let (params, final_residuals) =
+        linalg::levenberg_marquardt(&initial, residuals, REFINEMENT_ITERATIONS);
+
+    let camera = CameraModel::from_params(&params[..9]);
+    let board_poses = (0..observations.len())
+        .map(|view| pose_from_params(&params[9 + 6 * view..15 + 6 * view]))
+        .collect();
+    let squared_error: f64 = final_residuals.iter().map(|r| r * r).sum();
+    let rms_error = (2_f64 * squared_error / final_residuals.len() as f64).sqrt();
+
+    Ok(CameraCalibrationResult {
+        intrinsics: camera.to_intrinsics(width as f32, height as f32),
+        board_poses,
+        rms_error,
+    })
+}
+
+pub struct LaserPlaneFit {
+    pub plane: LaserCalib,
+    /// Number of points used for the fit
+    pub points: usize,
+    /// Root mean square distance of the points from the plane in meters
+    pub rms_error: f64,
+}
+
+pub struct LaserCalibrationResult {
+    pub left: LaserPlaneFit,
+    pub right: LaserPlaneFit,
+}
+
+/// Fits the laser planes from images of the chessboard at different poses.
+/// Every pose has three images, in the order of `AcquisitionMode::PerLaser`:
+/// lasers off, only the left laser on and only the right laser on. The board
+/// pose is estimated from the corners in the first image, the laser lines of
+/// the other two are then intersected with the board plane.
+pub fn calibrate_lasers(
+    images: &[(String, image::GrayImage)],
+    board: &Chessboard,
+    intrinsics: &CameraIntrinsics,
+    peak_detector: &dyn PeakDetector,
+) -> Result<LaserCalibrationResult> {
+    if !images.len().is_multiple_of(3) {
+        warn!("Image count is not a multiple of 3, ignoring the last images");
+    }
+
+    let mut left_points = Vec::<DVec3>::new();
+    let mut right_points = Vec::<DVec3>::new();
+    for view in images.chunks_exact(3) {
+        let [(name, off), (_, left), (_, right)] = view else {
+            unreachable!()
+        };
+        let Some(corners) = chessboard::find_corners(off, board.cols, board.rows) else {
+            warn!("Chessboard not found in {name}, skipping pose");
+            continue;
+        };
+        let Some(pose) = estimate_board_pose(intrinsics, board, &corners) else {
+            warn!("Board pose estimation failed for {name}, skipping pose");
+            continue;
+        };
+        info!(
+            "Found chessboard in {name} at {:.3} m",
+            pose.translation.length()
+        );
+
+        for (lit, points) in [(left, &mut left_points), (right, &mut right_points)] {
+            let image = imgproc::subtract_ambient(lit, off);
+            points.extend(laser_points_on_board(
+                &image,
+                board,
+                intrinsics,
+                &pose,
+                peak_detector,
+            ));
+        }
+    }
+
+    let hint = "image the board at more distances and angles";
+    Ok(LaserCalibrationResult {
+        left: fit_laser_plane(&left_points).map_err(|e| anyhow!("Left laser: {e}, {hint}"))?,
+        right: fit_laser_plane(&right_points).map_err(|e| anyhow!("Right laser: {e}, {hint}"))?,
+    })
+}
+
+/// Board to camera optical frame transform from the detected corners, given
+/// the camera intrinsics.
+pub fn estimate_board_pose(
+    intrinsics: &CameraIntrinsics,
+    board: &Chessboard,
+    corners: &[glam::Vec2],
+) -> Option<DAffine3> {
+    let object_points = board.object_points();
+    let board_points: Vec<DVec2> = object_points.iter().map(|p| p.truncate()).collect();
+    let normalized: Vec<DVec2> = corners
+        .iter()
+        .map(|c| {
+            let pixel = intrinsics.undistort_pixel(*c);
+            intrinsics.pixel_to_ray(pixel).truncate().as_dvec2()
+        })
+        .collect();
+
+    let h = estimate_homography(&board_points, &normalized)?;
+    let pose = pose_from_homography(&h, &CameraModel::normalized());
+
+    let (_, rotation, translation) = pose.to_scale_rotation_translation();
+    let mut initial = rotation.to_scaled_axis().to_array().to_vec();
+    initial.extend(translation.to_array());
+
+    let camera = CameraModel::from_intrinsics(intrinsics);
+    let residuals = |params: &[f64]| -> Vec<f64> {
+        let pose = pose_from_params(params);
+        object_points
+            .iter()
+            .zip(corners)
+            .flat_map(|(p, c)| {
+                let projected = camera.project(pose.transform_point3(*p));
+                [projected.x - c.x as f64, projected.y - c.y as f64]
+            })
+            .collect()
+    };
+    let (params, _) = linalg::levenberg_marquardt(&initial, residuals, REFINEMENT_ITERATIONS);
+    Some(pose_from_params(&params))
+}
+
+/// Intersects the laser line detected in `image` with the board plane,
+/// keeping only the points that fall on the board. Points are in the camera
+/// optical frame.
+fn laser_points_on_board(
+    image: &image::GrayImage,
+    board: &Chessboard,
+    intrinsics: &CameraIntrinsics,
+    board_pose: &DAffine3,
+    peak_detector: &dyn PeakDetector,
+) -> Vec<DVec3> {
+    let normal = board_pose.transform_vector3(DVec3::Z);
+    let offset = normal.dot(board_pose.translation);
+    let cam_2_board = board_pose.inverse();
+
+    // the squares extend one square beyond the outer corners
+    let s = board.square_size;
+    let (max_x, max_y) = (board.cols as f64 * s, board.rows as f64 * s);
+
+    imgproc::detect_laser_points(image, peak_detector)
+        .iter()
+        .filter_map(|point| {
+            let pixel = intrinsics.undistort_pixel(point.pixel);
+            let ray = intrinsics.pixel_to_ray(pixel).as_dvec3();
+            let t = offset / normal.dot(ray);
+            if !t.is_finite() || t <= 0_f64 {
+                return None;
+            }
+            let p = t * ray;
+            let on_board = cam_2_board.transform_point3(p);
+            let inside = (-s..=max_x).contains(&on_board.x) && (-s..=max_y).contains(&on_board.y);
+            inside.then_some(p)
+        })
+        .collect()
+}
+
+/// Least squares plane through `points`, fitted twice to drop the outliers.
+fn fit_laser_plane(points: &[DVec3]) -> Result<LaserPlaneFit> {
+    let (normal, offset, rms_error) = fit_plane(points)?;
+    let inliers: Vec<DVec3> = points
+        .iter()
+        .copied()
+        .filter(|p| (normal.dot(*p) - offset).abs() <= OUTLIER_RMS_FACTOR * rms_error)
+        .collect();
+    let (normal, offset, rms_error) = fit_plane(&inliers)?;
+
+    Ok(LaserPlaneFit {
+        plane: LaserCalib::from_plane(normal.as_vec3(), offset as f32),
+        points: inliers.len(),
+        rms_error,
+    })
+}
+
+/// Plane `normal · p = offset` minimizing the squared distances of `points`,
+/// returned with the RMS distance.
+fn fit_plane(points: &[DVec3]) -> Result<(DVec3, f64, f64)> {
+    if points.len() < 3 {
+        return Err(anyhow!(
+            "Found {} laser points, at least 3 are required",
+            points.len()
+        ));
+    }
+
+    let centroid = points.iter().copied().sum::<DVec3>() / points.len() as f64;
+    let mut covariance = Matrix::zeros(3, 3);
+    for p in points {
+        let d = (*p - centroid).to_array();
+        for i in 0..3 {
+            for j in 0..3 {
+                covariance[(i, j)] += d[i] * d[j] / points.len() as f64;
+            }
+        }
+    }
+    let (eigenvalues, eigenvectors) = symmetric_eigen(&covariance);
+    if eigenvalues[1].max(0_f64) < MIN_PLANE_SPREAD_RATIO.powi(2) * eigenvalues[2] {
+        return Err(anyhow!("points lie on a line"));
+    }
+
+    let column = eigenvectors.column(0);
+    let normal = DVec3::new(column[0], column[1], column[2]);
+    let offset = normal.dot(centroid);
+    let rms_error = eigenvalues[0].max(0_f64).sqrt();
+    Ok((normal, offset, rms_error))
+}
+
+pub struct TurntableCalibrationResult {
+    pub axis: TurntableAxis,
+    /// Root mean square distance in meters of the board centers from the
+    /// fitted circle
+    pub rms_error: f64,
+    /// Turntable rotation in degrees between consecutive images
+    pub step_angles: Vec<f64>,
+}
+
+/// Estimates the turntable axis from images of the chessboard standing on the
+/// turntable, taken at different motor angles. The board center, which must
+/// not lie on the axis, moves on a circle around it: the axis is the normal of
+/// the circle through its center.
+///
+/// The board height above the turntable is unknown, so the world origin of
+/// the camera extrinsics must lie on the turntable surface: the returned axis
+/// point is the point of the axis closest to the origin.
+pub fn calibrate_turntable(
+    images: &[(String, image::GrayImage)],
+    board: &Chessboard,
+    camera: &CameraCalib,
+) -> Result<TurntableCalibrationResult> {
+    let optical_2_world: glam::Mat4 =
+        (camera.extrinsics.as_affine() * camera.img_plane_2_cam()).into();
+    let optical_2_world = DAffine3::from_mat4(optical_2_world.as_dmat4());
+
+    let mut centers = Vec::<DVec3>::new();
+    for (name, img) in images {
+        let Some(corners) = chessboard::find_corners(img, board.cols, board.rows) else {
+            warn!("Chessboard not found in {name}");
+            continue;
+        };
+        let Some(pose) = estimate_board_pose(&camera.intrinsics, board, &corners) else {
+            warn!("Board pose estimation failed for {name}");
+            continue;
+        };
+        info!("Found chessboard in {name}");
+        centers.push(optical_2_world.transform_point3(pose.transform_point3(board.center())));
+    }
+    if centers.len() < MIN_CALIBRATION_IMAGES {
+        return Err(anyhow!(
+            "Chessboard found in {} images, at least {MIN_CALIBRATION_IMAGES} are required",
+            centers.len()
+        ));
+    }
+
+    let (mut direction, _, _) = fit_plane(&centers)
+        .map_err(|e| anyhow!("Board centers: {e}, is the board on the axis?"))?;
+    if direction.z < 0_f64 {
+        direction = -direction;
+    }
+
+    // circle fit in the plane of the centers, with u and v spanning the plane
+    let centroid = centers.iter().copied().sum::<DVec3>() / centers.len() as f64;
+    let u = direction.any_orthonormal_vector();
+    let v = direction.cross(u);
+    let projected: Vec<DVec2> = centers
+        .iter()
+        .map(|c| DVec2::new((*c - centroid).dot(u), (*c - centroid).dot(v)))
+        .collect();
+    let (center, radius) =
+        fit_circle(&projected).ok_or(anyhow!("Board centers circle fit failed"))?;
+
+    let squared_error: f64 = projected
+        .iter()
+        .map(|p| (p.distance(center) - radius).powi(2))
+        .sum();
+    let rms_error = (squared_error / projected.len() as f64).sqrt();
+
+    let step_angles = projected
+        .windows(2)
+        .map(|pair| {
+            let (a, b) = (pair[0] - center, pair[1] - center);
+            a.perp_dot(b).atan2(a.dot(b)).to_degrees()
+        })
+        .collect();
+
+    // the point of the axis closest to the world origin, which lies on the
+    // turntable surface
+    let on_axis = centroid + center.x * u + center.y * v;
+    let point = on_axis - direction * direction.dot(on_axis);
+
+    Ok(TurntableCalibrationResult {
+        axis: TurntableAxis {
+            direction: direction.as_vec3(),
+            point: point.as_vec3(),
+        },
+        rms_error,
+        step_angles,
+    })
+}
+
+/// Algebraic least squares circle fit, solving for `D`, `E` and `F` in
+/// `x² + y² + D x + E y + F = 0`. Returns the center and radius.
+fn fit_circle(points: &[DVec2]) -> Option<(DVec2, f64)> {
+    let mut a = Matrix::zeros(points.len(), 3);
+    let mut b = Vec::with_capacity(points.len());
+    for (k, p) in points.iter().enumerate() {
+        a[(k, 0)] = p.x;
+        a[(k, 1)] = p.y;
+        a[(k, 2)] = 1_f64;
+        b.push(-p.length_squared());
+    }
+    let solution = solve(&a.gram(), &a.transpose_mul_vec(&b))?;
+    let center = DVec2::new(-solution[0] / 2_f64, -solution[1] / 2_f64);
+    let radius = (center.length_squared() - solution[2]).sqrt();
+    radius.is_finite().then_some((center, radius))
+}
+
+/// Camera model in double precision, used while optimizing.
+pub struct CameraModel {
+    pub fx: f64,
+    pub fy: f64,
+    pub cx: f64,
+    pub cy: f64,
+    pub k1: f64,
+    pub k2: f64,
+    pub p1: f64,
+    pub p2: f64,
+    pub k3: f64,
+}
+
+impl CameraModel {
+    fn from_params(params: &[f64]) -> Self {
+        Self {
+            fx: params[0],
+            fy: params[1],
+            cx: params[2],
+            cy: params[3],
+            k1: params[4],
+            k2: params[5],
+            p1: params[6],
+            p2: params[7],
+            k3: params[8],
+        }
+    }
+
+    /// Distortion-free camera with unit focal length and the principal point
+    /// in the origin, projecting on normalized image coordinates.
+    fn normalized() -> Self {
+        Self::from_params(&[
+            1_f64, 1_f64, 0_f64, 0_f64, 0_f64, 0_f64, 0_f64, 0_f64, 0_f64,
+        ])
+    }
+
+    pub fn from_intrinsics(intrinsics: &CameraIntrinsics) -> Self {
+        let d = &intrinsics.distortion;
+        Self {
+            fx: intrinsics.fx as f64,
+            fy: intrinsics.fy as f64,
+            cx: intrinsics.cx as f64,
+            cy: intrinsics.cy as f64,
+            k1: d.k1 as f64,
+            k2: d.k2 as f64,
+            p1: d.p1 as f64,
+            p2: d.p2 as f64,
+            k3: d.k3 as f64,
+        }
+    }
+
+    fn to_intrinsics(&self, width: f32, height: f32) -> CameraIntrinsics {
+        CameraIntrinsics {
+            fx: self.fx as f32,
+            fy: self.fy as f32,
+            cx: self.cx as f32,
+            cy: self.cy as f32,
+            width,
+            height,
+            distortion: Distortion {
+                k1: self.k1 as f32,
+                k2: self.k2 as f32,
+                k3: self.k3 as f32,
+                p1: self.p1 as f32,
+                p2: self.p2 as f32,
+            },
+        }
+    }
+
+    /// Projects a point in the camera optical frame to pixel coordinates.
+    pub fn project(&self, p: DVec3) -> DVec2 {
+        let (x, y) = (p.x / p.z, p.y / p.z);
+        let r2 = x * x + y * y;
+        let radial = 1_f64 + r2 * (self.k1 + r2 * (self.k2 + r2 * self.k3));
+        let xd = x * radial + 2_f64 * self.p1 * x * y + self.p2 * (r2 + 2_f64 * x * x);
+        let yd = y * radial + self.p1 * (r2 + 2_f64 * y * y) + 2_f64 * self.p2 * x * y;
+        DVec2::new(self.fx * xd + self.cx, self.fy * yd + self.cy)
+    }
+}
+
+fn pose_from_params(params: &[f64]) -> DAffine3 {
+    let rotation = DQuat::from_scaled_axis(DVec3::new(params[0], params[1], params[2]));
+    let translation = DVec3::new(params[3], params[4], params[5]);
+    DAffine3::from_rotation_translation(rotation, translation)
+}
+
+/// Homography mapping `from` to `to` with the normalized DLT algorithm.
+pub fn estimate_homography(from: &[DVec2], to: &[DVec2]) -> Option<[[f64; 3]; 3]> {
+    if from.len() < 4 || from.len() != to.len() {
+        return None;
+    }
+
+    let (t_from, from_n) = normalize_points(from)?;
+    let (t_to, to_n) = normalize_points(to)?;
+
+    let mut a = Matrix::zeros(2 * from.len(), 9);
+    for (k, (p, q)) in from_n.iter().zip(&to_n).enumerate() {
+        let row_x = [
+            -p.x,
+            -p.y,
+            -1_f64,
+            0_f64,
+            0_f64,
+            0_f64,
+            q.x * p.x,
+            q.x * p.y,
+            q.x,
+        ];
+        let row_y = [
+            0_f64,
+            0_f64,
+            0_f64,
+            -p.x,
+            -p.y,
+            -1_f64,
+            q.y * p.x,
+            q.y * p.y,
+            q.y,
+        ];
+        for c in 0..9 {
+            a[(2 * k, c)] = row_x[c];
+            a[(2 * k + 1, c)] = row_y[c];
+        }
+    }
+    let h = null_vector(&a);
+    let h_n = [[h[0], h[1], h[2]], [h[3], h[4], h[5]], [h[6], h[7], h[8]]];
+
+    // H = T_to^-1 * H_n * T_from
+    let h = mat3_mul(&mat3_mul(&similarity_inverse(&t_to), &h_n), &t_from);
+    let scale = h[2][2];
+    if scale.abs() < 1e-12 {
+        return None;
+    }
+    Some(h.map(|row| row.map(|v| v / scale)))
+}
+
+/// Hartley normalization: centroid in the origin and mean distance √2.
+/// Returns the normalizing similarity and the normalized points.
+fn normalize_points(points: &[DVec2]) -> Option<([[f64; 3]; 3], Vec<DVec2>)> {
+    let centroid = points.iter().copied().sum::<DVec2>() / points.len() as f64;
+    let mean_distance =
+        points.iter().map(|p| p.distance(centroid)).sum::<f64>() / points.len() as f64;
+    if mean_distance < 1e-12 {
+        return None;
+    }
+    let s = std::f64::consts::SQRT_2 / mean_distance;
+    let normalized = points.iter().map(|p| (*p - centroid) * s).collect();
+    let similarity = [
+        [s, 0_f64, -s * centroid.x],
+        [0_f64, s, -s * centroid.y],
+        [0_f64, 0_f64, 1_f64],
+    ];
+    Some((similarity, normalized))
+}
+
+fn similarity_inverse(t: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let s = t[0][0];
+    [
+        [1_f64 / s, 0_f64, -t[0][2] / s],
+        [0_f64, 1_f64 / s, -t[1][2] / s],
+        [0_f64, 0_f64, 1_f64],
+    ]
+}
+
+fn mat3_mul(a: &[[f64; 3]; 3], b: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let mut result = [[0_f64; 3]; 3];
+    for (i, row) in result.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    result
+}
+
+/// Zero-skew pinhole intrinsics from the homographies of at least three
+/// views, solving for the image of the absolute conic `B = K^-T K^-1`.
+fn closed_form_intrinsics(homographies: &[[[f64; 3]; 3]]) -> Option<CameraModel> {
+    // v_ij as defined in Zhang's paper, h_i being the i-th column of H
+    let v = |h: &[[f64; 3]; 3], i: usize, j: usize| -> [f64; 6] {
+        [
+            h[0][i] * h[0][j],
+            h[0][i] * h[1][j] + h[1][i] * h[0][j],
+            h[1][i] * h[1][j],
+            h[2][i] * h[0][j] + h[0][i] * h[2][j],
+            h[2][i] * h[1][j] + h[1][i] * h[2][j],
+            h[2][i] * h[2][j],
+        ]
+    };
+
+    let mut a = Matrix::zeros(2 * homographies.len(), 6);
+    for (k, h) in homographies.iter().enumerate() {
+        let v12 = v(h, 0, 1);
+        let v11 = v(h, 0, 0);
+        let v22 = v(h, 1, 1);
+        for c in 0..6 {
+            a[(2 * k, c)] = v12[c];
+            a[(2 * k + 1, c)] = v11[c] - v22[c];
+        }
+    }
+    let b = null_vector(&a);
+    let (b11, b12, b22, b13, b23, b33) = (b[0], b[1], b[2], b[3], b[4], b[5]);
+
+    let denominator = b11 * b22 - b12 * b12;
+    let cy = (b12 * b13 - b11 * b23) / denominator;
+    let lambda = b33 - (b13 * b13 + cy * (b12 * b13 - b11 * b23)) / b11;
+    let fx = (lambda / b11).sqrt();
+    let fy = (lambda * b11 / denominator).sqrt();
+    let gamma = -b12 * fx * fx * fy / lambda;
+    let cx = gamma * cy / fy - b13 * fx * fx / lambda;
+
+    if !(fx.is_finite() && fy.is_finite() && cx.is_finite() && cy.is_finite()) {
+        return None;
+    }
+    Some(CameraModel {
+        fx,
+        fy,
+        cx,
+        cy,
+        k1: 0_f64,
+        k2: 0_f64,
+        p1: 0_f64,
+        p2: 0_f64,
+        k3: 0_f64,
+    })
+}
+
+/// Board pose from its homography, given the intrinsics. The rotation is
+/// orthonormalized, the refinement takes care of the residual error.
+fn pose_from_homography(h: &[[f64; 3]; 3], camera: &CameraModel) -> DAffine3 {
+    let k_inv = |c: DVec3| {
+        DVec3::new(
+            (c.x - camera.cx * c.z) / camera.fx,
+            (c.y - camera.cy * c.z) / camera.fy,
+            c.z,
+        )
+    };
+    let column = |i: usize| DVec3::new(h[0][i], h[1][i], h[2][i]);
+
+    let r1 = k_inv(column(0));
+    let mut lambda = 1_f64 / r1.length();
+    let mut t = lambda * k_inv(column(2));
+    if t.z < 0_f64 {
+        // the board must be in front of the camera
+        lambda = -lambda;
+        t = -t;
+    }
+    let r1 = (lambda * r1).normalize();
+    let r2 = lambda * k_inv(column(1));
+    let r2 = (r2 - r1 * r1.dot(r2)).normalize();
+    let r3 = r1.cross(r2);
+
+    let rotation = DQuat::from_mat3(&glam::DMat3::from_cols(r1, r2, r3));
+    DAffine3::from_rotation_translation(rotation, t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Renders the chessboard seen by `camera` from `pose`, with a white
+    /// margin one square wide around the squares and a grey background.
+    fn render_chessboard(
+        camera: &CameraModel,
+        board: &Chessboard,
+        pose: &DAffine3,
+        width: u32,
+        height: u32,
+    ) -> image::GrayImage {
+        const SUPERSAMPLING: u32 = 3;
+        let board_2_cam = pose;
+        let normal = board_2_cam.transform_vector3(DVec3::Z);
+        let origin = board_2_cam.translation;
+        let cam_2_board = board_2_cam.inverse();
+        let distortion = camera_distortion(camera);
+        let s = board.square_size;
+
+        image::GrayImage::from_fn(width, height, |u, v| {
+            let mut total = 0_f64;
+            for sy in 0..SUPERSAMPLING {
+                for sx in 0..SUPERSAMPLING {
+                    let pu = u as f64 + (sx as f64 + 0.5) / SUPERSAMPLING as f64 - 0.5;
+                    let pv = v as f64 + (sy as f64 + 0.5) / SUPERSAMPLING as f64 - 0.5;
+                    let ray = pixel_ray(camera, &distortion, pu, pv);
+                    let t = normal.dot(origin) / normal.dot(ray);
+                    let p = cam_2_board.transform_point3(t * ray);
+
+                    let (i, j) = ((p.x / s).floor() + 1_f64, (p.y / s).floor() + 1_f64);
+                    let squares_x = board.cols as f64 + 1_f64;
+                    let squares_y = board.rows as f64 + 1_f64;
+                    total += if i >= 0_f64 && j >= 0_f64 && i < squares_x && j < squares_y {
+                        if (i + j) as i64 % 2 == 0 {
+                            20_f64
+                        } else {
+                            235_f64
+                        }
+                    } else if i >= -1_f64 && j >= -1_f64 && i <= squares_x && j <= squares_y {
+                        235_f64
+                    } else {
+                        120_f64
+                    };
+                }
+            }
+            image::Luma([(total / (SUPERSAMPLING * SUPERSAMPLING) as f64) as u8])
+        })
+    }
+
+    /// Ray in the camera optical frame, with unit depth, through the pixel
+    /// `(pu, pv)` of the distorted image.
+    fn pixel_ray(camera: &CameraModel, distortion: &Distortion, pu: f64, pv: f64) -> DVec3 {
+        let distorted = glam::Vec2::new(
+            ((pu - camera.cx) / camera.fx) as f32,
+            ((pv - camera.cy) / camera.fy) as f32,
+        );
+        distortion.undistort(distorted).as_dvec2().extend(1_f64)
+    }
+
+    /// Renders the line the laser plane `normal · p = offset`, in the camera
+    /// optical frame, draws on the chessboard seen from `pose`, margin
+    /// included. The line is a gaussian about a millimeter wide.
+    fn render_laser_line(
+        camera: &CameraModel,
+        board: &Chessboard,
+        pose: &DAffine3,
+        (plane_normal, plane_offset): (DVec3, f64),
+        width: u32,
+        height: u32,
+    ) -> image::GrayImage {
+        const SIGMA: f64 = 0.001;
+        let normal = pose.transform_vector3(DVec3::Z);
+        let origin = pose.translation;
+        let cam_2_board = pose.inverse();
+        let distortion = camera_distortion(camera);
+        let s = board.square_size;
+        let (max_x, max_y) = (board.cols as f64 * s, board.rows as f64 * s);
+
+        image::GrayImage::from_fn(width, height, |u, v| {
+            let ray = pixel_ray(camera, &distortion, u as f64, v as f64);
+            let p = normal.dot(origin) / normal.dot(ray) * ray;
+            let on_board = cam_2_board.transform_point3(p);
+            if !(-s..=max_x).contains(&on_board.x) || !(-s..=max_y).contains(&on_board.y) {
+                return image::Luma([0]);
+            }
+            let d = (plane_normal.dot(p) - plane_offset) / SIGMA;
+            image::Luma([(120_f64 * (-d * d / 2_f64).exp()) as u8])
+        })
+    }
+
+    fn camera_distortion(camera: &CameraModel) -> Distortion {
+        Distortion {
+            k1: camera.k1 as f32,
+            k2: camera.k2 as f32,
+            k3: camera.k3 as f32,
+            p1: camera.p1 as f32,
+            p2: camera.p2 as f32,
+        }
+    }
+
+    fn synthetic_camera() -> CameraModel {
+        CameraModel {
+            fx: 820_f64,
+            fy: 810_f64,
+            cx: 330_f64,
+            cy: 235_f64,
+            k1: -0.12,
+            k2: 0.05,
+            p1: 0.001,
+            p2: -0.0005,
+            k3: 0_f64,
+        }
+    }
+
+    /// Pose of `board` rotated by `rotation` around its center, placed at
+    /// `center`.
+    fn centered_pose(board: &Chessboard, rotation: DQuat, center: DVec3) -> DAffine3 {
+        DAffine3::from_rotation_translation(rotation, center - rotation * board.center())
+    }
+
+    #[test]
+    fn calibration_recovers_synthetic_camera() {
+        let camera = synthetic_camera();
+        let board = Chessboard {
+            cols: 8,
+            rows: 6,
+            square_size: 0.03,
+        };
+        let center = DVec3::new(
+            -3.5_f64 * board.square_size,
+            -2.5_f64 * board.square_size,
+            0_f64,
+        );
+
+        // rotation angles and board center, covering the whole image
+        let views = [
+            (0.0_f64, 0.0_f64, 0.0_f64, DVec3::new(0_f64, 0_f64, 0.6_f64)),
+            (0.35, 0.0, 0.1, DVec3::new(-0.11, -0.07, 0.75)),
+            (-0.35, 0.1, -0.1, DVec3::new(0.11, -0.07, 0.75)),
+            (0.0, 0.4, 0.2, DVec3::new(-0.11, 0.07, 0.75)),
+            (0.1, -0.4, -0.2, DVec3::new(0.11, 0.07, 0.75)),
+            (0.3, 0.3, 0.5, DVec3::new(0_f64, 0_f64, 0.7)),
+        ];
+        let images: Vec<(String, image::GrayImage)> = views
+            .iter()
+            .enumerate()
+            .map(|(k, (rx, ry, rz, position))| {
+                let rotation = DQuat::from_euler(glam::EulerRot::XYZ, *rx, *ry, *rz);
+                let translation = *position + rotation * center;
+                let pose = DAffine3::from_rotation_translation(rotation, translation);
+                let image = render_chessboard(&camera, &board, &pose, 640, 480);
+                (format!("view_{k}"), image)
+            })
+            .collect();
+
+        let result = calibrate_camera(&images, &board).unwrap();
+        let intrinsics = &result.intrinsics;
+
+        assert!(result.rms_error < 0.2, "rms error {}", result.rms_error);
+        assert!(
+            (intrinsics.fx - 820_f32).abs() < 4_f32,
+            "fx {}",
+            intrinsics.fx
+        );
+        assert!(
+            (intrinsics.fy - 810_f32).abs() < 4_f32,
+            "fy {}",
+            intrinsics.fy
+        );
+        assert!(
+            (intrinsics.cx - 330_f32).abs() < 3_f32,
+            "cx {}",
+            intrinsics.cx
+        );
+        assert!(
+            (intrinsics.cy - 235_f32).abs() < 3_f32,
+            "cy {}",
+            intrinsics.cy
+        );
+        let k1 = intrinsics.distortion.k1;
+        assert!((k1 + 0.12_f32).abs() < 0.02_f32, "k1 {k1}");
+        assert_eq!(result.board_poses.len(), views.len());
+    }
+
+    #[test]
+    fn laser_calibration_recovers_synthetic_planes() {
+        let camera = synthetic_camera();
+        let intrinsics = camera.to_intrinsics(640_f32, 480_f32);
+        let board = Chessboard {
+            cols: 8,
+            rows: 6,
+            square_size: 0.03,
+        };
+        // vertical planes crossing the optical axis in front of the board
+        let plane = |normal: DVec3, through: DVec3| {
+            let normal = normal.normalize();
+            (normal, normal.dot(through))
+        };
+        let left = plane(DVec3::new(0.94, 0_f64, 0.34), DVec3::new(-0.05, 0_f64, 0.7));
+        let right = plane(DVec3::new(0.94, 0_f64, -0.34), DVec3::new(0.05, 0_f64, 0.7));
+
+        let views = [
+            (0_f64, 0_f64, 0_f64, DVec3::new(0_f64, 0_f64, 0.6)),
+            (0.1, 0.35, 0_f64, DVec3::new(0_f64, 0.02, 0.7)),
+            (-0.1, -0.35, 0.1, DVec3::new(0_f64, -0.02, 0.75)),
+            (0.3, 0.1, -0.1, DVec3::new(0_f64, 0_f64, 0.65)),
+        ];
+        let mut images = Vec::new();
+        for (k, (rx, ry, rz, center)) in views.iter().enumerate() {
+            let rotation = DQuat::from_euler(glam::EulerRot::XYZ, *rx, *ry, *rz);
+            let pose = centered_pose(&board, rotation, *center);
+            // dimmed so that the lines do not saturate on the white squares
+            let mut off = render_chessboard(&camera, &board, &pose, 640, 480);
+            off.pixels_mut().for_each(|p| p.0[0] /= 2);
+            let lit = |laser| {
+                let mut image = render_laser_line(&camera, &board, &pose, laser, 640, 480);
+                for (p, ambient) in image.pixels_mut().zip(off.pixels()) {
+                    p.0[0] += ambient.0[0];
+                }
+                image
+            };
+            images.push((format!("view_{k}_off"), off.clone()));
+            images.push((format!("view_{k}_left"), lit(left)));
+            images.push((format!("view_{k}_right"), lit(right)));
+        }
+
+        let detector = crate::peak::make_peak_detector(crate::peak::PeakDetectorType::CenterOfMass);
+        let result = calibrate_lasers(&images, &board, &intrinsics, detector.as_ref()).unwrap();
+        for (fit, (normal, offset)) in [(&result.left, left), (&result.right, right)] {
+            let expected = LaserCalib::from_plane(normal.as_vec3(), offset as f32);
+            let (plane, rms) = (&fit.plane, fit.rms_error);
+            assert!(rms < 0.001, "rms error {rms}");
+            assert!(fit.points > 500, "{} points", fit.points);
+            assert!(
+                plane.normal.dot(expected.normal) > 0.9995_f32,
+                "normal {} instead of {}",
+                plane.normal,
+                expected.normal
+            );
+            assert!(
+                (plane.offset - expected.offset).abs() < 0.001_f32,
+                "offset {} instead of {}",
+                plane.offset,
+                expected.offset
+            );
+        }
+    }
+
+    #[test]
+    fn turntable_calibration_recovers_synthetic_axis() {
+        let camera = synthetic_camera();
+        let intrinsics = camera.to_intrinsics(640_f32, 480_f32);
+        // the optical axis, looking down by 30 degrees, goes through
+        // (0, 0, 0.08) 0.6 m in front of the camera
+        let camera_calib: CameraCalib = serde_json::from_value(serde_json::json!({
+            "intrinsics": intrinsics,
+            "extrinsics": {
+                "rotation": [0_f32, 60_f32, 0_f32],
+                "translation": [0.52_f32, 0_f32, 0.38_f32],
+            },
+            "cam_2_img_plane_rotation": [0_f32, 180_f32, 90_f32],
+        }))
+        .unwrap();
+        let optical_2_world: glam::Mat4 =
+            (camera_calib.extrinsics.as_affine() * camera_calib.img_plane_2_cam()).into();
+        let optical_2_world = DAffine3::from_mat4(optical_2_world.as_dmat4());
+        let board = Chessboard {
+            cols: 8,
+            rows: 6,
+            square_size: 0.02,
+        };
+
+        let direction = DVec3::new(0.03, -0.02, 1_f64).normalize();
+        let axis_point = DVec3::new(0.01, -0.02, 0_f64);
+        // the board faces the camera and stands 4 cm off the axis
+        let (_, facing, _) = optical_2_world.to_scale_rotation_translation();
+        let board_center = axis_point + DVec3::new(0_f64, 0.04, 0.08);
+        let angles = [-40_f64, -20_f64, 0_f64, 20_f64, 40_f64];
+        let images: Vec<(String, image::GrayImage)> = angles
+            .iter()
+            .map(|angle| {
+                let turn = DAffine3::from_translation(axis_point)
+                    * DAffine3::from_axis_angle(direction, angle.to_radians())
+                    * DAffine3::from_translation(-axis_point);
+                let board_2_world = turn * centered_pose(&board, facing, board_center);
+                let pose = optical_2_world.inverse() * board_2_world;
+                let image = render_chessboard(&camera, &board, &pose, 640, 480);
+                (format!("turntable_{angle}"), image)
+            })
+            .collect();
+
+        let result = calibrate_turntable(&images, &board, &camera_calib).unwrap();
+        let axis = &result.axis;
+        assert!(result.rms_error < 0.001, "rms error {}", result.rms_error);
+        assert!(
+            axis.direction.as_dvec3().dot(direction) > 0.9995_f64,
+            "direction {} instead of {direction}",
+            axis.direction
+        );
+        // the point of the axis closest to the world origin
+        let expected = axis_point - direction * direction.dot(axis_point);
+        assert!(
+            axis.point.as_dvec3().distance(expected) < 0.002_f64,
+            "point {} instead of {expected}",
+            axis.point
+        );
+        assert_eq!(result.step_angles.len(), angles.len() - 1);
+        for step in &result.step_angles {
+            assert!((step - 20_f64).abs() < 0.5_f64, "step of {step} degrees");
+        }
+    }
+}
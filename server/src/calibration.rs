@@ -1,20 +1,72 @@
 use std::io::Read;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use image::buffer;
+use msg::response::{ErrorCode, ServerError};
 use serde::{Deserialize, Serialize};
 use serde_json;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Laser {
+    Left,
+    Right,
+}
+
+impl From<Laser> for msg::response::Laser {
+    fn from(laser: Laser) -> Self {
+        match laser {
+            Laser::Left => msg::response::Laser::Left,
+            Laser::Right => msg::response::Laser::Right,
+        }
+    }
+}
+
+/// Laser plane `normal · p = offset` in the camera optical frame: origin in
+/// the center of projection, x along the image columns, y along the image
+/// rows and z along the optical axis. Units are meters.
 #[derive(Serialize, Deserialize)]
+#[serde(from = "LaserCalibFormat")]
 pub struct LaserCalib {
-    // TODO(alberto): generalize to 3D
-    pub angle: f32,
-    pub baseline: f32,
+    pub normal: glam::Vec3,
+    pub offset: f32,
 }
 
 impl LaserCalib {
-    pub fn angle_rad(&self) -> f32 {
-        return self.angle.to_radians();
+    /// Builds the plane from a unit-less `normal`. The normal is flipped if
+    /// needed so that it points away from the camera and `offset` is positive.
+    pub fn from_plane(normal: glam::Vec3, offset: f32) -> Self {
+        let length = normal.length();
+        let sign = if offset < 0_f32 { -1_f32 } else { 1_f32 };
+        Self {
+            normal: sign * normal / length,
+            offset: sign * offset / length,
+        }
+    }
+
+    /// Vertical plane through `(baseline, 0, 0)` rotated by `angle` degrees
+    /// around the camera y axis, the model used by older calibration files.
+    pub fn from_angle_baseline(angle: f32, baseline: f32) -> Self {
+        let normal = glam::vec3(1_f32, 0_f32, angle.to_radians().tan());
+        Self::from_plane(normal, baseline)
+    }
+}
+
+/// On-disk representations of `LaserCalib`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LaserCalibFormat {
+    Plane { normal: glam::Vec3, offset: f32 },
+    AngleBaseline { angle: f32, baseline: f32 },
+}
+
+impl From<LaserCalibFormat> for LaserCalib {
+    fn from(format: LaserCalibFormat) -> Self {
+        match format {
+            LaserCalibFormat::Plane { normal, offset } => Self::from_plane(normal, offset),
+            LaserCalibFormat::AngleBaseline { angle, baseline } => {
+                Self::from_angle_baseline(angle, baseline)
+            }
+        }
     }
 }
 
@@ -36,17 +88,158 @@ impl RefSysTransform {
     }
 }
 
+/// Pinhole camera model with Brown-Conrady lens distortion. Focal lengths and
+/// principal point are in pixels.
 #[derive(Serialize, Deserialize)]
+#[serde(from = "CameraIntrinsicsFormat")]
 pub struct CameraIntrinsics {
-    pub focal_length: f32,
-    pub height: f32,
+    pub fx: f32,
+    pub fy: f32,
+    pub cx: f32,
+    pub cy: f32,
     pub width: f32,
-    pub meters_per_px: f32,
+    pub height: f32,
+    pub distortion: Distortion,
 }
 
 impl CameraIntrinsics {
-    pub fn focal_length_px(&self) -> f32 {
-        return self.focal_length / self.meters_per_px;
+    /// Ideal pinhole camera with the principal point in the image center.
+    pub fn from_focal_length(focal_length_px: f32, width: f32, height: f32) -> Self {
+        Self {
+            fx: focal_length_px,
+            fy: focal_length_px,
+            cx: width / 2_f32,
+            cy: height / 2_f32,
+            width,
+            height,
+            distortion: Distortion::default(),
+        }
+    }
+
+    /// Removes the lens distortion from a pixel, returning where it would be
+    /// seen by an ideal pinhole camera with the same intrinsics.
+    pub fn undistort_pixel(&self, pixel: glam::Vec2) -> glam::Vec2 {
+        let distorted = self.normalize(pixel);
+        self.denormalize(self.distortion.undistort(distorted))
+    }
+
+    /// Ray through an undistorted pixel, in the camera optical frame with
+    /// unit depth.
+    pub fn pixel_to_ray(&self, pixel: glam::Vec2) -> glam::Vec3 {
+        self.normalize(pixel).extend(1_f32)
+    }
+
+    /// Pixel where a point of the camera optical frame is seen, lens
+    /// distortion included. `None` for points behind the camera.
+    pub fn project(&self, p: glam::Vec3) -> Option<glam::Vec2> {
+        if p.z <= 0_f32 {
+            return None;
+        }
+        Some(self.denormalize(self.distortion.distort(p.truncate() / p.z)))
+    }
+
+    fn normalize(&self, pixel: glam::Vec2) -> glam::Vec2 {
+        glam::vec2((pixel.x - self.cx) / self.fx, (pixel.y - self.cy) / self.fy)
+    }
+
+    fn denormalize(&self, p: glam::Vec2) -> glam::Vec2 {
+        glam::vec2(p.x * self.fx + self.cx, p.y * self.fy + self.cy)
+    }
+}
+
+/// Brown-Conrady radial (`k1`, `k2`, `k3`) and tangential (`p1`, `p2`)
+/// distortion coefficients, same convention as OpenCV.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq)]
+pub struct Distortion {
+    pub k1: f32,
+    pub k2: f32,
+    pub k3: f32,
+    pub p1: f32,
+    pub p2: f32,
+}
+
+impl Distortion {
+    const UNDISTORT_ITERATIONS: usize = 10;
+
+    /// Applies the distortion to normalized image coordinates.
+    pub fn distort(&self, p: glam::Vec2) -> glam::Vec2 {
+        let r2 = p.length_squared();
+        let radial = 1_f32 + r2 * (self.k1 + r2 * (self.k2 + r2 * self.k3));
+        p * radial + self.tangential(p)
+    }
+
+    /// Inverts `distort` with fixed-point iterations, which converge quickly
+    /// for the moderate distortion of real lenses.
+    pub fn undistort(&self, distorted: glam::Vec2) -> glam::Vec2 {
+        let mut p = distorted;
+        for _ in 0..Self::UNDISTORT_ITERATIONS {
+            let r2 = p.length_squared();
+            let radial = 1_f32 + r2 * (self.k1 + r2 * (self.k2 + r2 * self.k3));
+            p = (distorted - self.tangential(p)) / radial;
+        }
+        p
+    }
+
+    fn tangential(&self, p: glam::Vec2) -> glam::Vec2 {
+        let r2 = p.length_squared();
+        glam::vec2(
+            2_f32 * self.p1 * p.x * p.y + self.p2 * (r2 + 2_f32 * p.x * p.x),
+            self.p1 * (r2 + 2_f32 * p.y * p.y) + 2_f32 * self.p2 * p.x * p.y,
+        )
+    }
+}
+
+/// On-disk representations of `CameraIntrinsics`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CameraIntrinsicsFormat {
+    Pinhole {
+        fx: f32,
+        fy: f32,
+        cx: f32,
+        cy: f32,
+        width: f32,
+        height: f32,
+        #[serde(default)]
+        distortion: Distortion,
+    },
+    /// Focal length and pixel size in meters, principal point in the image
+    /// center and no distortion
+    Legacy {
+        focal_length: f32,
+        height: f32,
+        width: f32,
+        meters_per_px: f32,
+    },
+}
+
+impl From<CameraIntrinsicsFormat> for CameraIntrinsics {
+    fn from(format: CameraIntrinsicsFormat) -> Self {
+        match format {
+            CameraIntrinsicsFormat::Pinhole {
+                fx,
+                fy,
+                cx,
+                cy,
+                width,
+                height,
+                distortion,
+            } => Self {
+                fx,
+                fy,
+                cx,
+                cy,
+                width,
+                height,
+                distortion,
+            },
+            CameraIntrinsicsFormat::Legacy {
+                focal_length,
+                height,
+                width,
+                meters_per_px,
+            } => Self::from_focal_length(focal_length / meters_per_px, width, height),
+        }
     }
 }
 
@@ -58,13 +251,91 @@ pub struct CameraCalib {
 }
 
 impl CameraCalib {
+    /// Transform from the camera optical frame, which shares its origin with
+    /// the camera frame, to the camera frame.
     pub fn img_plane_2_cam(&self) -> glam::Affine3A {
-        let t = glam::vec3(0_f32, 0_f32, -self.intrinsics.focal_length);
         let rx = self.cam_2_img_plane_rotation.x.to_radians();
         let ry = self.cam_2_img_plane_rotation.y.to_radians();
         let rz = self.cam_2_img_plane_rotation.z.to_radians();
         let rot = glam::Quat::from_euler(glam::EulerRot::XYZ, rx, ry, rz);
-        return glam::Affine3A::from_rotation_translation(rot, t).inverse();
+        glam::Affine3A::from_quat(rot).inverse()
+    }
+
+    /// Pixel where a point in world coordinates is seen, see
+    /// `CameraIntrinsics::project`.
+    pub fn world_to_pixel(&self, p: glam::Vec3) -> Option<glam::Vec2> {
+        let world_2_img_plane = (self.extrinsics.as_affine() * self.img_plane_2_cam()).inverse();
+        self.intrinsics
+            .project(world_2_img_plane.transform_point3(p))
+    }
+
+    /// Position of the camera in world coordinates.
+    pub fn position(&self) -> glam::Vec3 {
+        self.extrinsics.as_affine().translation.into()
+    }
+}
+
+/// Cylinder standing on the turntable that contains the scanned object.
+#[derive(Serialize, Deserialize)]
+pub struct ScanVolume {
+    pub radius: f32,
+    pub height: f32,
+}
+
+impl Default for ScanVolume {
+    fn default() -> Self {
+        Self {
+            radius: 0.15,
+            height: 0.3,
+        }
+    }
+}
+
+impl ScanVolume {
+    /// Points slightly below the turntable surface are accepted to tolerate
+    /// calibration errors.
+    const SURFACE_TOLERANCE: f32 = 0.01;
+
+    pub fn contains(&self, axis: &TurntableAxis, point: glam::Vec3) -> bool {
+        let (radius, height) = axis.cylindrical_coordinates(point);
+        let inside_radius = radius <= self.radius;
+        let inside_height = height >= -Self::SURFACE_TOLERANCE && height <= self.height;
+        inside_radius && inside_height
+    }
+}
+
+/// Rotation axis of the turntable in world coordinates. `direction` points
+/// up and `point` lies on the turntable surface.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TurntableAxis {
+    pub direction: glam::Vec3,
+    pub point: glam::Vec3,
+}
+
+impl Default for TurntableAxis {
+    fn default() -> Self {
+        Self {
+            direction: glam::Vec3::Z,
+            point: glam::Vec3::ZERO,
+        }
+    }
+}
+
+impl TurntableAxis {
+    /// Rotation of `angle` radians around the axis.
+    pub fn rotation(&self, angle: f32) -> glam::Affine3A {
+        let rotation = glam::Affine3A::from_axis_angle(self.direction.normalize(), angle);
+        glam::Affine3A::from_translation(self.point)
+            * rotation
+            * glam::Affine3A::from_translation(-self.point)
+    }
+
+    /// Distance of `p` from the axis and height above the turntable surface.
+    pub fn cylindrical_coordinates(&self, p: glam::Vec3) -> (f32, f32) {
+        let direction = self.direction.normalize();
+        let relative = p - self.point;
+        let height = relative.dot(direction);
+        ((relative - height * direction).length(), height)
     }
 }
 
@@ -73,6 +344,45 @@ pub struct Calibration {
     pub camera: CameraCalib,
     pub left_laser: LaserCalib,
     pub right_laser: LaserCalib,
+    #[serde(default)]
+    pub scan_volume: ScanVolume,
+    #[serde(default)]
+    pub turntable_axis: TurntableAxis,
+}
+
+impl Calibration {
+    /// Rejects the values triangulation cannot work with.
+    pub fn validate(&self) -> Result<()> {
+        let intrinsics = &self.camera.intrinsics;
+        let positive = |v: f32| v > 0_f32 && v.is_finite();
+        if ![
+            intrinsics.fx,
+            intrinsics.fy,
+            intrinsics.width,
+            intrinsics.height,
+        ]
+        .into_iter()
+        .all(positive)
+        {
+            bail!("the focal lengths and the image size must be positive");
+        }
+        for (name, laser) in [("left", &self.left_laser), ("right", &self.right_laser)] {
+            if !positive(laser.normal.length()) || !laser.offset.is_finite() {
+                bail!("the {name} laser plane has no normal");
+            }
+        }
+        if !positive(self.turntable_axis.direction.length()) {
+            bail!("the turntable axis has no direction");
+        }
+        Ok(())
+    }
+
+    pub fn laser(&self, laser: Laser) -> &LaserCalib {
+        match laser {
+            Laser::Left => &self.left_laser,
+            Laser::Right => &self.right_laser,
+        }
+    }
 }
 
 fn decorate_with_path(e: std::io::Error, path: &std::path::Path) -> std::io::Error {
@@ -80,7 +390,20 @@ fn decorate_with_path(e: std::io::Error, path: &std::path::Path) -> std::io::Err
     return std::io::Error::new(e.kind(), format!("{p}: {e}"));
 }
 
+/// Reads and validates a calibration file, failures are reported as
+/// `ErrorCode::CalibrationInvalid`.
 pub fn load_calibration(path: &std::path::Path) -> Result<Calibration> {
+    read_calibration(path).map_err(|e| {
+        let error = ServerError {
+            code: ErrorCode::CalibrationInvalid,
+            message: format!("Invalid calibration {}", path.display()),
+            details: Some(format!("{e:#}")),
+        };
+        error.into()
+    })
+}
+
+fn read_calibration(path: &std::path::Path) -> Result<Calibration> {
     let file = std::fs::File::open(path).map_err(|e| decorate_with_path(e, path))?;
     let mut reader = std::io::BufReader::new(file);
 
@@ -89,6 +412,122 @@ pub fn load_calibration(path: &std::path::Path) -> Result<Calibration> {
         .read_to_string(&mut buffer)
         .map_err(|e| decorate_with_path(e, path))?;
 
-    let calibration = serde_json::from_str(&buffer)?;
+    let calibration: Calibration = serde_json::from_str(&buffer)?;
+    calibration.validate()?;
     return Ok(calibration);
 }
+
+/// Replaces the entry at the JSON `pointer` (e.g. "/camera/intrinsics") of the
+/// calibration file, creating the file and the intermediate objects if needed.
+/// The other entries are left untouched.
+pub fn update_calibration_entry(
+    path: &std::path::Path,
+    pointer: &str,
+    value: impl Serialize,
+) -> Result<()> {
+    let mut root = match std::fs::read_to_string(path) {
+        Ok(buffer) => serde_json::from_str(&buffer)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => serde_json::json!({}),
+        Err(e) => return Err(decorate_with_path(e, path).into()),
+    };
+
+    let mut entry = &mut root;
+    for key in pointer.split('/').skip(1) {
+        if !entry.is_object() {
+            *entry = serde_json::json!({});
+        }
+        entry = entry
+            .as_object_mut()
+            .unwrap()
+            .entry(key)
+            .or_insert(serde_json::Value::Null);
+    }
+    *entry = serde_json::to_value(value)?;
+
+    let mut buffer = Vec::new();
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(b"\t");
+    let mut serializer = serde_json::Serializer::with_formatter(&mut buffer, formatter);
+    root.serialize(&mut serializer)?;
+    std::fs::write(path, buffer).map_err(|e| decorate_with_path(e, path))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::server_error;
+
+    const LEGACY_CALIBRATION: &str = r#"{
+        "camera": {
+            "intrinsics": {
+                "focal_length": 0.00474,
+                "height": 1280.0,
+                "width": 720.0,
+                "meters_per_px": 0.000005039
+            },
+            "extrinsics": { "rotation": [0.0, 60.0, 0.0], "translation": [0.129, 0.0, 0.246] },
+            "cam_2_img_plane_rotation": [0.0, 180.0, 90.0]
+        },
+        "right_laser": { "angle": 30.0, "baseline": 0.1 },
+        "left_laser": { "angle": -30.0, "baseline": -0.1 }
+    }"#;
+
+    #[test]
+    fn legacy_calibrations_still_load() {
+        let calib: Calibration = serde_json::from_str(LEGACY_CALIBRATION).unwrap();
+        calib.validate().unwrap();
+
+        let intrinsics = &calib.camera.intrinsics;
+        assert!((intrinsics.fx - 0.00474 / 0.000005039).abs() < 1e-2);
+        assert_eq!(intrinsics.fx, intrinsics.fy);
+        assert_eq!((intrinsics.cx, intrinsics.cy), (360_f32, 640_f32));
+        assert_eq!(intrinsics.distortion, Distortion::default());
+
+        let half_sqrt3 = 3_f32.sqrt() / 2_f32;
+        let right = &calib.right_laser;
+        assert!(right
+            .normal
+            .abs_diff_eq(glam::vec3(half_sqrt3, 0_f32, 0.5), 1e-6));
+        assert!((right.offset - 0.1 * half_sqrt3).abs() < 1e-6);
+        let left = &calib.left_laser;
+        assert!(left
+            .normal
+            .abs_diff_eq(glam::vec3(-half_sqrt3, 0_f32, 0.5), 1e-6));
+        assert!((left.offset - 0.1 * half_sqrt3).abs() < 1e-6);
+
+        assert_eq!(calib.turntable_axis.direction, glam::Vec3::Z);
+    }
+
+    #[test]
+    fn undistort_inverts_distort() {
+        let distortion = Distortion {
+            k1: -0.12,
+            k2: 0.05,
+            k3: -0.01,
+            p1: 0.002,
+            p2: -0.001,
+        };
+        for x in [-0.4_f32, -0.1, 0_f32, 0.25, 0.4] {
+            for y in [-0.3_f32, 0_f32, 0.15, 0.3] {
+                let p = glam::vec2(x, y);
+                let round_trip = distortion.undistort(distortion.distort(p));
+                assert!(round_trip.abs_diff_eq(p, 1e-5), "{p} became {round_trip}");
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_calibrations_are_reported_as_such() {
+        let dir = std::env::temp_dir().join(format!("scanner_bad_calib_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("calibration.json");
+        std::fs::write(&path, "{ \"camera\": 42 }").unwrap();
+
+        for path in [path.clone(), dir.join("missing.json")] {
+            let e = load_calibration(&path).err().unwrap();
+            assert_eq!(server_error(&e).code, ErrorCode::CalibrationInvalid);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,15 +1,20 @@
 use crate::calibration;
+use crate::calibration::Laser;
 use crate::imgproc;
+use crate::lasers;
 use crate::logging;
 use crate::motor;
+use crate::scanner::ScanControl;
+use crate::sessions::SessionRecorder;
 use anyhow::Result;
-use log::{error, info, warn};
-use msg::response;
-use msg::response::PointCloud;
+use clap::ValueEnum;
+use log::info;
+use msg::command::{ScanLasers, ScanParameters};
+use msg::response::ErrorCode;
+use msg::response::Progress;
 use msg::response::Response;
-use std::f32::consts::PI;
 use std::path::{Path, PathBuf};
-use std::{io, sync::mpsc, vec::IntoIter};
+use std::{sync::mpsc, vec::IntoIter};
 
 pub enum CameraType {
     DiskLoader(std::path::PathBuf),
@@ -20,30 +25,263 @@ pub enum CameraType {
 #[derive(Debug)]
 pub enum CameraError {
     CameraNotFound,
+    /// A frame could not be captured or read
+    FrameUnavailable(String),
+    /// The color frame is not the size of the calibrated images
+    WrongFrameSize {
+        expected: (u32, u32),
+        actual: (u32, u32),
+    },
+    #[cfg(feature = "camera")]
     WrongCameraConfig,
+    #[cfg(feature = "camera")]
     InvalidRequest,
 }
 
+impl CameraError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            CameraError::CameraNotFound => ErrorCode::CameraNotFound,
+            CameraError::FrameUnavailable(_) | CameraError::WrongFrameSize { .. } => {
+                ErrorCode::CameraFault
+            }
+            #[cfg(feature = "camera")]
+            CameraError::WrongCameraConfig | CameraError::InvalidRequest => ErrorCode::CameraFault,
+        }
+    }
+}
+
 impl std::error::Error for CameraError {}
 
 impl std::fmt::Display for CameraError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             CameraError::CameraNotFound => write!(f, "Camera not found"),
+            CameraError::FrameUnavailable(reason) => write!(f, "Frame unavailable: {reason}"),
+            CameraError::WrongFrameSize { expected, actual } => write!(
+                f,
+                "Frame size {}x{} does not match the calibrated {}x{}",
+                actual.0, actual.1, expected.0, expected.1
+            ),
+            #[cfg(feature = "camera")]
             CameraError::WrongCameraConfig => write!(f, "Wrong camera configuration"),
+            #[cfg(feature = "camera")]
             CameraError::InvalidRequest => write!(f, "Invalid request"),
         }
     }
 }
 
-pub trait Camera {
-    fn acquire_from_camera(
-        &mut self,
-        rec: &dyn logging::Logger,
-        calib: &calibration::Calibration,
-        motor: &mut dyn motor::StepperMotor,
-        scanned_data_queue: mpsc::Sender<Response>,
-    ) -> Result<Vec<glam::Vec3>>;
+/// Frames captured at each turntable step. Datasets loaded from disk must
+/// store the frames of each step in the same order, one after the other,
+/// followed by the color frame when the scan asks for colors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum AcquisitionMode {
+    /// A single frame with both lasers on
+    LaserOnly,
+    /// A frame with both lasers on followed by a frame with the lasers off
+    AmbientSubtraction,
+    /// A frame with the lasers off, then one with only the left laser on and
+    /// one with only the right laser on
+    PerLaser,
+}
+
+/// Everything the acquisition needs besides the camera.
+pub struct Acquisition<'a> {
+    pub rec: &'a dyn logging::Logger,
+    pub calib: &'a calibration::Calibration,
+    pub motor: &'a mut dyn motor::StepperMotor,
+    pub lasers: &'a mut dyn lasers::LaserDriver,
+    pub detector: &'a imgproc::LaserLineDetector,
+    pub mode: AcquisitionMode,
+    pub params: &'a ScanParameters,
+    pub control: &'a ScanControl,
+    pub session: &'a mut SessionRecorder,
+    pub scanned_data_queue: mpsc::Sender<Response>,
+}
+
+/// Pixels of a captured frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FrameFormat {
+    Gray,
+    Color,
+}
+
+pub trait Camera: Send {
+    fn acquire_from_camera(&mut self, acquisition: Acquisition) -> Result<u64>;
+}
+
+/// Rotates the turntable through the scan angles, capturing and processing
+/// the frames required by the acquisition mode at each of them. Stops early
+/// when `control` asks to. Returns the number of points scanned.
+fn scan(
+    acquisition: Acquisition,
+    mut capture: impl FnMut(FrameFormat) -> Result<image::DynamicImage>,
+) -> Result<u64> {
+    let Acquisition {
+        rec,
+        calib,
+        motor,
+        lasers,
+        detector,
+        mode,
+        params,
+        control,
+        session,
+        scanned_data_queue,
+    } = acquisition;
+
+    let mut points_so_far = 0_u64;
+    let mut turntable_deg = 0_f32;
+    let total_steps = params.steps();
+    let start_time = std::time::Instant::now();
+    for step in 0..total_steps {
+        if !control.checkpoint() {
+            info!("Scan stopped at step {step}");
+            break;
+        }
+
+        let angle_deg = params.angle_deg(step);
+        rotate_turntable(motor, turntable_deg, angle_deg)?;
+        turntable_deg = angle_deg;
+        control.set_angle(angle_deg);
+        std::thread::sleep(std::time::Duration::from_millis(params.settle_ms));
+
+        info!("Acquiring frames for step {step} at {angle_deg} degrees");
+        let frames = capture_step_frames(mode, params, lasers, &mut capture)?;
+        if let Some(color) = &frames.color {
+            check_frame_size(color, calib)?;
+        }
+        let new_points = imgproc::process_frames(
+            &frames,
+            step as i64,
+            angle_deg.to_radians(),
+            rec,
+            calib,
+            detector,
+        );
+        points_so_far += new_points.points.len() as u64;
+        session.record_step(step, angle_deg, &new_points)?;
+        scanned_data_queue.send(Response::PointCloud(new_points))?;
+
+        let progress = progress(step + 1, total_steps, angle_deg, points_so_far, start_time);
+        scanned_data_queue.send(Response::Progress(progress))?;
+    }
+
+    Ok(points_so_far)
+}
+
+/// The colors are sampled at the calibrated pixels, which only fall in place
+/// on frames of the calibrated size.
+fn check_frame_size(
+    frame: &image::RgbImage,
+    calib: &calibration::Calibration,
+) -> Result<(), CameraError> {
+    let intrinsics = &calib.camera.intrinsics;
+    let expected = (intrinsics.width as u32, intrinsics.height as u32);
+    if frame.dimensions() != expected {
+        return Err(CameraError::WrongFrameSize {
+            expected,
+            actual: frame.dimensions(),
+        });
+    }
+    Ok(())
+}
+
+/// The remaining time is estimated from the mean step duration so far.
+fn progress(
+    completed_steps: u32,
+    total_steps: u32,
+    angle_deg: f32,
+    points_so_far: u64,
+    start_time: std::time::Instant,
+) -> Progress {
+    let elapsed = start_time.elapsed();
+    let remaining_steps = total_steps - completed_steps;
+    let eta = elapsed.mul_f64(remaining_steps as f64 / completed_steps as f64);
+    Progress {
+        step: completed_steps,
+        total_steps,
+        angle_deg,
+        points_so_far,
+        elapsed_ms: elapsed.as_millis() as u64,
+        eta_ms: eta.as_millis() as u64,
+    }
+}
+
+/// Steps the motor from `from_deg` to `to_deg`. Both angles are rounded to
+/// whole motor steps, so rounding errors do not add up over the scan.
+fn rotate_turntable(motor: &mut dyn motor::StepperMotor, from_deg: f32, to_deg: f32) -> Result<()> {
+    let steps_per_rev = motor.steps_per_rev();
+    let motor_steps = |deg: f32| (deg / 360_f32 * steps_per_rev).round() as i64;
+    let steps = motor_steps(to_deg) - motor_steps(from_deg);
+    motor
+        .step(steps.max(0) as u32)
+        .map_err(|e| motor::motor_fault(motor, format!("{e:#}")))
+}
+
+/// Captures the frames required by `mode` for a turntable step, switching the
+/// selected lasers on and off between captures, then the color frame if the
+/// scan asks for one. Lasers are left off.
+fn capture_step_frames(
+    mode: AcquisitionMode,
+    params: &ScanParameters,
+    lasers: &mut dyn lasers::LaserDriver,
+    mut capture: impl FnMut(FrameFormat) -> Result<image::DynamicImage>,
+) -> Result<imgproc::StepFrames> {
+    let mut gray = || Ok(capture(FrameFormat::Gray)?.into_luma8());
+    let lit_laser = match params.lasers {
+        ScanLasers::Left => Some(Laser::Left),
+        ScanLasers::Right => Some(Laser::Right),
+        ScanLasers::Both => None,
+    };
+    let mut frames = match mode {
+        AcquisitionMode::LaserOnly => imgproc::StepFrames {
+            lit: vec![capture_lit_frame(lit_laser, lasers, &mut gray)?],
+            ambient: None,
+            color: None,
+        },
+        AcquisitionMode::AmbientSubtraction => {
+            let lit = vec![capture_lit_frame(lit_laser, lasers, &mut gray)?];
+            imgproc::StepFrames {
+                lit,
+                ambient: Some(gray()?),
+                color: None,
+            }
+        }
+        AcquisitionMode::PerLaser => {
+            let ambient = Some(gray()?);
+            let mut lit = Vec::new();
+            for laser in [Laser::Left, Laser::Right] {
+                if lit_laser.is_none_or(|selected| selected == laser) {
+                    lit.push(capture_lit_frame(Some(laser), lasers, &mut gray)?);
+                }
+            }
+            imgproc::StepFrames {
+                lit,
+                ambient,
+                color: None,
+            }
+        }
+    };
+    if params.color {
+        frames.color = Some(capture(FrameFormat::Color)?.into_rgb8());
+    }
+    Ok(frames)
+}
+
+/// Captures a frame with only `laser` on, or both if `None`.
+fn capture_lit_frame(
+    laser: Option<Laser>,
+    lasers: &mut dyn lasers::LaserDriver,
+    capture: &mut impl FnMut() -> Result<image::GrayImage>,
+) -> Result<(Option<Laser>, image::GrayImage)> {
+    match laser {
+        Some(laser) => lasers.set(laser, true),
+        None => lasers.set_all(true),
+    }
+    let frame = capture();
+    lasers.set_all(false);
+    Ok((laser, frame?))
 }
 
 pub fn make_camera(camera_type: CameraType) -> Result<Box<dyn Camera>> {
@@ -65,48 +303,37 @@ pub struct DiskCamera {
 }
 
 impl DiskCamera {
-    fn from_directory(path: &Path) -> Result<DiskCamera, io::Error> {
-        let images: Vec<PathBuf> = path
-            .read_dir()?
+    fn from_directory(path: &Path) -> Result<DiskCamera> {
+        let entries = path.read_dir().map_err(|e| {
+            anyhow::Error::new(CameraError::CameraNotFound)
+                .context(format!("No images in {}: {e}", path.display()))
+        })?;
+        let mut images: Vec<PathBuf> = entries
             .filter_map(|f| match f {
                 Ok(entry) => Some(entry.path()),
                 Err(_) => None,
             })
             .collect();
+        // frames of the same step are interleaved, keep them in order
+        images.sort();
         Ok(DiskCamera {
             iter: images.into_iter(),
         })
     }
 
-    fn get_image(&mut self) -> Result<image::GrayImage> {
-        match self.iter.next() {
-            Some(path) => Ok(image::open(path).unwrap().into_luma8()),
-            None => Err(io::Error::new(io::ErrorKind::NotFound, "No more images").into()),
-        }
+    fn get_image(&mut self) -> Result<image::DynamicImage, CameraError> {
+        let path = self
+            .iter
+            .next()
+            .ok_or_else(|| CameraError::FrameUnavailable("no more images".to_string()))?;
+        image::open(&path)
+            .map_err(|e| CameraError::FrameUnavailable(format!("{}: {e}", path.display())))
     }
 }
 
 impl Camera for DiskCamera {
-    fn acquire_from_camera(
-        &mut self,
-        rec: &dyn logging::Logger,
-        calib: &calibration::Calibration,
-        motor: &mut dyn motor::StepperMotor,
-        scanned_data_queue: mpsc::Sender<Response>,
-    ) -> Result<Vec<glam::Vec3>> {
-        let mut point_cloud = Vec::<glam::Vec3>::new();
-        let angle_per_step = 5_f32.to_radians();
-        let steps = (2_f32 * PI / angle_per_step).ceil() as i32;
-        for i in 0..steps {
-            let image = self.get_image()?;
-            let new_points =
-                imgproc::process_image(&image, i as i64, rec, angle_per_step, &calib, motor);
-
-            let response = PointCloud { points: new_points };
-            scanned_data_queue.send(Response::PointCloud(response))?;
-        }
-
-        Ok(point_cloud)
+    fn acquire_from_camera(&mut self, acquisition: Acquisition) -> Result<u64> {
+        scan(acquisition, |_| Ok(self.get_image()?))
     }
 }
 
@@ -126,26 +353,20 @@ pub mod real_camera {
         request::{Request, ReuseFlag},
         stream::StreamRole,
     };
-    use msg::response::PointCloud;
     use std::time::Duration;
 
     // drm-fourcc does not have MJPEG type yet, construct it from raw fourcc identifier
     //const MJPEG: PixelFormat = PixelFormat::new(u32::from_le_bytes([b'M', b'J', b'P', b'G']), 0);
 
     const YUV420: PixelFormat = PixelFormat::new(DrmFourcc::Yuv420 as u32, 0);
+    const CAPTURE_TIMEOUT: Duration = Duration::from_secs(2);
 
     pub struct PiCamera {
         pub num_buffers: u32,
     }
 
     impl Camera for PiCamera {
-        fn acquire_from_camera(
-            &mut self,
-            rec: &dyn logging::Logger,
-            calib: &calibration::Calibration,
-            motor: &mut dyn motor::StepperMotor,
-            scanned_data_queue: mpsc::Sender<Response>,
-        ) -> Result<Vec<glam::Vec3>> {
+        fn acquire_from_camera(&mut self, acquisition: Acquisition) -> Result<u64> {
             let mngr = CameraManager::new()?;
             let cameras = mngr.cameras();
             let cam = cameras.get(0).ok_or(CameraError::CameraNotFound)?;
@@ -218,34 +439,9 @@ pub mod real_camera {
 
             cam.start(None)?;
 
-            let mut point_cloud = Vec::<glam::Vec3>::new();
-            let angle_per_step = 5_f32.to_radians();
-            let steps = (2_f32 * PI / angle_per_step).ceil() as i32;
-            for i in 0..steps {
-                info!("Acquiring image {}", i);
-                let image = get_image(&cam, &stream, &frame_size, &mut reqs, &rx)?;
-                info!("Processing image {}", i);
-                imgproc::process_image(
-                    &image,
-                    i as i64,
-                    rec,
-                    angle_per_step,
-                    &calib,
-                    motor,
-                    &mut point_cloud,
-                )?;
-
-                let fake_data = i as f32;
-                let response = PointCloud {
-                    points: vec![glam::Vec3::new(fake_data, fake_data, fake_data)],
-                };
-                scanned_data_queue.send(Response::PointCloud(response))?;
-
-                motor.step(1);
-                std::thread::sleep(Duration::from_millis(100));
-            }
-
-            Ok(point_cloud)
+            scan(acquisition, |format| {
+                get_image(&cam, &stream, &frame_size, &mut reqs, &rx, format)
+            })
         }
     }
 
@@ -255,39 +451,250 @@ pub mod real_camera {
         frame_size: &libcamera::geometry::Size,
         requests: &mut Vec<Request>,
         rx: &std::sync::mpsc::Receiver<Request>,
-    ) -> Result<image::GrayImage> {
+        format: FrameFormat,
+    ) -> Result<image::DynamicImage> {
+        // requests completing after their capture timed out hold stale
+        // frames, they are only recycled
+        for mut late in rx.try_iter() {
+            late.reuse(ReuseFlag::REUSE_BUFFERS);
+            requests.push(late);
+        }
         let req = requests.pop().ok_or(CameraError::InvalidRequest)?;
-        camera.queue_request(req).unwrap();
+        camera.queue_request(req).map_err(|e| {
+            CameraError::FrameUnavailable(format!("failed to queue the capture request: {e}"))
+        })?;
 
         info!("Waiting for camera request execution");
-        let mut req = rx.recv_timeout(Duration::from_secs(2))?;
+        let mut req = rx.recv_timeout(CAPTURE_TIMEOUT).map_err(|_| {
+            CameraError::FrameUnavailable(format!("no frame within {CAPTURE_TIMEOUT:?}"))
+        })?;
         info!("Camera request {:?} completed!", req);
         info!("Metadata: {:#?}", req.metadata());
-        // Get framebuffer for our stream
+        let buffer_data = copy_planes(&req, stream, format);
+
+        // recycle request, even when its frame is unusable
+        req.reuse(ReuseFlag::REUSE_BUFFERS);
+        requests.push(req);
+        let mut buffer_data = buffer_data?;
+
+        let (width, height) = (frame_size.width, frame_size.height);
+        let image = match format {
+            FrameFormat::Gray => {
+                let luma = buffer_data.swap_remove(0);
+                image::GrayImage::from_raw(width, height, luma).map(image::DynamicImage::ImageLuma8)
+            }
+            FrameFormat::Color => {
+                yuv420_to_rgb(&buffer_data, width, height).map(image::DynamicImage::ImageRgb8)
+            }
+        };
+        Ok(image.ok_or(CameraError::InvalidRequest)?)
+    }
+
+    /// Copies the planes of the frame captured by `req`: the grayscale image
+    /// in the first one, the chroma at half resolution in the other two.
+    fn copy_planes(
+        req: &Request,
+        stream: &libcamera::stream::Stream,
+        format: FrameFormat,
+    ) -> Result<Vec<Vec<u8>>, CameraError> {
         let framebuffer: &MemoryMappedFrameBuffer<FrameBuffer> =
-            req.buffer(&stream).ok_or(CameraError::InvalidRequest)?;
+            req.buffer(stream).ok_or(CameraError::InvalidRequest)?;
         info!("FrameBuffer metadata: {:#?}", framebuffer.metadata());
 
-        // grayscale image encoded in first image plane
         let planes = framebuffer.data();
-        let image_data = planes.get(0).unwrap();
-        let data_length = framebuffer
-            .metadata()
-            .unwrap()
-            .planes()
-            .get(0)
-            .unwrap()
-            .bytes_used as usize;
-
-        // copy buffer data to Vec<u8>
-        let buffer_data = image_data[..data_length].to_vec();
-
-        // recycle request
-        req.reuse(ReuseFlag::REUSE_BUFFERS);
-        requests.push(req);
+        let metadata = framebuffer.metadata().ok_or(CameraError::InvalidRequest)?;
+        let plane_count = match format {
+            FrameFormat::Gray => 1,
+            FrameFormat::Color => 3,
+        };
+        let mut buffer_data = Vec::new();
+        for i in 0..plane_count {
+            let image_data = planes.get(i).ok_or(CameraError::InvalidRequest)?;
+            let data_length = metadata
+                .planes()
+                .get(i)
+                .ok_or(CameraError::InvalidRequest)?
+                .bytes_used as usize;
+            // copy buffer data to Vec<u8>
+            buffer_data.push(image_data[..data_length].to_vec());
+        }
+        Ok(buffer_data)
+    }
+
+    /// Full range BT.601 conversion, as in JPEG.
+    fn yuv420_to_rgb(planes: &[Vec<u8>], width: u32, height: u32) -> Option<image::RgbImage> {
+        let [y, u, v] = planes else {
+            return None;
+        };
+        let (w, h) = (width as usize, height as usize);
+        let chroma_w = w.div_ceil(2);
+        if y.len() < w * h {
+            return None;
+        }
+        let mut image = image::RgbImage::new(width, height);
+        for (x, row, pixel) in image.enumerate_pixels_mut() {
+            let (x, row) = (x as usize, row as usize);
+            let chroma = (row / 2) * chroma_w + x / 2;
+            let luma = y[row * w + x] as f32;
+            // truncated chroma planes leave the pixels gray
+            let cb = u.get(chroma).copied().unwrap_or(128) as f32 - 128_f32;
+            let cr = v.get(chroma).copied().unwrap_or(128) as f32 - 128_f32;
+            let channel = |value: f32| value.round().clamp(0_f32, 255_f32) as u8;
+            pixel.0 = [
+                channel(luma + 1.402_f32 * cr),
+                channel(luma - 0.344136_f32 * cb - 0.714136_f32 * cr),
+                channel(luma + 1.772_f32 * cb),
+            ];
+        }
+        Some(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lasers::LaserDriver;
+    use crate::scanner::server_error;
+
+    struct StalledMotor {}
+
+    impl motor::StepperMotor for StalledMotor {
+        fn steps_per_rev(&self) -> f32 {
+            200_f32
+        }
+
+        fn step(&mut self, _steps: u32) -> Result<()> {
+            anyhow::bail!("stalled")
+        }
+
+        fn name(&self) -> String {
+            "Stalled Motor".to_string()
+        }
+    }
+
+    /// A disk camera replaying one pixel frames whose value is their index.
+    fn numbered_frames(name: &str, count: u8) -> (DiskCamera, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("{name}_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for i in 0..count {
+            let frame = image::GrayImage::from_pixel(1, 1, image::Luma([i]));
+            frame.save(dir.join(format!("{i:03}.png"))).unwrap();
+        }
+        (DiskCamera::from_directory(&dir).unwrap(), dir)
+    }
+
+    #[test]
+    fn per_laser_frames_are_read_in_order() {
+        let (mut camera, dir) = numbered_frames("scanner_per_laser", 5);
+        let mut lasers = lasers::MockLaserDriver::default();
+        let value = |image: &image::GrayImage| image.get_pixel(0, 0).0[0];
+
+        let params = ScanParameters::default();
+        let mut capture =
+            |_: FrameFormat| -> Result<image::DynamicImage> { Ok(camera.get_image()?) };
+        let frames = capture_step_frames(
+            AcquisitionMode::PerLaser,
+            &params,
+            &mut lasers,
+            &mut capture,
+        )
+        .unwrap();
+        assert_eq!(frames.ambient.as_ref().map(value), Some(0));
+        let lit: Vec<(Option<Laser>, u8)> = frames
+            .lit
+            .iter()
+            .map(|(laser, image)| (*laser, value(image)))
+            .collect();
+        assert_eq!(lit, [(Some(Laser::Left), 1), (Some(Laser::Right), 2)]);
+        assert!(!lasers.is_on(Laser::Left) && !lasers.is_on(Laser::Right));
+
+        // a single laser skips the frame of the other one
+        let params = ScanParameters {
+            lasers: ScanLasers::Right,
+            ..Default::default()
+        };
+        let frames = capture_step_frames(
+            AcquisitionMode::PerLaser,
+            &params,
+            &mut lasers,
+            &mut capture,
+        )
+        .unwrap();
+        assert_eq!(frames.ambient.as_ref().map(value), Some(3));
+        let lit: Vec<(Option<Laser>, u8)> = frames
+            .lit
+            .iter()
+            .map(|(laser, image)| (*laser, value(image)))
+            .collect();
+        assert_eq!(lit, [(Some(Laser::Right), 4)]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ambient_frames_follow_the_lit_frame() {
+        let (mut camera, dir) = numbered_frames("scanner_ambient", 2);
+        let mut lasers = lasers::MockLaserDriver::default();
+        let mut capture =
+            |_: FrameFormat| -> Result<image::DynamicImage> { Ok(camera.get_image()?) };
+        let frames = capture_step_frames(
+            AcquisitionMode::AmbientSubtraction,
+            &ScanParameters::default(),
+            &mut lasers,
+            &mut capture,
+        )
+        .unwrap();
+        assert_eq!(frames.lit.len(), 1);
+        assert_eq!(frames.lit[0].0, None);
+        assert_eq!(frames.lit[0].1.get_pixel(0, 0).0[0], 0);
+        assert_eq!(frames.ambient.unwrap().get_pixel(0, 0).0[0], 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_missing_image_directory_is_camera_not_found() {
+        let dir = std::env::temp_dir().join(format!("scanner_no_images_{}", std::process::id()));
+        let e = DiskCamera::from_directory(&dir).err().unwrap();
+        assert_eq!(server_error(&e).code, ErrorCode::CameraNotFound);
+    }
+
+    #[test]
+    fn unreadable_and_missing_frames_are_camera_faults() {
+        let dir = std::env::temp_dir().join(format!("scanner_bad_images_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("0.png"), b"not a png").unwrap();
+
+        let mut camera = DiskCamera::from_directory(&dir).unwrap();
+        let unreadable: anyhow::Error = camera.get_image().err().unwrap().into();
+        assert_eq!(server_error(&unreadable).code, ErrorCode::CameraFault);
+        let exhausted: anyhow::Error = camera.get_image().err().unwrap().into();
+        assert_eq!(server_error(&exhausted).code, ErrorCode::CameraFault);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn eta_scales_the_mean_step_duration() {
+        let start_time = std::time::Instant::now() - std::time::Duration::from_millis(1000);
+        let first = progress(1, 4, 5_f32, 10, start_time);
+        assert_eq!(first.step, 1);
+        assert_eq!(first.total_steps, 4);
+        assert_eq!(first.angle_deg, 5_f32);
+        assert_eq!(first.points_so_far, 10);
+        assert!(first.elapsed_ms >= 1000);
+        // three more steps as long as the first one
+        assert!(first.eta_ms.abs_diff(3 * first.elapsed_ms) <= 3);
+
+        let last = progress(4, 4, 15_f32, 10, start_time);
+        assert_eq!(last.eta_ms, 0);
+    }
 
-        let image = image::GrayImage::from_raw(frame_size.width, frame_size.height, buffer_data)
-            .ok_or(CameraError::InvalidRequest)?;
-        return Ok(image);
+    #[test]
+    fn a_failing_motor_is_a_motor_fault() {
+        let e = rotate_turntable(&mut StalledMotor {}, 0_f32, 90_f32).unwrap_err();
+        let error = server_error(&e);
+        assert_eq!(error.code, ErrorCode::MotorFault);
+        assert_eq!(error.details.as_deref(), Some("stalled"));
     }
 }
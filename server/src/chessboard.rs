@@ -0,0 +1,337 @@
+//! Detection of the inner corners of a chessboard calibration target.
+//!
+//! Corner candidates are found with the ChESS detector (Bennett and Lasenby,
+//! "ChESS - Quick and robust detection of chess-board features", 2013),
+//! refined to sub-pixel accuracy with the gradient orthogonality constraint
+//! and finally linked into a grid by growing it from the most central corner.
+
+use glam::Vec2;
+use std::collections::{HashMap, VecDeque};
+
+/// Radius in pixels of the ring sampled by the ChESS detector. Squares must be
+/// at least twice as large for corners to be detected.
+const RING_RADIUS: f32 = 5_f32;
+/// Candidates weaker than this fraction of the strongest response are dropped
+const RESPONSE_THRESHOLD: f32 = 0.15;
+const REFINE_ITERATIONS: usize = 10;
+/// Maximum distance from the predicted grid position, relative to the grid
+/// spacing, for a candidate to be linked to the grid
+const LINK_TOLERANCE: f32 = 0.35;
+
+/// Finds the `cols` x `rows` inner corners of a chessboard. Corners are
+/// returned row by row, with the grid oriented so that its axes form a right
+/// handed frame with the optical axis of the camera.
+pub fn find_corners(image: &image::GrayImage, cols: usize, rows: usize) -> Option<Vec<Vec2>> {
+    let blurred = blur(image);
+    let candidates = detect_candidates(&blurred);
+    let refined: Vec<Vec2> = candidates
+        .iter()
+        .map(|c| refine_corner(&blurred, *c))
+        .collect();
+    let refined = deduplicate(refined, RING_RADIUS / 2_f32);
+    assemble_grid(&refined, cols, rows)
+}
+
+/// Grayscale image stored as floats, to avoid repeated conversions.
+struct FloatImage {
+    width: usize,
+    height: usize,
+    data: Vec<f32>,
+}
+
+impl FloatImage {
+    fn at(&self, x: isize, y: isize) -> f32 {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        self.data[y * self.width + x]
+    }
+}
+
+/// Separable [1 4 6 4 1] / 16 binomial blur, close to a Gaussian with sigma 1.
+fn blur(image: &image::GrayImage) -> FloatImage {
+    const KERNEL: [f32; 5] = [1_f32, 4_f32, 6_f32, 4_f32, 1_f32];
+    let source = FloatImage {
+        width: image.width() as usize,
+        height: image.height() as usize,
+        data: image.as_raw().iter().map(|p| *p as f32).collect(),
+    };
+
+    let convolve = |image: &FloatImage, dx: isize, dy: isize| -> FloatImage {
+        let mut data = vec![0_f32; image.data.len()];
+        for y in 0..image.height {
+            for x in 0..image.width {
+                let sum: f32 = KERNEL
+                    .iter()
+                    .enumerate()
+                    .map(|(k, w)| {
+                        let offset = k as isize - 2;
+                        w * image.at(x as isize + offset * dx, y as isize + offset * dy)
+                    })
+                    .sum();
+                data[y * image.width + x] = sum / 16_f32;
+            }
+        }
+        FloatImage {
+            width: image.width,
+            height: image.height,
+            data,
+        }
+    };
+
+    let horizontal = convolve(&source, 1, 0);
+    convolve(&horizontal, 0, 1)
+}
+
+/// Local maxima of the ChESS response.
+fn detect_candidates(image: &FloatImage) -> Vec<Vec2> {
+    let ring: Vec<(isize, isize)> = (0..16)
+        .map(|n| {
+            let angle = n as f32 * std::f32::consts::TAU / 16_f32;
+            (
+                (RING_RADIUS * angle.cos()).round() as isize,
+                (RING_RADIUS * angle.sin()).round() as isize,
+            )
+        })
+        .collect();
+
+    let margin = RING_RADIUS.ceil() as usize + 1;
+    let mut response = vec![0_f32; image.data.len()];
+    for y in margin..image.height.saturating_sub(margin) {
+        for x in margin..image.width.saturating_sub(margin) {
+            let (x, y) = (x as isize, y as isize);
+            let samples: Vec<f32> = ring
+                .iter()
+                .map(|(dx, dy)| image.at(x + dx, y + dy))
+                .collect();
+
+            let sum_response: f32 = (0..4)
+                .map(|n| (samples[n] + samples[n + 8] - samples[n + 4] - samples[n + 12]).abs())
+                .sum();
+            let diff_response: f32 = (0..8).map(|n| (samples[n] - samples[n + 8]).abs()).sum();
+            let ring_mean = samples.iter().sum::<f32>() / 16_f32;
+            let local_mean = (-1..=1)
+                .flat_map(|dy| (-1..=1).map(move |dx| (dx, dy)))
+                .map(|(dx, dy)| image.at(x + dx, y + dy))
+                .sum::<f32>()
+                / 9_f32;
+            let mean_response = 16_f32 * (ring_mean - local_mean).abs();
+
+            response[y as usize * image.width + x as usize] =
+                sum_response - diff_response - mean_response;
+        }
+    }
+
+    let max_response = response.iter().copied().fold(0_f32, f32::max);
+    if max_response <= 0_f32 {
+        return Vec::new();
+    }
+    let threshold = RESPONSE_THRESHOLD * max_response;
+
+    let radius = RING_RADIUS as isize;
+    let mut candidates = Vec::<Vec2>::new();
+    for y in margin..image.height.saturating_sub(margin) {
+        for x in margin..image.width.saturating_sub(margin) {
+            let value = response[y * image.width + x];
+            if value < threshold {
+                continue;
+            }
+            let is_maximum = (-radius..=radius)
+                .flat_map(|dy| (-radius..=radius).map(move |dx| (dx, dy)))
+                .all(|(dx, dy)| {
+                    let nx = (x as isize + dx).clamp(0, image.width as isize - 1) as usize;
+                    let ny = (y as isize + dy).clamp(0, image.height as isize - 1) as usize;
+                    let other = response[ny * image.width + nx];
+                    // break ties in favour of the first pixel in scan order
+                    other < value || (other == value && (dy, dx) >= (0, 0))
+                });
+            if is_maximum {
+                candidates.push(Vec2::new(x as f32, y as f32));
+            }
+        }
+    }
+    candidates
+}
+
+/// Moves the corner to the point where the image gradients of its
+/// neighbourhood are orthogonal to the direction towards it, the same
+/// approach as OpenCV's `cornerSubPix`.
+fn refine_corner(image: &FloatImage, corner: Vec2) -> Vec2 {
+    let half_window = RING_RADIUS as isize;
+    let mut q = corner;
+    for _ in 0..REFINE_ITERATIONS {
+        let (cx, cy) = (q.x.round() as isize, q.y.round() as isize);
+        let mut g = glam::Mat2::ZERO;
+        let mut b = Vec2::ZERO;
+        for dy in -half_window..=half_window {
+            for dx in -half_window..=half_window {
+                let (x, y) = (cx + dx, cy + dy);
+                let gradient = Vec2::new(
+                    (image.at(x + 1, y) - image.at(x - 1, y)) / 2_f32,
+                    (image.at(x, y + 1) - image.at(x, y - 1)) / 2_f32,
+                );
+                let ggt = glam::Mat2::from_cols(gradient * gradient.x, gradient * gradient.y);
+                g += ggt;
+                b += ggt * Vec2::new(x as f32, y as f32);
+            }
+        }
+
+        if g.determinant().abs() < 1e-6 {
+            break;
+        }
+        let next = g.inverse() * b;
+        if next.distance(corner) > RING_RADIUS {
+            // diverging, keep the last estimate
+            break;
+        }
+        let shift = next.distance(q);
+        q = next;
+        if shift < 0.01 {
+            break;
+        }
+    }
+    q
+}
+
+fn deduplicate(points: Vec<Vec2>, min_distance: f32) -> Vec<Vec2> {
+    let mut unique = Vec::<Vec2>::new();
+    for p in points {
+        if unique.iter().all(|u| u.distance(p) >= min_distance) {
+            unique.push(p);
+        }
+    }
+    unique
+}
+
+/// Links the corners into a grid starting from the one closest to their
+/// centroid and predicting each neighbour from the already linked ones.
+fn assemble_grid(corners: &[Vec2], cols: usize, rows: usize) -> Option<Vec<Vec2>> {
+    if corners.len() < cols * rows {
+        return None;
+    }
+
+    let centroid = corners.iter().copied().sum::<Vec2>() / corners.len() as f32;
+    let seed = closest(corners, centroid, &[])?;
+
+    // initial grid directions from the nearest neighbours of the seed
+    let mut neighbours: Vec<usize> = (0..corners.len()).filter(|&i| i != seed).collect();
+    neighbours.sort_by(|&a, &b| {
+        let da = corners[a].distance(corners[seed]);
+        let db = corners[b].distance(corners[seed]);
+        da.total_cmp(&db)
+    });
+    let u = corners[*neighbours.first()?] - corners[seed];
+    let v = neighbours
+        .iter()
+        .take(4)
+        .skip(1)
+        .map(|&i| corners[i] - corners[seed])
+        .min_by(|a, b| {
+            let ca = a.normalize().dot(u.normalize()).abs();
+            let cb = b.normalize().dot(u.normalize()).abs();
+            ca.total_cmp(&cb)
+        })?;
+
+    let mut grid = HashMap::<(i32, i32), usize>::new();
+    let mut used = vec![false; corners.len()];
+    grid.insert((0, 0), seed);
+    used[seed] = true;
+
+    let mut queue = VecDeque::from([(0_i32, 0_i32)]);
+    while let Some((i, j)) = queue.pop_front() {
+        let position = corners[grid[&(i, j)]];
+        for (di, dj) in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+            let target = (i + di, j + dj);
+            if grid.contains_key(&target) {
+                continue;
+            }
+
+            let step = local_step(corners, &grid, (i, j), (di, dj)).unwrap_or(if di != 0 {
+                u * di as f32
+            } else {
+                v * dj as f32
+            });
+            let predicted = position + step;
+            let Some(found) = closest(corners, predicted, &used) else {
+                continue;
+            };
+            if corners[found].distance(predicted) > LINK_TOLERANCE * step.length() {
+                continue;
+            }
+
+            grid.insert(target, found);
+            used[found] = true;
+            queue.push_back(target);
+        }
+    }
+
+    let (min_i, max_i) = grid.keys().fold((i32::MAX, i32::MIN), |(lo, hi), k| {
+        (lo.min(k.0), hi.max(k.0))
+    });
+    let (min_j, max_j) = grid.keys().fold((i32::MAX, i32::MIN), |(lo, hi), k| {
+        (lo.min(k.1), hi.max(k.1))
+    });
+    let width = (max_i - min_i + 1) as usize;
+    let height = (max_j - min_j + 1) as usize;
+    if grid.len() != width * height {
+        return None;
+    }
+
+    let at = |i: usize, j: usize| corners[grid[&(min_i + i as i32, min_j + j as i32)]];
+    let mut ordered: Vec<Vec<Vec2>> = if width == cols && height == rows {
+        (0..rows)
+            .map(|j| (0..cols).map(|i| at(i, j)).collect())
+            .collect()
+    } else if width == rows && height == cols {
+        (0..rows)
+            .map(|j| (0..cols).map(|i| at(j, i)).collect())
+            .collect()
+    } else {
+        return None;
+    };
+
+    // fix the handedness of the grid, in image coordinates the y axis points
+    // down so a right handed grid has a positive cross product
+    let x_axis = ordered[0][1] - ordered[0][0];
+    let y_axis = ordered[1][0] - ordered[0][0];
+    if x_axis.perp_dot(y_axis) < 0_f32 {
+        for row in &mut ordered {
+            row.reverse();
+        }
+    }
+
+    Some(ordered.into_iter().flatten().collect())
+}
+
+/// Step towards the neighbour `direction` of `(i, j)`, estimated from the
+/// already linked corners on the opposite side.
+fn local_step(
+    corners: &[Vec2],
+    grid: &HashMap<(i32, i32), usize>,
+    (i, j): (i32, i32),
+    (di, dj): (i32, i32),
+) -> Option<Vec2> {
+    let current = corners[grid[&(i, j)]];
+    if let Some(previous) = grid.get(&(i - di, j - dj)) {
+        return Some(current - corners[*previous]);
+    }
+
+    // no corner behind, use the step between two parallel neighbours
+    for (pi, pj) in [(dj, di), (-dj, -di)] {
+        let side = grid.get(&(i + pi, j + pj));
+        let side_next = grid.get(&(i + pi + di, j + pj + dj));
+        if let (Some(side), Some(side_next)) = (side, side_next) {
+            return Some(corners[*side_next] - corners[*side]);
+        }
+    }
+    None
+}
+
+fn closest(points: &[Vec2], target: Vec2, excluded: &[bool]) -> Option<usize> {
+    (0..points.len())
+        .filter(|&i| !excluded.get(i).copied().unwrap_or(false))
+        .min_by(|&a, &b| {
+            let da = points[a].distance_squared(target);
+            let db = points[b].distance_squared(target);
+            da.total_cmp(&db)
+        })
+}
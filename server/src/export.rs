@@ -0,0 +1,456 @@
+//! Point clouds and meshes written to the file formats read by the tools
+//! downstream: MeshLab and CloudCompare, scripts, PCL and 3D viewers.
+//!
+//! Every format can also be read back, the attributes it does not store are
+//! lost on the way.
+
+mod gltf;
+mod obj;
+mod pcd;
+mod ply;
+mod stl;
+mod xyz;
+
+use anyhow::{anyhow, bail, Result};
+use msg::command::ExportFormat;
+use msg::response::{Laser, PointCloud, Session};
+use std::io::Write;
+use std::path::Path;
+
+/// The cloud of a session with the step of each point. Sessions recorded
+/// before the points kept their step only know the step indices when the
+/// steps add up to the merged cloud, which is not the case for interrupted
+/// sessions.
+pub fn session_cloud(session: Session) -> PointCloud {
+    let mut cloud = session.cloud;
+    if cloud.steps.is_none() {
+        let steps: Vec<u32> = session
+            .info
+            .steps
+            .iter()
+            .flat_map(|s| std::iter::repeat_n(s.step, s.points as usize))
+            .collect();
+        cloud.steps = (steps.len() == cloud.points.len()).then_some(steps);
+    }
+    cloud
+}
+
+fn validate_cloud(cloud: &PointCloud) -> Result<()> {
+    let n = cloud.points.len();
+    let lengths = [
+        ("intensities", cloud.intensities.as_ref().map(Vec::len)),
+        ("normals", cloud.normals.as_ref().map(Vec::len)),
+        ("colors", cloud.colors.as_ref().map(Vec::len)),
+        ("steps", cloud.steps.as_ref().map(Vec::len)),
+        ("lasers", cloud.lasers.as_ref().map(Vec::len)),
+        ("pixels", cloud.pixels.as_ref().map(Vec::len)),
+    ];
+    for (name, len) in lengths {
+        if let Some(len) = len.filter(|&len| len != n) {
+            bail!("{len} {name} for {n} points");
+        }
+    }
+    Ok(())
+}
+
+/// Triangles joining the points of a cloud, counter-clockwise seen from
+/// outside.
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct Mesh {
+    pub vertices: PointCloud,
+    pub triangles: Vec<[u32; 3]>,
+}
+
+/// What the formats write, implemented by point clouds and meshes.
+pub trait Geometry {
+    fn vertices(&self) -> &PointCloud;
+    /// Empty for point clouds
+    fn triangles(&self) -> &[[u32; 3]] {
+        &[]
+    }
+
+    fn validate(&self) -> Result<()> {
+        validate_cloud(self.vertices())?;
+        let n = self.vertices().points.len();
+        if let Some(t) = self
+            .triangles()
+            .iter()
+            .find(|t| t.iter().any(|&i| i as usize >= n))
+        {
+            bail!("Triangle {t:?} refers to missing vertices, there are {n}");
+        }
+        Ok(())
+    }
+}
+
+impl Geometry for PointCloud {
+    fn vertices(&self) -> &PointCloud {
+        self
+    }
+}
+
+impl Geometry for Mesh {
+    fn vertices(&self) -> &PointCloud {
+        &self.vertices
+    }
+
+    fn triangles(&self) -> &[[u32; 3]] {
+        &self.triangles
+    }
+}
+
+pub trait Exporter {
+    /// Without the dot
+    fn extension(&self) -> &'static str;
+    fn write(&self, out: &mut dyn Write, geometry: &dyn Geometry) -> Result<()>;
+    /// Point clouds are read as meshes without triangles.
+    fn read(&self, bytes: &[u8]) -> Result<Mesh>;
+}
+
+pub fn make_exporter(format: ExportFormat) -> Box<dyn Exporter> {
+    match format {
+        ExportFormat::PlyAscii => Box::new(ply::Ply { binary: false }),
+        ExportFormat::PlyBinary => Box::new(ply::Ply { binary: true }),
+        ExportFormat::Xyz => Box::new(xyz::Xyz),
+        ExportFormat::Csv => Box::new(xyz::Csv),
+        ExportFormat::Pcd => Box::new(pcd::Pcd),
+        ExportFormat::Obj => Box::new(obj::Obj),
+        ExportFormat::Stl => Box::new(stl::Stl),
+        ExportFormat::Glb => Box::new(gltf::Glb),
+    }
+}
+
+/// Command line names of the formats, the first one of each extension is
+/// picked from the output file name.
+const FORMAT_NAMES: [(&str, ExportFormat); 8] = [
+    ("ply", ExportFormat::PlyBinary),
+    ("ply-ascii", ExportFormat::PlyAscii),
+    ("xyz", ExportFormat::Xyz),
+    ("csv", ExportFormat::Csv),
+    ("pcd", ExportFormat::Pcd),
+    ("obj", ExportFormat::Obj),
+    ("stl", ExportFormat::Stl),
+    ("glb", ExportFormat::Glb),
+];
+
+pub fn parse_format(name: &str) -> Result<ExportFormat, String> {
+    FORMAT_NAMES
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|&(_, format)| format)
+        .ok_or_else(|| {
+            let names: Vec<&str> = FORMAT_NAMES.iter().map(|(n, _)| *n).collect();
+            format!(
+                "unknown format {name:?}, expected one of {}",
+                names.join(", ")
+            )
+        })
+}
+
+pub fn format_from_path(path: &Path) -> Result<ExportFormat> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+    FORMAT_NAMES
+        .iter()
+        .map(|&(_, format)| format)
+        .find(|&format| {
+            make_exporter(format)
+                .extension()
+                .eq_ignore_ascii_case(extension)
+        })
+        .ok_or_else(|| anyhow!("Cannot guess the export format of {}", path.display()))
+}
+
+/// Numeric types of the binary formats.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn size(self) -> usize {
+        match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        }
+    }
+}
+
+/// Reads the values of a file body, either as whitespace separated text or
+/// as little-endian binary.
+enum Values<'a> {
+    Text(std::str::SplitAsciiWhitespace<'a>),
+    Binary(&'a [u8]),
+}
+
+impl<'a> Values<'a> {
+    fn new(body: &'a [u8], binary: bool) -> Result<Self> {
+        Ok(if binary {
+            Values::Binary(body)
+        } else {
+            Values::Text(std::str::from_utf8(body)?.split_ascii_whitespace())
+        })
+    }
+
+    /// Integers and single precision floats are exact in a `f64`.
+    fn read(&mut self, scalar: Scalar) -> Result<f64> {
+        match self {
+            Values::Text(tokens) => {
+                let token = tokens.next().ok_or_else(|| anyhow!("Truncated file"))?;
+                // parsing as f64 then rounding could be off by one ulp
+                match scalar {
+                    Scalar::F32 => Ok(token.parse::<f32>()? as f64),
+                    _ => Ok(token.parse()?),
+                }
+            }
+            Values::Binary(bytes) => {
+                if bytes.len() < scalar.size() {
+                    bail!("Truncated file");
+                }
+                let (value, rest) = bytes.split_at(scalar.size());
+                *bytes = rest;
+                let value = match scalar {
+                    Scalar::I8 => value[0] as i8 as f64,
+                    Scalar::U8 => value[0] as f64,
+                    Scalar::I16 => i16::from_le_bytes(value.try_into()?) as f64,
+                    Scalar::U16 => u16::from_le_bytes(value.try_into()?) as f64,
+                    Scalar::I32 => i32::from_le_bytes(value.try_into()?) as f64,
+                    Scalar::U32 => u32::from_le_bytes(value.try_into()?) as f64,
+                    Scalar::F32 => f32::from_le_bytes(value.try_into()?) as f64,
+                    Scalar::F64 => f64::from_le_bytes(value.try_into()?),
+                };
+                Ok(value)
+            }
+        }
+    }
+}
+
+/// Splits a file with a text header ending with the line starting with
+/// `last_line`, returns the header lines and the body.
+fn split_header<'a>(bytes: &'a [u8], last_line: &str) -> Result<(Vec<&'a str>, &'a [u8])> {
+    let mut lines = Vec::new();
+    let mut rest = bytes;
+    loop {
+        let end = rest
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or_else(|| anyhow!("Missing {last_line:?} header line"))?;
+        let line = std::str::from_utf8(&rest[..end])?.trim_end_matches('\r');
+        rest = &rest[end + 1..];
+        lines.push(line);
+        if line.starts_with(last_line) {
+            return Ok((lines, rest));
+        }
+    }
+}
+
+/// Attributes read from a file, a point misses an attribute when its vector
+/// is shorter.
+#[derive(Default)]
+struct CloudBuilder {
+    cloud: PointCloud,
+    intensities: Vec<f32>,
+    normals: Vec<glam::Vec3>,
+    colors: Vec<[u8; 3]>,
+    steps: Vec<u32>,
+    lasers: Vec<Laser>,
+    pixels: Vec<glam::Vec2>,
+}
+
+impl CloudBuilder {
+    /// Attributes present for every point are kept.
+    fn build(self) -> PointCloud {
+        let n = self.cloud.points.len();
+        let complete = |len: usize| n > 0 && len == n;
+        PointCloud {
+            intensities: complete(self.intensities.len()).then_some(self.intensities),
+            normals: complete(self.normals.len()).then_some(self.normals),
+            colors: complete(self.colors.len()).then_some(self.colors),
+            steps: complete(self.steps.len()).then_some(self.steps),
+            lasers: complete(self.lasers.len()).then_some(self.lasers),
+            pixels: complete(self.pixels.len()).then_some(self.pixels),
+            ..self.cloud
+        }
+    }
+}
+
+/// Number of a laser in the files, the one of the binary point clouds.
+fn laser_id(laser: Laser) -> u8 {
+    match laser {
+        Laser::Left => 0,
+        Laser::Right => 1,
+    }
+}
+
+fn laser_from_id(id: u32) -> Result<Laser> {
+    match id {
+        0 => Ok(Laser::Left),
+        1 => Ok(Laser::Right),
+        _ => bail!("Unknown laser {id}"),
+    }
+}
+
+/// Splits polygons into triangles around their first vertex.
+fn triangulate(polygon: &[u32], triangles: &mut Vec<[u32; 3]>) {
+    for i in 2..polygon.len() {
+        triangles.push([polygon[0], polygon[i - 1], polygon[i]]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cloud() -> PointCloud {
+        PointCloud {
+            points: vec![
+                glam::vec3(1_f32, 2_f32, 3_f32),
+                glam::vec3(-0.5_f32, 0.1_f32, 4_f32),
+                glam::vec3(0.001_f32, -7.25_f32, 1e-5_f32),
+            ],
+            intensities: Some(vec![0.25_f32, 1_f32, 0.3_f32]),
+            normals: Some(vec![
+                glam::Vec3::X,
+                glam::Vec3::Z,
+                glam::vec3(0.6, 0.8, 0.0),
+            ]),
+            colors: Some(vec![[255, 0, 10], [0, 128, 0], [1, 2, 3]]),
+            steps: Some(vec![0, 7, 7]),
+            lasers: Some(vec![Laser::Left, Laser::Right, Laser::Left]),
+            pixels: Some(vec![
+                glam::vec2(10.5_f32, 0_f32),
+                glam::vec2(1639.25_f32, 1231_f32),
+                glam::vec2(3_f32, 0.125_f32),
+            ]),
+        }
+    }
+
+    fn mesh() -> Mesh {
+        Mesh {
+            vertices: PointCloud {
+                points: vec![
+                    glam::Vec3::ZERO,
+                    glam::Vec3::X,
+                    glam::Vec3::Y,
+                    glam::vec3(1_f32, 1_f32, 0.5_f32),
+                ],
+                ..Default::default()
+            },
+            triangles: vec![[0, 1, 2], [1, 3, 2]],
+        }
+    }
+
+    fn round_trip(format: ExportFormat, geometry: &dyn Geometry) -> Mesh {
+        let exporter = make_exporter(format);
+        let mut bytes = Vec::new();
+        exporter.write(&mut bytes, geometry).unwrap();
+        exporter.read(&bytes).unwrap()
+    }
+
+    /// Only keeps the attributes stored by a format.
+    fn without(
+        mut cloud: PointCloud,
+        intensities: bool,
+        normals: bool,
+        colors: bool,
+        steps: bool,
+    ) -> PointCloud {
+        // the formats with the steps keep all the scan attributes
+        if !steps {
+            cloud.lasers = None;
+            cloud.pixels = None;
+        }
+        if !intensities {
+            cloud.intensities = None;
+        }
+        if !normals {
+            cloud.normals = None;
+        }
+        if !colors {
+            cloud.colors = None;
+        }
+        if !steps {
+            cloud.steps = None;
+        }
+        cloud
+    }
+
+    #[test]
+    fn point_clouds_round_trip() {
+        // intensities, normals, colors, steps with the lasers and pixels
+        let formats = [
+            (ExportFormat::PlyAscii, [true, true, true, true]),
+            (ExportFormat::PlyBinary, [true, true, true, true]),
+            (ExportFormat::Xyz, [false, false, false, false]),
+            (ExportFormat::Csv, [true, true, true, true]),
+            (ExportFormat::Pcd, [true, true, true, true]),
+            (ExportFormat::Obj, [false, true, true, false]),
+            (ExportFormat::Glb, [true, true, true, false]),
+        ];
+        for (format, [intensities, normals, colors, steps]) in formats {
+            let read = round_trip(format, &cloud());
+            let expected = without(cloud(), intensities, normals, colors, steps);
+            assert_eq!(read.vertices, expected, "{format:?}");
+            assert!(read.triangles.is_empty(), "{format:?}");
+        }
+    }
+
+    #[test]
+    fn meshes_round_trip() {
+        for format in [
+            ExportFormat::PlyAscii,
+            ExportFormat::PlyBinary,
+            ExportFormat::Obj,
+            ExportFormat::Stl,
+            ExportFormat::Glb,
+        ] {
+            assert_eq!(round_trip(format, &mesh()), mesh(), "{format:?}");
+        }
+    }
+
+    #[test]
+    fn invalid_geometry_is_refused() {
+        let mut cloud = cloud();
+        cloud.steps = Some(vec![0]);
+        let mut mesh = mesh();
+        mesh.triangles.push([0, 1, 4]);
+        for format in ExportFormat::ALL {
+            let exporter = make_exporter(format);
+            assert!(
+                exporter.write(&mut Vec::new(), &cloud).is_err(),
+                "{format:?}"
+            );
+            assert!(
+                exporter.write(&mut Vec::new(), &mesh).is_err(),
+                "{format:?}"
+            );
+        }
+        // STL only stores triangles
+        let stl = make_exporter(ExportFormat::Stl);
+        assert!(stl.write(&mut Vec::new(), &self::cloud()).is_err());
+    }
+
+    #[test]
+    fn formats_by_name() {
+        assert_eq!(parse_format("PLY-ascii"), Ok(ExportFormat::PlyAscii));
+        assert!(parse_format("las").is_err());
+        let path = Path::new("scan.ply");
+        assert_eq!(format_from_path(path).unwrap(), ExportFormat::PlyBinary);
+        assert_eq!(
+            format_from_path(Path::new("scan.GLB")).unwrap(),
+            ExportFormat::Glb
+        );
+        assert!(format_from_path(Path::new("scan")).is_err());
+    }
+}
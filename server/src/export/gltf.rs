@@ -0,0 +1,289 @@
+//! Binary glTF 2.0, a single file read by 3D viewers, Blender and web
+//! pages. Point clouds are drawn as points, intensities are stored in the
+//! custom `_INTENSITY` attribute.
+
+use super::{CloudBuilder, Exporter, Geometry, Mesh};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{json, Value};
+use std::io::Write;
+
+const MAGIC: &[u8; 4] = b"glTF";
+const VERSION: u32 = 2;
+const JSON_CHUNK: u32 = 0x4E4F534A;
+const BIN_CHUNK: u32 = 0x004E4942;
+
+const FLOAT: u32 = 5126;
+const UNSIGNED_BYTE: u32 = 5121;
+const UNSIGNED_SHORT: u32 = 5123;
+const UNSIGNED_INT: u32 = 5125;
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+const POINTS: u32 = 0;
+const TRIANGLES: u32 = 4;
+
+pub struct Glb;
+
+/// Buffer views and accessors of the binary chunk, one per attribute.
+#[derive(Default)]
+struct Buffers {
+    bin: Vec<u8>,
+    views: Vec<Value>,
+    accessors: Vec<Value>,
+}
+
+impl Buffers {
+    /// Returns the accessor index.
+    fn add(&mut self, data: &[u8], target: u32, mut accessor: Value) -> usize {
+        self.views.push(json!({
+            "buffer": 0,
+            "byteOffset": self.bin.len(),
+            "byteLength": data.len(),
+            "target": target,
+        }));
+        self.bin.extend_from_slice(data);
+        // vertex attributes must be aligned to 4 bytes
+        self.bin.resize(self.bin.len().next_multiple_of(4), 0);
+        accessor["bufferView"] = json!(self.views.len() - 1);
+        self.accessors.push(accessor);
+        self.accessors.len() - 1
+    }
+}
+
+fn le_bytes(values: impl IntoIterator<Item = f32>) -> Vec<u8> {
+    values.into_iter().flat_map(f32::to_le_bytes).collect()
+}
+
+impl Exporter for Glb {
+    fn extension(&self) -> &'static str {
+        "glb"
+    }
+
+    fn write(&self, out: &mut dyn Write, geometry: &dyn Geometry) -> Result<()> {
+        geometry.validate()?;
+        let cloud = geometry.vertices();
+        let triangles = geometry.triangles();
+        if cloud.points.is_empty() {
+            bail!("glTF files cannot be empty");
+        }
+
+        let n = cloud.points.len();
+        let mut buffers = Buffers::default();
+        let min = cloud
+            .points
+            .iter()
+            .fold(glam::Vec3::INFINITY, |m, p| m.min(*p));
+        let max = cloud
+            .points
+            .iter()
+            .fold(glam::Vec3::NEG_INFINITY, |m, p| m.max(*p));
+        let positions = le_bytes(cloud.points.iter().flat_map(|p| p.to_array()));
+        let accessor = json!({
+            "componentType": FLOAT,
+            "count": n,
+            "type": "VEC3",
+            "min": min.to_array(),
+            "max": max.to_array(),
+        });
+        let mut attributes = json!({
+            "POSITION": buffers.add(&positions, ARRAY_BUFFER, accessor),
+        });
+        if let Some(normals) = &cloud.normals {
+            let data = le_bytes(normals.iter().flat_map(|n| n.to_array()));
+            let accessor = json!({"componentType": FLOAT, "count": n, "type": "VEC3"});
+            attributes["NORMAL"] = json!(buffers.add(&data, ARRAY_BUFFER, accessor));
+        }
+        if let Some(colors) = &cloud.colors {
+            // RGBA, RGB would break the 4 bytes alignment of the vertices
+            let data: Vec<u8> = colors
+                .iter()
+                .flat_map(|&[r, g, b]| [r, g, b, 255])
+                .collect();
+            let accessor = json!({
+                "componentType": UNSIGNED_BYTE,
+                "normalized": true,
+                "count": n,
+                "type": "VEC4",
+            });
+            attributes["COLOR_0"] = json!(buffers.add(&data, ARRAY_BUFFER, accessor));
+        }
+        if let Some(intensities) = &cloud.intensities {
+            let data = le_bytes(intensities.iter().copied());
+            let accessor = json!({"componentType": FLOAT, "count": n, "type": "SCALAR"});
+            attributes["_INTENSITY"] = json!(buffers.add(&data, ARRAY_BUFFER, accessor));
+        }
+        let mut primitive = json!({"attributes": attributes, "mode": POINTS});
+        if !triangles.is_empty() {
+            let data: Vec<u8> = triangles
+                .iter()
+                .flatten()
+                .flat_map(|i| i.to_le_bytes())
+                .collect();
+            let accessor = json!({
+                "componentType": UNSIGNED_INT,
+                "count": 3 * triangles.len(),
+                "type": "SCALAR",
+            });
+            primitive["indices"] = json!(buffers.add(&data, ELEMENT_ARRAY_BUFFER, accessor));
+            primitive["mode"] = json!(TRIANGLES);
+        }
+
+        let document = json!({
+            "asset": {
+                "version": "2.0",
+                "generator": format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            },
+            "scene": 0,
+            "scenes": [{"nodes": [0]}],
+            "nodes": [{"mesh": 0}],
+            "meshes": [{"primitives": [primitive]}],
+            "accessors": buffers.accessors,
+            "bufferViews": buffers.views,
+            "buffers": [{"byteLength": buffers.bin.len()}],
+        });
+        let mut json = serde_json::to_vec(&document)?;
+        json.resize(json.len().next_multiple_of(4), b' ');
+
+        let length = 12 + 8 + json.len() + 8 + buffers.bin.len();
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        out.write_all(&(length as u32).to_le_bytes())?;
+        for (chunk_type, data) in [(JSON_CHUNK, &json), (BIN_CHUNK, &buffers.bin)] {
+            out.write_all(&(data.len() as u32).to_le_bytes())?;
+            out.write_all(&chunk_type.to_le_bytes())?;
+            out.write_all(data)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Reads the first primitive of the first mesh.
+    fn read(&self, bytes: &[u8]) -> Result<Mesh> {
+        if bytes.len() < 20 || &bytes[..4] != MAGIC {
+            bail!("Not a binary glTF file");
+        }
+        let u32_at = |offset: usize| -> Result<u32> {
+            let bytes = bytes
+                .get(offset..offset + 4)
+                .context("Truncated glTF file")?;
+            Ok(u32::from_le_bytes(bytes.try_into()?))
+        };
+        let mut json = None;
+        let mut bin: &[u8] = &[];
+        let mut offset = 12;
+        while offset < bytes.len() {
+            let length = u32_at(offset)? as usize;
+            let data = bytes
+                .get(offset + 8..offset + 8 + length)
+                .context("Truncated glTF chunk")?;
+            match u32_at(offset + 4)? {
+                JSON_CHUNK => json = Some(serde_json::from_slice::<Value>(data)?),
+                BIN_CHUNK => bin = data,
+                _ => {}
+            }
+            offset += 8 + length;
+        }
+        let document = json.ok_or_else(|| anyhow!("Missing glTF JSON chunk"))?;
+        let primitive = &document["meshes"][0]["primitives"][0];
+        let attributes = &primitive["attributes"];
+        let read = |accessor: &Value| read_accessor(&document, bin, accessor);
+
+        let mut builder = CloudBuilder::default();
+        let positions = read(&attributes["POSITION"])?;
+        builder.cloud.points = positions.chunks_exact(3).map(vec3).collect();
+        if !attributes["NORMAL"].is_null() {
+            builder.normals = read(&attributes["NORMAL"])?
+                .chunks_exact(3)
+                .map(vec3)
+                .collect();
+        }
+        if !attributes["COLOR_0"].is_null() {
+            let components =
+                match document["accessors"][index(&attributes["COLOR_0"])?]["type"].as_str() {
+                    Some("VEC3") => 3,
+                    _ => 4,
+                };
+            let channel = |c: f32| (c * 255_f32).round() as u8;
+            builder.colors = read(&attributes["COLOR_0"])?
+                .chunks_exact(components)
+                .map(|c| [channel(c[0]), channel(c[1]), channel(c[2])])
+                .collect();
+        }
+        if !attributes["_INTENSITY"].is_null() {
+            builder.intensities = read(&attributes["_INTENSITY"])?;
+        }
+        let triangles = if primitive["indices"].is_null() {
+            Vec::new()
+        } else {
+            let indices = read(&primitive["indices"])?;
+            indices
+                .chunks_exact(3)
+                .map(|t| [t[0] as u32, t[1] as u32, t[2] as u32])
+                .collect()
+        };
+        Ok(Mesh {
+            vertices: builder.build(),
+            triangles,
+        })
+    }
+}
+
+fn vec3(v: &[f32]) -> glam::Vec3 {
+    glam::vec3(v[0], v[1], v[2])
+}
+
+fn index(value: &Value) -> Result<usize> {
+    value
+        .as_u64()
+        .map(|i| i as usize)
+        .ok_or_else(|| anyhow!("Invalid glTF index {value}"))
+}
+
+/// Values of an accessor, normalized integers are scaled to [0, 1]. Indices
+/// up to 2^24 are exact.
+fn read_accessor(document: &Value, bin: &[u8], accessor: &Value) -> Result<Vec<f32>> {
+    let accessor = &document["accessors"][index(accessor)?];
+    let view = &document["bufferViews"][index(&accessor["bufferView"])?];
+    let components = match accessor["type"].as_str() {
+        Some("SCALAR") => 1,
+        Some("VEC2") => 2,
+        Some("VEC3") => 3,
+        Some("VEC4") => 4,
+        ty => bail!("Unsupported glTF accessor type {ty:?}"),
+    };
+    let (size, max) = match accessor["componentType"].as_u64().map(|t| t as u32) {
+        Some(FLOAT) => (4, None),
+        Some(UNSIGNED_BYTE) => (1, Some(u8::MAX as f32)),
+        Some(UNSIGNED_SHORT) => (2, Some(u16::MAX as f32)),
+        Some(UNSIGNED_INT) => (4, Some(u32::MAX as f32)),
+        ty => bail!("Unsupported glTF component type {ty:?}"),
+    };
+    let normalized = accessor["normalized"].as_bool().unwrap_or(false);
+    let count = index(&accessor["count"])?;
+    let start = view["byteOffset"].as_u64().unwrap_or(0) as usize
+        + accessor["byteOffset"].as_u64().unwrap_or(0) as usize;
+    let stride = view["byteStride"]
+        .as_u64()
+        .map_or(components * size, |s| s as usize);
+
+    let mut values = Vec::with_capacity(count * components);
+    for i in 0..count {
+        for c in 0..components {
+            let offset = start + i * stride + c * size;
+            let bytes = bin
+                .get(offset..offset + size)
+                .context("glTF accessor outside of the buffer")?;
+            let value = match size {
+                1 => bytes[0] as f32,
+                2 => u16::from_le_bytes(bytes.try_into()?) as f32,
+                _ if max.is_none() => f32::from_le_bytes(bytes.try_into()?),
+                _ => u32::from_le_bytes(bytes.try_into()?) as f32,
+            };
+            values.push(match max {
+                Some(max) if normalized => value / max,
+                _ => value,
+            });
+        }
+    }
+    Ok(values)
+}
@@ -0,0 +1,108 @@
+//! Wavefront OBJ. Vertex colors follow the coordinates on the `v` lines, the
+//! extension read by MeshLab and Blender.
+
+use super::{triangulate, CloudBuilder, Exporter, Geometry, Mesh};
+
+use anyhow::{anyhow, bail, Result};
+use std::io::Write;
+
+pub struct Obj;
+
+impl Exporter for Obj {
+    fn extension(&self) -> &'static str {
+        "obj"
+    }
+
+    fn write(&self, out: &mut dyn Write, geometry: &dyn Geometry) -> Result<()> {
+        geometry.validate()?;
+        let cloud = geometry.vertices();
+        writeln!(
+            out,
+            "# {} {}",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        )?;
+        for (i, p) in cloud.points.iter().enumerate() {
+            write!(out, "v {} {} {}", p.x, p.y, p.z)?;
+            if let Some(colors) = &cloud.colors {
+                let [r, g, b] = colors[i].map(|c| c as f32 / 255_f32);
+                write!(out, " {r} {g} {b}")?;
+            }
+            writeln!(out)?;
+        }
+        if let Some(normals) = &cloud.normals {
+            for n in normals {
+                writeln!(out, "vn {} {} {}", n.x, n.y, n.z)?;
+            }
+        }
+        // OBJ indices start from 1, normals have the same index as their vertex
+        for t in geometry.triangles() {
+            let [a, b, c] = t.map(|i| i + 1);
+            if cloud.normals.is_some() {
+                writeln!(out, "f {a}//{a} {b}//{b} {c}//{c}")?;
+            } else {
+                writeln!(out, "f {a} {b} {c}")?;
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Normals are only kept when there is one per vertex, texture
+    /// coordinates and materials are ignored.
+    fn read(&self, bytes: &[u8]) -> Result<Mesh> {
+        let mut builder = CloudBuilder::default();
+        let mut normals = Vec::new();
+        let mut triangles = Vec::new();
+        for line in std::str::from_utf8(bytes)?.lines() {
+            let mut words = line.split_ascii_whitespace();
+            match words.next() {
+                Some("v") => {
+                    let values = words.map(str::parse).collect::<Result<Vec<f32>, _>>()?;
+                    match values[..] {
+                        [x, y, z] => builder.cloud.points.push(glam::vec3(x, y, z)),
+                        [x, y, z, r, g, b] => {
+                            builder.cloud.points.push(glam::vec3(x, y, z));
+                            let channel = |c: f32| (c * 255_f32).round() as u8;
+                            builder.colors.push([channel(r), channel(g), channel(b)]);
+                        }
+                        _ => bail!("Unexpected OBJ vertex {line:?}"),
+                    }
+                }
+                Some("vn") => {
+                    let values = words.map(str::parse).collect::<Result<Vec<f32>, _>>()?;
+                    let [x, y, z] = values[..] else {
+                        bail!("Unexpected OBJ normal {line:?}");
+                    };
+                    normals.push(glam::vec3(x, y, z));
+                }
+                Some("f") => {
+                    let vertices = builder.cloud.points.len() as i64;
+                    let polygon = words
+                        .map(|w| vertex_index(w, vertices))
+                        .collect::<Result<Vec<u32>>>()?;
+                    triangulate(&polygon, &mut triangles);
+                }
+                _ => {}
+            }
+        }
+        if normals.len() == builder.cloud.points.len() {
+            builder.normals = normals;
+        }
+        Ok(Mesh {
+            vertices: builder.build(),
+            triangles,
+        })
+    }
+}
+
+/// Parses the vertex of a face corner, `v`, `v/vt`, `v//vn` or `v/vt/vn`,
+/// negative indices count back from the last vertex.
+fn vertex_index(corner: &str, vertices: i64) -> Result<u32> {
+    let v: i64 = corner.split('/').next().unwrap_or_default().parse()?;
+    let index = if v < 0 { vertices + v } else { v - 1 };
+    if !(0..vertices).contains(&index) {
+        return Err(anyhow!("OBJ face refers to missing vertex {v}"));
+    }
+    Ok(index as u32)
+}
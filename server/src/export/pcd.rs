@@ -0,0 +1,203 @@
+//! PCD v0.7, the point cloud format of PCL. Colors are packed in a float
+//! `rgb` field, the PCL convention.
+
+use super::{
+    laser_from_id, laser_id, split_header, CloudBuilder, Exporter, Geometry, Mesh, Scalar, Values,
+};
+
+use anyhow::{bail, Result};
+use std::io::Write;
+
+pub struct Pcd;
+
+impl Exporter for Pcd {
+    fn extension(&self) -> &'static str {
+        "pcd"
+    }
+
+    /// Binary data, the fastest to load in PCL.
+    fn write(&self, out: &mut dyn Write, geometry: &dyn Geometry) -> Result<()> {
+        geometry.validate()?;
+        let cloud = geometry.vertices();
+        // name and type of each field, all of them are 4 bytes
+        let mut fields = vec![("x", 'F'), ("y", 'F'), ("z", 'F')];
+        if cloud.normals.is_some() {
+            fields.extend([("normal_x", 'F'), ("normal_y", 'F'), ("normal_z", 'F')]);
+        }
+        if cloud.intensities.is_some() {
+            fields.push(("intensity", 'F'));
+        }
+        if cloud.colors.is_some() {
+            fields.push(("rgb", 'F'));
+        }
+        if cloud.steps.is_some() {
+            fields.push(("step", 'U'));
+        }
+        if cloud.lasers.is_some() {
+            fields.push(("laser", 'U'));
+        }
+        if cloud.pixels.is_some() {
+            fields.extend([("pixel_x", 'F'), ("pixel_y", 'F')]);
+        }
+        let join = |f: &dyn Fn(&(&str, char)) -> String| {
+            fields.iter().map(f).collect::<Vec<_>>().join(" ")
+        };
+
+        let n = cloud.points.len();
+        writeln!(out, "# .PCD v0.7 - Point Cloud Data file format")?;
+        writeln!(out, "VERSION 0.7")?;
+        writeln!(out, "FIELDS {}", join(&|(name, _)| name.to_string()))?;
+        writeln!(out, "SIZE {}", join(&|_| "4".to_string()))?;
+        writeln!(out, "TYPE {}", join(&|(_, ty)| ty.to_string()))?;
+        writeln!(out, "COUNT {}", join(&|_| "1".to_string()))?;
+        writeln!(out, "WIDTH {n}")?;
+        writeln!(out, "HEIGHT 1")?;
+        writeln!(out, "VIEWPOINT 0 0 0 1 0 0 0")?;
+        writeln!(out, "POINTS {n}")?;
+        writeln!(out, "DATA binary")?;
+
+        for (i, p) in cloud.points.iter().enumerate() {
+            for v in p.to_array() {
+                out.write_all(&v.to_le_bytes())?;
+            }
+            if let Some(normals) = &cloud.normals {
+                for v in normals[i].to_array() {
+                    out.write_all(&v.to_le_bytes())?;
+                }
+            }
+            if let Some(intensities) = &cloud.intensities {
+                out.write_all(&intensities[i].to_le_bytes())?;
+            }
+            if let Some(colors) = &cloud.colors {
+                let [r, g, b] = colors[i];
+                out.write_all(&u32::from_be_bytes([0, r, g, b]).to_le_bytes())?;
+            }
+            if let Some(steps) = &cloud.steps {
+                out.write_all(&steps[i].to_le_bytes())?;
+            }
+            if let Some(lasers) = &cloud.lasers {
+                out.write_all(&u32::from(laser_id(lasers[i])).to_le_bytes())?;
+            }
+            if let Some(pixels) = &cloud.pixels {
+                for v in pixels[i].to_array() {
+                    out.write_all(&v.to_le_bytes())?;
+                }
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Reads ASCII and binary data, compressed data is not supported.
+    fn read(&self, bytes: &[u8]) -> Result<Mesh> {
+        let (header, body) = split_header(bytes, "DATA")?;
+        let mut names: Vec<&str> = Vec::new();
+        let mut sizes: Vec<&str> = Vec::new();
+        let mut types: Vec<&str> = Vec::new();
+        let mut counts: Vec<&str> = Vec::new();
+        let mut points = 0;
+        let mut binary = false;
+        for line in &header {
+            let mut words = line.split_ascii_whitespace();
+            match words.next() {
+                Some("FIELDS") => names = words.collect(),
+                Some("SIZE") => sizes = words.collect(),
+                Some("TYPE") => types = words.collect(),
+                Some("COUNT") => counts = words.collect(),
+                Some("POINTS") => points = words.next().unwrap_or_default().parse()?,
+                Some("DATA") => match words.next() {
+                    Some("ascii") => binary = false,
+                    Some("binary") => binary = true,
+                    data => bail!("Unsupported PCD data {data:?}"),
+                },
+                _ => {}
+            }
+        }
+        if counts.is_empty() {
+            counts = vec!["1"; names.len()];
+        }
+        if sizes.len() != names.len() || types.len() != names.len() || counts.len() != names.len() {
+            bail!("Inconsistent PCD fields");
+        }
+        let fields = (0..names.len())
+            .map(|i| Ok((names[i], scalar(types[i], sizes[i])?, counts[i].parse()?)))
+            .collect::<Result<Vec<(&str, Scalar, usize)>>>()?;
+        let has = |name: &str| names.contains(&name);
+
+        let mut values = Values::new(body, binary)?;
+        let mut builder = CloudBuilder::default();
+        for _ in 0..points {
+            let mut point = [0_f64; FIELDS.len()];
+            for &(name, scalar, count) in &fields {
+                for _ in 0..count {
+                    let value = values.read(scalar)?;
+                    if let Some(i) = FIELDS.iter().position(|f| *f == name) {
+                        point[i] = value;
+                    }
+                }
+            }
+            let vec3 =
+                |i: usize| glam::vec3(point[i] as f32, point[i + 1] as f32, point[i + 2] as f32);
+            builder.cloud.points.push(vec3(0));
+            if has("normal_x") {
+                builder.normals.push(vec3(3));
+            }
+            if has("intensity") {
+                builder.intensities.push(point[6] as f32);
+            }
+            if has("rgb") {
+                let rgb = match fields.iter().find(|f| f.0 == "rgb") {
+                    Some((_, Scalar::F32, _)) => (point[7] as f32).to_bits(),
+                    _ => point[7] as u32,
+                };
+                let [_, r, g, b] = rgb.to_be_bytes();
+                builder.colors.push([r, g, b]);
+            }
+            if has("step") {
+                builder.steps.push(point[8] as u32);
+            }
+            if has("laser") {
+                builder.lasers.push(laser_from_id(point[9] as u32)?);
+            }
+            if has("pixel_x") {
+                builder
+                    .pixels
+                    .push(glam::vec2(point[10] as f32, point[11] as f32));
+            }
+        }
+        Ok(Mesh {
+            vertices: builder.build(),
+            triangles: Vec::new(),
+        })
+    }
+}
+
+/// Fields read, in the order of the values gathered for each point.
+const FIELDS: [&str; 12] = [
+    "x",
+    "y",
+    "z",
+    "normal_x",
+    "normal_y",
+    "normal_z",
+    "intensity",
+    "rgb",
+    "step",
+    "laser",
+    "pixel_x",
+    "pixel_y",
+];
+
+fn scalar(ty: &str, size: &str) -> Result<Scalar> {
+    Ok(match (ty, size) {
+        ("I", "1") => Scalar::I8,
+        ("U", "1") => Scalar::U8,
+        ("I", "2") => Scalar::I16,
+        ("U", "2") => Scalar::U16,
+        ("I", "4") => Scalar::I32,
+        ("U", "4") => Scalar::U32,
+        ("F", "4") => Scalar::F32,
+        ("F", "8") => Scalar::F64,
+        _ => bail!("Unsupported PCD type {ty}{size}"),
+    })
+}
@@ -0,0 +1,352 @@
+//! PLY, using the property names read by MeshLab and CloudCompare.
+
+use super::{
+    laser_from_id, laser_id, split_header, triangulate, CloudBuilder, Exporter, Geometry, Mesh,
+    Scalar, Values,
+};
+
+use anyhow::{anyhow, bail, Result};
+use std::io::Write;
+
+pub struct Ply {
+    /// Little-endian, much smaller and faster to load than ASCII
+    pub binary: bool,
+}
+
+impl Exporter for Ply {
+    fn extension(&self) -> &'static str {
+        "ply"
+    }
+
+    fn write(&self, out: &mut dyn Write, geometry: &dyn Geometry) -> Result<()> {
+        geometry.validate()?;
+        let cloud = geometry.vertices();
+        let triangles = geometry.triangles();
+
+        let format_name = match self.binary {
+            false => "ascii",
+            true => "binary_little_endian",
+        };
+        writeln!(out, "ply")?;
+        writeln!(out, "format {format_name} 1.0")?;
+        writeln!(
+            out,
+            "comment {} {}",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        )?;
+        writeln!(out, "element vertex {}", cloud.points.len())?;
+        for axis in ["x", "y", "z"] {
+            writeln!(out, "property float {axis}")?;
+        }
+        if cloud.normals.is_some() {
+            for axis in ["nx", "ny", "nz"] {
+                writeln!(out, "property float {axis}")?;
+            }
+        }
+        if cloud.intensities.is_some() {
+            writeln!(out, "property float intensity")?;
+        }
+        if cloud.colors.is_some() {
+            for channel in ["red", "green", "blue"] {
+                writeln!(out, "property uchar {channel}")?;
+            }
+        }
+        if cloud.steps.is_some() {
+            writeln!(out, "property uint step")?;
+        }
+        if cloud.lasers.is_some() {
+            writeln!(out, "property uchar laser")?;
+        }
+        if cloud.pixels.is_some() {
+            for axis in ["pixel_x", "pixel_y"] {
+                writeln!(out, "property float {axis}")?;
+            }
+        }
+        if !triangles.is_empty() {
+            writeln!(out, "element face {}", triangles.len())?;
+            writeln!(out, "property list uchar uint vertex_indices")?;
+        }
+        writeln!(out, "end_header")?;
+
+        for (i, p) in cloud.points.iter().enumerate() {
+            let normal = cloud.normals.as_ref().map(|n| n[i]);
+            let intensity = cloud.intensities.as_ref().map(|v| v[i]);
+            let color = cloud.colors.as_ref().map(|c| c[i]);
+            let step = cloud.steps.as_ref().map(|s| s[i]);
+            let laser = cloud.lasers.as_ref().map(|l| laser_id(l[i]));
+            let pixel = cloud.pixels.as_ref().map(|p| p[i]);
+            if self.binary {
+                for v in p.to_array() {
+                    out.write_all(&v.to_le_bytes())?;
+                }
+                if let Some(n) = normal {
+                    for v in n.to_array() {
+                        out.write_all(&v.to_le_bytes())?;
+                    }
+                }
+                if let Some(intensity) = intensity {
+                    out.write_all(&intensity.to_le_bytes())?;
+                }
+                if let Some(color) = color {
+                    out.write_all(&color)?;
+                }
+                if let Some(step) = step {
+                    out.write_all(&step.to_le_bytes())?;
+                }
+                if let Some(laser) = laser {
+                    out.write_all(&[laser])?;
+                }
+                if let Some(pixel) = pixel {
+                    for v in pixel.to_array() {
+                        out.write_all(&v.to_le_bytes())?;
+                    }
+                }
+            } else {
+                write!(out, "{} {} {}", p.x, p.y, p.z)?;
+                if let Some(n) = normal {
+                    write!(out, " {} {} {}", n.x, n.y, n.z)?;
+                }
+                if let Some(intensity) = intensity {
+                    write!(out, " {intensity}")?;
+                }
+                if let Some([r, g, b]) = color {
+                    write!(out, " {r} {g} {b}")?;
+                }
+                if let Some(step) = step {
+                    write!(out, " {step}")?;
+                }
+                if let Some(laser) = laser {
+                    write!(out, " {laser}")?;
+                }
+                if let Some(pixel) = pixel {
+                    write!(out, " {} {}", pixel.x, pixel.y)?;
+                }
+                writeln!(out)?;
+            }
+        }
+        for t in triangles {
+            if self.binary {
+                out.write_all(&[3])?;
+                for i in t {
+                    out.write_all(&i.to_le_bytes())?;
+                }
+            } else {
+                writeln!(out, "3 {} {} {}", t[0], t[1], t[2])?;
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Reads the vertices and faces of ASCII and binary little-endian files,
+    /// unknown elements and properties are skipped.
+    fn read(&self, bytes: &[u8]) -> Result<Mesh> {
+        let (header, body) = split_header(bytes, "end_header")?;
+        if header.first() != Some(&"ply") {
+            bail!("Not a PLY file");
+        }
+        let mut binary = false;
+        let mut elements: Vec<Element> = Vec::new();
+        for line in &header[1..] {
+            let words: Vec<&str> = line.split_ascii_whitespace().collect();
+            match words.as_slice() {
+                ["format", "ascii", _] => binary = false,
+                ["format", "binary_little_endian", _] => binary = true,
+                ["format", format, _] => bail!("Unsupported PLY format {format}"),
+                ["element", name, count] => elements.push(Element {
+                    name: name.to_string(),
+                    count: count.parse()?,
+                    properties: Vec::new(),
+                }),
+                ["property", "list", count, item, name] => last(&mut elements)?.properties.push(
+                    Property::List(scalar(count)?, scalar(item)?, name.to_string()),
+                ),
+                ["property", ty, name] => last(&mut elements)?
+                    .properties
+                    .push(Property::Scalar(scalar(ty)?, name.to_string())),
+                _ => {}
+            }
+        }
+
+        let mut values = Values::new(body, binary)?;
+        let mut builder = CloudBuilder::default();
+        let mut triangles = Vec::new();
+        for element in &elements {
+            for _ in 0..element.count {
+                let mut vertex = [0_f32; VERTEX_PROPERTIES.len()];
+                for property in &element.properties {
+                    match property {
+                        Property::Scalar(ty, name) => {
+                            let value = values.read(*ty)?;
+                            if let Some(i) = VERTEX_PROPERTIES.iter().position(|p| p == name) {
+                                vertex[i] = value as f32;
+                            }
+                        }
+                        Property::List(count, item, name) => {
+                            let count = values.read(*count)? as usize;
+                            let indices = (0..count)
+                                .map(|_| Ok(values.read(*item)? as u32))
+                                .collect::<Result<Vec<u32>>>()?;
+                            if element.name == "face"
+                                && matches!(name.as_str(), "vertex_indices" | "vertex_index")
+                            {
+                                triangulate(&indices, &mut triangles);
+                            }
+                        }
+                    }
+                }
+                if element.name == "vertex" {
+                    let has = |name: &str| element.properties.iter().any(|p| p.name() == name);
+                    builder
+                        .cloud
+                        .points
+                        .push(glam::vec3(vertex[0], vertex[1], vertex[2]));
+                    if has("nx") {
+                        builder
+                            .normals
+                            .push(glam::vec3(vertex[3], vertex[4], vertex[5]));
+                    }
+                    if has("intensity") {
+                        builder.intensities.push(vertex[6]);
+                    }
+                    if has("red") {
+                        builder
+                            .colors
+                            .push([vertex[7] as u8, vertex[8] as u8, vertex[9] as u8]);
+                    }
+                    if has("step") {
+                        builder.steps.push(vertex[10] as u32);
+                    }
+                    if has("laser") {
+                        builder.lasers.push(laser_from_id(vertex[11] as u32)?);
+                    }
+                    if has("pixel_x") {
+                        builder.pixels.push(glam::vec2(vertex[12], vertex[13]));
+                    }
+                }
+            }
+        }
+        Ok(Mesh {
+            vertices: builder.build(),
+            triangles,
+        })
+    }
+}
+
+/// Vertex properties read, in the order of the values gathered for each
+/// vertex.
+const VERTEX_PROPERTIES: [&str; 14] = [
+    "x",
+    "y",
+    "z",
+    "nx",
+    "ny",
+    "nz",
+    "intensity",
+    "red",
+    "green",
+    "blue",
+    "step",
+    "laser",
+    "pixel_x",
+    "pixel_y",
+];
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+enum Property {
+    Scalar(Scalar, String),
+    /// Types of the count and of the items
+    List(Scalar, Scalar, String),
+}
+
+impl Property {
+    fn name(&self) -> &str {
+        match self {
+            Property::Scalar(_, name) | Property::List(_, _, name) => name,
+        }
+    }
+}
+
+fn last(elements: &mut [Element]) -> Result<&mut Element> {
+    elements
+        .last_mut()
+        .ok_or_else(|| anyhow!("PLY property outside of an element"))
+}
+
+fn scalar(name: &str) -> Result<Scalar> {
+    Ok(match name {
+        "char" | "int8" => Scalar::I8,
+        "uchar" | "uint8" => Scalar::U8,
+        "short" | "int16" => Scalar::I16,
+        "ushort" | "uint16" => Scalar::U16,
+        "int" | "int32" => Scalar::I32,
+        "uint" | "uint32" => Scalar::U32,
+        "float" | "float32" => Scalar::F32,
+        "double" | "float64" => Scalar::F64,
+        _ => bail!("Unknown PLY type {name}"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use msg::response::PointCloud;
+
+    fn cloud() -> PointCloud {
+        PointCloud {
+            points: vec![
+                glam::vec3(1_f32, 2_f32, 3_f32),
+                glam::vec3(-0.5_f32, 0_f32, 4_f32),
+            ],
+            intensities: Some(vec![0.25_f32, 1_f32]),
+            normals: None,
+            colors: Some(vec![[255, 0, 10], [0, 128, 0]]),
+            steps: Some(vec![0, 7]),
+            lasers: None,
+            pixels: None,
+        }
+    }
+
+    fn header(format: &str) -> String {
+        format!(
+            "ply\nformat {format} 1.0\ncomment server {}\nelement vertex 2\n\
+             property float x\nproperty float y\nproperty float z\n\
+             property float intensity\n\
+             property uchar red\nproperty uchar green\nproperty uchar blue\n\
+             property uint step\nend_header\n",
+            env!("CARGO_PKG_VERSION")
+        )
+    }
+
+    #[test]
+    fn ascii_ply() {
+        let mut out = Vec::new();
+        Ply { binary: false }.write(&mut out, &cloud()).unwrap();
+        let expected = header("ascii") + "1 2 3 0.25 255 0 10 0\n-0.5 0 4 1 0 128 0 7\n";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test]
+    fn binary_ply() {
+        let mut out = Vec::new();
+        Ply { binary: true }.write(&mut out, &cloud()).unwrap();
+        let header = header("binary_little_endian");
+        assert!(out.starts_with(header.as_bytes()));
+
+        // 3 coordinates and the intensity, 3 color channels, the step
+        let vertex_size = 4 * 4 + 3 + 4;
+        let body = &out[header.len()..];
+        assert_eq!(body.len(), 2 * vertex_size);
+        let vertex = &body[vertex_size..];
+        assert_eq!(vertex[..4], (-0.5_f32).to_le_bytes());
+        assert_eq!(vertex[12..16], 1_f32.to_le_bytes());
+        assert_eq!(vertex[16..19], [0, 128, 0]);
+        assert_eq!(vertex[19..], 7_u32.to_le_bytes());
+    }
+}
@@ -0,0 +1,85 @@
+//! Binary STL, the format of 3D printing slicers. It only stores triangles,
+//! each with its own copy of the vertices.
+
+use super::{CloudBuilder, Exporter, Geometry, Mesh};
+
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::io::Write;
+
+const HEADER_SIZE: usize = 80;
+/// Normal, 3 vertices and a 2 bytes attribute
+const TRIANGLE_SIZE: usize = 12 * 4 + 2;
+
+pub struct Stl;
+
+impl Exporter for Stl {
+    fn extension(&self) -> &'static str {
+        "stl"
+    }
+
+    fn write(&self, out: &mut dyn Write, geometry: &dyn Geometry) -> Result<()> {
+        geometry.validate()?;
+        let triangles = geometry.triangles();
+        if triangles.is_empty() {
+            bail!("STL files only store triangles, mesh the point cloud first");
+        }
+        let points = &geometry.vertices().points;
+
+        let mut header = [0_u8; HEADER_SIZE];
+        let name = format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        // must not start with "solid", which marks ASCII files
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        out.write_all(&header)?;
+        out.write_all(&(triangles.len() as u32).to_le_bytes())?;
+        for t in triangles {
+            let [a, b, c] = t.map(|i| points[i as usize]);
+            let normal = (b - a).cross(c - a).normalize_or_zero();
+            for v in [normal, a, b, c] {
+                for x in v.to_array() {
+                    out.write_all(&x.to_le_bytes())?;
+                }
+            }
+            out.write_all(&[0, 0])?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Vertices with the same coordinates are merged.
+    fn read(&self, bytes: &[u8]) -> Result<Mesh> {
+        if bytes.len() < HEADER_SIZE + 4 {
+            bail!("Not a binary STL file");
+        }
+        let count = u32::from_le_bytes(bytes[HEADER_SIZE..HEADER_SIZE + 4].try_into()?) as usize;
+        let data = &bytes[HEADER_SIZE + 4..];
+        if data.len() != count * TRIANGLE_SIZE {
+            bail!("Expected {count} triangles in the STL file");
+        }
+
+        let mut builder = CloudBuilder::default();
+        let mut indices = HashMap::<[u32; 3], u32>::new();
+        let mut triangles = Vec::with_capacity(count);
+        for triangle in data.chunks_exact(TRIANGLE_SIZE) {
+            let value =
+                |i: usize| f32::from_le_bytes(triangle[4 * i..4 * i + 4].try_into().unwrap());
+            let mut t = [0_u32; 3];
+            for (corner, index) in t.iter_mut().enumerate() {
+                // skipping the normal
+                let first = 3 + 3 * corner;
+                let p = glam::vec3(value(first), value(first + 1), value(first + 2));
+                *index = *indices
+                    .entry(p.to_array().map(f32::to_bits))
+                    .or_insert_with(|| {
+                        builder.cloud.points.push(p);
+                        builder.cloud.points.len() as u32 - 1
+                    });
+            }
+            triangles.push(t);
+        }
+        Ok(Mesh {
+            vertices: builder.build(),
+            triangles,
+        })
+    }
+}
@@ -0,0 +1,164 @@
+//! Plain text point clouds, one point per line, for scripts and
+//! spreadsheets.
+
+use super::{laser_from_id, laser_id, CloudBuilder, Exporter, Geometry, Mesh};
+
+use anyhow::{anyhow, bail, Result};
+use std::io::Write;
+
+/// Space separated coordinates, without header.
+pub struct Xyz;
+
+impl Exporter for Xyz {
+    fn extension(&self) -> &'static str {
+        "xyz"
+    }
+
+    fn write(&self, out: &mut dyn Write, geometry: &dyn Geometry) -> Result<()> {
+        geometry.validate()?;
+        for p in &geometry.vertices().points {
+            writeln!(out, "{} {} {}", p.x, p.y, p.z)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Extra columns are ignored, as well as empty lines and `#` comments.
+    fn read(&self, bytes: &[u8]) -> Result<Mesh> {
+        let mut builder = CloudBuilder::default();
+        for line in std::str::from_utf8(bytes)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let values = line
+                .split_ascii_whitespace()
+                .take(3)
+                .map(str::parse)
+                .collect::<Result<Vec<f32>, _>>()?;
+            let [x, y, z] = values[..] else {
+                bail!("Expected 3 coordinates in {line:?}");
+            };
+            builder.cloud.points.push(glam::vec3(x, y, z));
+        }
+        Ok(Mesh {
+            vertices: builder.build(),
+            triangles: Vec::new(),
+        })
+    }
+}
+
+/// Comma separated, with a header line naming the columns.
+pub struct Csv;
+
+impl Exporter for Csv {
+    fn extension(&self) -> &'static str {
+        "csv"
+    }
+
+    fn write(&self, out: &mut dyn Write, geometry: &dyn Geometry) -> Result<()> {
+        geometry.validate()?;
+        let cloud = geometry.vertices();
+        let mut columns = vec!["x", "y", "z"];
+        if cloud.normals.is_some() {
+            columns.extend(["nx", "ny", "nz"]);
+        }
+        if cloud.intensities.is_some() {
+            columns.push("intensity");
+        }
+        if cloud.colors.is_some() {
+            columns.extend(["red", "green", "blue"]);
+        }
+        if cloud.steps.is_some() {
+            columns.push("step");
+        }
+        if cloud.lasers.is_some() {
+            columns.push("laser");
+        }
+        if cloud.pixels.is_some() {
+            columns.extend(["pixel_x", "pixel_y"]);
+        }
+        writeln!(out, "{}", columns.join(","))?;
+
+        for (i, p) in cloud.points.iter().enumerate() {
+            write!(out, "{},{},{}", p.x, p.y, p.z)?;
+            if let Some(normals) = &cloud.normals {
+                let n = normals[i];
+                write!(out, ",{},{},{}", n.x, n.y, n.z)?;
+            }
+            if let Some(intensities) = &cloud.intensities {
+                write!(out, ",{}", intensities[i])?;
+            }
+            if let Some(colors) = &cloud.colors {
+                let [r, g, b] = colors[i];
+                write!(out, ",{r},{g},{b}")?;
+            }
+            if let Some(steps) = &cloud.steps {
+                write!(out, ",{}", steps[i])?;
+            }
+            if let Some(lasers) = &cloud.lasers {
+                write!(out, ",{}", laser_id(lasers[i]))?;
+            }
+            if let Some(pixels) = &cloud.pixels {
+                let p = pixels[i];
+                write!(out, ",{},{}", p.x, p.y)?;
+            }
+            writeln!(out)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Columns are found by name, in any order. Unknown ones are ignored.
+    fn read(&self, bytes: &[u8]) -> Result<Mesh> {
+        let mut lines = std::str::from_utf8(bytes)?.lines();
+        let header = lines.next().ok_or_else(|| anyhow!("Empty CSV file"))?;
+        let names: Vec<&str> = header.split(',').map(str::trim).collect();
+        let column = |name: &str| names.iter().position(|n| *n == name);
+        let (Some(x), Some(y), Some(z)) = (column("x"), column("y"), column("z")) else {
+            bail!("Missing x, y or z column in {header:?}");
+        };
+        let normal = column("nx").zip(column("ny")).zip(column("nz"));
+        let intensity = column("intensity");
+        let color = column("red").zip(column("green")).zip(column("blue"));
+        let step = column("step");
+        let laser = column("laser");
+        let pixel = column("pixel_x").zip(column("pixel_y"));
+
+        let mut builder = CloudBuilder::default();
+        for line in lines.filter(|l| !l.trim().is_empty()) {
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            if fields.len() != names.len() {
+                bail!("Expected {} values in {line:?}", names.len());
+            }
+            let float = |i: usize| fields[i].parse::<f32>();
+            let vec3 = |x, y, z| {
+                Ok::<_, std::num::ParseFloatError>(glam::vec3(float(x)?, float(y)?, float(z)?))
+            };
+            builder.cloud.points.push(vec3(x, y, z)?);
+            if let Some(((nx, ny), nz)) = normal {
+                builder.normals.push(vec3(nx, ny, nz)?);
+            }
+            if let Some(i) = intensity {
+                builder.intensities.push(float(i)?);
+            }
+            if let Some(((r, g), b)) = color {
+                let channel = |i: usize| fields[i].parse::<u8>();
+                builder.colors.push([channel(r)?, channel(g)?, channel(b)?]);
+            }
+            if let Some(i) = step {
+                builder.steps.push(fields[i].parse()?);
+            }
+            if let Some(i) = laser {
+                builder.lasers.push(laser_from_id(fields[i].parse()?)?);
+            }
+            if let Some((u, v)) = pixel {
+                builder.pixels.push(glam::vec2(float(u)?, float(v)?));
+            }
+        }
+        Ok(Mesh {
+            vertices: builder.build(),
+            triangles: Vec::new(),
+        })
+    }
+}
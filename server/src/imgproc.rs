@@ -1,126 +1,548 @@
 use crate::calibration;
-use crate::calibration::LaserCalib;
+use crate::calibration::{Laser, LaserCalib};
 use crate::logging;
-use crate::motor::StepperMotor;
+use crate::peak::{make_peak_detector, PeakDetector, PeakDetectorType};
 use anyhow::Result;
+use clap::ValueEnum;
 use log::{error, info, warn};
+use msg::response::PointCloud;
+use std::borrow::Cow;
 
 const LOW_THRESHOLD: u8 = 30;
+/// Runs above threshold separated by at most this many dark pixels are
+/// considered the same laser line, noise easily splits a line in two.
+const MAX_RUN_GAP: usize = 2;
 
-pub fn process_image(
-    image: &image::GrayImage,
+/// Laser line position detected in a row of the image.
+pub struct LaserPoint {
+    pub pixel: glam::Vec2,
+    /// `pixel` as detected, before any undistortion
+    pub raw_pixel: glam::Vec2,
+    pub confidence: f32,
+    /// Brightest value of the run, in [0, 1]
+    pub intensity: f32,
+    /// Width in pixels of the run of bright pixels containing the peak
+    pub width: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DetectionMode {
+    /// Every bright run is a laser point, the image half picks the laser
+    AllPeaks,
+    /// At most one peak per laser per row, the laser planes pick the laser
+    BestPerLaser,
+}
+
+pub struct LaserLineDetector {
+    pub peak_detector: Box<dyn PeakDetector>,
+    pub mode: DetectionMode,
+}
+
+impl LaserLineDetector {
+    pub fn new(peak_detector_type: PeakDetectorType, mode: DetectionMode) -> Self {
+        Self {
+            peak_detector: make_peak_detector(peak_detector_type),
+            mode,
+        }
+    }
+}
+
+/// Frames captured at a single turntable step.
+pub struct StepFrames {
+    /// Frames with the lasers on, tagged with the only laser that was lit or
+    /// `None` if both were
+    pub lit: Vec<(Option<Laser>, image::GrayImage)>,
+    /// Frame with all the lasers off, subtracted from the lit ones
+    pub ambient: Option<image::GrayImage>,
+    /// Frame with all the lasers off the points take their color from
+    pub color: Option<image::RgbImage>,
+}
+
+/// Triangulates the laser points of the frames taken with the turntable at
+/// `turntable_angle` radians and brings them back to the turntable pose at the
+/// scan start. The points keep the laser, the pixel and the intensity they
+/// were detected with, `i` as their step, and their color when there is a
+/// color frame that sees all of them.
+pub fn process_frames(
+    frames: &StepFrames,
     i: i64,
+    turntable_angle: f32,
     rec: &dyn logging::Logger,
-    angle_per_step: f32,
     calib: &calibration::Calibration,
-    motor: &mut dyn StepperMotor,
-) -> Vec<glam::Vec3> {
-    rec.set_time_sequence("timeline", i as i64);
-    motor.step(1);
-    let res = rec.log_image(
-        "world/image",
-        image::DynamicImage::ImageLuma8(image.clone()),
-    );
-    if let Err(e) = res {
-        warn!("Failed to log image to logger: {e}");
-    }
+    detector: &LaserLineDetector,
+) -> PointCloud {
+    rec.set_time_sequence("timeline", i);
 
-    //let transform = glam::Affine3A::from_rotation_z(angle_per_step);
-    //for point in &mut *point_cloud {
-    //    *point = transform.transform_point3(*point);
-    //}
+    let mut new_points = PointCloud::default();
+    for (laser, image) in &frames.lit {
+        let image = match &frames.ambient {
+            Some(ambient) => Cow::Owned(subtract_ambient(image, ambient)),
+            None => Cow::Borrowed(image),
+        };
 
-    let mut new_points = triangulate(&image, &calib);
-    //rec.log_points("world/points_3d_cam", &new_points)?;
-    //point_cloud.append(&mut new_points);
-    //rec.log_points("world/points_3d_world", &point_cloud)?;
-    //Ok(())
+        let entity_path = match laser {
+            None => "world/image",
+            Some(Laser::Left) => "world/image_left",
+            Some(Laser::Right) => "world/image_right",
+        };
+        let res = rec.log_image(
+            entity_path,
+            image::DynamicImage::ImageLuma8(image.as_ref().clone()),
+        );
+        if let Err(e) = res {
+            warn!("Failed to log image to logger: {e}");
+        }
 
-    let transform = glam::Affine3A::from_rotation_z(-(i as f32) * angle_per_step);
-    for point in &mut new_points {
+        new_points.append(triangulate(&image, calib, detector, *laser));
+    }
+    if let Some(color) = &frames.color {
+        let colors: Option<Vec<[u8; 3]>> = sample_colors(&new_points.points, color, calib)
+            .into_iter()
+            .collect();
+        if colors.is_none() {
+            warn!("The color frame does not see every point, leaving the step uncolored");
+        }
+        new_points.colors = colors;
+    }
+
+    let transform = calib.turntable_axis.rotation(-turntable_angle);
+    for point in &mut new_points.points {
         *point = transform.transform_point3(*point);
     }
+    new_points.steps = Some(vec![i as u32; new_points.points.len()]);
+
+    new_points
+}
+
+/// Color of the pixel where the camera sees each point, the points being in
+/// world coordinates at the turntable pose of the frame. `None` for the points
+/// behind the camera or outside of the frame.
+pub fn sample_colors(
+    points: &[glam::Vec3],
+    image: &image::RgbImage,
+    calib: &calibration::Calibration,
+) -> Vec<Option<[u8; 3]>> {
+    points
+        .iter()
+        .map(|p| {
+            let pixel = calib.camera.world_to_pixel(*p)?.round();
+            let inside = pixel.x >= 0_f32
+                && pixel.y >= 0_f32
+                && pixel.x < image.width() as f32
+                && pixel.y < image.height() as f32;
+            inside.then(|| image.get_pixel(pixel.x as u32, pixel.y as u32).0)
+        })
+        .collect()
+}
+
+/// Removes the ambient light from `image` using a frame taken with the
+/// lasers off.
+pub fn subtract_ambient(image: &image::GrayImage, ambient: &image::GrayImage) -> image::GrayImage {
+    if image.dimensions() != ambient.dimensions() {
+        warn!(
+            "Ambient frame size {:?} does not match image size {:?}, skipping subtraction",
+            ambient.dimensions(),
+            image.dimensions()
+        );
+        return image.clone();
+    }
 
-    return new_points;
+    let mut result = image.clone();
+    for (pixel, ambient_pixel) in result.pixels_mut().zip(ambient.pixels()) {
+        pixel.0[0] = pixel.0[0].saturating_sub(ambient_pixel.0[0]);
+    }
+    result
 }
 
-fn triangulate(image: &image::GrayImage, calib: &calibration::Calibration) -> Vec<glam::Vec3> {
+fn triangulate(
+    image: &image::GrayImage,
+    calib: &calibration::Calibration,
+    detector: &LaserLineDetector,
+    lit_laser: Option<Laser>,
+) -> PointCloud {
     info!("Image info: dimensions {:?}", image.dimensions(),);
 
-    let width = image.width() as f32;
-    let height = image.height() as f32;
-    let img_2_img_center =
-        glam::Affine3A::from_translation(-glam::vec3(width / 2_f32, height / 2_f32, 0_f32));
+    let mut laser_points = detect_laser_points(image, detector.peak_detector.as_ref());
+    let mean_confidence =
+        laser_points.iter().map(|p| p.confidence).sum::<f32>() / laser_points.len().max(1) as f32;
+    info!(
+        "Detected {} laser points, mean confidence {mean_confidence:.2}",
+        laser_points.len()
+    );
 
-    let focal_length_px = calib.camera.intrinsics.focal_length_px();
-    let points: Vec<glam::Vec3> = detect_laser_points(&image)
-        .iter()
-        .map(|p| glam::vec3(p.x, p.y, focal_length_px))
-        .map(|p| img_2_img_center.transform_point3(p))
-        .collect();
-
-    let mut left_laser_points = Vec::<glam::Vec3>::new();
-    let mut right_laser_points = Vec::<glam::Vec3>::new();
-    for point in points {
-        if point.x >= 0_f32 {
-            right_laser_points.push(point);
-        } else {
-            left_laser_points.push(point);
+    let intrinsics = &calib.camera.intrinsics;
+    let image_size = glam::vec2(image.width() as f32, image.height() as f32);
+    if image_size != glam::vec2(intrinsics.width, intrinsics.height) {
+        warn!(
+            "Image size {image_size} does not match calibrated size {}x{}",
+            intrinsics.width, intrinsics.height
+        );
+    }
+    for point in &mut laser_points {
+        point.pixel = intrinsics.undistort_pixel(point.pixel);
+    }
+
+    let assigned_points = match detector.mode {
+        DetectionMode::AllPeaks => laser_points
+            .into_iter()
+            .map(|p| {
+                let laser = lit_laser.unwrap_or_else(|| laser_from_image_half(&p, intrinsics.cx));
+                (p, laser)
+            })
+            .collect(),
+        DetectionMode::BestPerLaser => {
+            let candidates = match lit_laser {
+                Some(laser) => vec![laser],
+                None => vec![Laser::Left, Laser::Right],
+            };
+            select_best_per_laser(laser_points, calib, &candidates)
+        }
+    };
+
+    let mut points = Vec::new();
+    let mut intensities = Vec::new();
+    let mut lasers = Vec::new();
+    let mut pixels = Vec::new();
+    for (p, laser) in &assigned_points {
+        if let Some(point) = back_project(p.pixel, calib.laser(*laser), calib) {
+            points.push(point);
+            intensities.push(p.intensity);
+            lasers.push((*laser).into());
+            pixels.push(p.raw_pixel);
         }
     }
+    PointCloud {
+        points,
+        intensities: Some(intensities),
+        lasers: Some(lasers),
+        pixels: Some(pixels),
+        ..Default::default()
+    }
+}
 
-    let meters_per_px = calib.camera.intrinsics.meters_per_px;
-    let mut right_projected_points: Vec<glam::Vec3> = right_laser_points
-        .iter()
-        .map(|p| project_on_laser_plane(*p, &calib.right_laser, meters_per_px))
-        .collect();
-    let mut left_projected_points: Vec<glam::Vec3> = left_laser_points
+/// Legacy assignment: the right laser is seen in the right half of the image,
+/// split at the principal point `cx`.
+fn laser_from_image_half(point: &LaserPoint, cx: f32) -> Laser {
+    if point.pixel.x >= cx {
+        Laser::Right
+    } else {
+        Laser::Left
+    }
+}
+
+/// Keeps at most one peak per laser in each detector row. Peaks are assigned to the
+/// candidate laser whose plane puts them inside the scan volume, competing
+/// peaks are resolved by `peak_score`.
+fn select_best_per_laser(
+    laser_points: Vec<LaserPoint>,
+    calib: &calibration::Calibration,
+    candidates: &[Laser],
+) -> Vec<(LaserPoint, Laser)> {
+    let expected_width = median_width(&laser_points);
+
+    let mut best = std::collections::BTreeMap::<(i64, Laser), (LaserPoint, f32)>::new();
+    for point in laser_points {
+        let Some(laser) = assign_laser(&point, calib, candidates) else {
+            continue;
+        };
+
+        let score = peak_score(&point, expected_width);
+        let key = (point.raw_pixel.y as i64, laser);
+        match best.get(&key) {
+            Some((_, best_score)) if *best_score >= score => {}
+            _ => {
+                best.insert(key, (point, score));
+            }
+        }
+    }
+
+    best.into_iter()
+        .map(|((_, laser), (point, _))| (point, laser))
+        .collect()
+}
+
+/// Triangulates the point with every candidate laser and picks the plane that
+/// yields a point inside the scan volume. When more than one does, the point
+/// closest to the turntable axis wins.
+fn assign_laser(
+    point: &LaserPoint,
+    calib: &calibration::Calibration,
+    candidates: &[Laser],
+) -> Option<Laser> {
+    candidates
         .iter()
-        .map(|p| project_on_laser_plane(*p, &calib.left_laser, meters_per_px))
-        .collect();
+        .filter_map(|&laser| {
+            let p = back_project(point.pixel, calib.laser(laser), calib)?;
+            Some((laser, p))
+        })
+        .filter(|(_, p)| calib.scan_volume.contains(&calib.turntable_axis, *p))
+        .map(|(laser, p)| (laser, calib.turntable_axis.cylindrical_coordinates(p).0))
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(laser, _)| laser)
+}
+
+/// Bright peaks as wide as the typical laser line score higher, reflections
+/// tend to be either much dimmer or much wider than the line itself.
+fn peak_score(point: &LaserPoint, expected_width: f32) -> f32 {
+    let width_error = (point.width - expected_width) / expected_width;
+    point.confidence * (-width_error * width_error).exp()
+}
 
-    let mut points = Vec::<glam::Vec3>::new();
-    points.append(&mut right_projected_points);
-    points.append(&mut left_projected_points);
+fn median_width(laser_points: &[LaserPoint]) -> f32 {
+    let mut widths: Vec<f32> = laser_points.iter().map(|p| p.width).collect();
+    if widths.is_empty() {
+        return 1_f32;
+    }
+    widths.sort_by(|a, b| a.total_cmp(b));
+    widths[widths.len() / 2]
+}
+
+/// Intersects the camera ray through the undistorted `pixel` with the laser
+/// plane and returns the point in world coordinates.
+fn back_project(
+    pixel: glam::Vec2,
+    laser_calib: &LaserCalib,
+    calib: &calibration::Calibration,
+) -> Option<glam::Vec3> {
+    let ray = calib.camera.intrinsics.pixel_to_ray(pixel);
+    let projected = project_on_laser_plane(ray, laser_calib)?;
 
     let img_plane_2_world = calib.camera.extrinsics.as_affine() * calib.camera.img_plane_2_cam();
-    return points
-        .iter()
-        .map(|p| meters_per_px * (*p))
-        .map(|p| img_plane_2_world.transform_point3(p))
-        .collect();
-}
-
-fn detect_laser_points(image: &image::GrayImage) -> Vec<glam::Vec2> {
-    let mut points = Vec::<glam::Vec2>::new();
-    for (_, row) in image.enumerate_rows() {
-        let mut laser_start: u32 = 0;
-        let mut laser_end: u32 = 0;
-        for (x, y, pixel) in row {
-            if pixel.0[0] > LOW_THRESHOLD {
-                if laser_start == 0 {
-                    laser_start = x;
-                }
-                laser_end = x;
-            } else {
-                if laser_start != 0 {
-                    let x = ((laser_start + laser_end) as f32) / 2_f32;
-                    points.push(glam::Vec2::new(x, y as f32));
-                    laser_start = 0;
-                    laser_end = 0;
-                }
+    Some(img_plane_2_world.transform_point3(projected))
+}
+
+pub fn detect_laser_points(
+    image: &image::GrayImage,
+    peak_detector: &dyn PeakDetector,
+) -> Vec<LaserPoint> {
+    let mut points = Vec::<LaserPoint>::new();
+    let width = image.width() as usize;
+    for (y, row) in image.as_raw().chunks_exact(width).enumerate() {
+        for (start, end) in find_laser_runs(row, LOW_THRESHOLD) {
+            if let Some(peak) = peak_detector.find_peak(row, start, end) {
+                let pixel = glam::Vec2::new(peak.x, y as f32);
+                let brightest = row[start..=end].iter().max().copied().unwrap_or(0);
+                points.push(LaserPoint {
+                    pixel,
+                    raw_pixel: pixel,
+                    confidence: peak.confidence,
+                    intensity: brightest as f32 / 255_f32,
+                    width: (end - start + 1) as f32,
+                });
             }
         }
     }
-    return points;
+    points
 }
 
-fn project_on_laser_plane(
-    p: glam::Vec3,
-    laser_calib: &LaserCalib,
-    meters_per_px: f32,
-) -> glam::Vec3 {
-    let laser_baseline_px = laser_calib.baseline / meters_per_px;
-    let denominator = p.z * laser_calib.angle_rad().tan() + p.x;
-    p * (laser_baseline_px / denominator)
+/// Returns the first and last pixel of every run of pixels above `threshold`.
+fn find_laser_runs(row: &[u8], threshold: u8) -> Vec<(usize, usize)> {
+    let mut runs = Vec::<(usize, usize)>::new();
+    for (x, pixel) in row.iter().enumerate() {
+        if *pixel <= threshold {
+            continue;
+        }
+        match runs.last_mut() {
+            Some((_, end)) if x - *end <= MAX_RUN_GAP + 1 => *end = x,
+            _ => runs.push((x, x)),
+        }
+    }
+    runs
+}
+
+/// Intersects the ray `t * ray` in the camera optical frame with the laser
+/// plane. Returns `None` if the plane is behind the camera or parallel to the
+/// ray.
+fn project_on_laser_plane(ray: glam::Vec3, laser_calib: &LaserCalib) -> Option<glam::Vec3> {
+    let t = laser_calib.offset / laser_calib.normal.dot(ray);
+    if !t.is_finite() || t <= 0_f32 {
+        return None;
+    }
+    Some(t * ray)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_calibration() -> calibration::Calibration {
+        let text = std::fs::read_to_string("calibration.json").unwrap();
+        serde_json::from_str(&text).unwrap()
+    }
+
+    fn laser_point(pixel: glam::Vec2, raw_pixel: glam::Vec2, confidence: f32) -> LaserPoint {
+        LaserPoint {
+            pixel,
+            raw_pixel,
+            confidence,
+            intensity: 1_f32,
+            width: 3_f32,
+        }
+    }
+
+    /// Columns of row `y` whose pixels triangulate inside the scan volume with
+    /// `laser`.
+    fn columns_in_volume(calib: &calibration::Calibration, laser: Laser, y: f32) -> Vec<f32> {
+        let width = calib.camera.intrinsics.width as usize;
+        (0..width)
+            .map(|x| x as f32)
+            .filter(|&x| {
+                let point = laser_point(glam::vec2(x, y), glam::vec2(x, y), 1_f32);
+                assign_laser(&point, calib, &[laser]) == Some(laser)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn angle_baseline_lasers_triangulate_as_before() {
+        // the triangulation of the angle and baseline calibrations, in the
+        // camera optical frame
+        let (focal_length, meters_per_px) = (0.00474_f32, 0.000005039_f32);
+        let legacy = |pixel: glam::Vec2, angle: f32, baseline: f32| {
+            let focal_length_px = focal_length / meters_per_px;
+            let p = glam::vec3(pixel.x - 360_f32, pixel.y - 640_f32, focal_length_px);
+            let baseline_px = baseline / meters_per_px;
+            meters_per_px * p * (baseline_px / (p.z * angle.to_radians().tan() + p.x))
+        };
+        let intrinsics = calibration::CameraIntrinsics::from_focal_length(
+            focal_length / meters_per_px,
+            720_f32,
+            1280_f32,
+        );
+
+        for (angle, baseline, x) in [(30_f32, 0.1_f32, 500_f32), (-30_f32, -0.1_f32, 200_f32)] {
+            let laser = LaserCalib::from_angle_baseline(angle, baseline);
+            for y in [0_f32, 333_f32, 640_f32, 1279_f32] {
+                let pixel = glam::vec2(x, y);
+                let expected = legacy(pixel, angle, baseline);
+                let p = project_on_laser_plane(intrinsics.pixel_to_ray(pixel), &laser).unwrap();
+                assert!(
+                    p.abs_diff_eq(expected, 1e-6_f32),
+                    "{p} instead of {expected} at {pixel}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ambient_light_is_subtracted() {
+        let image = image::GrayImage::from_raw(4, 1, vec![10, 200, 255, 0]).unwrap();
+        let ambient = image::GrayImage::from_raw(4, 1, vec![30, 50, 255, 7]).unwrap();
+        assert_eq!(subtract_ambient(&image, &ambient).as_raw(), &[0, 150, 0, 0]);
+
+        // frames of another size are ignored
+        let ambient = image::GrayImage::from_pixel(2, 2, image::Luma([100]));
+        assert_eq!(subtract_ambient(&image, &ambient), image);
+    }
+
+    #[test]
+    fn peaks_are_assigned_to_the_laser_that_puts_them_in_the_scan_volume() {
+        let mut calib = load_calibration();
+        let y = 640_f32;
+        let left = columns_in_volume(&calib, Laser::Left, y);
+        let right = columns_in_volume(&calib, Laser::Right, y);
+        let only_left: Vec<f32> = left
+            .iter()
+            .copied()
+            .filter(|x| !right.contains(x))
+            .collect();
+        let only_right: Vec<f32> = right
+            .iter()
+            .copied()
+            .filter(|x| !left.contains(x))
+            .collect();
+        assert!(!only_left.is_empty() && !only_right.is_empty());
+
+        let both = [Laser::Left, Laser::Right];
+        for (columns, laser) in [(&only_left, Laser::Left), (&only_right, Laser::Right)] {
+            for &x in columns.iter() {
+                let point = laser_point(glam::vec2(x, y), glam::vec2(x, y), 1_f32);
+                assert_eq!(assign_laser(&point, &calib, &both), Some(laser));
+            }
+        }
+        // inside the scan volume, then outside of a much thinner one
+        let x = right[right.len() - 1];
+        let point = laser_point(glam::vec2(x, y), glam::vec2(x, y), 1_f32);
+        assert!(assign_laser(&point, &calib, &both).is_some());
+        calib.scan_volume.radius = 0.001_f32;
+        assert_eq!(assign_laser(&point, &calib, &both), None);
+    }
+
+    #[test]
+    fn wide_reflections_lose_to_the_laser_line() {
+        let calib = load_calibration();
+        let y = 640_f32;
+        let columns = columns_in_volume(&calib, Laser::Right, y);
+        let (line_x, reflection_x) = (columns[0], columns[columns.len() - 1]);
+        let pixel = |x: f32, y: f32| glam::vec2(x, y);
+
+        // the other rows tell how wide the line is
+        let mut points: Vec<LaserPoint> = (0..5)
+            .map(|row| {
+                let y = y + 1_f32 + row as f32;
+                laser_point(pixel(line_x, y), pixel(line_x, y), 0.8)
+            })
+            .collect();
+        points.push(laser_point(pixel(line_x, y), pixel(line_x, y), 0.8));
+        // brighter, but spread over many more pixels than the line
+        points.push(LaserPoint {
+            width: 15_f32,
+            ..laser_point(pixel(reflection_x, y), pixel(reflection_x, y), 1_f32)
+        });
+
+        let selected = select_best_per_laser(points, &calib, &[Laser::Right]);
+        assert_eq!(selected.len(), 6);
+        let in_row: Vec<&LaserPoint> = selected
+            .iter()
+            .map(|(p, _)| p)
+            .filter(|p| p.raw_pixel.y == y)
+            .collect();
+        assert_eq!(in_row.len(), 1);
+        assert_eq!(in_row[0].pixel.x, line_x);
+    }
+
+    #[test]
+    fn only_the_points_in_the_color_frame_are_colored() {
+        let calib = load_calibration();
+        let x = columns_in_volume(&calib, Laser::Right, 640_f32)[0];
+        let visible = back_project(glam::vec2(x, 640_f32), &calib.right_laser, &calib).unwrap();
+        let camera = calib.camera.position();
+        let behind = 2_f32 * camera - visible;
+
+        let intrinsics = &calib.camera.intrinsics;
+        let (width, height) = (intrinsics.width as u32, intrinsics.height as u32);
+        let image = image::RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x / 3) as u8, (y / 5) as u8, 7])
+        });
+        let colors = sample_colors(&[visible, behind], &image, &calib);
+        assert_eq!(colors, [Some([(x / 3_f32) as u8, 128, 7]), None]);
+
+        let small = image::RgbImage::new(2, 2);
+        assert_eq!(sample_colors(&[visible], &small, &calib), [None]);
+    }
+
+    #[test]
+    fn peaks_are_grouped_by_the_row_they_were_detected_in() {
+        let calib = load_calibration();
+        let columns = columns_in_volume(&calib, Laser::Right, 640_f32);
+        assert!(columns.len() >= 2);
+        let (x1, x2) = (columns[0], columns[columns.len() - 1]);
+
+        // Undistortion moves peaks of the same detector row to different rows
+        let same_row = vec![
+            laser_point(glam::vec2(x1, 639.6), glam::vec2(x1, 640_f32), 0.5),
+            laser_point(glam::vec2(x2, 640.4), glam::vec2(x2, 640_f32), 0.9),
+        ];
+        let selected = select_best_per_laser(same_row, &calib, &[Laser::Right]);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].0.raw_pixel.x, x2);
+
+        // and peaks of different detector rows to the same one
+        let different_rows = vec![
+            laser_point(glam::vec2(x1, 640.2), glam::vec2(x1, 639_f32), 0.5),
+            laser_point(glam::vec2(x2, 640.2), glam::vec2(x2, 641_f32), 0.9),
+        ];
+        let selected = select_best_per_laser(different_rows, &calib, &[Laser::Right]);
+        assert_eq!(selected.len(), 2);
+    }
 }
@@ -0,0 +1,129 @@
+use crate::calibration::Laser;
+
+use anyhow::Result;
+use std::sync::{Arc, Mutex};
+
+pub trait LaserDriver: Send {
+    fn set(&mut self, laser: Laser, on: bool);
+    fn is_on(&self, laser: Laser) -> bool;
+    fn name(&self) -> String;
+
+    fn set_all(&mut self, on: bool) {
+        self.set(Laser::Left, on);
+        self.set(Laser::Right, on);
+    }
+}
+
+pub fn make_laser_driver() -> Result<Box<dyn LaserDriver>> {
+    #[cfg(feature = "lasers")]
+    let lasers: Box<dyn LaserDriver> = Box::new(real_lasers::GpioLasers::new()?);
+    #[cfg(not(feature = "lasers"))]
+    let lasers: Box<dyn LaserDriver> = Box::new(MockLaserDriver::default());
+    Ok(lasers)
+}
+
+#[cfg(feature = "lasers")]
+pub mod real_lasers {
+    use super::*;
+    use rppal::gpio::{Gpio, OutputPin};
+
+    const LEFT_LASER_PIN: u8 = 5;
+    const RIGHT_LASER_PIN: u8 = 6;
+
+    pub struct GpioLasers {
+        left: OutputPin,
+        right: OutputPin,
+    }
+
+    impl GpioLasers {
+        pub fn new() -> rppal::gpio::Result<GpioLasers> {
+            let gpio = Gpio::new()?;
+            let lasers = GpioLasers {
+                left: gpio.get(LEFT_LASER_PIN)?.into_output_low(),
+                right: gpio.get(RIGHT_LASER_PIN)?.into_output_low(),
+            };
+            Ok(lasers)
+        }
+
+        fn pin(&self, laser: Laser) -> &OutputPin {
+            match laser {
+                Laser::Left => &self.left,
+                Laser::Right => &self.right,
+            }
+        }
+    }
+
+    impl LaserDriver for GpioLasers {
+        fn set(&mut self, laser: Laser, on: bool) {
+            let pin = match laser {
+                Laser::Left => &mut self.left,
+                Laser::Right => &mut self.right,
+            };
+            if on {
+                pin.set_high();
+            } else {
+                pin.set_low();
+            }
+        }
+
+        fn is_on(&self, laser: Laser) -> bool {
+            self.pin(laser).is_set_high()
+        }
+
+        fn name(&self) -> String {
+            "GPIO lasers".to_string()
+        }
+    }
+}
+
+/// Keeps track of the requested laser state without driving any hardware.
+#[derive(Default)]
+pub struct MockLaserDriver {
+    left: bool,
+    right: bool,
+}
+
+impl LaserDriver for MockLaserDriver {
+    fn set(&mut self, laser: Laser, on: bool) {
+        match laser {
+            Laser::Left => self.left = on,
+            Laser::Right => self.right = on,
+        }
+    }
+
+    fn is_on(&self, laser: Laser) -> bool {
+        match laser {
+            Laser::Left => self.left,
+            Laser::Right => self.right,
+        }
+    }
+
+    fn name(&self) -> String {
+        "Mock Lasers".to_string()
+    }
+}
+
+/// Laser driver shared between the acquisition thread and the server, so the
+/// laser state can be reported while scanning.
+#[derive(Clone)]
+pub struct SharedLaserDriver(Arc<Mutex<Box<dyn LaserDriver>>>);
+
+impl SharedLaserDriver {
+    pub fn new(driver: Box<dyn LaserDriver>) -> Self {
+        Self(Arc::new(Mutex::new(driver)))
+    }
+}
+
+impl LaserDriver for SharedLaserDriver {
+    fn set(&mut self, laser: Laser, on: bool) {
+        self.0.lock().unwrap().set(laser, on);
+    }
+
+    fn is_on(&self, laser: Laser) -> bool {
+        self.0.lock().unwrap().is_on(laser)
+    }
+
+    fn name(&self) -> String {
+        self.0.lock().unwrap().name()
+    }
+}
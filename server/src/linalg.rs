@@ -0,0 +1,72 @@
+//! Nonlinear least squares for the calibration procedures, on top of the
+//! dense linear algebra of the point cloud crate.
+
+use pointcloud::linalg::{solve, Matrix};
+
+/// Minimizes the sum of squared `residuals` with the Levenberg-Marquardt
+/// algorithm, using a forward difference Jacobian. Returns the optimized
+/// parameters and the final residuals.
+pub fn levenberg_marquardt(
+    initial: &[f64],
+    residuals: impl Fn(&[f64]) -> Vec<f64>,
+    max_iterations: usize,
+) -> (Vec<f64>, Vec<f64>) {
+    let mut params = initial.to_vec();
+    let mut r = residuals(&params);
+    let mut cost: f64 = r.iter().map(|v| v * v).sum();
+    let mut lambda = 1e-3_f64;
+
+    for _ in 0..max_iterations {
+        let jacobian = numeric_jacobian(&params, &r, &residuals);
+        let jtj = jacobian.gram();
+        let gradient = jacobian.transpose_mul_vec(&r);
+
+        let mut improved = false;
+        while lambda < 1e10 {
+            let mut damped = jtj.clone();
+            for i in 0..params.len() {
+                damped[(i, i)] += lambda * jtj[(i, i)].max(1e-12);
+            }
+            let minus_gradient: Vec<f64> = gradient.iter().map(|g| -g).collect();
+            let Some(delta) = solve(&damped, &minus_gradient) else {
+                lambda *= 10_f64;
+                continue;
+            };
+
+            let candidate: Vec<f64> = params.iter().zip(&delta).map(|(p, d)| p + d).collect();
+            let candidate_r = residuals(&candidate);
+            let candidate_cost: f64 = candidate_r.iter().map(|v| v * v).sum();
+            if candidate_cost < cost {
+                let relative_decrease = (cost - candidate_cost) / cost.max(1e-300);
+                params = candidate;
+                r = candidate_r;
+                cost = candidate_cost;
+                lambda = (lambda / 10_f64).max(1e-12);
+                improved = relative_decrease > 1e-12;
+                break;
+            }
+            lambda *= 10_f64;
+        }
+
+        if !improved {
+            break;
+        }
+    }
+
+    (params, r)
+}
+
+fn numeric_jacobian(params: &[f64], r: &[f64], residuals: &impl Fn(&[f64]) -> Vec<f64>) -> Matrix {
+    let mut jacobian = Matrix::zeros(r.len(), params.len());
+    let mut perturbed = params.to_vec();
+    for j in 0..params.len() {
+        let h = 1e-7_f64 * params[j].abs().max(1e-2);
+        perturbed[j] = params[j] + h;
+        let r_h = residuals(&perturbed);
+        perturbed[j] = params[j];
+        for i in 0..r.len() {
+            jacobian[(i, j)] = (r_h[i] - r[i]) / h;
+        }
+    }
+    jacobian
+}
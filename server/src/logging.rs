@@ -5,7 +5,7 @@ use glam::Affine3A;
 use image::DynamicImage;
 use std::cfg;
 
-pub trait Logger {
+pub trait Logger: Send {
     fn log_transform(&self, id: &str, transform: &Affine3A) -> Result<()>;
     fn log_points(&self, id: &str, points: &[glam::Vec3]) -> Result<()>;
     fn log_image(&self, id: &str, image: DynamicImage) -> Result<()>;
@@ -66,16 +66,14 @@ pub mod rerun {
         }
 
         fn log_camera(&self, id: &str, camera_calibration: &CameraCalib) -> Result<()> {
-            let focal = camera_calibration.intrinsics.focal_length_px();
+            let intrinsics = &camera_calibration.intrinsics;
             self.rec.log_static(
                 "world/camera",
                 &rerun::Pinhole::from_focal_length_and_resolution(
-                    [focal, focal],
-                    [
-                        camera_calibration.intrinsics.width,
-                        camera_calibration.intrinsics.height,
-                    ],
+                    [intrinsics.fx, intrinsics.fy],
+                    [intrinsics.width, intrinsics.height],
                 )
+                .with_principal_point([intrinsics.cx, intrinsics.cy])
                 .with_camera_xyz(rerun::components::ViewCoordinates::DLB),
             )?;
             let result = self.log_transform(id, &camera_calibration.extrinsics.as_affine());
@@ -1,17 +1,28 @@
+mod calibrate;
 mod calibration;
 mod cameras;
+mod chessboard;
+mod export;
 mod imgproc;
+mod lasers;
+mod linalg;
 mod logging;
+mod meshing;
 mod motor;
+mod peak;
+mod postprocess;
+mod registration;
 mod scanner;
 mod server;
+mod sessions;
 
 use motor::make_stepper_motor;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 use env_logger;
 use log::{error, info, warn};
+use msg::command::ExportFormat;
 use msg::DEFAULT_SERVER_PORT;
 use std::path::PathBuf;
 
@@ -33,10 +44,108 @@ enum Commands {
         rerun_ip: std::net::Ipv4Addr,
         #[clap(default_value = "9876")]
         rerun_port: u16,
+        #[clap(long, value_enum, default_value_t = peak::PeakDetectorType::CenterOfMass)]
+        peak_detector: peak::PeakDetectorType,
+        #[clap(long, value_enum, default_value_t = imgproc::DetectionMode::AllPeaks)]
+        detection_mode: imgproc::DetectionMode,
+        #[clap(long, value_enum, default_value_t = cameras::AcquisitionMode::LaserOnly)]
+        acquisition_mode: cameras::AcquisitionMode,
+        /// Directory where the scan sessions are saved
+        #[clap(long, default_value = "sessions")]
+        sessions_dir: PathBuf,
+        /// Cleaning and normal estimation of the merged cloud of every scan
+        #[clap(flatten)]
+        post_process: postprocess::PostProcessOptions,
     },
     Motor {
         degrees: f32,
     },
+    /// Writes the point cloud of a saved scan session to a file
+    Export {
+        session: String,
+        output: PathBuf,
+        #[clap(long, default_value = "sessions")]
+        sessions_dir: PathBuf,
+        /// ply, ply-ascii, xyz, csv, pcd, obj, stl or glb, guessed from the
+        /// output extension by default
+        #[clap(long, value_parser = export::parse_format)]
+        format: Option<ExportFormat>,
+    },
+    /// Reconstructs the surface of a saved scan session and writes the mesh
+    /// to a file
+    Mesh {
+        session: String,
+        output: PathBuf,
+        #[clap(long, default_value = "sessions")]
+        sessions_dir: PathBuf,
+        /// Output format, see `export`
+        #[clap(long, value_parser = export::parse_format)]
+        format: Option<ExportFormat>,
+        #[clap(flatten)]
+        params: meshing::MeshParameters,
+    },
+    /// Aligns a saved scan session onto another one, e.g. the object
+    /// scanned upside down onto the upright scan, and saves both clouds
+    /// merged as a new session
+    Register {
+        session: String,
+        onto: String,
+        #[clap(long, default_value = "sessions")]
+        sessions_dir: PathBuf,
+        #[clap(flatten)]
+        params: registration::RegistrationParameters,
+    },
+    /// Cleans up a point cloud file: crops, outlier removal and
+    /// downsampling. Mesh triangles are dropped
+    Filter {
+        input: PathBuf,
+        output: PathBuf,
+        /// Output format, see `export`
+        #[clap(long, value_parser = export::parse_format)]
+        format: Option<ExportFormat>,
+        /// Calibration of the scan, needed by --crop-turntable
+        #[clap(long)]
+        calibration: Option<PathBuf>,
+        #[clap(flatten)]
+        filters: postprocess::FilterOptions,
+    },
+    /// Converts a point cloud or mesh file to another format, the formats
+    /// are guessed from the file extensions
+    Convert {
+        input: PathBuf,
+        output: PathBuf,
+        /// Output format, see `export`
+        #[clap(long, value_parser = export::parse_format)]
+        format: Option<ExportFormat>,
+    },
+    /// Estimates the camera intrinsics from chessboard images
+    CalibrateCamera {
+        image_dir: PathBuf,
+        #[clap(default_value = "calibration.json")]
+        calibration: PathBuf,
+        #[clap(flatten)]
+        board: calibrate::Chessboard,
+    },
+    /// Fits the laser planes from chessboard images taken with the lasers
+    /// off, only the left laser on and only the right laser on, at each pose
+    CalibrateLasers {
+        image_dir: PathBuf,
+        #[clap(default_value = "calibration.json")]
+        calibration: PathBuf,
+        #[clap(flatten)]
+        board: calibrate::Chessboard,
+        #[clap(long, value_enum, default_value_t = peak::PeakDetectorType::CenterOfMass)]
+        peak_detector: peak::PeakDetectorType,
+    },
+    /// Estimates the turntable rotation axis from images of a chessboard
+    /// standing on the turntable, taken at different motor angles
+    CalibrateTurntable {
+        image_dir: PathBuf,
+        #[clap(default_value = "calibration.json")]
+        calibration: PathBuf,
+        #[clap(flatten)]
+        board: calibrate::Chessboard,
+    },
 }
 
 fn main() -> Result<()> {
@@ -52,7 +161,208 @@ fn main() -> Result<()> {
             let steps_per_rev = motor.steps_per_rev();
             let steps = (degrees / 360_f32 * steps_per_rev) as u32;
             info!("Moving motor {} degrees, {} steps", degrees, steps);
-            motor.step(steps);
+            motor.step(steps)?;
+        }
+        Commands::Export {
+            session,
+            output,
+            sessions_dir,
+            format,
+        } => {
+            let format = match format {
+                Some(format) => format,
+                None => export::format_from_path(&output)?,
+            };
+            let store = sessions::SessionStore::open(&sessions_dir)?;
+            let cloud = export::session_cloud(store.load(&session)?);
+            let file = std::fs::File::create(&output)
+                .with_context(|| format!("Failed to create {}", output.display()))?;
+            export::make_exporter(format).write(&mut std::io::BufWriter::new(file), &cloud)?;
+            info!(
+                "Exported {} points to {}",
+                cloud.points.len(),
+                output.display()
+            );
+        }
+        Commands::Mesh {
+            session,
+            output,
+            sessions_dir,
+            format,
+            params,
+        } => {
+            let format = match format {
+                Some(format) => format,
+                None => export::format_from_path(&output)?,
+            };
+            let store = sessions::SessionStore::open(&sessions_dir)?;
+            let mesh = meshing::mesh_session(&store, &session, &params)?;
+            let file = std::fs::File::create(&output)
+                .with_context(|| format!("Failed to create {}", output.display()))?;
+            export::make_exporter(format).write(&mut std::io::BufWriter::new(file), &mesh)?;
+            info!(
+                "Wrote {} vertices and {} triangles to {}",
+                mesh.vertices.points.len(),
+                mesh.triangles.len(),
+                output.display()
+            );
+        }
+        Commands::Register {
+            session,
+            onto,
+            sessions_dir,
+            params,
+        } => {
+            let store = sessions::SessionStore::open(&sessions_dir)?;
+            let info = registration::register_sessions(&store, &session, &onto, &params)?;
+            info!("Saved the merged scans as session {}", info.id);
+        }
+        Commands::Filter {
+            input,
+            output,
+            format,
+            calibration,
+            filters,
+        } => {
+            let calibration = match calibration {
+                Some(path) => Some(calibration::load_calibration(&path)?),
+                None => None,
+            };
+            let filters = filters.filters(calibration.as_ref())?;
+            let bytes = std::fs::read(&input)
+                .with_context(|| format!("Failed to read {}", input.display()))?;
+            let cloud = export::make_exporter(export::format_from_path(&input)?)
+                .read(&bytes)
+                .with_context(|| format!("Failed to read {}", input.display()))?
+                .vertices;
+            let kept = pointcloud::apply_all(&filters, &cloud.points);
+            let filtered = cloud.select(&kept);
+            let format = match format {
+                Some(format) => format,
+                None => export::format_from_path(&output)?,
+            };
+            let file = std::fs::File::create(&output)
+                .with_context(|| format!("Failed to create {}", output.display()))?;
+            export::make_exporter(format).write(&mut std::io::BufWriter::new(file), &filtered)?;
+            info!(
+                "Kept {} of {} points in {}",
+                filtered.points.len(),
+                cloud.points.len(),
+                output.display()
+            );
+        }
+        Commands::Convert {
+            input,
+            output,
+            format,
+        } => {
+            let bytes = std::fs::read(&input)
+                .with_context(|| format!("Failed to read {}", input.display()))?;
+            let mesh = export::make_exporter(export::format_from_path(&input)?)
+                .read(&bytes)
+                .with_context(|| format!("Failed to read {}", input.display()))?;
+            let format = match format {
+                Some(format) => format,
+                None => export::format_from_path(&output)?,
+            };
+            let file = std::fs::File::create(&output)
+                .with_context(|| format!("Failed to create {}", output.display()))?;
+            export::make_exporter(format).write(&mut std::io::BufWriter::new(file), &mesh)?;
+            info!(
+                "Converted {} points and {} triangles to {}",
+                mesh.vertices.points.len(),
+                mesh.triangles.len(),
+                output.display()
+            );
+        }
+        Commands::CalibrateCamera {
+            image_dir,
+            calibration,
+            board,
+        } => {
+            let images = calibrate::load_images(&image_dir)?;
+            info!(
+                "Loaded {} images from {}",
+                images.len(),
+                image_dir.display()
+            );
+
+            let result = calibrate::calibrate_camera(&images, &board)?;
+            let intrinsics = &result.intrinsics;
+            let d = &intrinsics.distortion;
+            println!("Used {} images", result.board_poses.len());
+            println!("Reprojection error: {:.3} px", result.rms_error);
+            println!("fx: {:.3} fy: {:.3}", intrinsics.fx, intrinsics.fy);
+            println!("cx: {:.3} cy: {:.3}", intrinsics.cx, intrinsics.cy);
+            println!(
+                "k1: {:.5} k2: {:.5} k3: {:.5} p1: {:.5} p2: {:.5}",
+                d.k1, d.k2, d.k3, d.p1, d.p2
+            );
+
+            calibration::update_calibration_entry(&calibration, "/camera/intrinsics", intrinsics)?;
+            info!("Updated {}", calibration.display());
+        }
+        Commands::CalibrateLasers {
+            image_dir,
+            calibration,
+            board,
+            peak_detector,
+        } => {
+            let intrinsics = calibration::load_calibration(&calibration)?
+                .camera
+                .intrinsics;
+            let images = calibrate::load_images(&image_dir)?;
+            info!(
+                "Loaded {} images from {}",
+                images.len(),
+                image_dir.display()
+            );
+
+            let peak_detector = peak::make_peak_detector(peak_detector);
+            let result =
+                calibrate::calibrate_lasers(&images, &board, &intrinsics, peak_detector.as_ref())?;
+            for (name, fit) in [("Left", &result.left), ("Right", &result.right)] {
+                println!(
+                    "{name} laser: normal {:.5} offset {:.5} m, {} points, RMS error {:.2} mm",
+                    fit.plane.normal,
+                    fit.plane.offset,
+                    fit.points,
+                    fit.rms_error * 1000_f64
+                );
+            }
+
+            calibration::update_calibration_entry(&calibration, "/left_laser", &result.left.plane)?;
+            calibration::update_calibration_entry(
+                &calibration,
+                "/right_laser",
+                &result.right.plane,
+            )?;
+            info!("Updated {}", calibration.display());
+        }
+        Commands::CalibrateTurntable {
+            image_dir,
+            calibration,
+            board,
+        } => {
+            let camera = calibration::load_calibration(&calibration)?.camera;
+            let images = calibrate::load_images(&image_dir)?;
+            info!(
+                "Loaded {} images from {}",
+                images.len(),
+                image_dir.display()
+            );
+
+            let result = calibrate::calibrate_turntable(&images, &board, &camera)?;
+            let axis = &result.axis;
+            println!("Axis direction: {:.5}", axis.direction);
+            println!("Axis point: {:.5} m", axis.point);
+            println!("Circle fit error: {:.2} mm", result.rms_error * 1000_f64);
+            for (k, angle) in result.step_angles.iter().enumerate() {
+                println!("Rotation between images {k} and {}: {angle:.2} deg", k + 1);
+            }
+
+            calibration::update_calibration_entry(&calibration, "/turntable_axis", axis)?;
+            info!("Updated {}", calibration.display());
         }
         Commands::Run {
             port,
@@ -60,6 +370,11 @@ fn main() -> Result<()> {
             calibration,
             rerun_ip,
             rerun_port,
+            peak_detector,
+            detection_mode,
+            acquisition_mode,
+            sessions_dir,
+            post_process,
         } => {
             #[cfg(feature = "camera")]
             let camera_type = cameras::CameraType::RaspberryPi;
@@ -69,10 +384,17 @@ fn main() -> Result<()> {
             let reurn_server_address =
                 std::net::SocketAddr::new(std::net::IpAddr::V4(rerun_ip), rerun_port);
             info!("Initializing scanner...");
-            let mut scanner =
-                scanner::Scanner::new(camera_type, reurn_server_address, &calibration)?;
+            let scanner = scanner::Scanner::new(
+                camera_type,
+                reurn_server_address,
+                &calibration,
+                imgproc::LaserLineDetector::new(peak_detector, detection_mode),
+                acquisition_mode,
+                &sessions_dir,
+                &post_process,
+            )?;
 
-            server::run_websocket_server(port, &mut scanner)?;
+            server::run_websocket_server(port, scanner)?;
         }
     }
 
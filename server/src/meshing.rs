@@ -0,0 +1,664 @@
+//! Triangle meshes of the scanned surface.
+//!
+//! Each scan step sees the object through one laser plane per laser, so the
+//! points of a step are one or two profiles of the surface at a given angle
+//! around the turntable axis. Profiles neighbouring around the axis are
+//! stitched into strips of triangles, and when the scan covers a full turn
+//! the bottom and the top are closed with fans, giving a closed surface
+//! except where the lasers did not reach.
+//!
+//! Clouds whose steps are unknown, e.g. read from files, are meshed by ball
+//! pivoting instead: a ball rolls over the points and keeps every triangle
+//! it rests on without containing other points.
+
+use crate::calibration::TurntableAxis;
+use crate::export::{session_cloud, Mesh};
+use crate::sessions::SessionStore;
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use glam::Vec3;
+use log::info;
+use msg::response::PointCloud;
+use pointcloud::KdTree;
+use std::collections::{BTreeMap, HashSet};
+use std::f32::consts::TAU;
+
+/// Points of a step further apart than this around the axis belong to
+/// different lasers.
+const PROFILE_GAP_DEG: f32 = 10_f32;
+/// Points sampled to estimate the spacing of the cloud.
+const SPACING_SAMPLES: usize = 200;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum MeshMethod {
+    /// Profiles when the step of each point is known, ball pivoting
+    /// otherwise
+    Auto,
+    Profiles,
+    BallPivoting,
+}
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct MeshParameters {
+    #[clap(long, value_enum, default_value_t = MeshMethod::Auto)]
+    pub method: MeshMethod,
+    /// Height of the slices averaging the points of a profile, in meters
+    #[clap(long, default_value_t = 0.002)]
+    pub slice_height: f32,
+    /// Longest edge between two profiles in meters, longer triangles are
+    /// left out as holes
+    #[clap(long, default_value_t = 0.02)]
+    pub max_edge: f32,
+    /// Radius of the ball pivoting ball in meters, twice the typical
+    /// spacing of the points by default
+    #[clap(long)]
+    pub ball_radius: Option<f32>,
+}
+
+impl Default for MeshParameters {
+    fn default() -> Self {
+        Self {
+            method: MeshMethod::Auto,
+            slice_height: 0.002_f32,
+            max_edge: 0.02_f32,
+            ball_radius: None,
+        }
+    }
+}
+
+/// Meshes the merged cloud of a saved session, around the turntable axis of
+/// the calibration used for the scan.
+pub fn mesh_session(sessions: &SessionStore, id: &str, params: &MeshParameters) -> Result<Mesh> {
+    let cloud = session_cloud(sessions.load(id)?);
+    let calibration = sessions.calibration(id)?;
+    reconstruct(&cloud, &calibration.turntable_axis, params)
+}
+
+pub fn reconstruct(
+    cloud: &PointCloud,
+    axis: &TurntableAxis,
+    params: &MeshParameters,
+) -> Result<Mesh> {
+    if cloud.points.len() < 3 {
+        return Ok(Mesh {
+            vertices: cloud.clone(),
+            triangles: Vec::new(),
+        });
+    }
+    let method = match (params.method, &cloud.steps) {
+        (MeshMethod::Auto, Some(_)) => MeshMethod::Profiles,
+        (MeshMethod::Auto, None) => MeshMethod::BallPivoting,
+        (method, _) => method,
+    };
+    let mesh = match method {
+        MeshMethod::Profiles => {
+            let Some(steps) = &cloud.steps else {
+                bail!("The scan steps of the points are unknown, the profiles cannot be stitched");
+            };
+            if params.slice_height.is_nan() || params.slice_height <= 0_f32 {
+                bail!("Invalid slice height {}", params.slice_height);
+            }
+            stitch_profiles(&cloud.points, steps, axis, params)
+        }
+        _ => {
+            let radius = match params.ball_radius {
+                Some(radius) => radius,
+                None => 2_f32 * typical_spacing(&cloud.points),
+            };
+            if radius.is_nan() || radius <= 0_f32 {
+                bail!("Invalid ball radius {radius}");
+            }
+            // the estimated normals, when the cloud has them, know better
+            // than the axis where each point was seen from
+            let frame = AxisFrame::new(axis, &cloud.points);
+            let normals = cloud.normals.as_deref();
+            let outward = |i: u32| {
+                let i = i as usize;
+                match normals.map_or(Vec3::ZERO, |normals| normals[i]) {
+                    Vec3::ZERO => frame.outward(cloud.points[i]),
+                    normal => normal,
+                }
+            };
+            let triangles = ball_pivoting(&cloud.points, outward, radius);
+            Mesh {
+                vertices: cloud.clone(),
+                triangles,
+            }
+        }
+    };
+    info!(
+        "Meshed {} points with {method:?}: {} vertices, {} triangles",
+        cloud.points.len(),
+        mesh.vertices.points.len(),
+        mesh.triangles.len()
+    );
+    Ok(mesh)
+}
+
+/// Cylindrical coordinates around the turntable axis. Azimuths grow
+/// counter-clockwise seen from above.
+struct AxisFrame {
+    origin: Vec3,
+    up: Vec3,
+    u: Vec3,
+    v: Vec3,
+}
+
+impl AxisFrame {
+    /// The origin is on the axis, at the mean height of the points.
+    fn new(axis: &TurntableAxis, points: &[Vec3]) -> Self {
+        let up = axis.direction.normalize();
+        let (u, _) = up.any_orthonormal_pair();
+        let mean_height = points
+            .iter()
+            .map(|p| (*p - axis.point).dot(up))
+            .sum::<f32>()
+            / points.len().max(1) as f32;
+        Self {
+            origin: axis.point + mean_height * up,
+            up,
+            u,
+            v: up.cross(u),
+        }
+    }
+
+    fn azimuth(&self, p: Vec3) -> f32 {
+        let relative = p - self.origin;
+        relative
+            .dot(self.v)
+            .atan2(relative.dot(self.u))
+            .rem_euclid(TAU)
+    }
+
+    fn height(&self, p: Vec3) -> f32 {
+        (p - self.origin).dot(self.up)
+    }
+
+    /// Rough surface orientation, away from the middle of the object.
+    fn outward(&self, p: Vec3) -> Vec3 {
+        (p - self.origin).normalize_or_zero()
+    }
+}
+
+/// Points of one laser line at one step, bottom to top.
+struct Profile {
+    azimuth: f32,
+    vertices: Vec<u32>,
+}
+
+fn stitch_profiles(
+    points: &[Vec3],
+    steps: &[u32],
+    axis: &TurntableAxis,
+    params: &MeshParameters,
+) -> Mesh {
+    let frame = AxisFrame::new(axis, points);
+    let mut by_step: BTreeMap<u32, Vec<(f32, Vec3)>> = BTreeMap::new();
+    for (p, step) in points.iter().zip(steps) {
+        by_step
+            .entry(*step)
+            .or_default()
+            .push((frame.azimuth(*p), *p));
+    }
+
+    let mut vertices = Vec::new();
+    let mut profiles = Vec::new();
+    for step_points in by_step.into_values() {
+        for line in split_lines(step_points, PROFILE_GAP_DEG.to_radians()) {
+            let (sin, cos) = line
+                .iter()
+                .fold((0_f32, 0_f32), |(s, c), (a, _)| (s + a.sin(), c + a.cos()));
+            // averages the points of each slice, noise and the peaks of
+            // reflections make profiles thicker than a line
+            let mut slices: BTreeMap<i64, (Vec3, f32)> = BTreeMap::new();
+            for (_, p) in &line {
+                let slice = (frame.height(*p) / params.slice_height).floor() as i64;
+                let (sum, count) = slices.entry(slice).or_default();
+                *sum += *p;
+                *count += 1_f32;
+            }
+            if slices.len() < 2 {
+                continue;
+            }
+            let first = vertices.len() as u32;
+            vertices.extend(slices.into_values().map(|(sum, count)| sum / count));
+            profiles.push(Profile {
+                azimuth: sin.atan2(cos).rem_euclid(TAU),
+                vertices: (first..vertices.len() as u32).collect(),
+            });
+        }
+    }
+    profiles.sort_by(|a, b| a.azimuth.total_cmp(&b.azimuth));
+
+    let mut triangles = Vec::new();
+    for pair in profiles.windows(2) {
+        stitch(
+            &pair[0],
+            &pair[1],
+            &vertices,
+            params.max_edge,
+            &mut triangles,
+        );
+    }
+    if is_full_turn(&profiles) {
+        let (last, first) = (&profiles[profiles.len() - 1], &profiles[0]);
+        stitch(last, first, &vertices, params.max_edge, &mut triangles);
+        let bottom: Vec<u32> = profiles.iter().map(|p| p.vertices[0]).collect();
+        let top: Vec<u32> = profiles
+            .iter()
+            .map(|p| *p.vertices.last().unwrap())
+            .collect();
+        cap(&bottom, false, &mut vertices, &mut triangles);
+        cap(&top, true, &mut vertices, &mut triangles);
+    }
+
+    Mesh {
+        vertices: PointCloud {
+            points: vertices,
+            ..Default::default()
+        },
+        triangles,
+    }
+}
+
+/// Splits the points of a step at the gaps around the axis, one line per
+/// laser.
+fn split_lines(mut points: Vec<(f32, Vec3)>, gap: f32) -> Vec<Vec<(f32, Vec3)>> {
+    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let is_gap = |a: f32, b: f32| (b - a).rem_euclid(TAU) > gap;
+    let n = points.len();
+    let Some(last) = (0..n)
+        .rev()
+        .find(|&i| is_gap(points[i].0, points[(i + 1) % n].0))
+    else {
+        return vec![points];
+    };
+    // start right after a gap, so that no line wraps around
+    points.rotate_left((last + 1) % n);
+    let mut lines = vec![Vec::new()];
+    for i in 0..n {
+        lines.last_mut().unwrap().push(points[i]);
+        if i + 1 < n && is_gap(points[i].0, points[i + 1].0) {
+            lines.push(Vec::new());
+        }
+    }
+    lines
+}
+
+/// Whether the profiles go all around the axis, the gap between the last
+/// and the first one being no wider than the others.
+fn is_full_turn(profiles: &[Profile]) -> bool {
+    if profiles.len() < 3 {
+        return false;
+    }
+    let widest = profiles
+        .windows(2)
+        .map(|pair| pair[1].azimuth - pair[0].azimuth)
+        .fold(0_f32, f32::max);
+    let closing = profiles[0].azimuth + TAU - profiles[profiles.len() - 1].azimuth;
+    closing <= 2_f32 * widest
+}
+
+/// Joins two profiles, `b` following `a` counter-clockwise, taking the
+/// shortest diagonal at each triangle.
+fn stitch(a: &Profile, b: &Profile, points: &[Vec3], max_edge: f32, triangles: &mut Vec<[u32; 3]>) {
+    let (a, b) = (&a.vertices, &b.vertices);
+    let distance = |i: u32, j: u32| points[i as usize].distance(points[j as usize]);
+    let (mut i, mut j) = (0, 0);
+    while i + 1 < a.len() || j + 1 < b.len() {
+        let climb_a = if i + 1 == a.len() {
+            false
+        } else if j + 1 == b.len() {
+            true
+        } else {
+            distance(a[i + 1], b[j]) < distance(a[i], b[j + 1])
+        };
+        let triangle = if climb_a {
+            i += 1;
+            [a[i - 1], b[j], a[i]]
+        } else {
+            j += 1;
+            [a[i], b[j - 1], b[j]]
+        };
+        let longest = (0..3)
+            .map(|k| distance(triangle[k], triangle[(k + 1) % 3]))
+            .fold(0_f32, f32::max);
+        if longest <= max_edge {
+            triangles.push(triangle);
+        }
+    }
+}
+
+/// Closes a ring of vertices ordered counter-clockwise with a fan around
+/// their centroid, facing up for the top.
+fn cap(ring: &[u32], top: bool, points: &mut Vec<Vec3>, triangles: &mut Vec<[u32; 3]>) {
+    let centroid = ring.iter().map(|&i| points[i as usize]).sum::<Vec3>() / ring.len() as f32;
+    let center = points.len() as u32;
+    points.push(centroid);
+    for k in 0..ring.len() {
+        let (a, b) = (ring[k], ring[(k + 1) % ring.len()]);
+        triangles.push(if top { [center, a, b] } else { [center, b, a] });
+    }
+}
+
+/// Median distance of a sample of the points to their nearest neighbour.
+fn typical_spacing(points: &[Vec3]) -> f32 {
+    let tree = KdTree::new(points);
+    let stride = (points.len() / SPACING_SAMPLES).max(1);
+    let mut distances: Vec<f32> = (0..points.len())
+        .step_by(stride)
+        .filter_map(|i| {
+            // the nearest point is the point itself
+            let nearest = tree.nearest(points[i], 2);
+            nearest.get(1).map(|&(_, d)| d)
+        })
+        .filter(|&d| d > 0_f32)
+        .collect();
+    if distances.is_empty() {
+        return 0_f32;
+    }
+    let middle = distances.len() / 2;
+    *distances.select_nth_unstable_by(middle, f32::total_cmp).1
+}
+
+/// Edge of the mesh border the ball can pivot around, `a` to `b` being
+/// counter-clockwise in the triangle it belongs to.
+struct FrontEdge {
+    a: u32,
+    b: u32,
+    opposite: u32,
+    center: Vec3,
+}
+
+/// Ball pivoting (Bernardini et al., 1999). `outward` gives the side of the
+/// surface each point, by index, is seen from, the triangles face it.
+fn ball_pivoting(points: &[Vec3], outward: impl Fn(u32) -> Vec3, radius: f32) -> Vec<[u32; 3]> {
+    let mut pivoting = BallPivoting {
+        points,
+        outward,
+        radius,
+        tree: KdTree::new(points),
+        used: vec![false; points.len()],
+        edges: HashSet::new(),
+        front: Vec::new(),
+        triangles: Vec::new(),
+    };
+    for seed in 0..points.len() as u32 {
+        if pivoting.used[seed as usize] {
+            continue;
+        }
+        if let Some((triangle, center)) = pivoting.seed(seed) {
+            pivoting.add(triangle, center);
+            pivoting.expand();
+        }
+    }
+    pivoting.triangles
+}
+
+struct BallPivoting<'a, F> {
+    points: &'a [Vec3],
+    outward: F,
+    radius: f32,
+    tree: KdTree<'a>,
+    used: Vec<bool>,
+    /// Directed edges of the triangles, each one is used at most once so
+    /// that the mesh stays a consistently oriented manifold
+    edges: HashSet<(u32, u32)>,
+    front: Vec<FrontEdge>,
+    triangles: Vec<[u32; 3]>,
+}
+
+impl<F: Fn(u32) -> Vec3> BallPivoting<'_, F> {
+    fn point(&self, i: u32) -> Vec3 {
+        self.points[i as usize]
+    }
+
+    fn within(&self, center: Vec3, radius: f32) -> impl Iterator<Item = u32> {
+        self.tree
+            .within(center, radius)
+            .into_iter()
+            .map(|i| i as u32)
+    }
+
+    /// A triangle of unused points around `seed` with an empty ball on its
+    /// outer side.
+    fn seed(&self, seed: u32) -> Option<([u32; 3], Vec3)> {
+        let p = self.point(seed);
+        let mut neighbours: Vec<u32> = self
+            .within(p, 2_f32 * self.radius)
+            .filter(|&i| i != seed && !self.used[i as usize])
+            .collect();
+        neighbours.sort_by(|&i, &j| {
+            let d = |k: u32| self.point(k).distance_squared(p);
+            d(i).total_cmp(&d(j))
+        });
+        for (n, &j) in neighbours.iter().enumerate() {
+            for &k in &neighbours[n + 1..] {
+                let normal = (self.point(j) - p).cross(self.point(k) - p);
+                let triangle = match normal.dot((self.outward)(seed)) > 0_f32 {
+                    true => [seed, j, k],
+                    false => [seed, k, j],
+                };
+                if let Some(center) = self.ball(triangle) {
+                    return Some((triangle, center));
+                }
+            }
+        }
+        None
+    }
+
+    /// Center of the empty ball resting on the triangle, on the side it
+    /// faces, when the triangle faces outward.
+    fn ball(&self, triangle: [u32; 3]) -> Option<Vec3> {
+        let [a, b, c] = triangle.map(|i| self.point(i));
+        let center = ball_center(a, b, c, self.radius)?;
+        let normal = (b - a).cross(c - a);
+        let faces_out = triangle
+            .iter()
+            .all(|&i| normal.dot((self.outward)(i)) > 0_f32);
+        let empty = self
+            .within(center, self.radius * (1_f32 - 1e-3_f32))
+            .all(|i| triangle.contains(&i));
+        (faces_out && empty).then_some(center)
+    }
+
+    fn add(&mut self, triangle: [u32; 3], center: Vec3) {
+        for k in 0..3 {
+            let (a, b, opposite) = (triangle[k], triangle[(k + 1) % 3], triangle[(k + 2) % 3]);
+            self.edges.insert((a, b));
+            self.used[a as usize] = true;
+            // the other side is already meshed
+            if !self.edges.contains(&(b, a)) {
+                self.front.push(FrontEdge {
+                    a,
+                    b,
+                    opposite,
+                    center,
+                });
+            }
+        }
+        self.triangles.push(triangle);
+    }
+
+    fn expand(&mut self) {
+        while let Some(edge) = self.front.pop() {
+            if self.edges.contains(&(edge.b, edge.a)) {
+                continue;
+            }
+            if let Some((k, center)) = self.pivot(&edge) {
+                self.add([edge.b, edge.a, k], center);
+            }
+        }
+    }
+
+    /// Rolls the ball over the edge, away from the triangle, and returns
+    /// the first point it touches.
+    fn pivot(&self, edge: &FrontEdge) -> Option<(u32, Vec3)> {
+        let (a, b) = (self.point(edge.a), self.point(edge.b));
+        let middle = (a + b) / 2_f32;
+        let axis = (b - a).normalize();
+        let from = edge.center - middle;
+        let mut candidates: Vec<(f32, u32, Vec3)> = self
+            .within(middle, 2_f32 * self.radius)
+            .filter(|&k| k != edge.a && k != edge.b && k != edge.opposite)
+            .filter_map(|k| {
+                let center = ball_center(b, a, self.point(k), self.radius)?;
+                let to = center - middle;
+                let angle = axis.dot(from.cross(to)).atan2(from.dot(to)).rem_euclid(TAU);
+                Some((angle, k, center))
+            })
+            .collect();
+        candidates.sort_by(|x, y| x.0.total_cmp(&y.0));
+        candidates.into_iter().find_map(|(_, k, _)| {
+            let triangle = [edge.b, edge.a, k];
+            let free = !self.edges.contains(&(edge.b, edge.a))
+                && !self.edges.contains(&(edge.a, k))
+                && !self.edges.contains(&(k, edge.b));
+            if !free {
+                return None;
+            }
+            self.ball(triangle).map(|center| (k, center))
+        })
+    }
+}
+
+/// Center of the sphere of `radius` through the three points, on the side
+/// of the counter-clockwise normal.
+fn ball_center(a: Vec3, b: Vec3, c: Vec3, radius: f32) -> Option<Vec3> {
+    let (ab, ac) = (b - a, c - a);
+    let normal = ab.cross(ac);
+    let area = normal.length_squared();
+    if area < f32::EPSILON * ab.length_squared() * ac.length_squared() {
+        return None;
+    }
+    let circumcenter = a
+        + (ac.length_squared() * normal.cross(ab) + ab.length_squared() * ac.cross(normal))
+            / (2_f32 * area);
+    let height = radius * radius - circumcenter.distance_squared(a);
+    (height >= 0_f32).then(|| circumcenter + normal.normalize() * height.sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RADIUS: f32 = 0.05_f32;
+    const HEIGHT: f32 = 0.1_f32;
+
+    /// Scan of a cylinder standing on the turntable, with two lasers
+    /// seeing it 60 degrees apart and a few points per height.
+    fn cylinder_scan(steps: u32, step_deg: f32) -> PointCloud {
+        let mut cloud = PointCloud {
+            steps: Some(Vec::new()),
+            ..Default::default()
+        };
+        for step in 0..steps {
+            for laser_deg in [0_f32, 61_f32] {
+                let angle = (step as f32 * step_deg + laser_deg).to_radians();
+                for h in 0..=100 {
+                    for offset in [-0.0002_f32, 0.0002_f32] {
+                        let radius = RADIUS + offset;
+                        let height = h as f32 * HEIGHT / 100_f32;
+                        cloud.points.push(glam::vec3(
+                            radius * angle.cos(),
+                            radius * angle.sin(),
+                            height,
+                        ));
+                        cloud.steps.as_mut().unwrap().push(step);
+                    }
+                }
+            }
+        }
+        cloud
+    }
+
+    /// Every edge of a closed and consistently oriented surface is walked
+    /// once in each direction.
+    fn open_edges(mesh: &Mesh) -> usize {
+        let edges: HashSet<(u32, u32)> = mesh
+            .triangles
+            .iter()
+            .flat_map(|t| (0..3).map(move |k| (t[k], t[(k + 1) % 3])))
+            .collect();
+        assert_eq!(edges.len(), 3 * mesh.triangles.len(), "repeated edges");
+        edges
+            .iter()
+            .filter(|(a, b)| !edges.contains(&(*b, *a)))
+            .count()
+    }
+
+    /// Every triangle faces away from `center`.
+    fn faces_out(mesh: &Mesh, center: Vec3) -> bool {
+        mesh.triangles.iter().all(|t| {
+            let [a, b, c] = t.map(|i| mesh.vertices.points[i as usize]);
+            (b - a).cross(c - a).dot((a + b + c) / 3_f32 - center) > 0_f32
+        })
+    }
+
+    #[test]
+    fn full_turns_are_closed() {
+        let cloud = cylinder_scan(72, 5_f32);
+        let mesh = reconstruct(&cloud, &TurntableAxis::default(), &Default::default()).unwrap();
+        assert!(mesh.triangles.len() > 2 * 72 * 2 * 40);
+        assert_eq!(open_edges(&mesh), 0);
+        assert!(faces_out(&mesh, glam::vec3(0_f32, 0_f32, HEIGHT / 2_f32)));
+    }
+
+    #[test]
+    fn partial_scans_are_left_open() {
+        let cloud = cylinder_scan(18, 5_f32);
+        let mesh = reconstruct(&cloud, &TurntableAxis::default(), &Default::default()).unwrap();
+        assert!(mesh.triangles.len() > 18 * 2 * 2 * 40);
+        assert!(open_edges(&mesh) > 0);
+        assert!(faces_out(&mesh, glam::vec3(0_f32, 0_f32, HEIGHT / 2_f32)));
+    }
+
+    #[test]
+    fn ball_pivoting_meshes_unstructured_clouds() {
+        // evenly spread over a sphere
+        let n = 800;
+        let center = glam::vec3(0.01_f32, -0.02_f32, 0.05_f32);
+        let points: Vec<Vec3> = pointcloud::sphere_directions(n)
+            .into_iter()
+            .map(|direction| center + 0.05_f32 * direction)
+            .collect();
+        let cloud = PointCloud {
+            points,
+            ..Default::default()
+        };
+        let axis = TurntableAxis {
+            point: center,
+            ..Default::default()
+        };
+        let mesh = reconstruct(&cloud, &axis, &Default::default()).unwrap();
+        assert_eq!(mesh.vertices, cloud);
+        assert!(faces_out(&mesh, center));
+        // a closed surface has twice as many triangles as vertices
+        assert!(mesh.triangles.len() > 2 * n * 95 / 100);
+        assert!(open_edges(&mesh) < n / 20);
+    }
+
+    #[test]
+    fn profiles_need_the_steps() {
+        let mut cloud = cylinder_scan(4, 5_f32);
+        cloud.steps = None;
+        let params = MeshParameters {
+            method: MeshMethod::Profiles,
+            ..Default::default()
+        };
+        assert!(reconstruct(&cloud, &TurntableAxis::default(), &params).is_err());
+    }
+
+    #[test]
+    fn slice_height_must_be_positive() {
+        let cloud = cylinder_scan(4, 5_f32);
+        for slice_height in [0_f32, -0.002_f32, f32::NAN] {
+            let params = MeshParameters {
+                slice_height,
+                ..Default::default()
+            };
+            assert!(reconstruct(&cloud, &TurntableAxis::default(), &params).is_err());
+        }
+    }
+}
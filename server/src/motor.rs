@@ -1,15 +1,24 @@
 use anyhow::Result;
+use msg::response::{ErrorCode, ServerError};
 use std::cfg;
 
-pub trait StepperMotor {
-    fn step(&mut self, steps: u32);
+pub trait StepperMotor: Send {
+    fn step(&mut self, steps: u32) -> Result<()>;
     fn steps_per_rev(&self) -> f32;
     fn name(&self) -> String;
 }
 
 pub fn make_stepper_motor() -> Result<Box<dyn StepperMotor>> {
     #[cfg(feature = "motor")]
-    let motor: Box<dyn StepperMotor> = Box::new(real_motor::NemaStepperMotor::new()?);
+    let motor: Box<dyn StepperMotor> =
+        Box::new(real_motor::NemaStepperMotor::new().map_err(|e| {
+            let error = ServerError {
+                code: ErrorCode::MotorFault,
+                message: "Failed to initialize the stepper motor".to_string(),
+                details: Some(e.to_string()),
+            };
+            anyhow::Error::from(error)
+        })?);
     #[cfg(not(feature = "motor"))]
     let motor: Box<dyn StepperMotor> = Box::new(MockStepperMotor {});
     return Ok(motor);
@@ -45,7 +54,7 @@ pub mod real_motor {
             return 200_f32;
         }
 
-        fn step(&mut self, steps: u32) {
+        fn step(&mut self, steps: u32) -> Result<()> {
             use rppal::gpio::Level;
 
             const SINGLE_PHASE_STEPPING: [[Level; 4]; 4] = [
@@ -83,6 +92,7 @@ pub mod real_motor {
                 self.pin4.write(sequence[3]);
                 std::thread::sleep(std::time::Duration::from_millis(1));
             }
+            Ok(())
         }
 
         fn name(&self) -> String {
@@ -91,6 +101,16 @@ pub mod real_motor {
     }
 }
 
+/// Reports `e` from `motor` as `ErrorCode::MotorFault`.
+pub fn motor_fault(motor: &dyn StepperMotor, e: impl std::fmt::Display) -> anyhow::Error {
+    ServerError {
+        code: ErrorCode::MotorFault,
+        message: format!("{} failed", motor.name()),
+        details: Some(e.to_string()),
+    }
+    .into()
+}
+
 pub struct MockStepperMotor {}
 
 impl StepperMotor for MockStepperMotor {
@@ -98,7 +118,9 @@ impl StepperMotor for MockStepperMotor {
         return 0_f32;
     }
 
-    fn step(&mut self, _steps: u32) {}
+    fn step(&mut self, _steps: u32) -> Result<()> {
+        Ok(())
+    }
 
     fn name(&self) -> String {
         return "Mock Motor".to_string();
@@ -0,0 +1,268 @@
+use clap::ValueEnum;
+
+/// Sub-pixel position of the laser line along an image row.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Peak {
+    pub x: f32,
+    /// How much the estimate can be trusted, in [0, 1]
+    pub confidence: f32,
+}
+
+pub trait PeakDetector: Send {
+    /// Estimates the laser peak of the pixels `row[start..=end]`, which are
+    /// all above the detection threshold. Pixels outside of the range can be
+    /// read by the detector to improve the estimate.
+    fn find_peak(&self, row: &[u8], start: usize, end: usize) -> Option<Peak>;
+    fn name(&self) -> String;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PeakDetectorType {
+    CenterOfMass,
+    Gaussian,
+    Parabolic,
+    BlaisRioux,
+}
+
+pub fn make_peak_detector(detector_type: PeakDetectorType) -> Box<dyn PeakDetector> {
+    match detector_type {
+        PeakDetectorType::CenterOfMass => Box::new(CenterOfMass {}),
+        PeakDetectorType::Gaussian => Box::new(GaussianFit {}),
+        PeakDetectorType::Parabolic => Box::new(ParabolicFit {}),
+        PeakDetectorType::BlaisRioux => Box::new(BlaisRioux {}),
+    }
+}
+
+/// Intensity-weighted mean of the pixel positions.
+pub struct CenterOfMass {}
+
+impl PeakDetector for CenterOfMass {
+    fn find_peak(&self, row: &[u8], start: usize, end: usize) -> Option<Peak> {
+        let x = center_of_mass(row, start, end)?;
+        Some(Peak {
+            x,
+            confidence: intensity_confidence(row, start, end),
+        })
+    }
+
+    fn name(&self) -> String {
+        "center of mass".to_string()
+    }
+}
+
+/// Fits a Gaussian through the brightest pixel and its two neighbours.
+pub struct GaussianFit {}
+
+impl PeakDetector for GaussianFit {
+    fn find_peak(&self, row: &[u8], start: usize, end: usize) -> Option<Peak> {
+        // shift by one to avoid ln(0)
+        let ln = |i: isize| (pixel_at(row, i) + 1_f32).ln();
+        three_point_fit(row, start, end, ln)
+    }
+
+    fn name(&self) -> String {
+        "gaussian fit".to_string()
+    }
+}
+
+/// Fits a parabola through the brightest pixel and its two neighbours.
+pub struct ParabolicFit {}
+
+impl PeakDetector for ParabolicFit {
+    fn find_peak(&self, row: &[u8], start: usize, end: usize) -> Option<Peak> {
+        three_point_fit(row, start, end, |i| pixel_at(row, i))
+    }
+
+    fn name(&self) -> String {
+        "parabolic fit".to_string()
+    }
+}
+
+/// Linear interpolation of the zero crossing of the 4th order derivative
+/// filter `g(i) = f(i-2) + f(i-1) - f(i+1) - f(i+2)`, as described by
+/// Blais and Rioux in "Real-time numerical peak detector" (1986).
+pub struct BlaisRioux {}
+
+impl PeakDetector for BlaisRioux {
+    fn find_peak(&self, row: &[u8], start: usize, end: usize) -> Option<Peak> {
+        let g = |i: isize| {
+            pixel_at(row, i - 2) + pixel_at(row, i - 1)
+                - pixel_at(row, i + 1)
+                - pixel_at(row, i + 2)
+        };
+
+        // g is negative on the rising edge of the peak and positive on the
+        // falling one, and zero over a saturated plateau in between. Pick the
+        // crossing closest to the brightest pixel.
+        let max = argmax(row, start, end) as isize;
+        let crossing = (start as isize - 1..=end as isize)
+            .filter(|&i| g(i) < 0_f32)
+            .filter_map(|i| {
+                let next = (i + 1..=end as isize + 1).find(|&k| g(k) != 0_f32)?;
+                (g(next) > 0_f32).then_some((i, next))
+            })
+            .min_by_key(|&(i, next)| (i + next - 2 * max).abs());
+
+        let confidence = intensity_confidence(row, start, end);
+        let peak = match crossing {
+            Some((i, next)) => Peak {
+                x: i as f32 + (next - i) as f32 * g(i) / (g(i) - g(next)),
+                confidence,
+            },
+            None => Peak {
+                x: center_of_mass(row, start, end)?,
+                confidence: FALLBACK_CONFIDENCE_FACTOR * confidence,
+            },
+        };
+        Some(peak)
+    }
+
+    fn name(&self) -> String {
+        "Blais-Rioux".to_string()
+    }
+}
+
+/// Confidence penalty applied when a detector cannot fit its model (e.g. on a
+/// saturated plateau) and falls back to the center of mass.
+const FALLBACK_CONFIDENCE_FACTOR: f32 = 0.5;
+
+fn pixel_at(row: &[u8], i: isize) -> f32 {
+    if i < 0 {
+        return 0_f32;
+    }
+    row.get(i as usize).map_or(0_f32, |p| *p as f32)
+}
+
+/// Index of the brightest pixel, the center of the plateau if it is saturated.
+fn argmax(row: &[u8], start: usize, end: usize) -> usize {
+    let max = row[start..=end].iter().max().copied().unwrap_or(0);
+    let first = (start..=end).find(|&i| row[i] == max).unwrap_or(start);
+    let last = (start..=end).rev().find(|&i| row[i] == max).unwrap_or(end);
+    (first + last) / 2
+}
+
+fn center_of_mass(row: &[u8], start: usize, end: usize) -> Option<f32> {
+    let (weighted_sum, total) = (start..=end)
+        .map(|i| (i as f32, row[i] as f32))
+        .fold((0_f32, 0_f32), |(ws, t), (x, w)| (ws + x * w, t + w));
+    if total <= 0_f32 {
+        return None;
+    }
+    Some(weighted_sum / total)
+}
+
+fn intensity_confidence(row: &[u8], start: usize, end: usize) -> f32 {
+    let max = row[start..=end].iter().max().copied().unwrap_or(0);
+    max as f32 / u8::MAX as f32
+}
+
+/// Closed-form vertex of the parabola through `f(m-1)`, `f(m)` and `f(m+1)`,
+/// `m` being the brightest pixel of the run.
+fn three_point_fit(row: &[u8], start: usize, end: usize, f: impl Fn(isize) -> f32) -> Option<Peak> {
+    let m = argmax(row, start, end) as isize;
+    let (a, b, c) = (f(m - 1), f(m), f(m + 1));
+    let denominator = 2_f32 * (a - 2_f32 * b + c);
+    let confidence = intensity_confidence(row, start, end);
+
+    let delta = (a - c) / denominator;
+    if !delta.is_finite() || delta.abs() > 0.5_f32 {
+        return Some(Peak {
+            x: center_of_mass(row, start, end)?,
+            confidence: FALLBACK_CONFIDENCE_FACTOR * confidence,
+        });
+    }
+    Some(Peak {
+        x: m as f32 + delta,
+        confidence,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DETECTORS: [PeakDetectorType; 4] = [
+        PeakDetectorType::CenterOfMass,
+        PeakDetectorType::Gaussian,
+        PeakDetectorType::Parabolic,
+        PeakDetectorType::BlaisRioux,
+    ];
+
+    /// Row with a gaussian laser line centered on `center`, and the run of
+    /// pixels above the detection threshold.
+    fn gaussian_row(center: f32, sigma: f32) -> (Vec<u8>, usize, usize) {
+        let row: Vec<u8> = (0..40)
+            .map(|i| {
+                let d = (i as f32 - center) / sigma;
+                (220_f32 * (-d * d / 2_f32).exp()).round() as u8
+            })
+            .collect();
+        let start = row.iter().position(|&p| p > 30).unwrap();
+        let end = row.iter().rposition(|&p| p > 30).unwrap();
+        (row, start, end)
+    }
+
+    #[test]
+    fn detectors_find_sub_pixel_peaks() {
+        for detector_type in DETECTORS {
+            let detector = make_peak_detector(detector_type);
+            // the parabola is biased toward the center of the pixel, the
+            // center of mass by the threshold cutting the tails unevenly
+            let tolerance = match detector_type {
+                PeakDetectorType::Parabolic => 0.15_f32,
+                PeakDetectorType::CenterOfMass => 0.1_f32,
+                _ => 0.05_f32,
+            };
+            for offset in [0_f32, 0.2, 0.35, 0.5, 0.8] {
+                let center = 17_f32 + offset;
+                let (row, start, end) = gaussian_row(center, 1.5_f32);
+                let peak = detector.find_peak(&row, start, end).unwrap();
+                assert!(
+                    (peak.x - center).abs() < tolerance,
+                    "{} found {} instead of {center}",
+                    detector.name(),
+                    peak.x
+                );
+                let brightest = *row.iter().max().unwrap() as f32;
+                assert_eq!(peak.confidence, brightest / 255_f32);
+            }
+        }
+    }
+
+    #[test]
+    fn dark_rows_have_no_peak() {
+        let row = [0_u8; 16];
+        for detector_type in DETECTORS {
+            let detector = make_peak_detector(detector_type);
+            assert_eq!(detector.find_peak(&row, 0, row.len() - 1), None);
+            assert_eq!(detector.find_peak(&row, 5, 5), None);
+        }
+    }
+
+    #[test]
+    fn saturated_rows_peak_in_the_plateau_center() {
+        let mut middle = [0_u8; 24];
+        middle[9..=17].copy_from_slice(&[120, 255, 255, 255, 255, 255, 255, 255, 120]);
+        let mut first = [0_u8; 8];
+        first[..4].copy_from_slice(&[255, 255, 255, 90]);
+        let mut last = [0_u8; 8];
+        last[4..].copy_from_slice(&[90, 255, 255, 255]);
+        for detector_type in DETECTORS {
+            let detector = make_peak_detector(detector_type);
+            for (row, start, end, center) in [
+                (&middle[..], 9, 17, 13_f32),
+                (&first[..], 0, 3, 1.2_f32),
+                (&last[..], 4, 7, 5.8_f32),
+            ] {
+                let peak = detector.find_peak(row, start, end).unwrap();
+                assert!(
+                    (peak.x - center).abs() < 0.5_f32,
+                    "{} found {} instead of {center}",
+                    detector.name(),
+                    peak.x
+                );
+                assert!(peak.confidence <= 1_f32);
+            }
+        }
+    }
+}
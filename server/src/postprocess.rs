@@ -0,0 +1,294 @@
+use crate::calibration::{Calibration, TurntableAxis};
+
+use anyhow::{bail, Result};
+use glam::Vec3;
+use log::{info, warn};
+use msg::response::{PointCloud, StepRecord};
+use pointcloud::{BoundingBox, Cylinder, Filter};
+use std::collections::HashMap;
+
+/// Cleaning of the scanned points, every filter is off by default. They run
+/// in the order of the options: crops, outlier removal, then downsampling.
+#[derive(clap::Args, Clone, Debug)]
+pub struct FilterOptions {
+    /// Drops the points lower than this above the turntable surface, in
+    /// meters, and the ones outside the scan volume of the calibration
+    #[clap(long)]
+    pub crop_turntable: Option<f32>,
+    /// Drops the points outside of the box, "x,y,z" in meters, with
+    /// --crop-max
+    #[clap(long, value_parser = parse_vec3, requires = "crop_max")]
+    pub crop_min: Option<glam::Vec3>,
+    #[clap(long, value_parser = parse_vec3, requires = "crop_min")]
+    pub crop_max: Option<glam::Vec3>,
+    /// Removes statistical outliers, comparing the mean distance of each
+    /// point to this many neighbours with the rest of the cloud
+    #[clap(long)]
+    pub statistical_outliers: Option<usize>,
+    /// Standard deviations above the mean distance where statistical
+    /// outliers start
+    #[clap(long, default_value_t = 2_f32)]
+    pub std_ratio: f32,
+    /// Removes the points with fewer than --min-neighbours other points
+    /// within this radius, in meters
+    #[clap(long)]
+    pub radius_outliers: Option<f32>,
+    #[clap(long, default_value_t = 4)]
+    pub min_neighbours: usize,
+    /// Keeps one point per voxel of this size, in meters
+    #[clap(long)]
+    pub voxel_size: Option<f32>,
+}
+
+impl Default for FilterOptions {
+    fn default() -> Self {
+        Self {
+            crop_turntable: None,
+            crop_min: None,
+            crop_max: None,
+            statistical_outliers: None,
+            std_ratio: 2_f32,
+            radius_outliers: None,
+            min_neighbours: 4,
+            voxel_size: None,
+        }
+    }
+}
+
+impl FilterOptions {
+    /// The turntable crop needs the calibration, for the turntable axis and
+    /// the scan volume.
+    pub fn filters(&self, calibration: Option<&Calibration>) -> Result<Vec<Filter>> {
+        let mut filters = Vec::new();
+        if let Some(height) = self.crop_turntable {
+            let Some(calibration) = calibration else {
+                bail!("Cropping the turntable needs the calibration");
+            };
+            let axis = &calibration.turntable_axis;
+            let direction = axis.direction.normalize();
+            let volume = &calibration.scan_volume;
+            filters.push(Filter::CropCylinder(Cylinder {
+                base: axis.point + height * direction,
+                axis: direction,
+                radius: volume.radius,
+                height: volume.height - height,
+            }));
+        }
+        if let (Some(min), Some(max)) = (self.crop_min, self.crop_max) {
+            filters.push(Filter::CropBox(BoundingBox { min, max }));
+        }
+        if let Some(neighbours) = self.statistical_outliers {
+            filters.push(Filter::StatisticalOutliers {
+                neighbours,
+                std_ratio: self.std_ratio,
+            });
+        }
+        if let Some(radius) = self.radius_outliers {
+            filters.push(Filter::RadiusOutliers {
+                radius,
+                min_neighbours: self.min_neighbours,
+            });
+        }
+        if let Some(voxel_size) = self.voxel_size {
+            if voxel_size <= 0_f32 {
+                bail!("The voxel size must be positive, got {voxel_size}");
+            }
+            filters.push(Filter::VoxelDownsample { voxel_size });
+        }
+        Ok(filters)
+    }
+}
+
+/// What happens to the merged cloud of every scan once it ends.
+#[derive(clap::Args, Clone, Debug)]
+pub struct PostProcessOptions {
+    #[clap(flatten)]
+    pub filters: FilterOptions,
+    /// Neighbours used to estimate the normal of each point, 0 skips the
+    /// normals
+    #[clap(long, default_value_t = 16)]
+    pub normal_neighbours: usize,
+}
+
+impl Default for PostProcessOptions {
+    fn default() -> Self {
+        Self {
+            filters: FilterOptions::default(),
+            normal_neighbours: 16,
+        }
+    }
+}
+
+impl PostProcessOptions {
+    pub fn post_process(&self, calibration: &Calibration) -> Result<PostProcess> {
+        Ok(PostProcess {
+            filters: self.filters.filters(Some(calibration))?,
+            normal_neighbours: self.normal_neighbours,
+            camera: calibration.camera.position(),
+            axis: calibration.turntable_axis.clone(),
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct PostProcess {
+    pub filters: Vec<Filter>,
+    pub normal_neighbours: usize,
+    /// Camera position at the scan start
+    pub camera: Vec3,
+    pub axis: TurntableAxis,
+}
+
+impl PostProcess {
+    /// Filters the merged cloud, then estimates the normals and turns them
+    /// toward the camera position of the step each point comes from. The
+    /// steps then count the points they kept, the points are merged in step
+    /// order. The other point attributes follow the points they belong to.
+    pub fn apply(&self, mut cloud: PointCloud, steps: &mut [StepRecord]) -> PointCloud {
+        if !self.filters.is_empty() {
+            let kept = pointcloud::apply_all(&self.filters, &cloud.points);
+            let (mut end, mut counted) = (0, 0);
+            for step in steps.iter_mut() {
+                end += step.points as usize;
+                let first = counted;
+                while counted < kept.len() && kept[counted] < end {
+                    counted += 1;
+                }
+                step.points = (counted - first) as u64;
+            }
+            info!(
+                "Post-processing kept {} of {} points",
+                kept.len(),
+                cloud.points.len()
+            );
+            cloud = cloud.select(&kept);
+        }
+        let normals = (self.normal_neighbours > 0).then(|| {
+            let mut normals = pointcloud::estimate_normals(&cloud.points, self.normal_neighbours);
+            match self.viewpoints(&cloud, steps) {
+                Some(viewpoints) => {
+                    pointcloud::orient_normals(&mut normals, &cloud.points, &viewpoints)
+                }
+                None => warn!("Points without a recorded step, the normals are not oriented"),
+            }
+            normals
+        });
+        PointCloud { normals, ..cloud }
+    }
+
+    /// Camera position of the step each point comes from, in the turntable
+    /// pose of the scan start.
+    fn viewpoints(&self, cloud: &PointCloud, steps: &[StepRecord]) -> Option<Vec<Vec3>> {
+        let angles: HashMap<u32, f32> = steps.iter().map(|s| (s.step, s.angle_deg)).collect();
+        cloud
+            .steps
+            .as_ref()?
+            .iter()
+            .map(|step| {
+                // the points of a step are rotated back by its angle
+                let rotation = self.axis.rotation(-angles.get(step)?.to_radians());
+                Some(rotation.transform_point3(self.camera))
+            })
+            .collect()
+    }
+}
+
+fn parse_vec3(text: &str) -> Result<glam::Vec3, String> {
+    let values = text
+        .split(',')
+        .map(|v| v.trim().parse::<f32>().map_err(|e| format!("{v:?}: {e}")))
+        .collect::<Result<Vec<f32>, String>>()?;
+    match values[..] {
+        [x, y, z] => Ok(glam::vec3(x, y, z)),
+        _ => Err(format!("expected x,y,z, got {text:?}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_follow_the_options() {
+        let options = FilterOptions {
+            crop_turntable: Some(0.005_f32),
+            statistical_outliers: Some(8),
+            voxel_size: Some(0.001_f32),
+            ..Default::default()
+        };
+        assert!(options.filters(None).is_err());
+
+        let text = std::fs::read_to_string("calibration.json").unwrap();
+        let calibration: Calibration = serde_json::from_str(&text).unwrap();
+        let filters = options.filters(Some(&calibration)).unwrap();
+        assert_eq!(filters.len(), 3);
+        let Filter::CropCylinder(cylinder) = &filters[0] else {
+            panic!("the crops come first");
+        };
+        let axis = &calibration.turntable_axis;
+        assert!(!cylinder.contains(axis.point));
+        assert!(cylinder.contains(axis.point + 0.01_f32 * axis.direction.normalize()));
+        assert_eq!(
+            filters[2],
+            Filter::VoxelDownsample {
+                voxel_size: 0.001_f32
+            }
+        );
+        assert_eq!(parse_vec3("1, 2,3"), Ok(glam::vec3(1_f32, 2_f32, 3_f32)));
+        assert!(parse_vec3("1,2").is_err());
+    }
+
+    #[test]
+    fn normals_face_the_camera_of_their_step() {
+        let text = std::fs::read_to_string("calibration.json").unwrap();
+        let calibration: Calibration = serde_json::from_str(&text).unwrap();
+        let options = PostProcessOptions {
+            normal_neighbours: 8,
+            ..Default::default()
+        };
+        let post_process = options.post_process(&calibration).unwrap();
+        let axis = &calibration.turntable_axis;
+        let up = axis.direction.normalize();
+        let camera = calibration.camera.position();
+        // the same wall, facing the camera, seen from both sides of the
+        // turntable, and far enough apart not to share neighbours
+        let toward = (camera - axis.point).reject_from(up).normalize();
+        let side = up.cross(toward);
+        let wall = |offset: f32| -> Vec<Vec3> {
+            (0..100)
+                .map(|i| {
+                    let (x, y) = ((i % 10) as f32 * 0.005_f32, (i / 10) as f32 * 0.005_f32);
+                    axis.point + x * side + (y + offset) * up
+                })
+                .collect()
+        };
+        let points = [wall(0_f32), wall(1_f32)].concat();
+        let mut steps: Vec<StepRecord> = [0_f32, 180_f32]
+            .iter()
+            .enumerate()
+            .map(|(step, &angle_deg)| StepRecord {
+                step: step as u32,
+                angle_deg,
+                points: 100,
+                captured_at_ms: 0,
+            })
+            .collect();
+        let cloud = PointCloud {
+            points,
+            // the steps out of order, the orientation must follow the points
+            steps: Some([vec![1; 100], vec![0; 100]].concat()),
+            ..Default::default()
+        };
+        let cloud = post_process.apply(cloud, &mut steps);
+        let normals = cloud.normals.clone().unwrap();
+        assert!(normals[..100].iter().all(|n| n.dot(toward) < -0.99_f32));
+        assert!(normals[100..].iter().all(|n| n.dot(toward) > 0.99_f32));
+
+        let options = PostProcessOptions {
+            normal_neighbours: 0,
+            ..Default::default()
+        };
+        let post_process = options.post_process(&calibration).unwrap();
+        assert!(post_process.apply(cloud, &mut steps).normals.is_none());
+    }
+}
@@ -0,0 +1,249 @@
+//! Registration of two scan sessions of the same object, e.g. one upright
+//! and one flipped to reach the top and the bottom the turntable hides.
+//!
+//! Both clouds are downsampled first. RANSAC over matching FPFH features
+//! finds a coarse alignment whatever the poses, then point-to-plane ICP
+//! refines it. The source cloud is moved into the target frame and both are
+//! saved as a new session, with the transform in its metadata.
+
+use crate::sessions::SessionStore;
+
+use anyhow::{bail, Context, Result};
+use glam::Vec3;
+use log::info;
+use msg::response::{ErrorCode, PointCloud, Registration, ServerError, SessionInfo};
+use pointcloud::Alignment;
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct RegistrationParameters {
+    /// Voxel size of the downsampled clouds the alignment works on, in
+    /// meters. Features look 5 voxels around each point
+    #[clap(long, default_value_t = 0.003_f32)]
+    pub voxel_size: f32,
+    /// Candidate coarse alignments tried
+    #[clap(long, default_value_t = 20000)]
+    pub ransac_iterations: usize,
+    #[clap(long, default_value_t = 30)]
+    pub icp_iterations: usize,
+    /// Fraction of the source points that must end up close to the target,
+    /// the registration fails below it
+    #[clap(long, default_value_t = 0.3_f32)]
+    pub min_fitness: f32,
+}
+
+impl Default for RegistrationParameters {
+    fn default() -> Self {
+        Self {
+            voxel_size: 0.003_f32,
+            ransac_iterations: 20000,
+            icp_iterations: 30,
+            min_fitness: 0.3_f32,
+        }
+    }
+}
+
+/// Aligns session `id` onto session `onto` and saves the merged clouds as a
+/// new session.
+pub fn register_sessions(
+    sessions: &SessionStore,
+    id: &str,
+    onto: &str,
+    params: &RegistrationParameters,
+) -> Result<SessionInfo> {
+    if id == onto {
+        let message = format!("Session {id} cannot be registered onto itself");
+        return Err(ServerError::new(ErrorCode::InvalidParameters, message).into());
+    }
+    let source = sessions.load(id)?.cloud;
+    let target = sessions.load(onto)?.cloud;
+    let alignment = register(&source, &target, params)
+        .with_context(|| format!("Failed to register session {id} onto {onto}"))?;
+    let registration = Registration {
+        source: id.to_string(),
+        target: onto.to_string(),
+        transform: glam::Mat4::from(alignment.transform),
+        fitness: alignment.fitness,
+        rmse: alignment.rmse,
+    };
+    sessions.save_registered(registration, merge(target, &source, alignment.transform))
+}
+
+/// Transform from the `source` to the `target` frame.
+pub fn register(
+    source: &PointCloud,
+    target: &PointCloud,
+    params: &RegistrationParameters,
+) -> Result<Alignment> {
+    if params.voxel_size.is_nan() || params.voxel_size <= 0_f32 {
+        bail!("The voxel size must be positive, got {}", params.voxel_size);
+    }
+    let (source_points, source_normals) = downsample(source, params.voxel_size);
+    let (target_points, target_normals) = downsample(target, params.voxel_size);
+    let radius = 5_f32 * params.voxel_size;
+    let source_features = pointcloud::fpfh(&source_points, &source_normals, radius);
+    let target_features = pointcloud::fpfh(&target_points, &target_normals, radius);
+    let coarse = pointcloud::coarse_alignment(
+        &source_points,
+        &source_features,
+        &target_points,
+        &target_features,
+        params.ransac_iterations,
+        1.5_f32 * params.voxel_size,
+    )
+    .context("The scans have no matching features")?;
+    info!(
+        "Coarse alignment of {} onto {} points: fitness {:.2}, rmse {:.4} m",
+        source_points.len(),
+        target_points.len(),
+        coarse.fitness,
+        coarse.rmse
+    );
+    let fine = pointcloud::icp_point_to_plane(
+        &source_points,
+        &target_points,
+        &target_normals,
+        coarse.transform,
+        2_f32 * params.voxel_size,
+        params.icp_iterations,
+    );
+    info!(
+        "ICP alignment: fitness {:.2}, rmse {:.4} m",
+        fine.fitness, fine.rmse
+    );
+    if fine.fitness < params.min_fitness {
+        bail!(
+            "Only {:.0}% of the points overlap after the alignment, {:.0}% are needed",
+            100_f32 * fine.fitness,
+            100_f32 * params.min_fitness
+        );
+    }
+    Ok(fine)
+}
+
+/// Points kept by the voxel grid with their normals, the ones estimated at
+/// the end of the scan when the session has them. Otherwise they are
+/// estimated now and turned away from the middle of the cloud.
+fn downsample(cloud: &PointCloud, voxel_size: f32) -> (Vec<Vec3>, Vec<Vec3>) {
+    let kept = pointcloud::voxel_downsample(&cloud.points, voxel_size);
+    let points = pointcloud::select(&cloud.points, &kept);
+    let normals = match &cloud.normals {
+        Some(normals) => pointcloud::select(normals, &kept),
+        None => {
+            let mut normals = pointcloud::estimate_normals(&points, 16);
+            let centroid = points.iter().sum::<Vec3>() / points.len().max(1) as f32;
+            let viewpoints: Vec<Vec3> = points.iter().map(|p| 2_f32 * *p - centroid).collect();
+            pointcloud::orient_normals(&mut normals, &points, &viewpoints);
+            normals
+        }
+    };
+    (points, normals)
+}
+
+/// The target points followed by the source points moved into the target
+/// frame, the attributes are kept when both clouds have them. The steps are
+/// dropped, as the scans have different ones.
+fn merge(mut target: PointCloud, source: &PointCloud, transform: glam::Affine3A) -> PointCloud {
+    let mut moved = source.clone();
+    for point in &mut moved.points {
+        *point = transform.transform_point3(*point);
+    }
+    for normal in moved.normals.iter_mut().flatten() {
+        *normal = transform.transform_vector3(*normal);
+    }
+    target.append(moved);
+    target.steps = None;
+    target
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibration::Calibration;
+    use msg::command::ScanParameters;
+    use msg::response::SessionOutcome;
+
+    /// A bumpy ellipsoid without symmetries, `n` points spread over its
+    /// directions from the origin.
+    fn object(n: usize) -> Vec<Vec3> {
+        pointcloud::sphere_directions(n)
+            .into_iter()
+            .map(|direction| {
+                let azimuth = direction.y.atan2(direction.x);
+                let polar = direction.z.acos();
+                let bumps = 1_f32
+                    + 0.25_f32 * (2_f32 * azimuth + 0.5_f32).sin() * polar.sin()
+                    + 0.2_f32 * (3_f32 * polar).cos()
+                    + 0.1_f32 * azimuth.sin();
+                Vec3::new(0.05_f32, 0.04_f32, 0.07_f32) * bumps * direction
+            })
+            .collect()
+    }
+
+    #[test]
+    fn flipped_sessions_are_merged() {
+        let root = std::env::temp_dir().join(format!("scanner_registered_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let store = SessionStore::open(&root).unwrap();
+        let text = std::fs::read_to_string("calibration.json").unwrap();
+        let calibration: Calibration = serde_json::from_str(&text).unwrap();
+
+        // upside down and moved aside on the turntable
+        let flip = glam::Affine3A::from_rotation_translation(
+            glam::Quat::from_axis_angle(Vec3::X, 3_f32),
+            Vec3::new(0.02_f32, -0.01_f32, 0.1_f32),
+        );
+        let scans = [
+            object(3000),
+            object(2500)
+                .iter()
+                .map(|p| flip.transform_point3(*p))
+                .collect(),
+        ];
+        let mut ids = Vec::new();
+        for points in &scans {
+            let mut recorder = store
+                .create(&ScanParameters::default(), &calibration)
+                .unwrap();
+            let id = store
+                .list()
+                .unwrap()
+                .into_iter()
+                .map(|s| s.id)
+                .find(|id| !ids.contains(id))
+                .unwrap();
+            ids.push(id);
+            let cloud = PointCloud {
+                points: points.clone(),
+                steps: Some(vec![0; points.len()]),
+                ..Default::default()
+            };
+            recorder.record_step(0, 0_f32, &cloud).unwrap();
+            recorder
+                .finish(None, &crate::postprocess::PostProcess::default())
+                .unwrap();
+        }
+        let params = RegistrationParameters {
+            voxel_size: 0.005_f32,
+            ransac_iterations: 5000,
+            ..Default::default()
+        };
+        assert!(register_sessions(&store, &ids[0], &ids[0], &params).is_err());
+
+        let info = register_sessions(&store, &ids[1], &ids[0], &params).unwrap();
+        assert!(matches!(info.outcome, SessionOutcome::Completed));
+        let registration = info.registration.unwrap();
+        assert_eq!(registration.source, ids[1]);
+        assert_eq!(registration.target, ids[0]);
+        let expected = glam::Mat4::from(flip.inverse());
+        assert!(registration.transform.abs_diff_eq(expected, 2e-3_f32));
+        assert!(registration.fitness > 0.9_f32);
+
+        let merged = store.load(&info.id).unwrap();
+        assert_eq!(merged.cloud.points.len(), 5500);
+        assert!(merged.info.steps.is_empty());
+        assert!(merged.cloud.steps.is_none());
+        // the calibration is the one of the target
+        assert!(store.calibration(&info.id).is_ok());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,19 +1,118 @@
 use crate::calibration;
+use crate::calibration::Laser;
 use crate::cameras;
+use crate::imgproc;
+use crate::lasers;
+use crate::lasers::LaserDriver;
 use crate::logging;
 use crate::motor;
+use crate::postprocess;
+use crate::sessions;
 
-use msg::response::Response;
-use std::sync::mpsc;
+use log::{error, info};
+use msg::command::ScanParameters;
+use msg::response::{ErrorCode, Response, ScannerState, ServerError};
+use std::sync::{mpsc, Arc, Condvar, Mutex, PoisonError};
+use std::thread::JoinHandle;
 
-pub struct Scanner {
+#[derive(Clone, Default)]
+struct ControlState {
+    state: ScannerState,
+    /// Turntable angle relative to its position at the scan start
+    angle_deg: f32,
+    last_error: Option<ServerError>,
+}
+
+/// Scanner state shared with the acquisition thread.
+pub struct ScanControl {
+    inner: Mutex<ControlState>,
+    changed: Condvar,
+}
+
+impl ScanControl {
+    fn new() -> Self {
+        Self {
+            inner: Mutex::new(ControlState::default()),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Called by the acquisition between steps, blocks while the scan is
+    /// paused. Returns false when the scan must stop.
+    pub fn checkpoint(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        while inner.state == ScannerState::Paused {
+            inner = self.changed.wait(inner).unwrap();
+        }
+        inner.state == ScannerState::Scanning
+    }
+
+    pub fn set_angle(&self, angle_deg: f32) {
+        self.inner.lock().unwrap().angle_deg = angle_deg;
+    }
+
+    /// Moves to `to` if `allowed` accepts the current state, otherwise returns
+    /// the current state.
+    fn transition(
+        &self,
+        allowed: impl Fn(ScannerState) -> bool,
+        to: ScannerState,
+    ) -> Result<(), ScannerState> {
+        let mut inner = self.inner.lock().unwrap();
+        if !allowed(inner.state) {
+            return Err(inner.state);
+        }
+        inner.state = to;
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// Starts a scan from the idle or error state, the error of the previous
+    /// scan is cleared.
+    fn begin(&self) -> Result<(), ScannerState> {
+        let mut inner = self.inner.lock().unwrap();
+        if !matches!(inner.state, ScannerState::Idle | ScannerState::Error) {
+            return Err(inner.state);
+        }
+        inner.state = ScannerState::Scanning;
+        inner.angle_deg = 0_f32;
+        inner.last_error = None;
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    fn set(&self, to: ScannerState) {
+        let _ = self.transition(|_| true, to);
+    }
+
+    fn fail(&self, error: ServerError) {
+        self.inner.lock().unwrap().last_error = Some(error);
+        self.set(ScannerState::Error);
+    }
+
+    fn snapshot(&self) -> ControlState {
+        self.inner.lock().unwrap().clone()
+    }
+}
+
+/// Devices and processing used by the acquisition thread.
+struct ScanDevices {
     data_logger: Box<dyn logging::Logger>,
     motor: Box<dyn motor::StepperMotor>,
     camera: Box<dyn cameras::Camera>,
     calibration: calibration::Calibration,
-    laser_1: bool,
-    laser_2: bool,
-    motor_position: f32,
+    detector: imgproc::LaserLineDetector,
+    acquisition_mode: cameras::AcquisitionMode,
+    /// Applied to the merged cloud of every scan
+    post_process: postprocess::PostProcess,
+}
+
+pub struct Scanner {
+    devices: Arc<Mutex<ScanDevices>>,
+    lasers: lasers::SharedLaserDriver,
+    control: Arc<ScanControl>,
+    sessions: Arc<sessions::SessionStore>,
+    worker: Option<JoinHandle<()>>,
 }
 
 impl Scanner {
@@ -21,48 +120,280 @@ impl Scanner {
         camera_type: cameras::CameraType,
         data_logger_address: std::net::SocketAddr,
         calibration_path: &std::path::Path,
+        detector: imgproc::LaserLineDetector,
+        acquisition_mode: cameras::AcquisitionMode,
+        sessions_dir: &std::path::Path,
+        post_process: &postprocess::PostProcessOptions,
     ) -> anyhow::Result<Self> {
         let data_logger = logging::make_logger("data_logger", data_logger_address)?;
         let motor = motor::make_stepper_motor()?;
         let camera = cameras::make_camera(camera_type)?;
-        let calibration = calibration::load_calibration(&calibration_path)?;
+        let lasers = lasers::make_laser_driver()?;
+        let calibration = calibration::load_calibration(calibration_path)?;
+        let post_process = post_process.post_process(&calibration)?;
+        if !post_process.filters.is_empty() {
+            info!("Post-processing scans with {:?}", post_process.filters);
+        }
+        let sessions = sessions::SessionStore::open(sessions_dir)?;
+        sessions.recover_interrupted()?;
+        info!("Saving scan sessions to {}", sessions_dir.display());
+        info!(
+            "Using {} peak detector, {:?} detection mode",
+            detector.peak_detector.name(),
+            detector.mode
+        );
+        info!(
+            "Using {}, {:?} acquisition mode",
+            lasers.name(),
+            acquisition_mode
+        );
 
-        let scanner = Self {
+        // TODO(alberto): should we return an error if camera logging fails?
+        data_logger.log_camera("world/camera", &calibration.camera)?;
+
+        let devices = ScanDevices {
             data_logger,
             motor,
             camera,
             calibration,
-            laser_1: false,
-            laser_2: false,
-            motor_position: 0_f32,
+            detector,
+            acquisition_mode,
+            post_process,
         };
-        // TODO(alberto): should we return an error if camera logging fails?
-        scanner
-            .data_logger
-            .log_camera("world/camera", &scanner.calibration.camera)?;
-        Ok(scanner)
+        Ok(Self {
+            devices: Arc::new(Mutex::new(devices)),
+            lasers: lasers::SharedLaserDriver::new(lasers),
+            control: Arc::new(ScanControl::new()),
+            sessions: Arc::new(sessions),
+            worker: None,
+        })
     }
 
-    pub fn start(&mut self, scanned_data_queue: mpsc::Sender<Response>) -> anyhow::Result<()> {
-        let _ = self.camera.acquire_from_camera(
-            self.data_logger.as_ref(),
-            &self.calibration,
-            self.motor.as_mut(),
-            scanned_data_queue,
-        )?;
-        return Ok(());
+    /// Starts the acquisition on a separate thread. Point clouds, progress and
+    /// errors are sent to `scanned_data_queue` as the scan goes on, followed by
+    /// the scanner status when it ends.
+    pub fn start(
+        &mut self,
+        params: &ScanParameters,
+        scanned_data_queue: mpsc::Sender<Response>,
+    ) -> anyhow::Result<()> {
+        params
+            .validate()
+            .map_err(|e| ServerError::new(ErrorCode::InvalidParameters, e))?;
+        self.join_finished_worker();
+        self.control.begin().map_err(|state| {
+            ServerError::new(
+                ErrorCode::Busy,
+                format!("Cannot start a scan while {state:?}"),
+            )
+        })?;
+        info!("Starting scan: {params:?}");
+
+        let devices = self.devices.clone();
+        let mut lasers = self.lasers.clone();
+        let control = self.control.clone();
+        let sessions = self.sessions.clone();
+        let params = params.clone();
+        let worker = std::thread::Builder::new()
+            .name("acquisition".to_string())
+            .spawn(move || {
+                let mut devices = devices.lock().unwrap_or_else(PoisonError::into_inner);
+                let devices = &mut *devices;
+                let result =
+                    sessions
+                        .create(&params, &devices.calibration)
+                        .and_then(|mut session| {
+                            let acquisition = cameras::Acquisition {
+                                rec: devices.data_logger.as_ref(),
+                                calib: &devices.calibration,
+                                motor: devices.motor.as_mut(),
+                                lasers: &mut lasers,
+                                detector: &devices.detector,
+                                mode: devices.acquisition_mode,
+                                params: &params,
+                                control: &control,
+                                session: &mut session,
+                                scanned_data_queue: scanned_data_queue.clone(),
+                            };
+                            let result = devices.camera.acquire_from_camera(acquisition);
+                            // the scan result is still reported if the session cannot be saved
+                            let error = result.as_ref().err().map(server_error);
+                            if let Err(e) = session.finish(error, &devices.post_process) {
+                                error!("Failed to save the scan session: {e:#}");
+                            }
+                            result
+                        });
+                lasers.set_all(false);
+
+                match result {
+                    Ok(points) => {
+                        info!("Scan finished with {points} points");
+                        control.set(ScannerState::Idle);
+                    }
+                    Err(e) => {
+                        error!("Scan failed: {e:#}");
+                        let error = server_error(&e);
+                        let _ = scanned_data_queue.send(Response::Error(error.clone()));
+                        control.fail(error);
+                    }
+                }
+                let _ = scanned_data_queue.send(Response::Status(status(&control, &lasers)));
+            });
+        match worker {
+            Ok(worker) => self.worker = Some(worker),
+            Err(e) => {
+                self.control
+                    .fail(ServerError::new(ErrorCode::Internal, e.to_string()));
+                return Err(e.into());
+            }
+        }
+        Ok(())
     }
 
-    pub fn stop(&self) {}
+    /// Asks the acquisition to stop after the current step.
+    pub fn stop(&mut self) -> anyhow::Result<()> {
+        self.join_finished_worker();
+        self.control
+            .transition(
+                |s| matches!(s, ScannerState::Scanning | ScannerState::Paused),
+                ScannerState::Stopping,
+            )
+            .map_err(|state| invalid_state("stop", state))?;
+        info!("Stopping scan");
+        Ok(())
+    }
 
-    pub fn status(&mut self) -> msg::response::Status {
-        self.motor_position += 1_f32;
-        msg::response::Status {
-            lasers: msg::response::LasersData {
-                laser_1: self.laser_1,
-                laser_2: self.laser_2,
-            },
-            motor_speed: self.motor_position,
+    /// Suspends the acquisition after the current step.
+    pub fn pause(&mut self) -> anyhow::Result<()> {
+        self.join_finished_worker();
+        self.control
+            .transition(|s| s == ScannerState::Scanning, ScannerState::Paused)
+            .map_err(|state| invalid_state("pause", state))?;
+        info!("Pausing scan");
+        Ok(())
+    }
+
+    pub fn resume(&mut self) -> anyhow::Result<()> {
+        self.join_finished_worker();
+        self.control
+            .transition(|s| s == ScannerState::Paused, ScannerState::Scanning)
+            .map_err(|state| invalid_state("resume", state))?;
+        info!("Resuming scan");
+        Ok(())
+    }
+
+    /// Joins the acquisition thread if it has terminated, turning a panic into
+    /// the error state.
+    fn join_finished_worker(&mut self) {
+        if !self.worker.as_ref().is_some_and(|w| w.is_finished()) {
+            return;
+        }
+        if let Some(Err(_)) = self.worker.take().map(JoinHandle::join) {
+            error!("Acquisition thread panicked");
+            self.control.fail(ServerError::new(
+                ErrorCode::Internal,
+                "Acquisition thread panicked",
+            ));
         }
     }
+
+    pub fn status(&mut self) -> msg::response::Status {
+        self.join_finished_worker();
+        status(&self.control, &self.lasers)
+    }
+
+    /// Shared, so that slow work on the sessions does not hold the scanner.
+    pub fn sessions(&self) -> Arc<sessions::SessionStore> {
+        self.sessions.clone()
+    }
+}
+
+fn invalid_state(command: &str, state: ScannerState) -> ServerError {
+    ServerError::new(
+        ErrorCode::InvalidState,
+        format!("Cannot {command} while {state:?}"),
+    )
+}
+
+/// Reports `e` to the clients, with the code of the first typed error found
+/// among its causes.
+pub fn server_error(e: &anyhow::Error) -> ServerError {
+    if let Some(error) = e.chain().find_map(|c| c.downcast_ref::<ServerError>()) {
+        return error.clone();
+    }
+    let code = e
+        .chain()
+        .find_map(|c| c.downcast_ref::<cameras::CameraError>())
+        .map_or(ErrorCode::Internal, cameras::CameraError::code);
+    let causes: Vec<String> = e.chain().skip(1).map(|c| c.to_string()).collect();
+    ServerError {
+        code,
+        message: e.to_string(),
+        details: (!causes.is_empty()).then(|| causes.join(": ")),
+    }
+}
+
+fn status(control: &ScanControl, lasers: &dyn LaserDriver) -> msg::response::Status {
+    let control = control.snapshot();
+    msg::response::Status {
+        state: control.state,
+        lasers: msg::response::LasersData {
+            laser_1: lasers.is_on(Laser::Left),
+            laser_2: lasers.is_on(Laser::Right),
+        },
+        angle_deg: control.angle_deg,
+        last_error: control.last_error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scans_are_paused_resumed_and_stopped() {
+        let control = Arc::new(ScanControl::new());
+        control.begin().unwrap();
+        assert_eq!(control.begin(), Err(ScannerState::Scanning));
+        assert!(control.checkpoint());
+
+        let resume = |s| s == ScannerState::Paused;
+        assert_eq!(
+            control.transition(resume, ScannerState::Scanning),
+            Err(ScannerState::Scanning)
+        );
+        control
+            .transition(|s| s == ScannerState::Scanning, ScannerState::Paused)
+            .unwrap();
+        // the acquisition waits at its next checkpoint until resumed
+        let acquisition = {
+            let control = control.clone();
+            std::thread::spawn(move || control.checkpoint())
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!acquisition.is_finished());
+        control.transition(resume, ScannerState::Scanning).unwrap();
+        assert!(acquisition.join().unwrap());
+
+        control.set(ScannerState::Stopping);
+        assert!(!control.checkpoint());
+        assert_eq!(control.begin(), Err(ScannerState::Stopping));
+    }
+
+    #[test]
+    fn a_new_scan_clears_the_last_error() {
+        let control = ScanControl::new();
+        control.begin().unwrap();
+        control.set_angle(90_f32);
+        control.fail(ServerError::new(ErrorCode::MotorFault, "stalled"));
+        let failed = control.snapshot();
+        assert_eq!(failed.state, ScannerState::Error);
+        assert!(failed.last_error.is_some());
+
+        control.begin().unwrap();
+        let restarted = control.snapshot();
+        assert_eq!(restarted.state, ScannerState::Scanning);
+        assert_eq!(restarted.angle_deg, 0_f32);
+        assert!(restarted.last_error.is_none());
+    }
 }
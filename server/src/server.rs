@@ -1,15 +1,109 @@
+use crate::export;
+use crate::meshing::{self, MeshParameters};
+use crate::registration::{self, RegistrationParameters};
 use crate::scanner;
+use crate::sessions::SessionStore;
 use log::{error, info, warn};
-use msg::response::Response;
-use std::net::{SocketAddr, TcpStream};
-use std::sync::{mpsc, Arc};
+use msg::command::{Capability, ExportFormat, Hello, PointCloudFormat, Request, ScanParameters};
+use msg::response::{
+    ErrorCode, File, IncompatibleProtocol, Response, ServerError, ServerHello, ServerMessage,
+};
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex};
 use tungstenite;
 
-pub fn run_websocket_server(port: u16, scanner: &mut scanner::Scanner) -> anyhow::Result<()> {
+const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+const READ_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(50);
+
+type ClientId = u64;
+
+/// Connected clients and the one controlling the scanner, if any.
+#[derive(Default)]
+struct Clients {
+    senders: HashMap<ClientId, mpsc::Sender<ServerMessage>>,
+    next_id: ClientId,
+    controller: Option<ClientId>,
+}
+
+impl Clients {
+    fn add(&mut self, sender: mpsc::Sender<ServerMessage>) -> ClientId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.senders.insert(id, sender);
+        id
+    }
+
+    fn remove(&mut self, id: ClientId) {
+        self.senders.remove(&id);
+        self.release_control(id);
+    }
+
+    /// Gives control to `id` unless another client already holds it.
+    fn take_control(&mut self, id: ClientId) -> anyhow::Result<()> {
+        match self.controller {
+            Some(controller) if controller != id => Err(ServerError::new(
+                ErrorCode::NotInControl,
+                "The scanner is controlled by another client",
+            )
+            .into()),
+            Some(_) => Ok(()),
+            None => {
+                info!("Client {id} took control of the scanner");
+                self.controller = Some(id);
+                Ok(())
+            }
+        }
+    }
+
+    fn release_control(&mut self, id: ClientId) {
+        if self.controller == Some(id) {
+            info!("Client {id} released control of the scanner");
+            self.controller = None;
+        }
+    }
+
+    fn broadcast(&self, response: &Response) {
+        for (id, sender) in &self.senders {
+            if let Err(e) = sender.send(ServerMessage::Event(response.clone())) {
+                warn!("Failed to queue message for client {id}: {e}");
+            }
+        }
+    }
+}
+
+/// State shared by the connections.
+struct Shared {
+    scanner: Mutex<scanner::Scanner>,
+    clients: Mutex<Clients>,
+    /// Messages for every client: scanned data and status changes
+    broadcast: mpsc::Sender<Response>,
+}
+
+pub fn run_websocket_server(port: u16, scanner: scanner::Scanner) -> anyhow::Result<()> {
     info!("Starting WebSocket server...");
     let connection_string = format!("0.0.0.0:{port}");
-    let server = std::net::TcpListener::bind(connection_string)?;
+    let server = TcpListener::bind(connection_string)?;
     info!("WebSocket server listening for incoming connections on port {port}");
+    serve(server, scanner)
+}
+
+/// Serves each client on its own thread. All of them receive the scan data,
+/// but only one at a time can control the scanner.
+fn serve(server: TcpListener, scanner: scanner::Scanner) -> anyhow::Result<()> {
+    let (broadcast, broadcast_queue) = mpsc::channel::<Response>();
+    let shared = Arc::new(Shared {
+        scanner: Mutex::new(scanner),
+        clients: Mutex::new(Clients::default()),
+        broadcast,
+    });
+
+    let broadcaster = shared.clone();
+    std::thread::spawn(move || {
+        for response in broadcast_queue {
+            broadcaster.clients.lock().unwrap().broadcast(&response);
+        }
+    });
 
     for stream in server.incoming() {
         let stream = match stream {
@@ -29,65 +123,53 @@ pub fn run_websocket_server(port: u16, scanner: &mut scanner::Scanner) -> anyhow
         };
 
         info!("New connection from {}", addr);
-        let client = match tungstenite::accept(stream) {
-            Ok(client) => client,
-            Err(e) => {
-                error!("Failed to accept WebSocket connection: {e}");
-                continue;
-            }
-        };
-
-        info!("WebSocket client connected: {}", addr);
-
-        match handle_connection(client, &addr, scanner) {
+        let shared = shared.clone();
+        std::thread::spawn(move || match accept_client(stream, &addr, &shared) {
             Ok(_) => info!("Closed connection with {addr}"),
             Err(e) => {
                 error!("Error: {e} - connection with {addr} closed")
             }
-        }
+        });
     }
 
-    return Ok(());
+    Ok(())
+}
+
+fn accept_client(stream: TcpStream, addr: &SocketAddr, shared: &Shared) -> anyhow::Result<()> {
+    // the handshake is given time to complete, a timeout in the middle of it
+    // would drop the client
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let client = tungstenite::accept(stream)?;
+    // reads must not hold the connection forever, the queued messages are
+    // sent between them
+    client.get_ref().set_read_timeout(Some(READ_TIMEOUT))?;
+    info!("WebSocket client connected: {}", addr);
+    handle_connection(client, addr, shared)
 }
 
 fn handle_connection(
-    connection: tungstenite::WebSocket<TcpStream>,
+    mut connection: tungstenite::WebSocket<TcpStream>,
     peer_address: &SocketAddr,
-    scanner: &mut scanner::Scanner,
+    shared: &Shared,
 ) -> anyhow::Result<()> {
-    let connection = Arc::new(std::sync::Mutex::new(connection));
-    let receiver = connection.clone();
-    let sender = connection.clone();
-
-    let (send_msg, outgoing_msgs) = mpsc::channel::<Response>();
-
-    let sender_thread = std::thread::spawn(move || {
-        info!("Sender thread started");
-        for msg in outgoing_msgs {
-            let msg = match serde_json::to_string(&msg) {
-                Ok(s) => {
-                    info!("Serialized message: {s}");
-                    tungstenite::Message::text(s)
-                }
-                Err(e) => {
-                    error!("Failed to serialize message: {e}");
-                    continue;
-                }
-            };
-
-            let mut sender = sender.lock().unwrap();
-            if let Err(e) = sender.write(msg) {
-                error!("Failed to send message: {e}");
-            }
-        }
-        info!("Sender thread finished");
-    });
+    let (send_msg, outgoing_msgs) = mpsc::channel::<ServerMessage>();
+    let client_id = shared.clients.lock().unwrap().add(send_msg.clone());
+    info!("Client {peer_address} has id {client_id}");
+    let mut format = PointCloudFormat::default();
 
     loop {
-        let message_res = receiver.lock().unwrap().read();
-        let message = match message_res {
+        send_queued(&mut connection, &outgoing_msgs, format);
+        let message = match connection.read() {
             Ok(msg) => msg,
             Err(e) => match e {
+                tungstenite::Error::Io(ref io_error)
+                    if matches!(
+                        io_error.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue;
+                }
                 tungstenite::Error::ConnectionClosed => {
                     info!("Connection closed by client: {peer_address}");
                     break;
@@ -101,66 +183,753 @@ fn handle_connection(
         };
 
         info!("Received message: {:?}", message);
-        let response = match message {
+        let unparsed_reply = |response| ServerMessage::Reply { id: None, response };
+        let reply = match message {
             tungstenite::Message::Close(_) => {
                 info!("Client {peer_address} requested disconnection");
-                Response::Close
+                ServerMessage::Event(Response::Close)
             }
             tungstenite::Message::Text(text) => {
                 info!("Text message received: {text}");
-                match serde_json::from_str(&text) {
-                    Ok(command) => process_message(command, scanner, &send_msg),
+                match serde_json::from_str::<Request>(&text) {
+                    Ok(request) => ServerMessage::Reply {
+                        id: Some(request.id),
+                        response: process_message(request.command, client_id, &mut format, shared),
+                    },
                     Err(e) => {
-                        error!("Failed to parse command: {e}");
-                        Response::Error(format!("Invalid command: {e}"))
+                        error!("Failed to parse request: {e}");
+                        unparsed_reply(invalid_command(format!("Invalid request: {e}")))
                     }
                 }
             }
             tungstenite::Message::Binary(_) => {
                 warn!("Binary message received, not supported");
-                Response::Error("Binary messages are not supported".to_string())
+                unparsed_reply(invalid_command("Binary messages are not supported"))
             }
             _ => {
                 warn!("Unsupported message type");
-                Response::Error("Unsupported message type".to_string())
+                unparsed_reply(invalid_command("Unsupported message type"))
             }
         };
 
-        let res = send_msg.send(response);
+        let refused = matches!(
+            reply,
+            ServerMessage::Reply {
+                response: Response::IncompatibleProtocol(_),
+                ..
+            }
+        );
+        let res = send_msg.send(reply);
         if let Err(e) = res {
             error!("Internal send queue broken: {e}");
             error!("Closing connection with {peer_address}");
             break;
         }
+        if refused {
+            info!("Closing connection with {peer_address}: incompatible protocol");
+            break;
+        }
     }
 
-    drop(send_msg); // Close the sender channel to stop the sender thread
-    sender_thread.join().expect("Failed to join sender thread");
-    return Ok(());
+    shared.clients.lock().unwrap().remove(client_id);
+    // the last replies, such as a refused handshake, go out before closing
+    send_queued(&mut connection, &outgoing_msgs, format);
+    // no-op if the client already closed the connection
+    let _ = connection.close(None);
+    Ok(())
+}
+
+/// Sends the messages queued for the client, in the point cloud `format` it
+/// asked for.
+fn send_queued(
+    connection: &mut tungstenite::WebSocket<TcpStream>,
+    queue: &mpsc::Receiver<ServerMessage>,
+    format: PointCloudFormat,
+) {
+    for msg in queue.try_iter() {
+        let msg = match serialize(&msg, format) {
+            Ok(msg) => msg,
+            Err(e) => {
+                error!("Failed to serialize message: {e}");
+                continue;
+            }
+        };
+        if let Err(e) = connection.send(msg) {
+            error!("Failed to send message: {e}");
+        }
+    }
+}
+
+fn invalid_command(message: impl Into<String>) -> Response {
+    Response::Error(ServerError::new(ErrorCode::InvalidCommand, message))
+}
+
+/// Point clouds are sent in the format requested by the client, everything
+/// else as JSON text.
+fn serialize(
+    message: &ServerMessage,
+    format: PointCloudFormat,
+) -> anyhow::Result<tungstenite::Message> {
+    let msg = match (message, format) {
+        (ServerMessage::Event(Response::PointCloud(cloud)), PointCloudFormat::Binary) => {
+            let bytes = msg::binary::encode_point_cloud(cloud)?;
+            info!("Serialized point cloud: {} bytes", bytes.len());
+            tungstenite::Message::binary(bytes)
+        }
+        _ => {
+            let s = serde_json::to_string(message)?;
+            info!("Serialized message: {s}");
+            tungstenite::Message::text(s)
+        }
+    };
+    Ok(msg)
+}
+
+/// Answers the client hello, refusing clients built for another protocol
+/// version.
+fn handshake(client: ClientId, hello: &Hello) -> Response {
+    info!(
+        "Client {client} is {} with protocol version {} and capabilities {:?}",
+        hello.client_name, hello.protocol_version, hello.capabilities
+    );
+    if hello.protocol_version != msg::PROTOCOL_VERSION {
+        warn!(
+            "Client {client} protocol version {} is not supported, expected {}",
+            hello.protocol_version,
+            msg::PROTOCOL_VERSION
+        );
+        return Response::IncompatibleProtocol(IncompatibleProtocol {
+            server_version: msg::PROTOCOL_VERSION,
+            client_version: hello.protocol_version,
+        });
+    }
+    Response::Hello(ServerHello {
+        protocol_version: msg::PROTOCOL_VERSION,
+        server_name: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        capabilities: vec![Capability::BinaryPointCloud],
+    })
 }
 
 fn process_message(
     command: msg::command::Command,
-    scanner: &mut scanner::Scanner,
-    sender: &mpsc::Sender<Response>,
+    client: ClientId,
+    format: &mut PointCloudFormat,
+    shared: &Shared,
 ) -> Response {
     use msg::command::Command as cmd;
     let response = match command {
-        cmd::Status => Ok(Response::Status(scanner.status())),
-        cmd::Replay => replay(scanner, sender.clone()),
+        cmd::Hello(ref hello) => Ok(handshake(client, hello)),
+        cmd::Status => Ok(Response::Status(shared.scanner.lock().unwrap().status())),
+        cmd::ReleaseControl => {
+            shared.clients.lock().unwrap().release_control(client);
+            Ok(Response::Ok)
+        }
+        cmd::SetPointCloudFormat(new_format) => {
+            info!("Client {client} receives point clouds as {new_format:?}");
+            *format = new_format;
+            Ok(Response::Ok)
+        }
+        cmd::ListSessions => {
+            let scanner = shared.scanner.lock().unwrap();
+            scanner.sessions().list().map(Response::Sessions)
+        }
+        cmd::GetSession(ref id) => {
+            let scanner = shared.scanner.lock().unwrap();
+            scanner.sessions().load(id).map(Response::Session)
+        }
+        cmd::RenameSession { ref id, ref name } => {
+            let scanner = shared.scanner.lock().unwrap();
+            scanner.sessions().rename(id, name).map(|()| Response::Ok)
+        }
+        cmd::DeleteSession(ref id) => {
+            let scanner = shared.scanner.lock().unwrap();
+            scanner.sessions().delete(id).map(|()| Response::Ok)
+        }
+        cmd::ExportSession {
+            ref id,
+            format,
+            mesh,
+        } => {
+            let sessions = shared.scanner.lock().unwrap().sessions();
+            export_session(&sessions, id, format, mesh)
+        }
+        cmd::MeshSession(ref id) => {
+            let sessions = shared.scanner.lock().unwrap().sessions();
+            meshing::mesh_session(&sessions, id, &MeshParameters::default()).map(|mesh| {
+                Response::Mesh(msg::response::Mesh {
+                    vertices: mesh.vertices.points,
+                    triangles: mesh.triangles,
+                })
+            })
+        }
+        cmd::RegisterSession { ref id, ref onto } => {
+            let sessions = shared.scanner.lock().unwrap().sessions();
+            let params = RegistrationParameters::default();
+            registration::register_sessions(&sessions, id, onto, &params)
+                .and_then(|info| sessions.load(&info.id))
+                .map(Response::Session)
+        }
+        _ => control_scanner(&command, client, shared),
     };
 
     match response {
         Ok(res) => res,
-        Err(e) => Response::Error(format!("Error processing command {command:?}: {e}")),
+        Err(e) => {
+            error!("Error processing command {command:?}: {e:#}");
+            Response::Error(scanner::server_error(&e))
+        }
+    }
+}
+
+/// Runs a command changing the scanner state, taking control of the scanner
+/// if nobody holds it. The new status is broadcast to every client.
+fn control_scanner(
+    command: &msg::command::Command,
+    client: ClientId,
+    shared: &Shared,
+) -> anyhow::Result<Response> {
+    use msg::command::Command as cmd;
+    shared.clients.lock().unwrap().take_control(client)?;
+
+    let mut scanner = shared.scanner.lock().unwrap();
+    let sender = shared.broadcast.clone();
+    match command {
+        cmd::Replay => replay(&mut scanner, sender)?,
+        cmd::StartScan(params) => start_scan(&mut scanner, params, sender)?,
+        cmd::Stop => scanner.stop()?,
+        cmd::Pause => scanner.pause()?,
+        cmd::Resume => scanner.resume()?,
+        cmd::Hello(_)
+        | cmd::Status
+        | cmd::ReleaseControl
+        | cmd::SetPointCloudFormat(_)
+        | cmd::ListSessions
+        | cmd::GetSession(_)
+        | cmd::RenameSession { .. }
+        | cmd::DeleteSession(_)
+        | cmd::ExportSession { .. }
+        | cmd::MeshSession(_)
+        | cmd::RegisterSession { .. } => unreachable!("not a scanner command"),
     }
+
+    let _ = shared.broadcast.send(Response::Status(scanner.status()));
+    Ok(Response::Ok)
 }
 
-fn replay(
+fn replay(scanner: &mut scanner::Scanner, sender: mpsc::Sender<Response>) -> anyhow::Result<()> {
+    info!("Replay command received. Starting replay...");
+    let params = ScanParameters {
+        settle_ms: 0,
+        ..Default::default()
+    };
+    scanner.start(&params, sender)?;
+    Ok(())
+}
+
+fn start_scan(
     scanner: &mut scanner::Scanner,
+    params: &ScanParameters,
     sender: mpsc::Sender<Response>,
+) -> anyhow::Result<()> {
+    info!("Start scan command received");
+    scanner.start(params, sender)
+}
+
+fn export_session(
+    sessions: &SessionStore,
+    id: &str,
+    format: ExportFormat,
+    mesh: bool,
 ) -> anyhow::Result<Response> {
-    info!("Replay command received. Starting replay...");
-    scanner.start(sender)?;
-    return Ok(msg::response::Response::Ok);
+    let session = sessions.load(id)?;
+    let mut name = session.info.name.clone();
+    let cloud = export::session_cloud(session);
+    let exporter = export::make_exporter(format);
+    let mut data = Vec::new();
+    if mesh {
+        let axis = sessions.calibration(id)?.turntable_axis;
+        let mesh = meshing::reconstruct(&cloud, &axis, &MeshParameters::default())?;
+        exporter.write(&mut data, &mesh)?;
+        name += "-mesh";
+    } else {
+        exporter.write(&mut data, &cloud)?;
+    }
+    let name = format!("{name}.{}", exporter.extension());
+    info!("Exporting session {id} as {name}, {} bytes", data.len());
+    Ok(Response::File(File { name, data }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cameras, imgproc, peak};
+    use msg::command::{Command, RequestId};
+    use msg::response::{ScannerState, SessionOutcome};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::{Duration, Instant};
+
+    type Client = tungstenite::WebSocket<tungstenite::stream::MaybeTlsStream<TcpStream>>;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Starts a server replaying `frames` blank images, returns its address.
+    fn start_server(name: &str, frames: u32) -> SocketAddr {
+        let blank = image::DynamicImage::ImageLuma8(image::GrayImage::new(64, 48));
+        start_server_with_frames(name, &vec![blank; frames as usize])
+    }
+
+    /// Starts a server replaying `frames` in order, returns its address.
+    fn start_server_with_frames(name: &str, frames: &[image::DynamicImage]) -> SocketAddr {
+        let image_dir = std::env::temp_dir().join(format!("{name}_{}", std::process::id()));
+        let sessions_dir = image_dir.with_extension("sessions");
+        std::fs::create_dir_all(&image_dir).unwrap();
+        for (i, frame) in frames.iter().enumerate() {
+            frame.save(image_dir.join(format!("{i:03}.png"))).unwrap();
+        }
+
+        let detector = imgproc::LaserLineDetector::new(
+            peak::PeakDetectorType::CenterOfMass,
+            imgproc::DetectionMode::AllPeaks,
+        );
+        let scanner = scanner::Scanner::new(
+            cameras::CameraType::DiskLoader(image_dir),
+            "127.0.0.1:9876".parse().unwrap(),
+            std::path::Path::new("calibration.json"),
+            detector,
+            cameras::AcquisitionMode::LaserOnly,
+            &sessions_dir,
+            &crate::postprocess::PostProcessOptions::default(),
+        )
+        .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || serve(listener, scanner));
+        address
+    }
+
+    fn connect(address: SocketAddr) -> Client {
+        let (client, _) = tungstenite::connect(format!("ws://{address}")).unwrap();
+        if let tungstenite::stream::MaybeTlsStream::Plain(stream) = client.get_ref() {
+            stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        }
+        client
+    }
+
+    fn send(client: &mut Client, command: Command) -> RequestId {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let text = serde_json::to_string(&Request { id, command }).unwrap();
+        client.send(tungstenite::Message::text(text)).unwrap();
+        id
+    }
+
+    fn read(client: &mut Client) -> ServerMessage {
+        match client.read().unwrap() {
+            tungstenite::Message::Binary(bytes) => ServerMessage::Event(Response::PointCloud(
+                msg::binary::decode_point_cloud(&bytes).unwrap(),
+            )),
+            message => serde_json::from_str(message.to_text().unwrap()).unwrap(),
+        }
+    }
+
+    /// Reads messages until the reply to request `id`.
+    fn wait_for_reply(client: &mut Client, id: RequestId) -> Response {
+        let start = Instant::now();
+        while start.elapsed() < TIMEOUT {
+            match read(client) {
+                ServerMessage::Reply {
+                    id: Some(reply_id),
+                    response,
+                } if reply_id == id => return response,
+                _ => {}
+            }
+        }
+        panic!("no reply to request {id} within {TIMEOUT:?}");
+    }
+
+    /// Reads messages until an event matching `expected`.
+    fn wait_for_event(client: &mut Client, expected: impl Fn(&Response) -> bool) -> Response {
+        let start = Instant::now();
+        while start.elapsed() < TIMEOUT {
+            if let ServerMessage::Event(response) = read(client) {
+                if expected(&response) {
+                    return response;
+                }
+            }
+        }
+        panic!("no matching event within {TIMEOUT:?}");
+    }
+
+    /// Skips the messages until the first point cloud, in any format.
+    fn next_point_cloud(client: &mut Client) -> tungstenite::Message {
+        loop {
+            let message = client.read().unwrap();
+            let text = message.to_text().unwrap_or_default();
+            if message.is_binary() || text.starts_with("{\"Event\":{\"PointCloud\"") {
+                return message;
+            }
+        }
+    }
+
+    fn request(client: &mut Client, command: Command) -> Response {
+        let id = send(client, command);
+        wait_for_reply(client, id)
+    }
+
+    fn assert_error(response: Response, expected: ErrorCode) {
+        match response {
+            Response::Error(e) => assert_eq!(e.code, expected, "{e}"),
+            _ => panic!("expected a {expected:?} error"),
+        }
+    }
+
+    fn slow_scan() -> Command {
+        Command::StartScan(ScanParameters {
+            step_deg: 10_f32,
+            settle_ms: 100,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn clients_are_served_concurrently() {
+        let address = start_server("scanner_server_concurrent", 1);
+        let mut a = connect(address);
+        let mut b = connect(address);
+
+        // a connection does not block the others
+        let a_id = send(&mut a, Command::Status);
+        let b_id = send(&mut b, Command::Status);
+        for (client, id) in [(&mut b, b_id), (&mut a, a_id)] {
+            let reply = wait_for_reply(client, id);
+            assert!(matches!(reply, Response::Status(s) if s.state == ScannerState::Idle));
+        }
+    }
+
+    #[test]
+    fn clients_slow_to_upgrade_are_accepted() {
+        let address = start_server("scanner_server_slow_upgrade", 1);
+        let stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        // the upgrade request comes after several read timeouts of the server
+        std::thread::sleep(READ_TIMEOUT * 4);
+        let stream = tungstenite::stream::MaybeTlsStream::Plain(stream);
+        let (mut client, _) = tungstenite::client(format!("ws://{address}"), stream).unwrap();
+        let reply = request(&mut client, Command::Status);
+        assert!(matches!(reply, Response::Status(s) if s.state == ScannerState::Idle));
+    }
+
+    #[test]
+    fn invalid_requests_get_a_reply_without_id() {
+        let address = start_server("scanner_server_invalid", 1);
+        let mut client = connect(address);
+        let text = r#"{"id":1,"command":"Unknown"}"#;
+        client.send(tungstenite::Message::text(text)).unwrap();
+        assert!(matches!(
+            read(&mut client),
+            ServerMessage::Reply {
+                id: None,
+                response: Response::Error(ServerError {
+                    code: ErrorCode::InvalidCommand,
+                    ..
+                })
+            }
+        ));
+    }
+
+    #[test]
+    fn incompatible_clients_are_refused() {
+        let address = start_server("scanner_server_handshake", 1);
+        let hello = |protocol_version| {
+            Command::Hello(Hello {
+                protocol_version,
+                client_name: "test".to_string(),
+                capabilities: vec![Capability::BinaryPointCloud],
+            })
+        };
+
+        let mut client = connect(address);
+        let reply = request(&mut client, hello(msg::PROTOCOL_VERSION));
+        assert!(matches!(reply, Response::Hello(h) if h.protocol_version == msg::PROTOCOL_VERSION));
+
+        let mut client = connect(address);
+        let reply = request(&mut client, hello(msg::PROTOCOL_VERSION + 1));
+        assert!(matches!(
+            reply,
+            Response::IncompatibleProtocol(IncompatibleProtocol {
+                server_version: msg::PROTOCOL_VERSION,
+                client_version,
+            }) if client_version == msg::PROTOCOL_VERSION + 1
+        ));
+        assert!(matches!(client.read(), Ok(tungstenite::Message::Close(_))));
+    }
+
+    #[test]
+    fn scan_data_is_broadcast_to_every_client() {
+        let address = start_server("scanner_server_broadcast", 36);
+        let mut a = connect(address);
+        let mut b = connect(address);
+        let binary = Command::SetPointCloudFormat(PointCloudFormat::Binary);
+        assert!(matches!(request(&mut b, binary), Response::Ok));
+
+        assert!(matches!(request(&mut a, slow_scan()), Response::Ok));
+        assert!(next_point_cloud(&mut a).is_text());
+        assert!(next_point_cloud(&mut b).is_binary());
+        for client in [&mut a, &mut b] {
+            wait_for_event(client, |r| matches!(r, Response::Progress(_)));
+        }
+        assert!(matches!(request(&mut a, Command::Stop), Response::Ok));
+        wait_for_event(
+            &mut b,
+            |r| matches!(r, Response::Status(s) if s.state == ScannerState::Idle),
+        );
+    }
+
+    #[test]
+    fn only_one_client_controls_the_scanner() {
+        let address = start_server("scanner_server_control", 36);
+        let mut a = connect(address);
+        let mut b = connect(address);
+
+        assert!(matches!(request(&mut a, slow_scan()), Response::Ok));
+        assert_error(request(&mut a, slow_scan()), ErrorCode::Busy);
+        assert_error(request(&mut b, Command::Pause), ErrorCode::NotInControl);
+        // reading the status does not need control
+        assert!(matches!(
+            request(&mut b, Command::Status),
+            Response::Status(_)
+        ));
+
+        assert!(matches!(
+            request(&mut a, Command::ReleaseControl),
+            Response::Ok
+        ));
+        assert!(matches!(request(&mut b, Command::Pause), Response::Ok));
+        assert_error(request(&mut a, Command::Resume), ErrorCode::NotInControl);
+
+        // disconnecting releases control as well
+        b.close(None).unwrap();
+        drop(b);
+        let start = Instant::now();
+        loop {
+            match request(&mut a, Command::Resume) {
+                Response::Ok => break,
+                _ if start.elapsed() < TIMEOUT => std::thread::sleep(Duration::from_millis(50)),
+                _ => panic!("control was not released on disconnection"),
+            }
+        }
+        assert!(matches!(request(&mut a, Command::Stop), Response::Ok));
+    }
+
+    /// A vertical laser line in the right half of the calibrated image.
+    fn laser_line_frames(count: usize) -> Vec<image::DynamicImage> {
+        let laser = image::GrayImage::from_fn(720, 1280, |x, _| match x {
+            500 => image::Luma([255]),
+            _ => image::Luma([0]),
+        });
+        vec![laser.into(); count]
+    }
+
+    /// Scans the frames in three steps, returns the client and the session.
+    fn scanned_session(name: &str, frames: &[image::DynamicImage]) -> (Client, String) {
+        let address = start_server_with_frames(name, frames);
+        let mut client = connect(address);
+        let scan = Command::StartScan(ScanParameters {
+            step_deg: 120_f32,
+            ..Default::default()
+        });
+        assert!(matches!(request(&mut client, scan), Response::Ok));
+        wait_for_event(
+            &mut client,
+            |r| matches!(r, Response::Status(s) if s.state == ScannerState::Idle),
+        );
+        let Response::Sessions(sessions) = request(&mut client, Command::ListSessions) else {
+            panic!("expected the sessions");
+        };
+        assert_eq!(sessions.len(), 1);
+        let id = sessions[0].id.clone();
+        (client, id)
+    }
+
+    fn export(client: &mut Client, id: &str, format: ExportFormat, mesh: bool) -> File {
+        let export = Command::ExportSession {
+            id: id.to_string(),
+            format,
+            mesh,
+        };
+        match request(client, export) {
+            Response::File(file) => file,
+            _ => panic!("expected a file"),
+        }
+    }
+
+    #[test]
+    fn finished_scans_are_kept_as_sessions() {
+        let blank = image::DynamicImage::ImageLuma8(image::GrayImage::new(64, 48));
+        let (mut client, id) = scanned_session("scanner_server_sessions", &vec![blank; 3]);
+        let Response::Sessions(sessions) = request(&mut client, Command::ListSessions) else {
+            panic!("expected the sessions");
+        };
+        assert!(matches!(sessions[0].outcome, SessionOutcome::Completed));
+        assert_eq!(sessions[0].steps.len(), 3);
+
+        let reply = request(&mut client, Command::GetSession(id.clone()));
+        assert!(matches!(reply, Response::Session(s) if s.info.id == id));
+        let delete = Command::DeleteSession(id.clone());
+        assert!(matches!(request(&mut client, delete), Response::Ok));
+        assert_error(
+            request(&mut client, Command::GetSession(id)),
+            ErrorCode::SessionNotFound,
+        );
+    }
+
+    #[test]
+    fn sessions_are_downloaded_as_ply() {
+        let (mut client, id) = scanned_session("scanner_server_ply", &laser_line_frames(3));
+        let file = export(&mut client, &id, ExportFormat::PlyAscii, false);
+        assert_eq!(file.name, format!("{id}.ply"));
+        assert!(file.data.starts_with(b"ply\nformat ascii 1.0\n"));
+        let file = export(&mut client, &id, ExportFormat::PlyBinary, false);
+        assert_eq!(file.name, format!("{id}.ply"));
+        assert!(file
+            .data
+            .starts_with(b"ply\nformat binary_little_endian 1.0\n"));
+    }
+
+    #[test]
+    fn sessions_are_downloaded_in_every_format() {
+        let (mut client, id) = scanned_session("scanner_server_formats", &laser_line_frames(3));
+        let Response::Session(session) = request(&mut client, Command::GetSession(id.clone()))
+        else {
+            panic!("expected the session");
+        };
+        assert_eq!(session.cloud.points.len(), 3 * 1280);
+
+        let point_formats = [
+            ExportFormat::PlyAscii,
+            ExportFormat::PlyBinary,
+            ExportFormat::Xyz,
+            ExportFormat::Csv,
+            ExportFormat::Pcd,
+        ];
+        for format in point_formats {
+            let exporter = export::make_exporter(format);
+            let file = export(&mut client, &id, format, false);
+            assert_eq!(file.name, format!("{id}.{}", exporter.extension()));
+            let cloud = exporter.read(&file.data).unwrap();
+            assert_eq!(cloud.vertices.points, session.cloud.points, "{format:?}");
+        }
+
+        // STL only stores triangles, the others store both
+        let points_as_stl = Command::ExportSession {
+            id: id.clone(),
+            format: ExportFormat::Stl,
+            mesh: false,
+        };
+        assert!(matches!(
+            request(&mut client, points_as_stl),
+            Response::Error(_)
+        ));
+        for format in [ExportFormat::Obj, ExportFormat::Stl, ExportFormat::Glb] {
+            let exporter = export::make_exporter(format);
+            let file = export(&mut client, &id, format, true);
+            assert_eq!(file.name, format!("{id}-mesh.{}", exporter.extension()));
+            let mesh = exporter.read(&file.data).unwrap();
+            assert!(!mesh.triangles.is_empty(), "{format:?}");
+        }
+    }
+
+    #[test]
+    fn sessions_are_meshed() {
+        let (mut client, id) = scanned_session("scanner_server_mesh", &laser_line_frames(3));
+        let Response::Mesh(mesh) = request(&mut client, Command::MeshSession(id.clone())) else {
+            panic!("expected the mesh");
+        };
+        assert!(!mesh.triangles.is_empty());
+        let n = mesh.vertices.len() as u32;
+        assert!(mesh.triangles.iter().flatten().all(|&i| i < n));
+
+        let file = export(&mut client, &id, ExportFormat::PlyAscii, true);
+        assert_eq!(file.name, format!("{id}-mesh.ply"));
+        let read = export::make_exporter(ExportFormat::PlyAscii)
+            .read(&file.data)
+            .unwrap();
+        assert_eq!(read.triangles, mesh.triangles);
+    }
+
+    #[test]
+    fn sessions_are_not_registered_onto_themselves() {
+        let blank = image::DynamicImage::ImageLuma8(image::GrayImage::new(64, 48));
+        let (mut client, id) = scanned_session("scanner_server_register", &vec![blank; 3]);
+        let register = Command::RegisterSession {
+            id: id.clone(),
+            onto: id,
+        };
+        assert_error(request(&mut client, register), ErrorCode::InvalidParameters);
+    }
+
+    #[test]
+    fn points_are_colored_from_the_color_frames() {
+        // at each step a vertical laser line in the right half of the
+        // calibrated image, then a color frame whose red and green tell the
+        // pixel
+        let (width, height) = (720, 1280);
+        let laser = image::GrayImage::from_fn(width, height, |x, _| match x {
+            500 => image::Luma([255]),
+            _ => image::Luma([0]),
+        });
+        let color = |x: u32, y: u32| [(x / 3) as u8, (y / 5) as u8, 7];
+        let frame = image::RgbImage::from_fn(width, height, |x, y| image::Rgb(color(x, y)));
+        let frames: Vec<image::DynamicImage> = (0..3)
+            .flat_map(|_| [laser.clone().into(), frame.clone().into()])
+            .collect();
+        let address = start_server_with_frames("scanner_server_colors", &frames);
+        let mut client = connect(address);
+        let scan = Command::StartScan(ScanParameters {
+            step_deg: 120_f32,
+            color: true,
+            ..Default::default()
+        });
+        assert!(matches!(request(&mut client, scan), Response::Ok));
+        let Response::PointCloud(cloud) =
+            wait_for_event(&mut client, |r| matches!(r, Response::PointCloud(_)))
+        else {
+            unreachable!();
+        };
+        assert_eq!(cloud.points.len(), height as usize);
+        let (colors, pixels) = (cloud.colors.unwrap(), cloud.pixels.unwrap());
+        for (c, pixel) in colors.iter().zip(&pixels) {
+            assert_eq!(*c, color(pixel.x.round() as u32, pixel.y.round() as u32));
+        }
+        wait_for_event(
+            &mut client,
+            |r| matches!(r, Response::Status(s) if s.state == ScannerState::Idle),
+        );
+        let Response::Sessions(sessions) = request(&mut client, Command::ListSessions) else {
+            panic!("expected the sessions");
+        };
+        assert!(matches!(sessions[0].outcome, SessionOutcome::Completed));
+        let reply = request(&mut client, Command::GetSession(sessions[0].id.clone()));
+        let Response::Session(session) = reply else {
+            panic!("expected the session");
+        };
+        assert_eq!(session.cloud.colors.unwrap().len(), 3 * height as usize);
+    }
+
+    #[test]
+    fn color_frames_of_another_size_are_camera_faults() {
+        let laser = laser_line_frames(1).remove(0);
+        let color = image::RgbImage::new(64, 48).into();
+        let address = start_server_with_frames("scanner_server_color_size", &[laser, color]);
+        let mut client = connect(address);
+        let scan = Command::StartScan(ScanParameters {
+            step_deg: 120_f32,
+            color: true,
+            ..Default::default()
+        });
+        assert!(matches!(request(&mut client, scan), Response::Ok));
+        let error = wait_for_event(&mut client, |r| matches!(r, Response::Error(_)));
+        assert_error(error, ErrorCode::CameraFault);
+    }
 }
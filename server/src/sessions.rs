@@ -0,0 +1,397 @@
+use crate::calibration::Calibration;
+use crate::postprocess::PostProcess;
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use msg::command::ScanParameters;
+use msg::response::{
+    ErrorCode, PointCloud, Registration, ServerError, Session, SessionInfo, SessionOutcome,
+    StepRecord,
+};
+use std::path::{Path, PathBuf};
+
+const INFO_FILE: &str = "session.json";
+const CALIBRATION_FILE: &str = "calibration.json";
+/// Point clouds are stored in the `msg::binary` format
+const MERGED_CLOUD_FILE: &str = "merged.pcld";
+const STEPS_DIR: &str = "steps";
+
+/// Scans saved on disk, one directory per session:
+/// - `session.json`: parameters, timestamps and outcome
+/// - `calibration.json`: the calibration used for the scan
+/// - `steps/NNNN.pcld`: the points of each step, written as the scan goes
+/// - `merged.pcld`: all the points, written when the scan ends
+pub struct SessionStore {
+    root: PathBuf,
+}
+
+impl SessionStore {
+    pub fn open(root: &Path) -> Result<Self> {
+        std::fs::create_dir_all(root)
+            .with_context(|| format!("Failed to create sessions directory {}", root.display()))?;
+        Ok(Self {
+            root: root.to_path_buf(),
+        })
+    }
+
+    /// Marks the sessions left running by a previous server as interrupted,
+    /// only the server recording in this store may call it.
+    pub fn recover_interrupted(&self) -> Result<()> {
+        for mut session in self.list()? {
+            if matches!(session.outcome, SessionOutcome::Running) {
+                warn!("Session {} was interrupted", session.id);
+                session.outcome = SessionOutcome::Interrupted;
+                write_json(&self.root.join(&session.id).join(INFO_FILE), &session)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn create(
+        &self,
+        parameters: &ScanParameters,
+        calibration: &Calibration,
+    ) -> Result<SessionRecorder> {
+        let started_at_ms = now_ms();
+        let (id, dir) = self.new_session_dir(started_at_ms);
+        std::fs::create_dir_all(dir.join(STEPS_DIR))?;
+        write_json(&dir.join(CALIBRATION_FILE), calibration)?;
+
+        let info = SessionInfo {
+            name: id.clone(),
+            id,
+            parameters: parameters.clone(),
+            started_at_ms,
+            finished_at_ms: None,
+            outcome: SessionOutcome::Running,
+            steps: Vec::new(),
+            points: 0,
+            registration: None,
+        };
+        write_json(&dir.join(INFO_FILE), &info)?;
+        info!("Recording scan session {}", info.id);
+        Ok(SessionRecorder {
+            dir,
+            info,
+            cloud: PointCloud::default(),
+        })
+    }
+
+    /// Saves the merged cloud of two registered sessions as a new session in
+    /// the frame, and with the calibration, of the target.
+    pub fn save_registered(
+        &self,
+        registration: Registration,
+        cloud: PointCloud,
+    ) -> Result<SessionInfo> {
+        let source: SessionInfo =
+            read_json(&self.session_dir(&registration.source)?.join(INFO_FILE))?;
+        let target_dir = self.session_dir(&registration.target)?;
+        let target: SessionInfo = read_json(&target_dir.join(INFO_FILE))?;
+        check_not_running(&source)?;
+        check_not_running(&target)?;
+        let now = now_ms();
+        let (id, dir) = self.new_session_dir(now);
+        std::fs::create_dir_all(&dir)?;
+        std::fs::copy(
+            target_dir.join(CALIBRATION_FILE),
+            dir.join(CALIBRATION_FILE),
+        )?;
+        let merged = msg::binary::encode_point_cloud(&cloud)?;
+        write_atomically(&dir.join(MERGED_CLOUD_FILE), &merged)?;
+
+        let info = SessionInfo {
+            id,
+            name: format!("{} + {}", target.name, source.name),
+            parameters: target.parameters,
+            started_at_ms: now,
+            finished_at_ms: Some(now),
+            outcome: SessionOutcome::Completed,
+            steps: Vec::new(),
+            points: cloud.points.len() as u64,
+            registration: Some(Box::new(registration)),
+        };
+        // the session only shows up once complete
+        write_json(&dir.join(INFO_FILE), &info)?;
+        info!("Saved registered scan session {}", info.id);
+        Ok(info)
+    }
+
+    /// Sessions sorted by start time, unreadable ones are skipped.
+    pub fn list(&self) -> Result<Vec<SessionInfo>> {
+        let mut sessions = Vec::new();
+        for entry in std::fs::read_dir(&self.root)? {
+            let path = entry?.path().join(INFO_FILE);
+            if !path.exists() {
+                continue;
+            }
+            match read_json::<SessionInfo>(&path) {
+                Ok(info) => sessions.push(info),
+                Err(e) => warn!("Skipping session {}: {e:#}", path.display()),
+            }
+        }
+        sessions.sort_by_key(|s| s.started_at_ms);
+        Ok(sessions)
+    }
+
+    pub fn load(&self, id: &str) -> Result<Session> {
+        let dir = self.session_dir(id)?;
+        let info: SessionInfo = read_json(&dir.join(INFO_FILE))?;
+        let merged = dir.join(MERGED_CLOUD_FILE);
+        let cloud = if merged.exists() {
+            msg::binary::decode_point_cloud(&std::fs::read(merged)?)?
+        } else {
+            // running or interrupted, merge what has been saved so far
+            let mut cloud = PointCloud::default();
+            for step in &info.steps {
+                let bytes = std::fs::read(dir.join(STEPS_DIR).join(step_file(step.step)))?;
+                cloud.append(msg::binary::decode_point_cloud(&bytes)?);
+            }
+            cloud
+        };
+        Ok(Session { info, cloud })
+    }
+
+    /// Calibration used for the scan.
+    pub fn calibration(&self, id: &str) -> Result<Calibration> {
+        crate::calibration::load_calibration(&self.session_dir(id)?.join(CALIBRATION_FILE))
+    }
+
+    pub fn rename(&self, id: &str, name: &str) -> Result<()> {
+        let path = self.session_dir(id)?.join(INFO_FILE);
+        let mut info: SessionInfo = read_json(&path)?;
+        check_not_running(&info)?;
+        info.name = name.to_string();
+        write_json(&path, &info)
+    }
+
+    pub fn delete(&self, id: &str) -> Result<()> {
+        let dir = self.session_dir(id)?;
+        check_not_running(&read_json(&dir.join(INFO_FILE))?)?;
+        std::fs::remove_dir_all(&dir)?;
+        info!("Deleted scan session {id}");
+        Ok(())
+    }
+
+    fn new_session_dir(&self, started_at_ms: u64) -> (String, PathBuf) {
+        let mut id = format!("scan-{started_at_ms}");
+        let mut suffix = 1;
+        while self.root.join(&id).exists() {
+            id = format!("scan-{started_at_ms}-{suffix}");
+            suffix += 1;
+        }
+        let dir = self.root.join(&id);
+        (id, dir)
+    }
+
+    /// Ids come from the clients, they must not point outside of the store.
+    fn session_dir(&self, id: &str) -> Result<PathBuf> {
+        let valid = !id.is_empty()
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        let dir = self.root.join(id);
+        if !valid || !dir.join(INFO_FILE).exists() {
+            let message = format!("No scan session {id:?}");
+            return Err(ServerError::new(ErrorCode::SessionNotFound, message).into());
+        }
+        Ok(dir)
+    }
+}
+
+/// Saves a running scan, step by step.
+pub struct SessionRecorder {
+    dir: PathBuf,
+    info: SessionInfo,
+    cloud: PointCloud,
+}
+
+impl SessionRecorder {
+    pub fn record_step(&mut self, step: u32, angle_deg: f32, cloud: &PointCloud) -> Result<()> {
+        let path = self.dir.join(STEPS_DIR).join(step_file(step));
+        write_atomically(&path, &msg::binary::encode_point_cloud(cloud)?)?;
+        self.cloud.append(cloud.clone());
+
+        self.info.steps.push(StepRecord {
+            step,
+            angle_deg,
+            points: cloud.points.len() as u64,
+            captured_at_ms: now_ms(),
+        });
+        self.info.points = self.cloud.points.len() as u64;
+        write_json(&self.dir.join(INFO_FILE), &self.info)
+    }
+
+    /// Saves the merged cloud and the outcome, `error` is the reason of a
+    /// failed scan. The merged cloud goes through `post_process` first, the
+    /// step files keep the raw points.
+    pub fn finish(mut self, error: Option<ServerError>, post_process: &PostProcess) -> Result<()> {
+        let cloud = std::mem::take(&mut self.cloud);
+        let cloud = post_process.apply(cloud, &mut self.info.steps);
+        self.info.points = cloud.points.len() as u64;
+        let merged = msg::binary::encode_point_cloud(&cloud)?;
+        write_atomically(&self.dir.join(MERGED_CLOUD_FILE), &merged)?;
+
+        self.info.finished_at_ms = Some(now_ms());
+        self.info.outcome = match error {
+            Some(error) => SessionOutcome::Failed(error),
+            None if self.info.steps.len() as u32 == self.info.parameters.steps() => {
+                SessionOutcome::Completed
+            }
+            None => SessionOutcome::Stopped,
+        };
+        info!(
+            "Scan session {} {:?} with {} points",
+            self.info.id, self.info.outcome, self.info.points
+        );
+        write_json(&self.dir.join(INFO_FILE), &self.info)
+    }
+}
+
+fn check_not_running(info: &SessionInfo) -> Result<()> {
+    if matches!(info.outcome, SessionOutcome::Running) {
+        let message = format!("Scan session {} is still running", info.id);
+        return Err(ServerError::new(ErrorCode::Busy, message).into());
+    }
+    Ok(())
+}
+
+fn step_file(step: u32) -> String {
+    format!("{step:04}.pcld")
+}
+
+fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    serde_json::from_str(&text).with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+fn write_json(path: &Path, value: &impl serde::Serialize) -> Result<()> {
+    write_atomically(path, serde_json::to_string_pretty(value)?.as_bytes())
+}
+
+/// Readers never see a partially written file, even if the server dies.
+fn write_atomically(path: &Path, bytes: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, bytes).with_context(|| format!("Failed to write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calibration() -> Calibration {
+        let text = std::fs::read_to_string("calibration.json").unwrap();
+        serde_json::from_str(&text).unwrap()
+    }
+
+    /// Points captured at `step`, as the scan sends them.
+    fn step_cloud(step: u32, points: &[glam::Vec3]) -> PointCloud {
+        PointCloud {
+            points: points.to_vec(),
+            steps: Some(vec![step; points.len()]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sessions_are_recorded_and_managed() {
+        let root = std::env::temp_dir().join(format!("scanner_sessions_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let store = SessionStore::open(&root).unwrap();
+        let params = ScanParameters {
+            step_deg: 120_f32,
+            ..Default::default()
+        };
+
+        let mut recorder = store.create(&params, &calibration()).unwrap();
+        let id = store.list().unwrap()[0].id.clone();
+        recorder
+            .record_step(0, 0_f32, &step_cloud(0, &[glam::Vec3::X]))
+            .unwrap();
+        recorder
+            .record_step(1, 120_f32, &step_cloud(1, &[glam::Vec3::Y, glam::Vec3::Z]))
+            .unwrap();
+
+        // a running session can be fetched but not changed
+        let running = store.load(&id).unwrap();
+        assert!(matches!(running.info.outcome, SessionOutcome::Running));
+        assert_eq!(running.cloud.points.len(), 3);
+        assert_eq!(running.cloud.steps, Some(vec![0, 1, 1]));
+        assert!(store.delete(&id).is_err());
+
+        recorder.finish(None, &PostProcess::default()).unwrap();
+        let sessions = store.list().unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert!(matches!(sessions[0].outcome, SessionOutcome::Stopped));
+        assert_eq!(sessions[0].steps.len(), 2);
+        assert!(root.join(&id).join(CALIBRATION_FILE).exists());
+
+        store.rename(&id, "mug").unwrap();
+        let session = store.load(&id).unwrap();
+        assert_eq!(session.info.name, "mug");
+        assert_eq!(
+            session.cloud.points,
+            vec![glam::Vec3::X, glam::Vec3::Y, glam::Vec3::Z]
+        );
+
+        let Err(not_found) = store.load("../sessions") else {
+            panic!("ids must not leave the sessions directory");
+        };
+        assert!(matches!(
+            not_found.downcast_ref::<ServerError>(),
+            Some(ServerError {
+                code: ErrorCode::SessionNotFound,
+                ..
+            })
+        ));
+        store.delete(&id).unwrap();
+        assert!(store.list().unwrap().is_empty());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn merged_clouds_are_post_processed() {
+        let root = std::env::temp_dir().join(format!("scanner_filtered_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let store = SessionStore::open(&root).unwrap();
+        let mut recorder = store
+            .create(&ScanParameters::default(), &calibration())
+            .unwrap();
+        let id = store.list().unwrap()[0].id.clone();
+        recorder
+            .record_step(0, 0_f32, &step_cloud(0, &[glam::Vec3::X, -glam::Vec3::X]))
+            .unwrap();
+        recorder
+            .record_step(1, 5_f32, &step_cloud(1, &[-glam::Vec3::Y]))
+            .unwrap();
+        recorder
+            .record_step(2, 10_f32, &step_cloud(2, &[glam::Vec3::Z]))
+            .unwrap();
+
+        let positive = pointcloud::Filter::CropBox(pointcloud::BoundingBox {
+            min: glam::Vec3::ZERO,
+            max: glam::Vec3::ONE,
+        });
+        let post_process = PostProcess {
+            filters: vec![positive],
+            ..Default::default()
+        };
+        recorder.finish(None, &post_process).unwrap();
+        let session = store.load(&id).unwrap();
+        assert_eq!(session.cloud.points, vec![glam::Vec3::X, glam::Vec3::Z]);
+        assert_eq!(session.cloud.steps, Some(vec![0, 2]));
+        assert_eq!(session.info.points, 2);
+        let counts: Vec<u64> = session.info.steps.iter().map(|s| s.points).collect();
+        assert_eq!(counts, vec![1, 0, 1]);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}